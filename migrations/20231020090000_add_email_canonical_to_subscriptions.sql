@@ -0,0 +1,116 @@
+-- Add migration script here
+-- `email_canonical` is the address as `SubscriberEmail::canonical` computes it, uniqueness is enforced on it
+-- so `Foo@Example.COM` and `foo@example.com` are the same subscriber
+BEGIN;
+    -- RFC 3492, for the internationalised labels of a domain
+    CREATE FUNCTION pg_temp.punycode(label TEXT) RETURNS TEXT AS $$
+    DECLARE
+        code_points INT[] := ARRAY(SELECT ascii(c) FROM regexp_split_to_table(label, '') AS c);
+        output TEXT := '';
+        n INT := 128;
+        delta INT := 0;
+        bias INT := 72;
+        basic INT;
+        handled INT;
+        m INT;
+        q INT;
+        k INT;
+        t INT;
+        c INT;
+    BEGIN
+        FOREACH c IN ARRAY code_points LOOP
+            IF c < 128 THEN
+                output := output || chr(c);
+            END IF;
+        END LOOP;
+        basic := length(output);
+        handled := basic;
+        IF basic > 0 THEN
+            output := output || '-';
+        END IF;
+        WHILE handled < cardinality(code_points) LOOP
+            SELECT min(x) INTO m FROM unnest(code_points) AS x WHERE x >= n;
+            delta := delta + (m - n) * (handled + 1);
+            n := m;
+            FOREACH c IN ARRAY code_points LOOP
+                IF c < n THEN
+                    delta := delta + 1;
+                END IF;
+                IF c = n THEN
+                    q := delta;
+                    k := 36;
+                    LOOP
+                        t := CASE WHEN k <= bias THEN 1 WHEN k >= bias + 26 THEN 26 ELSE k - bias END;
+                        EXIT WHEN q < t;
+                        output := output || pg_temp.punycode_digit(t + (q - t) % (36 - t));
+                        q := (q - t) / (36 - t);
+                        k := k + 36;
+                    END LOOP;
+                    output := output || pg_temp.punycode_digit(q);
+                    -- adapt the bias
+                    delta := CASE WHEN handled = basic THEN delta / 700 ELSE delta / 2 END;
+                    delta := delta + delta / (handled + 1);
+                    k := 0;
+                    WHILE delta > 455 LOOP
+                        delta := delta / 35;
+                        k := k + 36;
+                    END LOOP;
+                    bias := k + 36 * delta / (delta + 38);
+                    delta := 0;
+                    handled := handled + 1;
+                END IF;
+            END LOOP;
+            delta := delta + 1;
+            n := n + 1;
+        END LOOP;
+        RETURN output;
+    END
+    $$ LANGUAGE plpgsql IMMUTABLE;
+
+    CREATE FUNCTION pg_temp.punycode_digit(d INT) RETURNS TEXT AS $$
+        SELECT CASE WHEN d < 26 THEN chr(97 + d) ELSE chr(22 + d) END
+    $$ LANGUAGE sql IMMUTABLE;
+
+    -- the local part lowercased as ASCII, the domain mapped (NFKC, lowercase) and punycoded label by label
+    CREATE FUNCTION pg_temp.canonical_email(email TEXT) RETURNS TEXT AS $$
+        SELECT translate(substring(address FROM '^(.*)@'), 'ABCDEFGHIJKLMNOPQRSTUVWXYZ', 'abcdefghijklmnopqrstuvwxyz')
+            || '@'
+            || (
+                SELECT string_agg(
+                    CASE WHEN label ~ '^[\x01-\x7f]*$' THEN label ELSE 'xn--' || pg_temp.punycode(label) END,
+                    '.' ORDER BY ord
+                )
+                FROM regexp_split_to_table(
+                    lower(normalize(translate(substring(address FROM '@([^@]*)$'), '。．｡', '...'), NFKC)),
+                    '\.'
+                ) WITH ORDINALITY AS labels(label, ord)
+            )
+        FROM (SELECT trim(email) AS address) AS parts
+    $$ LANGUAGE sql IMMUTABLE;
+
+    ALTER TABLE subscriptions ADD COLUMN email_canonical TEXT;
+    UPDATE subscriptions SET email_canonical = pg_temp.canonical_email(email);
+
+    -- addresses that differ only in case are merged into one subscriber: the confirmed one, else the oldest,
+    -- which takes over the confirmation tokens of the others
+    CREATE TEMPORARY TABLE merged_subscriptions ON COMMIT DROP AS
+        SELECT id, first_value(id) OVER same_address AS kept_id
+        FROM subscriptions
+        WINDOW same_address AS (
+            PARTITION BY email_canonical
+            ORDER BY status = 'confirmed' DESC, subscribed_at, id
+        );
+    DELETE FROM merged_subscriptions WHERE id = kept_id;
+    UPDATE subscriptions_token
+        SET subscription_id = merged_subscriptions.kept_id
+        FROM merged_subscriptions
+        WHERE subscriptions_token.subscription_id = merged_subscriptions.id;
+    DELETE FROM subscriptions USING merged_subscriptions WHERE subscriptions.id = merged_subscriptions.id;
+
+    ALTER TABLE subscriptions ALTER COLUMN email_canonical SET NOT NULL;
+    ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_email_canonical_key UNIQUE (email_canonical);
+
+    DROP FUNCTION pg_temp.canonical_email(TEXT);
+    DROP FUNCTION pg_temp.punycode(TEXT);
+    DROP FUNCTION pg_temp.punycode_digit(INT);
+COMMIT;
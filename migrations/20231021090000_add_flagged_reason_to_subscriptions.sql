@@ -0,0 +1,3 @@
+-- Add migration script here
+-- reason code set when the domain policy accepted a subscription but flagged it for review
+ALTER TABLE subscriptions ADD COLUMN flagged_reason TEXT NULL;
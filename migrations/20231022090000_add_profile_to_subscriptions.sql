@@ -0,0 +1,11 @@
+-- Add migration script here
+-- optional profile data, edited by the subscriber from the preference centre
+ALTER TABLE subscriptions
+    ADD COLUMN language TEXT NULL,
+    ADD COLUMN timezone TEXT NULL,
+    ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}'::jsonb,
+    ADD COLUMN topics TEXT[] NOT NULL DEFAULT '{}';
+-- a token carrying `new_email` confirms an email change rather than the subscription
+ALTER TABLE subscriptions_token
+    ADD COLUMN new_email TEXT NULL,
+    ADD COLUMN new_email_canonical TEXT NULL;
@@ -0,0 +1,34 @@
+-- Add migration script here
+-- one deployment can run several newsletters, each subscriber confirms every list separately
+CREATE TABLE lists (
+    id uuid NOT NULL PRIMARY KEY,
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    -- the list used when a subscription or newsletter doesn't name one
+    is_default BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+CREATE UNIQUE INDEX lists_single_default ON lists (is_default) WHERE is_default;
+INSERT INTO lists (id, slug, name, is_default) VALUES (gen_random_uuid(), 'newsletter', 'Newsletter', true);
+
+-- status is one of pending_confirmation, confirmed, unsubscribed
+CREATE TABLE list_memberships (
+    list_id uuid NOT NULL REFERENCES lists(id) ON DELETE CASCADE,
+    subscription_id uuid NOT NULL REFERENCES subscriptions(id) ON DELETE CASCADE,
+    status TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    confirmed_at TIMESTAMP WITH TIME ZONE NULL,
+    unsubscribed_at TIMESTAMP WITH TIME ZONE NULL,
+    PRIMARY KEY (list_id, subscription_id)
+);
+CREATE INDEX list_memberships_subscription_id ON list_memberships (subscription_id);
+
+-- existing subscribers belong to the default list
+INSERT INTO list_memberships (list_id, subscription_id, status, created_at, confirmed_at)
+SELECT lists.id, subscriptions.id, subscriptions.status, subscriptions.subscribed_at,
+    CASE WHEN subscriptions.status = 'confirmed' THEN subscriptions.subscribed_at END
+FROM subscriptions CROSS JOIN lists WHERE lists.is_default;
+
+-- the list a confirmation token confirms, NULL for email changes
+ALTER TABLE subscriptions_token ADD COLUMN list_id uuid NULL REFERENCES lists(id) ON DELETE CASCADE;
+UPDATE subscriptions_token SET list_id = (SELECT id FROM lists WHERE is_default) WHERE new_email IS NULL;
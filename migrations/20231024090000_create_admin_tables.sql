@@ -0,0 +1,24 @@
+-- Add migration script here
+-- operators of the admin API, authenticated with HTTP Basic against an argon2 PHC string
+CREATE TABLE users (
+    user_id uuid NOT NULL PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
+
+-- every change made through the admin API, `after` is NULL for deletions
+CREATE TABLE admin_audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    actor_id uuid NOT NULL REFERENCES users(user_id),
+    action TEXT NOT NULL,
+    subscriber_id uuid NOT NULL,
+    before JSONB NOT NULL,
+    after JSONB NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+CREATE INDEX admin_audit_log_subscriber_id ON admin_audit_log (subscriber_id, created_at);
+
+-- listing is ordered by (subscribed_at, id) and searched by email prefix
+CREATE INDEX subscriptions_subscribed_at_id ON subscriptions (subscribed_at DESC, id DESC);
+CREATE INDEX subscriptions_status_subscribed_at_id ON subscriptions (status, subscribed_at DESC, id DESC);
+CREATE INDEX subscriptions_email_canonical_prefix ON subscriptions (email_canonical text_pattern_ops);
@@ -0,0 +1,33 @@
+-- Add migration script here
+-- a CSV upload through the admin API, the counts are kept up to date batch by batch
+-- status is one of running, completed, failed
+CREATE TABLE import_jobs (
+    id uuid NOT NULL PRIMARY KEY,
+    actor_id uuid NOT NULL REFERENCES users(user_id),
+    list_id uuid NOT NULL REFERENCES lists(id) ON DELETE CASCADE,
+    -- the operator asserted the rows consented elsewhere, so they may be imported as confirmed
+    prior_consent BOOLEAN NOT NULL,
+    status TEXT NOT NULL,
+    accepted INTEGER NOT NULL DEFAULT 0,
+    duplicates INTEGER NOT NULL DEFAULT 0,
+    rejected INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    finished_at TIMESTAMP WITH TIME ZONE NULL
+);
+
+-- the validation report, one entry per data row of the file
+-- outcome is one of accepted, duplicate, rejected
+CREATE TABLE import_job_rows (
+    job_id uuid NOT NULL REFERENCES import_jobs(id) ON DELETE CASCADE,
+    row_number BIGINT NOT NULL,
+    email TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    field TEXT NULL,
+    reason TEXT NULL,
+    message TEXT NULL,
+    PRIMARY KEY (job_id, row_number)
+);
+
+-- confirmation emails waiting for the background mailer, set for imported subscribers
+ALTER TABLE subscriptions_token ADD COLUMN send_pending BOOLEAN NOT NULL DEFAULT false;
+CREATE INDEX subscriptions_token_send_pending ON subscriptions_token (created_at) WHERE send_pending;
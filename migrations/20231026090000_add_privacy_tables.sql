@@ -0,0 +1,26 @@
+-- Add migration script here
+-- every email sent to a subscriber, reported by data subject access requests
+CREATE TABLE sent_emails (
+    id BIGSERIAL PRIMARY KEY,
+    subscription_id uuid NOT NULL REFERENCES subscriptions(id) ON DELETE CASCADE,
+    -- one of confirmation, newsletter, preferences_link, email_change
+    kind TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    sent_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+CREATE INDEX sent_emails_subscription_id ON sent_emails (subscription_id, sent_at);
+
+-- erased addresses that must not be imported again, kept as an HMAC of the canonical address
+CREATE TABLE suppressions (
+    email_hash TEXT NOT NULL PRIMARY KEY,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+-- access and erasure requests are about an address, which may have no subscriber,
+-- and erasure strips the subscriber's data from earlier entries
+ALTER TABLE admin_audit_log ALTER COLUMN subscriber_id DROP NOT NULL;
+ALTER TABLE admin_audit_log ALTER COLUMN before DROP NOT NULL;
+ALTER TABLE admin_audit_log ADD COLUMN subject_hash TEXT NULL;
+ALTER TABLE admin_audit_log ADD COLUMN redacted_at TIMESTAMP WITH TIME ZONE NULL;
+CREATE INDEX admin_audit_log_subject_hash ON admin_audit_log (subject_hash) WHERE subject_hash IS NOT NULL;
@@ -0,0 +1,33 @@
+-- Add migration script here
+-- proof of consent: every subscribe, confirmation, import and unsubscribe, as it happened
+-- event is one of subscribe, confirm, import, unsubscribe
+CREATE TABLE consent_records (
+    id BIGSERIAL PRIMARY KEY,
+    subscription_id uuid NOT NULL REFERENCES subscriptions(id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    -- the list slug as it was, NULL for an unsubscribe from every list
+    list TEXT NULL,
+    -- the signup form, or `import:<job id>`
+    source TEXT NULL,
+    policy_version TEXT NULL,
+    ip TEXT NULL,
+    user_agent TEXT NULL,
+    -- the operator behind an import
+    actor_id uuid NULL,
+    occurred_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+CREATE INDEX consent_records_subscription_id ON consent_records (subscription_id, id);
+
+-- append-only: rows are never changed, and only go when their subscriber is erased
+CREATE FUNCTION consent_records_append_only() RETURNS trigger AS $$
+BEGIN
+    -- deletes cascading from `subscriptions` run from within the foreign key's own trigger
+    IF TG_OP = 'DELETE' AND pg_trigger_depth() > 1 THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'consent_records is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER consent_records_append_only
+    BEFORE UPDATE OR DELETE ON consent_records
+    FOR EACH ROW EXECUTE FUNCTION consent_records_append_only();
@@ -0,0 +1,24 @@
+-- Every newsletter published, sent at once or at `send_at`.
+CREATE TABLE newsletter_issues (
+    id uuid PRIMARY KEY,
+    title TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    plain_content TEXT NOT NULL,
+    -- slugs of the targeted lists, the default list when empty
+    lists TEXT[] NOT NULL,
+    segment JSONB,
+    status TEXT NOT NULL CHECK (status IN ('scheduled', 'sending', 'sent', 'cancelled', 'failed')),
+    send_at timestamptz NOT NULL,
+    -- the zone `send_at` was given in, for display
+    timezone TEXT,
+    last_error TEXT,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    updated_at timestamptz NOT NULL DEFAULT now(),
+    started_at timestamptz,
+    finished_at timestamptz
+);
+CREATE INDEX newsletter_issues_due ON newsletter_issues (send_at) WHERE status IN ('scheduled', 'sending');
+
+-- which issue a newsletter email was, so an interrupted delivery resumes without sending twice
+ALTER TABLE sent_emails ADD COLUMN issue_id uuid REFERENCES newsletter_issues (id) ON DELETE SET NULL;
+CREATE UNIQUE INDEX sent_emails_issue_recipient ON sent_emails (issue_id, subscription_id) WHERE issue_id IS NOT NULL;
@@ -0,0 +1,26 @@
+-- issues are written as drafts, revised, and optionally approved by a second admin before they are sent
+ALTER TABLE newsletter_issues DROP CONSTRAINT newsletter_issues_status_check;
+ALTER TABLE newsletter_issues ADD CONSTRAINT newsletter_issues_status_check
+    CHECK (status IN ('draft', 'pending_approval', 'scheduled', 'sending', 'sent', 'cancelled', 'failed'));
+-- unset until the issue is published
+ALTER TABLE newsletter_issues ALTER COLUMN send_at DROP NOT NULL;
+ALTER TABLE newsletter_issues ADD COLUMN revision INT NOT NULL DEFAULT 1;
+ALTER TABLE newsletter_issues ADD COLUMN created_by uuid NULL REFERENCES users(user_id);
+ALTER TABLE newsletter_issues ADD COLUMN publish_requested_by uuid NULL REFERENCES users(user_id);
+ALTER TABLE newsletter_issues ADD COLUMN approved_by uuid NULL REFERENCES users(user_id);
+
+-- every version of an issue's content, the latest is also on the issue
+CREATE TABLE newsletter_issue_revisions (
+    issue_id uuid NOT NULL REFERENCES newsletter_issues(id) ON DELETE CASCADE,
+    revision INT NOT NULL,
+    title TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    plain_content TEXT NOT NULL,
+    lists TEXT[] NOT NULL,
+    segment JSONB,
+    edited_by uuid NULL REFERENCES users(user_id),
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (issue_id, revision)
+);
+INSERT INTO newsletter_issue_revisions (issue_id, revision, title, html_content, plain_content, lists, segment, created_at)
+SELECT id, 1, title, html_content, plain_content, lists, segment, created_at FROM newsletter_issues;
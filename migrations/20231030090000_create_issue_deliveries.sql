@@ -0,0 +1,20 @@
+-- Where each recipient of an issue is at: queued when the delivery starts, then sent or failed,
+-- and bounced if the provider reports it later.
+CREATE TABLE issue_deliveries (
+    issue_id uuid NOT NULL REFERENCES newsletter_issues (id) ON DELETE CASCADE,
+    subscription_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    status TEXT NOT NULL DEFAULT 'queued' CHECK (status IN ('queued', 'sent', 'failed', 'bounced')),
+    -- why it failed or bounced
+    error TEXT,
+    attempts INT NOT NULL DEFAULT 0,
+    -- the provider's id for the email, bounce reports refer to it
+    message_id TEXT,
+    updated_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (issue_id, subscription_id)
+);
+CREATE INDEX issue_deliveries_status ON issue_deliveries (issue_id, status, updated_at);
+CREATE UNIQUE INDEX issue_deliveries_message_id ON issue_deliveries (message_id) WHERE message_id IS NOT NULL;
+
+-- issues delivered before this was tracked
+INSERT INTO issue_deliveries (issue_id, subscription_id, status, attempts, updated_at)
+SELECT issue_id, subscription_id, 'sent', 1, sent_at FROM sent_emails WHERE issue_id IS NOT NULL;
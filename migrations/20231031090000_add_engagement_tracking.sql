@@ -0,0 +1,15 @@
+-- set from the preference centre, no opens or clicks are recorded for the subscriber
+ALTER TABLE subscriptions ADD COLUMN tracking_opt_out BOOLEAN NOT NULL DEFAULT false;
+
+-- Opens and clicks of newsletter issues, recorded through the tracking pixel and links.
+CREATE TABLE engagement_events (
+    id BIGSERIAL PRIMARY KEY,
+    issue_id uuid NOT NULL REFERENCES newsletter_issues (id) ON DELETE CASCADE,
+    subscription_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (kind IN ('open', 'click')),
+    -- the link followed, for clicks
+    url TEXT,
+    occurred_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX engagement_events_issue ON engagement_events (issue_id, kind);
+CREATE INDEX engagement_events_subscription ON engagement_events (subscription_id, occurred_at);
@@ -0,0 +1,3 @@
+-- Issues published to the public web archive, under this slug.
+ALTER TABLE newsletter_issues ADD COLUMN archive_slug TEXT UNIQUE;
+CREATE INDEX newsletter_issues_archived_idx ON newsletter_issues (finished_at DESC) WHERE archive_slug IS NOT NULL;
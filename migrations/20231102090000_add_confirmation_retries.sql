@@ -0,0 +1,7 @@
+-- queued confirmation emails that fail are retried later, with a growing delay, then given up on
+ALTER TABLE subscriptions_token ADD COLUMN send_attempts INT NOT NULL DEFAULT 0;
+ALTER TABLE subscriptions_token ADD COLUMN next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();
+-- set when the mailer gave up, `send_pending` is false then
+ALTER TABLE subscriptions_token ADD COLUMN send_failed_at TIMESTAMP WITH TIME ZONE NULL;
+DROP INDEX subscriptions_token_send_pending;
+CREATE INDEX subscriptions_token_send_pending ON subscriptions_token (next_attempt_at) WHERE send_pending;
@@ -0,0 +1,13 @@
+-- the ledger outlives its subscriber: deleting one keeps the proof of consent, only an erasure removes it
+ALTER TABLE consent_records DROP CONSTRAINT consent_records_subscription_id_fkey;
+
+-- append-only: rows are never changed, and only go when the erasure of their subscriber says so
+CREATE OR REPLACE FUNCTION consent_records_append_only() RETURNS trigger AS $$
+BEGIN
+    -- set with SET LOCAL by the erasure, for its transaction only
+    IF TG_OP = 'DELETE' AND current_setting('consent_records.erasure', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'consent_records is append-only';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,7 @@
+-- when the issue first went out, even if some recipients failed; unlike `finished_at` a resend to them doesn't
+-- move it, so an archived issue keeps its date and stays listed while the resend runs
+ALTER TABLE newsletter_issues ADD COLUMN published_at timestamptz;
+UPDATE newsletter_issues SET published_at = finished_at WHERE status IN ('sent', 'failed');
+
+DROP INDEX newsletter_issues_archived_idx;
+CREATE INDEX newsletter_issues_archived_idx ON newsletter_issues (published_at DESC) WHERE archive_slug IS NOT NULL;
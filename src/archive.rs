@@ -0,0 +1,186 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use uuid::Uuid;
+
+use crate::template::{escape_html, Escape, Recipient, Template};
+use crate::tracking::Tracker;
+
+pub const TITLE: &str = "Newsletter archive";
+
+// tracked links, with the token in either quote style
+static TRACKED_HREF: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)(<a\b[^>]*?\shref\s*=\s*)(?:"[^"]*/t/c/([^"/]*)"|'[^']*/t/c/([^'/]*)')"#).unwrap());
+// links only meaningful to the recipient, dropped with their text
+static RECIPIENT_ANCHOR: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)<a\b[^>]*?\shref\s*=\s*["'][^"']*/subscriptions/(?:unsubscribe|preferences)\b[^>]*>.*?</a\s*>"#)
+        .unwrap()
+});
+static OPEN_PIXEL: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?i)<img\b[^>]*?\ssrc\s*=\s*["'][^"']*/t/o/[^>]*>"#).unwrap());
+static BODY: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<body\b[^>]*>(.*)</body\s*>").unwrap());
+
+// An issue as published to the archive.
+pub struct ArchivedIssue {
+    pub id: Uuid,
+    pub title: String,
+    pub slug: String,
+    pub html_content: String,
+    pub published_at: DateTime<Utc>,
+}
+
+// Where an issue lives in the archive: its title in lowercase words, then the start of its id
+// so that issues with the same title don't clash.
+pub fn archive_slug(title: &str, id: Uuid) -> String {
+    let words = title
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let mut slug: String = words.chars().take(60).collect();
+    slug = slug.trim_end_matches('-').to_string();
+    if !slug.is_empty() {
+        slug.push('-');
+    }
+    slug.push_str(&id.to_simple().to_string()[..8]);
+    slug
+}
+
+// The content of an issue as anyone may read it. Placeholders get their defaults, as for a
+// subscriber without a name or attributes. Stored issues don't carry what is added per recipient
+// when sending, but content pasted from a received email could: tracked links go straight to
+// where they pointed, and the open pixel, unsubscribe and preferences links go.
+pub fn public_content(html: &str, tracker: &Tracker) -> String {
+    let anyone = Recipient {
+        name: "",
+        email: "",
+        // a link of the kind dropped below
+        unsubscribe_url: "/subscriptions/unsubscribe",
+        attributes: &serde_json::Value::Null,
+    };
+    // issues from before placeholders could have stray braces
+    let html = match Template::parse(html) {
+        Ok(template) => template.render(&anyone, Escape::Html),
+        Err(_) => html.to_string(),
+    };
+    let untracked = TRACKED_HREF.replace_all(&html, |captures: &Captures| {
+        let token = captures.get(2).or_else(|| captures.get(3)).map_or("", |m| m.as_str());
+        let url = tracker.verify_click(token).map(|(_, url)| url).unwrap_or_else(|_| "#".to_string());
+        format!("{}\"{}\"", &captures[1], escape_html(&url))
+    });
+    let without_pixel = OPEN_PIXEL.replace_all(&untracked, "");
+    RECIPIENT_ANCHOR.replace_all(&without_pixel, "").into_owned()
+}
+
+pub fn index_page(issues: &[ArchivedIssue]) -> String {
+    let items: String = issues
+        .iter()
+        .map(|issue| {
+            format!(
+                "<li><a href=\"/archive/{}\">{}</a> <time datetime=\"{}\">{}</time></li>\n",
+                escape_html(&issue.slug),
+                escape_html(&issue.title),
+                rfc3339(issue.published_at),
+                issue.published_at.format("%B %-d, %Y")
+            )
+        })
+        .collect();
+    page(TITLE, &format!("<h1>{}</h1>\n<ul>\n{}</ul>", TITLE, items))
+}
+
+// `content` as returned by `public_content`
+pub fn issue_page(issue: &ArchivedIssue, content: &str) -> String {
+    // a full HTML document only contributes its body
+    let content = BODY.captures(content).map_or(content, |body| body.get(1).unwrap().as_str());
+    page(
+        &issue.title,
+        &format!(
+            "<p><a href=\"/archive\">{}</a></p>\n<h1>{}</h1>\n<time datetime=\"{}\">{}</time>\n<article>\n{}\n</article>",
+            TITLE,
+            escape_html(&issue.title),
+            rfc3339(issue.published_at),
+            issue.published_at.format("%B %-d, %Y"),
+            content
+        ),
+    )
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <link rel=\"alternate\" type=\"application/atom+xml\" href=\"/feed.xml\">\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        escape_html(title),
+        body
+    )
+}
+
+// An Atom feed of the issues, newest first, each with its public content.
+pub fn atom_feed(issues: &[(ArchivedIssue, String)], base_url: &str) -> String {
+    // nothing published yet: a fixed date, so the feed doesn't change until something is
+    let updated = issues
+        .iter()
+        .map(|(issue, _)| issue.published_at)
+        .max()
+        .map_or_else(|| "1970-01-01T00:00:00Z".to_string(), rfc3339);
+    let entries: String = issues
+        .iter()
+        .map(|(issue, content)| {
+            format!(
+                "<entry>\n<title>{}</title>\n<id>urn:uuid:{}</id>\n<link rel=\"alternate\" href=\"{}/archive/{}\"/>\n\
+                 <updated>{}</updated>\n<content type=\"html\">{}</content>\n</entry>\n",
+                escape_html(&issue.title),
+                issue.id,
+                escape_html(base_url),
+                escape_html(&issue.slug),
+                rfc3339(issue.published_at),
+                escape_html(content)
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         <title>{title}</title>\n<id>{base}/archive</id>\n<link rel=\"alternate\" href=\"{base}/archive\"/>\n\
+         <link rel=\"self\" href=\"{base}/feed.xml\"/>\n<author><name>{title}</name></author>\n<updated>{}</updated>\n{}</feed>\n",
+        updated,
+        entries,
+        title = TITLE,
+        base = escape_html(base_url)
+    )
+}
+
+fn rfc3339(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{archive_slug, public_content};
+    use crate::signing::LinkSigner;
+    use crate::tracking::{TrackedEmail, Tracker};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    #[test]
+    fn slugs_are_made_of_the_title_and_the_id() {
+        let id = Uuid::parse_str("0b5ad2a4-4d1f-4a8e-9d5c-2f0e6f1a7c3b").unwrap();
+
+        assert_eq!(archive_slug("October's news: Rust & more!", id), "october-s-news-rust-more-0b5ad2a4");
+        assert_eq!(archive_slug("Ça va ?", id), "a-va-0b5ad2a4");
+        assert_eq!(archive_slug("日本", id), "0b5ad2a4");
+    }
+
+    #[test]
+    fn what_was_added_for_the_recipient_is_stripped() {
+        let tracker = Tracker::new(LinkSigner::new(Secret::new("key".to_string())), true);
+        let email = TrackedEmail { issue_id: Uuid::new_v4(), subscription_id: Uuid::new_v4() };
+        let sent = tracker.instrument(
+            r#"<body><p>Hi {{ name | default: "reader" }}, <a href="https://example.com/?a=1&amp;b=2">Read</a></p><p><a href="http://base/subscriptions/unsubscribe?token=abc">Unsubscribe</a></p><a href="{{ unsubscribe_url }}">Leave</a></body>"#,
+            "http://base",
+            &email,
+        );
+
+        let public = public_content(&sent, &tracker);
+
+        assert_eq!(public, r#"<body><p>Hi reader, <a href="https://example.com/?a=1&amp;b=2">Read</a></p><p></p></body>"#);
+    }
+}
@@ -0,0 +1,166 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::Engine;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::problem::Problem;
+use crate::router::error_chain_fmt;
+
+// verified when the username is unknown, so the response time doesn't tell which usernames exist
+const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=15000,t=2,p=1$gZiV/M1gPc22ElAH/Jh1Hw$CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno";
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum AuthError {
+    #[error("Invalid credentials")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::InvalidCredentials(_) => StatusCode::UNAUTHORIZED,
+            AuthError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        let mut response = Problem::new(self.status_code()).response();
+        if let AuthError::InvalidCredentials(_) = self {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(r#"Basic realm="admin""#),
+            );
+        }
+        response
+    }
+}
+
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    let header_value = headers
+        .get(header::AUTHORIZATION)
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string")?;
+    let encoded = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .context("Failed to base64-decode 'Basic' credentials")?;
+    let decoded = String::from_utf8(decoded).context("The decoded credentials are not valid UTF8")?;
+    let (username, password) = decoded
+        .split_once(':')
+        .context("A username and a password must be provided in 'Basic' auth")?;
+    Ok(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
+pub async fn validate_credentials(credentials: Credentials, pool: &PgPool) -> Result<Uuid, AuthError> {
+    let stored = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        credentials.username,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the stored credentials")?;
+    let (user_id, expected_password_hash) = match stored {
+        Some(row) => (Some(row.user_id), Secret::new(row.password_hash)),
+        None => (None, Secret::new(DUMMY_PASSWORD_HASH.to_string())),
+    };
+    // hashing is CPU-bound, keep it off the async workers
+    tokio::task::spawn_blocking(move || verify_password_hash(expected_password_hash, credentials.password))
+        .await
+        .context("Failed to spawn blocking task")??;
+    user_id.ok_or_else(|| AuthError::InvalidCredentials(anyhow::anyhow!("Unknown username")))
+}
+
+fn verify_password_hash(expected_password_hash: Secret<String>, password: Secret<String>) -> Result<(), AuthError> {
+    let expected_password_hash =
+        PasswordHash::new(expected_password_hash.expose_secret()).context("Failed to parse hash in PHC string format")?;
+    Argon2::default()
+        .verify_password(password.expose_secret().as_bytes(), &expected_password_hash)
+        .context("Invalid password")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+// Adds an operator of the admin API, e.g. through `zero2rs create-admin <username>`.
+pub async fn create_user(pool: &PgPool, username: &str, password: Secret<String>) -> Result<Uuid, anyhow::Error> {
+    let username = username.trim();
+    if username.is_empty() || password.expose_secret().is_empty() {
+        anyhow::bail!("The username and the password can't be empty");
+    }
+    let password_hash = tokio::task::spawn_blocking(move || compute_password_hash(password))
+        .await
+        .context("Failed to spawn blocking task")??;
+    let user_id = Uuid::new_v4();
+    let created = sqlx::query!(
+        r#"INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3) ON CONFLICT (username) DO NOTHING"#,
+        user_id,
+        username,
+        password_hash.expose_secret(),
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the user")?;
+    if created.rows_affected() == 0 {
+        anyhow::bail!("There is already a user named {:?}", username);
+    }
+    Ok(user_id)
+}
+
+// the parameters of `DUMMY_PASSWORD_HASH`, so known and unknown usernames take as long to check
+fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(15000, 2, 1, None).unwrap())
+        .hash_password(password.expose_secret().as_bytes(), &salt)
+        .context("Failed to hash the password")?
+        .to_string();
+    Ok(Secret::new(password_hash))
+}
+
+// An operator authenticated with HTTP Basic, extracting it guards every `/admin` handler.
+pub struct AdminUser {
+    pub user_id: Uuid,
+}
+
+impl FromRequest for AdminUser {
+    type Error = AuthError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let credentials = basic_authentication(req.headers());
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+        Box::pin(async move {
+            let credentials = credentials.map_err(AuthError::InvalidCredentials)?;
+            let pool = pool.context("The connection pool is not registered")?;
+            let user_id = validate_credentials(credentials, &pool).await?;
+            Ok(AdminUser { user_id })
+        })
+    }
+}
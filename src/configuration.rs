@@ -1,13 +1,21 @@
 use config::ConfigError;
 use secrecy::{Secret, ExposeSecret};
 
-use crate::domain::SubscriberEmail;
+use crate::domain::{EmailError, SubscriberEmail};
+use crate::telemetry::RedactionPolicy;
 #[derive(serde::Deserialize)]
 #[derive(Clone)]
 pub struct Settings{
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub readiness: ReadinessSettings,
+    pub telemetry: TelemetrySettings,
+    pub domain_verification: DomainVerificationSettings,
+    pub domain_policy: DomainPolicySettings,
+    pub preferences: PreferencesSettings,
+    pub consent: ConsentSettings,
+    pub newsletter: NewsletterSettings,
 }
 #[derive(serde::Deserialize)]
 #[derive(Clone)]
@@ -16,9 +24,11 @@ pub struct EmailClientSettings {
     pub sender_email: String,
     pub authorization_token: Secret<String>,
     pub timeout_miliseconds: u64,
+    // the provider sends it in `X-Webhook-Token` with bounce reports
+    pub webhook_token: Secret<String>,
 }
 impl EmailClientSettings {
-    pub fn sender(&self) -> Result<SubscriberEmail, String> {
+    pub fn sender(&self) -> Result<SubscriberEmail, EmailError> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
     pub fn timeout(&self) -> std::time::Duration {
@@ -27,6 +37,117 @@ impl EmailClientSettings {
 }
 #[derive(serde::Deserialize)]
 #[derive(Clone)]
+pub struct ReadinessSettings {
+    pub check_timeout_milliseconds: u64,
+    // the email provider is reported in `/readyz` but never makes it fail
+    pub check_email_provider: bool,
+}
+impl ReadinessSettings {
+    pub fn check_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.check_timeout_milliseconds)
+    }
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
+pub struct TelemetrySettings {
+    // OTLP/HTTP traces endpoint, e.g. "http://localhost:4318/v1/traces", no export when unset
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    pub export_timeout_milliseconds: u64,
+    // how email addresses and names appear in logs and span fields
+    #[serde(default)]
+    pub redaction: RedactionPolicy,
+}
+impl TelemetrySettings {
+    pub fn export_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.export_timeout_milliseconds)
+    }
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
+pub struct DomainVerificationSettings {
+    // look the subscriber's domain up in DNS before accepting them
+    pub enabled: bool,
+    pub cache_ttl_seconds: u64,
+    pub lookup_timeout_milliseconds: u64,
+}
+impl DomainVerificationSettings {
+    pub fn cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cache_ttl_seconds)
+    }
+    pub fn lookup_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.lookup_timeout_milliseconds)
+    }
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
+pub struct DomainPolicySettings {
+    // replaces the bundled list of disposable domains, one domain per line
+    pub disposable_domains_path: Option<String>,
+    pub disposable_action: PolicyAction,
+    // exact domains or `*.example.com` wildcards that bypass every other rule
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<DenyRule>,
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
+pub struct DenyRule {
+    pub pattern: String,
+    pub action: PolicyAction,
+}
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Accept,
+    // accept the subscription but record why it looks suspicious
+    Flag,
+    Reject,
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
+pub struct PreferencesSettings {
+    // how long a preference centre link stays valid
+    pub link_ttl_minutes: i64,
+    // unsubscribe links sit in old newsletters, they last much longer
+    pub unsubscribe_link_ttl_days: i64,
+    // the topics a subscriber can pick from
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+impl PreferencesSettings {
+    pub fn link_ttl(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.link_ttl_minutes)
+    }
+    pub fn unsubscribe_link_ttl(&self) -> chrono::Duration {
+        chrono::Duration::days(self.unsubscribe_link_ttl_days)
+    }
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
+pub struct ConsentSettings {
+    // the privacy policy signup forms show, recorded when a form doesn't say which version it showed
+    pub policy_version: String,
+    // proxies whose X-Forwarded-For is believed, as addresses or CIDR ranges
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
+pub struct NewsletterSettings {
+    // a published issue waits for a second admin to approve it
+    #[serde(default)]
+    pub require_approval: bool,
+    // domains test sends may go to, e.g. "example.com"; any address when empty
+    #[serde(default)]
+    pub internal_domains: Vec<String>,
+    // rewrite newsletter links and add an open pixel, subscribers can still opt out
+    #[serde(default)]
+    pub track_engagement: bool,
+}
+#[derive(serde::Deserialize)]
+#[derive(Clone)]
 pub struct DatabaseSettings {
     pub username: String,
     pub password: Secret<String>,
@@ -64,4 +185,15 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    #[serde(deserialize_with = "serde_aux::deserialize_number_from_string")]
+    pub shutdown_grace_period_seconds: u64,
+    // key for the links we sign and email out, e.g. to the preference centre
+    pub hmac_secret: Secret<String>,
+    // key for the hashes on the suppression list, never rotated: erased addresses would come back
+    pub suppression_key: Secret<String>,
+}
+impl ApplicationSettings {
+    pub fn shutdown_grace_period(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_grace_period_seconds)
+    }
 }
\ No newline at end of file
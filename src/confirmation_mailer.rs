@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::metrics::observe_acquire;
+use crate::router::{send_confirmation_email, CONFIRMATION_SUBJECT};
+use crate::sent_emails::{self, record_sent};
+use crate::shutdown::WorkerGuard;
+use crate::telemetry::Sensitive;
+
+// how long to wait before looking again when the outbox is empty or the last attempt failed
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// a confirmation that failed this many times is given up on
+const MAX_ATTEMPTS: i32 = 5;
+// before the first retry, doubled after every failure
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+enum ExecutionOutcome {
+    Sent,
+    Failed,
+    Empty,
+}
+
+// Sends the confirmation emails queued with `send_pending`, e.g. for imported subscribers,
+// one at a time so a shutdown only waits for the email in progress.
+pub async fn run_confirmation_mailer(
+    pool: PgPool,
+    email_client: EmailClient,
+    base_url: String,
+    mut worker: WorkerGuard,
+) {
+    while !worker.is_triggered() {
+        match send_next_confirmation(&pool, &email_client, &base_url).await {
+            Ok(ExecutionOutcome::Sent) => continue,
+            Ok(ExecutionOutcome::Failed | ExecutionOutcome::Empty) => {}
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, "failed to send a queued confirmation email");
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = worker.triggered() => {}
+        }
+    }
+}
+
+// the row stays locked while sending, so several instances never send the same email
+#[tracing::instrument(name = "Sending a queued confirmation email", skip_all)]
+async fn send_next_confirmation(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let next = sqlx::query!(
+        r#"
+        SELECT subscriptions_token.subscriptions_token, subscriptions_token.send_attempts,
+            subscriptions.id, subscriptions.email, lists.name AS list_name
+        FROM subscriptions_token
+        JOIN subscriptions ON subscriptions.id = subscriptions_token.subscription_id
+        JOIN lists ON lists.id = subscriptions_token.list_id
+        WHERE subscriptions_token.send_pending AND subscriptions_token.next_attempt_at <= now()
+        ORDER BY subscriptions_token.next_attempt_at
+        LIMIT 1
+        FOR UPDATE OF subscriptions_token SKIP LOCKED
+        "#
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to fetch the next queued confirmation")?;
+    let next = match next {
+        Some(next) => next,
+        None => return Ok(ExecutionOutcome::Empty),
+    };
+    match SubscriberEmail::parse(next.email) {
+        Ok(email) => {
+            if let Err(e) = send_confirmation_email(email_client, &email, &next.list_name, base_url, &next.subscriptions_token).await {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    attempts = next.send_attempts + 1,
+                    "failed to send the queued confirmation email to {}",
+                    Sensitive(&email)
+                );
+                schedule_retry(&mut transaction, &next.subscriptions_token, next.send_attempts + 1).await?;
+                transaction.commit().await.context("Failed to commit SQL transaction")?;
+                return Ok(ExecutionOutcome::Failed);
+            }
+            record_sent(&mut transaction, next.id, sent_emails::CONFIRMATION, CONFIRMATION_SUBJECT)
+                .await
+                .context("Failed to record the confirmation email")?;
+        }
+        Err(e) => {
+            tracing::warn!(error.cause_chain = ?e, "dropping a queued confirmation to an invalid email");
+        }
+    }
+    sqlx::query!(
+        r#"UPDATE subscriptions_token SET send_pending = false, updated_at = now() WHERE subscriptions_token = $1"#,
+        next.subscriptions_token
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to mark the confirmation as sent")?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(ExecutionOutcome::Sent)
+}
+
+// Tries again after `RETRY_DELAY` doubled for every earlier failure, or gives up after `MAX_ATTEMPTS`.
+async fn schedule_retry(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriptions_token: &str,
+    attempts: i32,
+) -> Result<(), anyhow::Error> {
+    let delay = RETRY_DELAY * 2u32.pow((attempts - 1).clamp(0, 16) as u32);
+    let give_up = attempts >= MAX_ATTEMPTS;
+    if give_up {
+        tracing::error!(attempts, "giving up on a queued confirmation email");
+    }
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions_token SET
+            send_attempts = $2,
+            next_attempt_at = now() + make_interval(secs => $3),
+            send_pending = NOT $4,
+            send_failed_at = CASE WHEN $4 THEN now() END,
+            updated_at = now()
+        WHERE subscriptions_token = $1
+        "#,
+        subscriptions_token,
+        attempts,
+        delay.as_secs_f64(),
+        give_up
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to schedule the confirmation for a retry")?;
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+use crate::configuration::ConsentSettings;
+
+// what a consent record is about, stored as `consent_records.event`
+pub const SUBSCRIBE: &str = "subscribe";
+pub const CONFIRM: &str = "confirm";
+pub const IMPORT: &str = "import";
+pub const UNSUBSCRIBE: &str = "unsubscribe";
+
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+// Addresses whose X-Forwarded-For header we believe, e.g. our load balancer.
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    // a bare address is a single-host range
+    pub fn parse(ranges: &[String]) -> Result<Self, String> {
+        ranges
+            .iter()
+            .map(|range| {
+                range
+                    .parse::<IpNet>()
+                    .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid trusted proxy `{}`", range))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(ip))
+    }
+
+    // The peer, unless it is a trusted proxy: then the right-most address in X-Forwarded-For
+    // that isn't one, since anything to the left of it could have been made up by the client.
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: &[&str]) -> Option<IpAddr> {
+        let mut client = peer?;
+        let mut hops = forwarded_for.iter().rev().flat_map(|header| header.rsplit(',')).map(str::trim);
+        while self.contains(&client) {
+            match hops.next().and_then(|hop| hop.parse().ok()) {
+                Some(hop) => client = hop,
+                None => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+// Settings for `consent_records`, shared by the handlers that write them.
+pub struct ConsentPolicy {
+    pub policy_version: String,
+    pub trusted_proxies: TrustedProxies,
+}
+
+impl ConsentPolicy {
+    pub fn from_settings(settings: &ConsentSettings) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            policy_version: settings.policy_version.clone(),
+            trusted_proxies: TrustedProxies::parse(&settings.trusted_proxies)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+        })
+    }
+}
+
+// Where a request came from, as recorded in the consent ledger.
+pub struct RequestOrigin {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequest for RequestOrigin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let peer = req.peer_addr().map(|address| address.ip());
+        let forwarded_for: Vec<&str> = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        let ip = match req.app_data::<web::Data<ConsentPolicy>>() {
+            Some(policy) => policy.trusted_proxies.client_ip(peer, &forwarded_for),
+            None => peer,
+        };
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        ready(Ok(RequestOrigin {
+            ip: ip.map(|ip| ip.to_string()),
+            user_agent,
+        }))
+    }
+}
+
+pub struct ConsentRecord<'a> {
+    pub event: &'static str,
+    pub list: Option<&'a str>,
+    pub source: Option<&'a str>,
+    pub policy_version: Option<&'a str>,
+    pub origin: Option<&'a RequestOrigin>,
+    // the operator who gave it on the subscriber's behalf, e.g. by importing them
+    pub actor_id: Option<Uuid>,
+}
+
+// the ledger is append-only, the table refuses updates
+pub async fn record_consent<'e>(
+    executor: impl PgExecutor<'e>,
+    subscription_id: Uuid,
+    record: ConsentRecord<'_>,
+) -> Result<(), sqlx::Error> {
+    record_consents(executor, &[subscription_id], record).await
+}
+
+// the same record for each of `subscription_ids`, in one statement
+pub async fn record_consents<'e>(
+    executor: impl PgExecutor<'e>,
+    subscription_ids: &[Uuid],
+    record: ConsentRecord<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO consent_records (subscription_id, event, list, source, policy_version, ip, user_agent, actor_id)
+        SELECT id, $2, $3, $4, $5, $6, $7, $8 FROM UNNEST($1::uuid[]) AS input(id)
+        "#,
+        subscription_ids,
+        record.event,
+        record.list,
+        record.source,
+        record.policy_version,
+        record.origin.and_then(|origin| origin.ip.as_deref()),
+        record.origin.and_then(|origin| origin.user_agent.as_deref()),
+        record.actor_id,
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+pub struct ConsentEntry {
+    pub event: String,
+    pub list: Option<String>,
+    pub source: Option<String>,
+    pub policy_version: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub actor_id: Option<Uuid>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+// oldest first
+pub async fn consent_history<'e>(
+    executor: impl PgExecutor<'e>,
+    subscription_id: Uuid,
+) -> Result<Vec<ConsentEntry>, sqlx::Error> {
+    sqlx::query_as!(
+        ConsentEntry,
+        r#"
+        SELECT event, list, source, policy_version, ip, user_agent, actor_id, occurred_at
+        FROM consent_records WHERE subscription_id = $1 ORDER BY id
+        "#,
+        subscription_id
+    )
+    .fetch_all(executor)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrustedProxies;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let proxies = TrustedProxies::parse(&["10.0.0.0/8".to_string()]).unwrap();
+        assert_eq!(proxies.client_ip(ip("203.0.113.7"), &["198.51.100.1"]), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn the_right_most_untrusted_hop_is_the_client() {
+        let proxies = TrustedProxies::parse(&["10.0.0.0/8".to_string(), "192.0.2.1".to_string()]).unwrap();
+        // the left-most entry was sent by the client and can't be trusted
+        let forwarded_for = ["1.1.1.1, 198.51.100.1", "192.0.2.1"];
+        assert_eq!(proxies.client_ip(ip("10.0.0.2"), &forwarded_for), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn a_malformed_hop_stops_at_the_last_trusted_address() {
+        let proxies = TrustedProxies::parse(&["10.0.0.0/8".to_string()]).unwrap();
+        assert_eq!(proxies.client_ip(ip("10.0.0.2"), &["garbage"]), ip("10.0.0.2"));
+        assert_eq!(proxies.client_ip(ip("10.0.0.2"), &[]), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(TrustedProxies::parse(&["proxy.local".to_string()]).is_err());
+    }
+}
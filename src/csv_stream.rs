@@ -0,0 +1,168 @@
+use csv_core::{ReadRecordResult, Reader};
+
+// a quoted field left open could otherwise grow a record to the size of the upload
+const MAX_RECORD_SIZE: usize = 64 * 1024;
+// and a line of commas would take a position and a string per field
+const MAX_FIELDS: usize = 1024;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum RecordTooLarge {
+    #[error("A CSV record is longer than {max} bytes")]
+    Bytes { max: usize },
+    #[error("A CSV record has more than {max} fields")]
+    Fields { max: usize },
+}
+
+impl RecordTooLarge {
+    pub fn max(&self) -> usize {
+        match self {
+            RecordTooLarge::Bytes { max } | RecordTooLarge::Fields { max } => *max,
+        }
+    }
+}
+
+// Splits a CSV byte stream into records as the chunks arrive, without buffering the whole input.
+// Fields are decoded lossily, invalid UTF-8 is left to the validation of the field.
+pub struct CsvStream {
+    reader: Reader,
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+}
+
+impl CsvStream {
+    pub fn new() -> Self {
+        Self {
+            reader: Reader::new(),
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+        }
+    }
+
+    // appends the records completed by `chunk` to `records`
+    pub fn feed(&mut self, chunk: &[u8], records: &mut Vec<Vec<String>>) -> Result<(), RecordTooLarge> {
+        if !chunk.is_empty() {
+            self.read(chunk, records)?;
+        }
+        Ok(())
+    }
+
+    // flushes the last record when the input doesn't end with a newline
+    pub fn finish(&mut self, records: &mut Vec<Vec<String>>) -> Result<(), RecordTooLarge> {
+        self.read(&[], records)
+    }
+
+    // csv-core treats empty input as the end of the stream
+    fn read(&mut self, mut input: &[u8], records: &mut Vec<Vec<String>>) -> Result<(), RecordTooLarge> {
+        let at_end = input.is_empty();
+        loop {
+            let (result, read, written, ends) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[read..];
+            self.output_len += written;
+            self.ends_len += ends;
+            match result {
+                ReadRecordResult::InputEmpty if !at_end => return Ok(()),
+                ReadRecordResult::End => return Ok(()),
+                ReadRecordResult::OutputFull => {
+                    if self.output.len() >= MAX_RECORD_SIZE {
+                        return Err(RecordTooLarge::Bytes { max: MAX_RECORD_SIZE });
+                    }
+                    self.output.resize(self.output.len() * 2, 0);
+                }
+                ReadRecordResult::OutputEndsFull => {
+                    if self.ends.len() >= MAX_FIELDS {
+                        return Err(RecordTooLarge::Fields { max: MAX_FIELDS });
+                    }
+                    self.ends.resize(self.ends.len() * 2, 0);
+                }
+                ReadRecordResult::Record => {
+                    records.push(self.take_record());
+                }
+                ReadRecordResult::InputEmpty => {}
+            }
+        }
+    }
+
+    fn take_record(&mut self) -> Vec<String> {
+        let mut start = 0;
+        let fields = self.ends[..self.ends_len]
+            .iter()
+            .map(|&end| {
+                let field = String::from_utf8_lossy(&self.output[start..end]).into_owned();
+                start = end;
+                field
+            })
+            .collect();
+        self.output_len = 0;
+        self.ends_len = 0;
+        fields
+    }
+}
+
+impl Default for CsvStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CsvStream, RecordTooLarge, MAX_FIELDS};
+
+    fn parse_in_chunks(input: &str, chunk_size: usize) -> Vec<Vec<String>> {
+        let mut stream = CsvStream::new();
+        let mut records = Vec::new();
+        for chunk in input.as_bytes().chunks(chunk_size) {
+            stream.feed(chunk, &mut records).unwrap();
+        }
+        stream.finish(&mut records).unwrap();
+        records
+    }
+
+    #[test]
+    fn records_split_across_chunks_are_reassembled() {
+        let input = "email,name\r\n\"a@example.com\",\"Doe, \"\"Jane\"\"\"\nb@example.com,Bob";
+        let expected = vec![
+            vec!["email", "name"],
+            vec!["a@example.com", "Doe, \"Jane\""],
+            vec!["b@example.com", "Bob"],
+        ];
+        for chunk_size in [1, 2, 7, 1024] {
+            assert_eq!(parse_in_chunks(input, chunk_size), expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn fields_longer_than_the_initial_buffer_are_kept_whole() {
+        let long = "x".repeat(5000);
+        let records = parse_in_chunks(&format!("{},y\n", long), 100);
+        assert_eq!(records, vec![vec![long, "y".to_string()]]);
+    }
+
+    #[test]
+    fn an_unterminated_quote_is_bounded() {
+        let mut stream = CsvStream::new();
+        let mut records = Vec::new();
+        let chunk = format!("\"{}", "x".repeat(1024));
+        let result = (0..100).try_for_each(|_| stream.feed(chunk.as_bytes(), &mut records));
+        assert!(matches!(result, Err(RecordTooLarge::Bytes { .. })));
+    }
+
+    #[test]
+    fn the_number_of_fields_is_bounded() {
+        let mut records = Vec::new();
+        let widest = format!("{}\n", ",".repeat(MAX_FIELDS - 1));
+        CsvStream::new().feed(widest.as_bytes(), &mut records).unwrap();
+        assert_eq!(records[0].len(), MAX_FIELDS);
+
+        let result = CsvStream::new().feed(format!("{}\n", ",".repeat(MAX_FIELDS)).as_bytes(), &mut records);
+        assert_eq!(result, Err(RecordTooLarge::Fields { max: MAX_FIELDS }));
+    }
+}
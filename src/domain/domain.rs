@@ -1,61 +0,0 @@
-use unicode_segmentation::UnicodeSegmentation;
-
-pub struct NewSubscripber {
-    pub email: String,
-    pub name: SubscriberName,
-}
-#[derive(Debug)]
-pub struct SubscriberName(String);
-
-impl SubscriberName {
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
-        let is_empty_or_whitespace =  s.trim().is_empty();
-        let is_too_long = s.graphemes(true).count() > 256;
-        let forbidden_character = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contain_forbidden_character = s.chars().any(|e| forbidden_character.contains(&e));
-        if is_empty_or_whitespace || is_too_long || contain_forbidden_character {
-            Err(format!("{} is not a valid subscriber name.", s))
-        } else {
-            Ok(Self(s))
-        }
-    }
-}
-impl AsRef<str> for SubscriberName {
-    fn as_ref(&self) -> &str {
-        &self.0
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::domain::SubscriberName;
-    use claim::{assert_err, assert_ok};
-    #[test]
-    fn a_256_grapheme_long_name_is_valid(){
-        let name = "a".repeat(256);
-        assert_ok!(SubscriberName::parse(name));
-    }
-    #[test]
-    fn a_257_grapheme_long_name_is_rejected(){
-        let name = "a".repeat(257);
-        assert_err!(SubscriberName::parse(name));
-    }
-    #[test]
-    fn whitespace_only_names_are_rejected() {
-        let name = " ".to_string();
-        assert_err!(SubscriberName::parse(name));
-    }
-    #[test]
-    fn empty_string_is_rejected() {
-        let name = "".to_string();
-        assert_err!(SubscriberName::parse(name));
-    }
-    #[test]
-    fn names_containing_an_invalid_character_are_rejected() {
-        for name in &['/', '(', ')', '"', '<', '>', '\\', '{', '}'] {
-            let name = name.to_string();
-            assert_err!(SubscriberName::parse(name));
-        }
-    }
-
-}
\ No newline at end of file
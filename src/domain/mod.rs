@@ -1,8 +1,20 @@
 mod subscriber_name;
 mod subscriber_email;
 mod newsubscriber;
+mod subscriber_profile;
 
 
-pub use subscriber_name::SubscriberName;
-pub use subscriber_email::SubscriberEmail;
+pub use subscriber_name::{NameError, SubscriberName};
+pub use subscriber_email::{EmailError, SubscriberEmail};
 pub use newsubscriber::NewSubscriber;
+pub use subscriber_profile::{CustomAttributes, Language, ProfileError, TimeZone};
+
+// Parse errors of the domain types, reported per field in API responses.
+pub trait DomainError: std::error::Error {
+    // stable and machine-readable, e.g. `too_long`
+    fn code(&self) -> &'static str;
+    // the limit that was exceeded, if any
+    fn max(&self) -> Option<usize> {
+        None
+    }
+}
@@ -1,6 +1,8 @@
 use crate::domain::SubscriberName;
-use super::SubscriberEmail;
+use super::{Language, SubscriberEmail, TimeZone};
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    pub language: Option<Language>,
+    pub timezone: Option<TimeZone>,
 }
\ No newline at end of file
@@ -1,44 +1,177 @@
-use validator::validate_email;
-#[derive(Debug)]
-pub struct SubscriberEmail(String);
+// RFC 5321 limits, in octets
+const MAX_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_LABEL_LENGTH: usize = 63;
+
+use super::DomainError;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum EmailError {
+    #[error("The email address is empty")]
+    Empty,
+    #[error("The email address is missing an '@'")]
+    MissingAt,
+    #[error("The email address is missing the part before the '@'")]
+    MissingUser,
+    #[error("The email address is missing the domain")]
+    MissingDomain,
+    #[error("The part before the '@' is longer than {max} characters")]
+    UserTooLong { max: usize },
+    #[error("The email address is longer than {max} characters")]
+    TooLong { max: usize },
+    #[error("The part before the '@' is not valid")]
+    InvalidUser,
+    #[error("The domain is not valid")]
+    InvalidDomain,
+    #[error("The domain has no top-level domain")]
+    MissingTopLevelDomain,
+    #[error("IP address literals are not supported")]
+    AddressLiteral,
+}
+
+impl DomainError for EmailError {
+    fn code(&self) -> &'static str {
+        match self {
+            EmailError::Empty => "empty",
+            EmailError::MissingAt => "missing_at",
+            EmailError::MissingUser => "missing_user",
+            EmailError::MissingDomain => "missing_domain",
+            EmailError::UserTooLong { .. } => "user_too_long",
+            EmailError::TooLong { .. } => "too_long",
+            EmailError::InvalidUser => "invalid_user",
+            EmailError::InvalidDomain => "invalid_domain",
+            EmailError::MissingTopLevelDomain => "missing_top_level_domain",
+            EmailError::AddressLiteral => "address_literal",
+        }
+    }
+    fn max(&self) -> Option<usize> {
+        match self {
+            EmailError::UserTooLong { max } | EmailError::TooLong { max } => Some(*max),
+            _ => None,
+        }
+    }
+}
+
+// `address` is what we send to: the local part as typed and the lowercased ASCII (punycode) domain.
+// `canonical` also lowercases the local part and is what uniqueness is checked against.
+#[derive(Debug, Clone)]
+pub struct SubscriberEmail {
+    address: String,
+    canonical: String,
+}
 impl SubscriberEmail {
-    pub fn parse(s: String) -> Result<SubscriberEmail, String> {
+    pub fn parse(s: String) -> Result<SubscriberEmail, EmailError> {
+        let s = s.trim();
         if s.is_empty() {
-            return Err("Email should not be empty".to_string());
+            return Err(EmailError::Empty);
         }
-        let parts: Vec<&str> = s.split('@').collect();
-        if parts.len() != 2 {
-            return Err("Invalid email".to_string());
-        }
-        let user = parts[0];
-        let domain = parts[1];
-        if user.is_empty() {
-            return Err("Invalid email: no user".to_string());
+        // a quoted local part may itself contain '@', the domain never does
+        let (local, domain) = match s.rsplit_once('@') {
+            Some(parts) => parts,
+            None => return Err(EmailError::MissingAt),
+        };
+        if local.is_empty() {
+            return Err(EmailError::MissingUser);
         }
         if domain.is_empty() {
-            return Err("Invalid email: no domain".to_string());
+            return Err(EmailError::MissingDomain);
         }
-        Ok(SubscriberEmail(s))
-    }
-    pub fn parse_use_external_library(s: String) -> Result<SubscriberEmail, String> {
-        if !validate_email(&s) {
-            return Err(String::from("Invalid email"));
-        } else {
-            Ok(SubscriberEmail(s))
+        parse_local_part(local)?;
+        let domain = parse_domain(domain)?;
+        let address = format!("{}@{}", local, domain);
+        if address.len() > MAX_LENGTH {
+            return Err(EmailError::TooLong { max: MAX_LENGTH });
         }
-
+        let canonical = format!("{}@{}", local.to_ascii_lowercase(), domain);
+        Ok(SubscriberEmail { address, canonical })
+    }
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+    pub fn local_part(&self) -> &str {
+        self.address.rsplit_once('@').unwrap().0
+    }
+    pub fn domain(&self) -> &str {
+        self.address.rsplit_once('@').unwrap().1
     }
 }
 
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.address
+    }
+
+}
+
+// atext from RFC 5322 section 3.2.3
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+fn parse_local_part(local: &str) -> Result<(), EmailError> {
+    if local.len() > MAX_LOCAL_PART_LENGTH {
+        return Err(EmailError::UserTooLong { max: MAX_LOCAL_PART_LENGTH });
+    }
+    if local.len() >= 2 && local.starts_with('"') && local.ends_with('"') {
+        return parse_quoted_string(&local[1..local.len() - 1]);
+    }
+    // dot-atom: atoms separated by single dots
+    if local.split('.').any(|atom| atom.is_empty()) {
+        return Err(EmailError::InvalidUser);
+    }
+    if !local.chars().all(|c| c == '.' || is_atext(c)) {
+        return Err(EmailError::InvalidUser);
+    }
+    Ok(())
+}
+
+// the inside of a quoted local part, e.g. `"john doe"` or `"a\"b"`
+fn parse_quoted_string(quoted: &str) -> Result<(), EmailError> {
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) if (' '..='~').contains(&escaped) => {}
+                _ => return Err(EmailError::InvalidUser),
+            },
+            '"' => return Err(EmailError::InvalidUser),
+            c if (' '..='~').contains(&c) => {}
+            _ => return Err(EmailError::InvalidUser),
+        }
+    }
+    Ok(())
+}
+
+// returns the lowercased ASCII form, internationalised domains are converted to punycode
+fn parse_domain(domain: &str) -> Result<String, EmailError> {
+    if domain.starts_with('[') {
+        return Err(EmailError::AddressLiteral);
     }
-    
+    let ascii = idna::domain_to_ascii(domain)
+        .map_err(|_| EmailError::InvalidDomain)?;
+    let labels: Vec<&str> = ascii.split('.').collect();
+    if labels.len() < 2 {
+        return Err(EmailError::MissingTopLevelDomain);
+    }
+    for label in &labels {
+        let valid = !label.is_empty()
+            && label.len() <= MAX_LABEL_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !valid {
+            return Err(EmailError::InvalidDomain);
+        }
+    }
+    if labels[labels.len() - 1].chars().all(|c| c.is_ascii_digit()) {
+        return Err(EmailError::InvalidDomain);
+    }
+    Ok(ascii)
 }
+
 #[cfg(test)]
 mod tests {
-    use super::SubscriberEmail;
+    use super::{EmailError, SubscriberEmail};
     use claim::{assert_ok, assert_err};
     use fake::{faker::internet::en::SafeEmail, Fake};
 
@@ -59,6 +192,33 @@ mod tests {
         SubscriberEmail::parse(valid_email.0).is_ok()
     }
 
+    // a valid address broken in one of the ways users (or bots) actually break them
+    #[derive(Debug, Clone)]
+    struct InvalidEmailFixture(String);
+
+    impl quickcheck::Arbitrary for InvalidEmailFixture {
+        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+            let email: String = SafeEmail().fake_with_rng(g);
+            let (user, domain) = email.split_once('@').unwrap();
+            let invalid = match (0..9).fake_with_rng::<usize, G>(g) {
+                0 => format!("{}{}", user, domain),
+                1 => format!("{}@@{}", user, domain),
+                2 => format!("{} {}@{}", user, user, domain),
+                3 => format!(".{}@{}", user, domain),
+                4 => format!("{}..{}@{}", user, user, domain),
+                5 => format!("{}@{}", "a".repeat(65), domain),
+                6 => format!("{}@{}.{}", user, "a".repeat(250), domain),
+                7 => format!("{}@-{}", user, domain),
+                _ => format!("{}@{}", user, domain.split('.').next().unwrap()),
+            };
+            Self(invalid)
+        }
+    }
+    #[quickcheck_macros::quickcheck]
+    fn invalid_emails_are_rejected(invalid_email: InvalidEmailFixture) -> bool {
+        SubscriberEmail::parse(invalid_email.0).is_err()
+    }
+
     #[test]
     fn valid_email() {
         let email = SafeEmail().fake();
@@ -86,6 +246,50 @@ mod tests {
     #[test]
     fn invalid_email_no_domain() {
         let email = SubscriberEmail::parse(String::from("example@"));
-        assert_err!(email);
+        assert_eq!(assert_err!(email), EmailError::MissingDomain);
+    }
+
+    #[test]
+    fn whitespace_and_double_at_are_rejected() {
+        assert_err!(SubscriberEmail::parse(String::from("a b@c.com")));
+        assert_err!(SubscriberEmail::parse(String::from("x@@example.com")));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn local_part_of_64_characters_is_accepted_but_not_65() {
+        assert_ok!(SubscriberEmail::parse(format!("{}@example.com", "a".repeat(64))));
+        assert_eq!(
+            assert_err!(SubscriberEmail::parse(format!("{}@example.com", "a".repeat(65)))),
+            EmailError::UserTooLong { max: 64 }
+        );
+    }
+
+    #[test]
+    fn address_longer_than_254_characters_is_rejected() {
+        let domain = format!("{}.{}.{}.{}.com", "a".repeat(63), "b".repeat(63), "c".repeat(63), "d".repeat(50));
+        assert_ok!(SubscriberEmail::parse(format!("u@{}", domain)));
+        assert_err!(SubscriberEmail::parse(format!("{}@{}", "u".repeat(10), domain)));
+    }
+
+    #[test]
+    fn quoted_local_parts_are_accepted() {
+        assert_ok!(SubscriberEmail::parse(String::from(r#""john doe"@example.com"#)));
+        assert_ok!(SubscriberEmail::parse(String::from(r#""a@b\"c"@example.com"#)));
+        assert_err!(SubscriberEmail::parse(String::from(r#""a"b"@example.com"#)));
+    }
+
+    #[test]
+    fn domain_is_lowercased_and_local_part_kept() {
+        let email = SubscriberEmail::parse(String::from("Foo@Example.COM")).unwrap();
+        assert_eq!(email.as_ref(), "Foo@example.com");
+        assert_eq!(email.canonical(), "foo@example.com");
+        let other = SubscriberEmail::parse(String::from("foo@example.com")).unwrap();
+        assert_eq!(email.canonical(), other.canonical());
+    }
+
+    #[test]
+    fn internationalised_domains_are_converted_to_punycode() {
+        let email = SubscriberEmail::parse(String::from("user@bücher.example")).unwrap();
+        assert_eq!(email.as_ref(), "user@xn--bcher-kva.example");
+    }
+}
@@ -1,30 +1,132 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 use unicode_segmentation::UnicodeSegmentation;
 
+use super::DomainError;
+
+const MAX_LENGTH: usize = 256;
+const FORBIDDEN_CHARACTERS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+
+// messages never include the input, they end up in logs and responses
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum NameError {
+    #[error("The name is empty")]
+    Empty,
+    #[error("The name is longer than {max} characters")]
+    TooLong { max: usize },
+    #[error("The name contains one of / ( ) \" < > \\ {{ }}")]
+    ForbiddenCharacter,
+    #[error("The name contains invisible or control characters")]
+    ControlCharacter,
+    #[error("The name has no letters")]
+    OnlyCombiningMarks,
+}
+
+impl DomainError for NameError {
+    fn code(&self) -> &'static str {
+        match self {
+            NameError::Empty => "empty",
+            NameError::TooLong { .. } => "too_long",
+            NameError::ForbiddenCharacter => "forbidden_character",
+            NameError::ControlCharacter => "control_character",
+            NameError::OnlyCombiningMarks => "only_combining_marks",
+        }
+    }
+    fn max(&self) -> Option<usize> {
+        match self {
+            NameError::TooLong { max } => Some(*max),
+            _ => None,
+        }
+    }
+}
+
+// NFC-normalised, with runs of whitespace collapsed to a single space.
 #[derive(Debug)]
-pub struct SubscriberName(String);
+pub struct SubscriberName {
+    name: String,
+    confusable: bool,
+}
 
 impl SubscriberName {
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
-        let is_empty_or_whitespace =  s.trim().is_empty();
-        let is_too_long = s.graphemes(true).count() > 256;
-        let forbidden_character = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contain_forbidden_character = s.chars().any(|e| forbidden_character.contains(&e));
-        if is_empty_or_whitespace || is_too_long || contain_forbidden_character {
-            Err(format!("{} is not a valid subscriber name.", s))
-        } else {
-            Ok(Self(s))
+    pub fn parse(s: String) -> Result<SubscriberName, NameError> {
+        // checked before collapsing whitespace, which would hide line breaks
+        if s.chars().any(is_invisible_or_control) {
+            return Err(NameError::ControlCharacter);
         }
+        let name = s.nfc().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" ");
+        if name.is_empty() {
+            return Err(NameError::Empty);
+        }
+        if name.graphemes(true).count() > MAX_LENGTH {
+            return Err(NameError::TooLong { max: MAX_LENGTH });
+        }
+        if name.chars().any(|e| FORBIDDEN_CHARACTERS.contains(&e)) {
+            return Err(NameError::ForbiddenCharacter);
+        }
+        if name.chars().all(|c| c == ' ' || is_combining_mark(c)) {
+            return Err(NameError::OnlyCombiningMarks);
+        }
+        let confusable = name.split(' ').any(is_confusable_word);
+        Ok(Self { name, confusable })
+    }
+    // mixes lookalike scripts, e.g. a Cyrillic "А" in "Аdmin"; accepted, but worth a review
+    pub fn is_confusable(&self) -> bool {
+        self.confusable
     }
 }
 impl AsRef<str> for SubscriberName {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.name
+    }
+}
+
+// control characters other than tabs, bidi marks, overrides and isolates, zero-width characters
+fn is_invisible_or_control(c: char) -> bool {
+    (c.is_control() && c != '\t')
+        || matches!(c, '\u{061C}' | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2069}' | '\u{FEFF}')
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Other,
+}
+
+fn script(c: char) -> Script {
+    match c {
+        'A'..='Z' | 'a'..='z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => Script::Latin,
+        '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => Script::Greek,
+        '\u{0400}'..='\u{052F}' | '\u{1C80}'..='\u{1C8F}' | '\u{2DE0}'..='\u{2DFF}' | '\u{A640}'..='\u{A69F}' => Script::Cyrillic,
+        _ => Script::Other,
+    }
+}
+
+// Greek and Cyrillic letters that render like Latin ones
+const LATIN_LOOKALIKES: &str = "АВЕЅІЈКМНОРСТХУаеіјорсухѕԁһԛԝΑΒΕΖΗΙΚΜΝΟΡΤΥΧοινρτυ";
+
+// A word is confusable when it mixes Latin with Greek or Cyrillic letters,
+// or is written entirely with Greek/Cyrillic letters that look Latin ("раураl").
+fn is_confusable_word(word: &str) -> bool {
+    let scripts: Vec<Script> = word
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .map(script)
+        .filter(|s| *s != Script::Other)
+        .collect();
+    let has_latin = scripts.contains(&Script::Latin);
+    let has_lookalike_script = scripts.iter().any(|s| *s == Script::Greek || *s == Script::Cyrillic);
+    if has_latin && has_lookalike_script {
+        return true;
     }
+    let letters: Vec<char> = word.chars().filter(|c| c.is_alphabetic()).collect();
+    !has_latin && has_lookalike_script && letters.len() > 1 && letters.iter().all(|c| LATIN_LOOKALIKES.contains(*c))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberName;
+    use crate::domain::{NameError, SubscriberName};
     use claim::{assert_err, assert_ok};
     #[test]
     fn a_256_grapheme_long_name_is_valid(){
@@ -34,7 +136,7 @@ mod tests {
     #[test]
     fn a_257_grapheme_long_name_is_rejected(){
         let name = "a".repeat(257);
-        assert_err!(SubscriberName::parse(name));
+        assert_eq!(assert_err!(SubscriberName::parse(name)), NameError::TooLong { max: 256 });
     }
     #[test]
     fn whitespace_only_names_are_rejected() {
@@ -53,5 +155,41 @@ mod tests {
             assert_err!(SubscriberName::parse(name));
         }
     }
+    #[test]
+    fn names_are_nfc_normalised_and_whitespace_is_collapsed() {
+        // "e" followed by a combining acute accent
+        let name = SubscriberName::parse("  Jose\u{0301}   Saramago ".to_string()).unwrap();
+        assert_eq!(name.as_ref(), "Jos\u{00E9} Saramago");
+    }
+    #[test]
+    fn control_bidi_and_zero_width_characters_are_rejected() {
+        for name in ["le\u{0000}guin", "le\u{202E}niug", "le\u{200D}guin", "le\u{2066}guin", "\u{FEFF}le guin"] {
+            assert_eq!(assert_err!(SubscriberName::parse(name.to_string())), NameError::ControlCharacter);
+        }
+    }
+    #[test]
+    fn names_made_only_of_combining_marks_are_rejected() {
+        let name = "\u{0301}\u{0308} \u{0300}".to_string();
+        assert_eq!(assert_err!(SubscriberName::parse(name)), NameError::OnlyCombiningMarks);
+    }
+    #[test]
+    fn mixed_script_lookalikes_are_flagged_as_confusable() {
+        // the first letter is a Cyrillic capital A
+        assert!(SubscriberName::parse("\u{0410}dmin".to_string()).unwrap().is_confusable());
+        // Cyrillic letters only, spelling "paypal"
+        assert!(SubscriberName::parse("\u{0440}\u{0430}\u{0443}\u{0440}\u{0430}l".to_string()).unwrap().is_confusable());
+        assert!(SubscriberName::parse("\u{0440}\u{0430}\u{0443}\u{0440}\u{0430}".to_string()).unwrap().is_confusable());
+    }
+    #[test]
+    fn genuine_non_latin_names_are_not_confusable() {
+        for name in ["Лев Толстой", "Νίκος Καζαντζάκης", "Ursula K. Le Guin", "李白", "Zoë Ångström"] {
+            assert!(!SubscriberName::parse(name.to_string()).unwrap().is_confusable(), "{}", name);
+        }
+    }
+    #[test]
+    fn errors_do_not_echo_the_input() {
+        let error = SubscriberName::parse("<script>".to_string()).unwrap_err();
+        assert!(!error.to_string().contains("script"));
+    }
 
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+use super::DomainError;
+
+const MAX_LANGUAGE_LENGTH: usize = 35;
+const MAX_ATTRIBUTES: usize = 50;
+const MAX_ATTRIBUTE_KEY_LENGTH: usize = 64;
+const MAX_ATTRIBUTES_SIZE: usize = 8 * 1024;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ProfileError {
+    #[error("The language is not a valid language tag, e.g. \"en\" or \"pt-BR\"")]
+    InvalidLanguage,
+    #[error("The timezone is not a known IANA timezone, e.g. \"Europe/Paris\"")]
+    UnknownTimezone,
+    #[error("The attributes must be a JSON object")]
+    AttributesNotAnObject,
+    #[error("There are more than {max} attributes")]
+    TooManyAttributes { max: usize },
+    #[error("An attribute name is empty or longer than {max} characters")]
+    InvalidAttributeKey { max: usize },
+    #[error("The attributes are larger than {max} bytes")]
+    AttributesTooLarge { max: usize },
+}
+
+impl DomainError for ProfileError {
+    fn code(&self) -> &'static str {
+        match self {
+            ProfileError::InvalidLanguage => "invalid_language",
+            ProfileError::UnknownTimezone => "unknown_timezone",
+            ProfileError::AttributesNotAnObject => "not_an_object",
+            ProfileError::TooManyAttributes { .. } => "too_many_attributes",
+            ProfileError::InvalidAttributeKey { .. } => "invalid_attribute_key",
+            ProfileError::AttributesTooLarge { .. } => "too_large",
+        }
+    }
+    fn max(&self) -> Option<usize> {
+        match self {
+            ProfileError::TooManyAttributes { max }
+            | ProfileError::InvalidAttributeKey { max }
+            | ProfileError::AttributesTooLarge { max } => Some(*max),
+            _ => None,
+        }
+    }
+}
+
+// A BCP 47 language tag in its conventional casing, e.g. `pt-BR` or `zh-Hant`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Language(String);
+
+impl Language {
+    pub fn parse(s: &str) -> Result<Language, ProfileError> {
+        let s = s.trim();
+        if s.is_empty() || s.len() > MAX_LANGUAGE_LENGTH {
+            return Err(ProfileError::InvalidLanguage);
+        }
+        let mut subtags = s.split(['-', '_']);
+        let primary = subtags.next().unwrap_or_default();
+        if !(2..=3).contains(&primary.len()) || !primary.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(ProfileError::InvalidLanguage);
+        }
+        let mut tag = primary.to_ascii_lowercase();
+        for subtag in subtags {
+            if !(1..=8).contains(&subtag.len()) || !subtag.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(ProfileError::InvalidLanguage);
+            }
+            tag.push('-');
+            match subtag.len() {
+                // region, e.g. BR
+                2 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => tag.push_str(&subtag.to_ascii_uppercase()),
+                // script, e.g. Hant
+                4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                    tag.push_str(&subtag[..1].to_ascii_uppercase());
+                    tag.push_str(&subtag[1..].to_ascii_lowercase());
+                }
+                _ => tag.push_str(&subtag.to_ascii_lowercase()),
+            }
+        }
+        Ok(Language(tag))
+    }
+}
+
+impl AsRef<str> for Language {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+// An IANA timezone name, e.g. `Europe/Paris`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeZone(chrono_tz::Tz);
+
+impl TimeZone {
+    pub fn parse(s: &str) -> Result<TimeZone, ProfileError> {
+        s.trim()
+            .parse::<chrono_tz::Tz>()
+            .map(TimeZone)
+            .map_err(|_| ProfileError::UnknownTimezone)
+    }
+    pub fn tz(&self) -> chrono_tz::Tz {
+        self.0
+    }
+}
+
+impl AsRef<str> for TimeZone {
+    fn as_ref(&self) -> &str {
+        self.0.name()
+    }
+}
+
+// Free-form subscriber data stored as JSONB, a bounded JSON object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomAttributes(serde_json::Value);
+
+impl CustomAttributes {
+    pub fn parse(value: serde_json::Value) -> Result<CustomAttributes, ProfileError> {
+        let object = value.as_object().ok_or(ProfileError::AttributesNotAnObject)?;
+        if object.len() > MAX_ATTRIBUTES {
+            return Err(ProfileError::TooManyAttributes { max: MAX_ATTRIBUTES });
+        }
+        if object.keys().any(|key| key.is_empty() || key.chars().count() > MAX_ATTRIBUTE_KEY_LENGTH) {
+            return Err(ProfileError::InvalidAttributeKey { max: MAX_ATTRIBUTE_KEY_LENGTH });
+        }
+        if value.to_string().len() > MAX_ATTRIBUTES_SIZE {
+            return Err(ProfileError::AttributesTooLarge { max: MAX_ATTRIBUTES_SIZE });
+        }
+        Ok(CustomAttributes(value))
+    }
+}
+
+impl AsRef<serde_json::Value> for CustomAttributes {
+    fn as_ref(&self) -> &serde_json::Value {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CustomAttributes, Language, ProfileError, TimeZone};
+    use claim::{assert_err, assert_ok};
+    use serde_json::json;
+
+    #[test]
+    fn language_tags_are_normalised() {
+        assert_eq!(Language::parse("EN").unwrap().as_ref(), "en");
+        assert_eq!(Language::parse("pt_br").unwrap().as_ref(), "pt-BR");
+        assert_eq!(Language::parse("zh-hant-tw").unwrap().as_ref(), "zh-Hant-TW");
+    }
+
+    #[test]
+    fn malformed_language_tags_are_rejected() {
+        for tag in ["", "e", "english", "en-", "en--us", "12", "en-toolongsubtag"] {
+            assert_eq!(assert_err!(Language::parse(tag)), ProfileError::InvalidLanguage, "{}", tag);
+        }
+    }
+
+    #[test]
+    fn only_iana_timezones_are_accepted() {
+        assert_eq!(TimeZone::parse("Europe/Paris").unwrap().as_ref(), "Europe/Paris");
+        assert_err!(TimeZone::parse("Mars/Olympus_Mons"));
+        assert_err!(TimeZone::parse("+02:00"));
+    }
+
+    #[test]
+    fn attributes_must_be_a_bounded_object() {
+        assert_ok!(CustomAttributes::parse(json!({"company": "Acme", "seats": 3})));
+        assert_err!(CustomAttributes::parse(json!(["company"])));
+        assert_err!(CustomAttributes::parse(json!({"": 1})));
+        let many: serde_json::Map<String, serde_json::Value> = (0..51).map(|i| (i.to_string(), json!(i))).collect();
+        assert_eq!(
+            assert_err!(CustomAttributes::parse(many.into())),
+            ProfileError::TooManyAttributes { max: 50 }
+        );
+        assert_err!(CustomAttributes::parse(json!({"bio": "a".repeat(9000)})));
+    }
+}
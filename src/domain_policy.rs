@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+
+use crate::configuration::{DomainPolicySettings, PolicyAction};
+
+const BUNDLED_DISPOSABLE_DOMAINS: &str = include_str!("../configuration/disposable_domains.txt");
+
+// What `subscribe` should do with an address, see `DomainPolicy::evaluate`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Accept,
+    // accepted, the reason is stored with the subscription for review
+    Flag(&'static str),
+    Reject(&'static str),
+}
+
+// machine-readable reason codes, returned to clients and stored on flagged subscriptions
+pub const DISPOSABLE_DOMAIN: &str = "disposable_domain";
+pub const DENIED_DOMAIN: &str = "denied_domain";
+
+// `*.example.com` matches any subdomain of example.com, anything else the exact domain.
+#[derive(Debug, Clone)]
+pub struct DomainPattern(String);
+
+impl DomainPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.trim().to_lowercase();
+        let exact = pattern.strip_prefix("*.").unwrap_or(&pattern);
+        if exact.is_empty() || exact.contains('*') {
+            return Err(format!("invalid domain pattern `{}`", pattern));
+        }
+        Ok(Self(pattern))
+    }
+    pub fn matches(&self, domain: &str) -> bool {
+        match self.0.strip_prefix("*.") {
+            Some(parent) => domain
+                .strip_suffix(parent)
+                .is_some_and(|sub| sub.ends_with('.')),
+            None => domain == self.0,
+        }
+    }
+}
+
+pub struct DomainPolicy {
+    disposable: HashSet<String>,
+    disposable_action: PolicyAction,
+    allow: Vec<DomainPattern>,
+    deny: Vec<(DomainPattern, PolicyAction)>,
+}
+
+impl DomainPolicy {
+    pub fn from_settings(settings: &DomainPolicySettings) -> Result<Self, std::io::Error> {
+        let disposable = match &settings.disposable_domains_path {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => BUNDLED_DISPOSABLE_DOMAINS.to_string(),
+        };
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        let allow = settings
+            .allow
+            .iter()
+            .map(|pattern| DomainPattern::parse(pattern))
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+        let deny = settings
+            .deny
+            .iter()
+            .map(|rule| Ok((DomainPattern::parse(&rule.pattern)?, rule.action)))
+            .collect::<Result<_, String>>()
+            .map_err(invalid)?;
+        Ok(Self {
+            disposable: parse_domain_list(&disposable),
+            disposable_action: settings.disposable_action,
+            allow,
+            deny,
+        })
+    }
+
+    // the allow list wins, then the first matching deny rule, then the disposable list
+    pub fn evaluate(&self, domain: &str) -> PolicyDecision {
+        if self.allow.iter().any(|pattern| pattern.matches(domain)) {
+            return PolicyDecision::Accept;
+        }
+        if let Some((_, action)) = self.deny.iter().find(|(pattern, _)| pattern.matches(domain)) {
+            return action.decide(DENIED_DOMAIN);
+        }
+        if parent_domains(domain).any(|d| self.disposable.contains(d)) {
+            return self.disposable_action.decide(DISPOSABLE_DOMAIN);
+        }
+        PolicyDecision::Accept
+    }
+}
+
+impl PolicyAction {
+    fn decide(&self, reason: &'static str) -> PolicyDecision {
+        match self {
+            PolicyAction::Reject => PolicyDecision::Reject(reason),
+            PolicyAction::Flag => PolicyDecision::Flag(reason),
+            PolicyAction::Accept => PolicyDecision::Accept,
+        }
+    }
+}
+
+fn parse_domain_list(list: &str) -> HashSet<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// `a.b.example.com`, `b.example.com`, `example.com`, `com`
+fn parent_domains(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |d| d.split_once('.').map(|(_, parent)| parent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::DenyRule;
+
+    fn settings() -> DomainPolicySettings {
+        DomainPolicySettings {
+            disposable_domains_path: None,
+            disposable_action: PolicyAction::Reject,
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+
+    #[test]
+    fn bundled_disposable_domains_and_their_subdomains_are_rejected() {
+        let policy = DomainPolicy::from_settings(&settings()).unwrap();
+        assert_eq!(policy.evaluate("mailinator.com"), PolicyDecision::Reject(DISPOSABLE_DOMAIN));
+        assert_eq!(policy.evaluate("eu.mailinator.com"), PolicyDecision::Reject(DISPOSABLE_DOMAIN));
+        assert_eq!(policy.evaluate("gmail.com"), PolicyDecision::Accept);
+    }
+
+    #[test]
+    fn disposable_domains_can_be_flagged_instead() {
+        let policy = DomainPolicy::from_settings(&DomainPolicySettings {
+            disposable_action: PolicyAction::Flag,
+            ..settings()
+        })
+        .unwrap();
+        assert_eq!(policy.evaluate("yopmail.com"), PolicyDecision::Flag(DISPOSABLE_DOMAIN));
+    }
+
+    #[test]
+    fn deny_rules_apply_their_own_action() {
+        let policy = DomainPolicy::from_settings(&DomainPolicySettings {
+            deny: vec![
+                DenyRule { pattern: "spam.example".into(), action: PolicyAction::Reject },
+                DenyRule { pattern: "*.suspicious.example".into(), action: PolicyAction::Flag },
+            ],
+            ..settings()
+        })
+        .unwrap();
+        assert_eq!(policy.evaluate("spam.example"), PolicyDecision::Reject(DENIED_DOMAIN));
+        assert_eq!(policy.evaluate("mx.suspicious.example"), PolicyDecision::Flag(DENIED_DOMAIN));
+        assert_eq!(policy.evaluate("suspicious.example"), PolicyDecision::Accept);
+        assert_eq!(policy.evaluate("notsuspicious.example"), PolicyDecision::Accept);
+    }
+
+    #[test]
+    fn the_allow_list_overrides_everything_else() {
+        let policy = DomainPolicy::from_settings(&DomainPolicySettings {
+            allow: vec!["mailinator.com".into()],
+            deny: vec![DenyRule { pattern: "*.com".into(), action: PolicyAction::Reject }],
+            ..settings()
+        })
+        .unwrap();
+        assert_eq!(policy.evaluate("mailinator.com"), PolicyDecision::Accept);
+        assert_eq!(policy.evaluate("gmail.com"), PolicyDecision::Reject(DENIED_DOMAIN));
+    }
+
+    #[test]
+    fn malformed_patterns_are_refused_at_startup() {
+        assert!(DomainPattern::parse("*").is_err());
+        assert!(DomainPattern::parse("mail.*.com").is_err());
+        let result = DomainPolicy::from_settings(&DomainPolicySettings {
+            allow: vec!["*.".into()],
+            ..settings()
+        });
+        assert!(result.is_err());
+    }
+}
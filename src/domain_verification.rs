@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::configuration::DomainVerificationSettings;
+use crate::domain::SubscriberEmail;
+
+#[async_trait::async_trait]
+pub trait DomainVerifier: Send + Sync {
+    // whether mail can be delivered to the domain: it has an MX record,
+    // or an A/AAAA record used as the implicit MX (RFC 5321 section 5.1)
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error>;
+}
+
+// Looks the domain up through the system resolver.
+pub struct DnsDomainVerifier {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsDomainVerifier {
+    pub fn from_system_conf(timeout: Duration) -> Result<Self, ResolveError> {
+        let (config, mut options) = trust_dns_resolver::system_conf::read_system_conf()?;
+        options.timeout = timeout;
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio(config, options)?,
+        })
+    }
+}
+
+fn is_no_records(e: &ResolveError) -> bool {
+    matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+#[async_trait::async_trait]
+impl DomainVerifier for DnsDomainVerifier {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        // fully qualified, so the resolver search list is not applied
+        let fqdn = format!("{}.", domain);
+        match self.resolver.mx_lookup(fqdn.as_str()).await {
+            Ok(mx) if mx.iter().next().is_some() => return Ok(true),
+            Ok(_) => {}
+            Err(e) if is_no_records(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+        match self.resolver.lookup_ip(fqdn.as_str()).await {
+            Ok(ips) => Ok(ips.iter().next().is_some()),
+            Err(e) if is_no_records(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Accepts the domains it was given and nothing else, for tests.
+pub struct InMemoryDomainVerifier {
+    domains: HashSet<String>,
+}
+
+impl InMemoryDomainVerifier {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(domains: I) -> Self {
+        Self {
+            domains: domains.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainVerifier for InMemoryDomainVerifier {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        Ok(self.domains.contains(domain))
+    }
+}
+
+// domains cached by `DomainVerification::from_settings`, anyone can make us look up new ones
+pub const MAX_CACHED_DOMAINS: usize = 10_000;
+
+// Remembers answers per domain for `ttl`, lookup errors are not cached. At most `capacity` domains
+// are kept: expired answers go first, then the oldest ones.
+pub struct CachedDomainVerifier<V> {
+    inner: V,
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl<V> CachedDomainVerifier<V> {
+    pub fn new(inner: V, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            inner,
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn insert(&self, domain: &str, accepts: bool) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, at)| now.duration_since(*at) < self.ttl);
+        if entries.len() >= self.capacity && !entries.contains_key(domain) {
+            let oldest = entries.iter().min_by_key(|(_, (_, at))| *at).map(|(domain, _)| domain.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(domain.to_string(), (accepts, now));
+    }
+}
+
+#[async_trait::async_trait]
+impl<V: DomainVerifier> DomainVerifier for CachedDomainVerifier<V> {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        if let Some((accepts, at)) = self.entries.lock().unwrap().get(domain) {
+            if at.elapsed() < self.ttl {
+                return Ok(*accepts);
+            }
+        }
+        let accepts = self.inner.accepts_mail(domain).await?;
+        self.insert(domain, accepts);
+        Ok(accepts)
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum DomainRejection {
+    #[error("The email domain looks misspelled, did you mean {suggestion}?")]
+    Misspelled { suggestion: String },
+    #[error("The email domain does not accept mail")]
+    NoMailServer,
+}
+
+impl DomainRejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DomainRejection::Misspelled { .. } => "misspelled_domain",
+            DomainRejection::NoMailServer => "no_mail_server",
+        }
+    }
+    pub fn suggestion(&self) -> Option<&str> {
+        match self {
+            DomainRejection::Misspelled { suggestion } => Some(suggestion),
+            DomainRejection::NoMailServer => None,
+        }
+    }
+}
+
+// Shared with the `subscribe` handler, `None` when verification is disabled.
+#[derive(Clone)]
+pub struct DomainVerification(pub Option<Arc<dyn DomainVerifier>>);
+
+impl DomainVerification {
+    pub fn from_settings(settings: &DomainVerificationSettings) -> Result<Self, ResolveError> {
+        if !settings.enabled {
+            return Ok(Self(None));
+        }
+        let resolver = DnsDomainVerifier::from_system_conf(settings.lookup_timeout())?;
+        let resolver = CachedDomainVerifier::new(resolver, settings.cache_ttl(), MAX_CACHED_DOMAINS);
+        Ok(Self(Some(Arc::new(resolver))))
+    }
+
+    // `keep_domain` is the subscriber's answer to a suggestion: the domain is what they meant
+    pub async fn check(&self, email: &SubscriberEmail, keep_domain: bool) -> Result<(), DomainRejection> {
+        let verifier = match &self.0 {
+            Some(verifier) => verifier,
+            None => return Ok(()),
+        };
+        // typos of big providers may well resolve, so they are caught before the lookup
+        if let Some(domain) = suggest_domain(email.domain()).filter(|_| !keep_domain) {
+            return Err(DomainRejection::Misspelled {
+                suggestion: format!("{}@{}", email.local_part(), domain),
+            });
+        }
+        match verifier.accepts_mail(email.domain()).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(DomainRejection::NoMailServer),
+            Err(e) => {
+                // a resolver outage must not stop people from subscribing
+                tracing::warn!(error.cause_chain = ?e, "domain lookup failed, accepting the address");
+                Ok(())
+            }
+        }
+    }
+}
+
+const KNOWN_PROVIDERS: &[&str] = &[
+    "gmail.com", "googlemail.com", "yahoo.com", "ymail.com", "hotmail.com", "outlook.com",
+    "live.com", "msn.com", "icloud.com", "me.com", "aol.com", "protonmail.com", "proton.me",
+    "gmx.com", "gmx.de", "mail.com", "web.de", "yandex.ru", "comcast.net", "qq.com",
+];
+
+// real providers a single edit or two away from one of the above
+const LOOKALIKE_PROVIDERS: &[&str] = &[
+    "email.com", "mailo.com", "q.com", "gmx.net", "yandex.ua", "yandex.by", "yandex.kz",
+];
+
+// the big provider `domain` is most likely a typo of, if any
+pub fn suggest_domain(domain: &str) -> Option<&'static str> {
+    if KNOWN_PROVIDERS.contains(&domain) || LOOKALIKE_PROVIDERS.contains(&domain) {
+        return None;
+    }
+    // one edit on short names already turns them into unrelated domains
+    let max_distance = if domain.len() >= 9 { 2 } else { 1 };
+    KNOWN_PROVIDERS
+        .iter()
+        .map(|provider| (edit_distance(domain, provider), *provider))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, provider)| provider)
+}
+
+// optimal string alignment distance, a transposition such as `gmial` counts as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    fn verification(domains: &[&str]) -> DomainVerification {
+        DomainVerification(Some(Arc::new(InMemoryDomainVerifier::new(domains.to_vec()))))
+    }
+
+    #[test]
+    fn common_misspellings_get_a_suggestion() {
+        assert_eq!(suggest_domain("gmial.com"), Some("gmail.com"));
+        assert_eq!(suggest_domain("hotmial.com"), Some("hotmail.com"));
+        assert_eq!(suggest_domain("yahooo.com"), Some("yahoo.com"));
+        assert_eq!(suggest_domain("gmail.com"), None);
+        assert_eq!(suggest_domain("mail.com"), None);
+        assert_eq!(suggest_domain("example.com"), None);
+    }
+
+    #[test]
+    fn real_providers_are_not_taken_for_typos() {
+        for domain in LOOKALIKE_PROVIDERS {
+            assert_eq!(suggest_domain(domain), None, "{}", domain);
+        }
+    }
+
+    #[tokio::test]
+    async fn misspelled_domains_are_rejected_with_a_suggestion() {
+        let rejection = verification(&["gmial.com"]).check(&email("ursula@gmial.com"), false).await.unwrap_err();
+        assert_eq!(rejection.suggestion(), Some("ursula@gmail.com"));
+    }
+
+    #[tokio::test]
+    async fn a_suggestion_can_be_declined() {
+        let verification = verification(&["gmial.com"]);
+        assert_eq!(verification.check(&email("ursula@gmial.com"), true).await, Ok(()));
+        assert_eq!(
+            verification.check(&email("ursula@gmaill.com"), true).await,
+            Err(DomainRejection::NoMailServer)
+        );
+    }
+
+    #[tokio::test]
+    async fn domains_without_mail_records_are_rejected() {
+        let verification = verification(&["example.com"]);
+        assert_eq!(verification.check(&email("ursula@example.com"), false).await, Ok(()));
+        assert_eq!(
+            verification.check(&email("ursula@nowhere.example"), false).await,
+            Err(DomainRejection::NoMailServer)
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_is_checked_when_disabled() {
+        assert_eq!(DomainVerification(None).check(&email("ursula@gmial.com"), false).await, Ok(()));
+    }
+
+    struct CountingVerifier(AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl DomainVerifier for CountingVerifier {
+        async fn accepts_mail(&self, _domain: &str) -> Result<bool, anyhow::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_until_the_ttl_expires() {
+        let cached = CachedDomainVerifier::new(CountingVerifier(AtomicUsize::new(0)), Duration::from_millis(50), 10);
+        cached.accepts_mail("example.com").await.unwrap();
+        cached.accepts_mail("example.com").await.unwrap();
+        assert_eq!(cached.inner.0.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        cached.accepts_mail("example.com").await.unwrap();
+        assert_eq!(cached.inner.0.load(Ordering::SeqCst), 2);
+    }
+    #[tokio::test]
+    async fn the_cache_keeps_at_most_its_capacity() {
+        let cached = CachedDomainVerifier::new(CountingVerifier(AtomicUsize::new(0)), Duration::from_secs(60), 2);
+        for domain in ["a.example", "b.example", "c.example"] {
+            cached.accepts_mail(domain).await.unwrap();
+        }
+        assert_eq!(cached.entries.lock().unwrap().len(), 2);
+        // the oldest went to make room
+        cached.accepts_mail("a.example").await.unwrap();
+        cached.accepts_mail("c.example").await.unwrap();
+        assert_eq!(cached.inner.0.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn expired_answers_are_dropped_when_another_is_cached() {
+        let cached = CachedDomainVerifier::new(CountingVerifier(AtomicUsize::new(0)), Duration::from_millis(50), 10);
+        cached.accepts_mail("a.example").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        cached.accepts_mail("b.example").await.unwrap();
+        assert_eq!(cached.entries.lock().unwrap().keys().collect::<Vec<_>>(), ["b.example"]);
+    }
+}
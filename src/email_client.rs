@@ -1,9 +1,13 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use crate::domain::SubscriberEmail;
+use crate::metrics::METRICS;
+use opentelemetry_http::HeaderInjector;
 use reqwest::Client;
 use secrecy::{Secret, ExposeSecret};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 // define email client structure
+#[derive(Clone)]
 pub struct EmailClient {
     http_client: Client,
     base_url: String,
@@ -23,8 +27,38 @@ impl EmailClient {
         html_content: &str,
         text_content: &str,
     ) -> Result<(), reqwest::Error> {
-        self.http_client
-            .post(&format!("{}/email", self.base_url))
+        self.send_message(recipient, subject, html_content, text_content).await?;
+        Ok(())
+    }
+    // like `send_email`, with the provider's id for the message when it gives one
+    pub async fn send_message(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<Option<String>, reqwest::Error> {
+        let started = Instant::now();
+        let result = self.post_email(recipient, subject, html_content, text_content).await;
+        let outcome = match &result {
+            Ok(_) => "success",
+            Err(e) if e.is_timeout() => "timeout",
+            Err(e) if e.is_status() => "rejected",
+            Err(_) => "error",
+        };
+        METRICS.observe_email_send(outcome, started);
+        result
+    }
+    async fn post_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<Option<String>, reqwest::Error> {
+        let response = self.http_client
+            .post(format!("{}/email", self.base_url))
+            .headers(trace_context_headers())
             .header("X-Postmark-Server-Token", self.authorization_token.expose_secret() )
             .json(&SendEmailRequest {
                 from: self.sender.as_ref(),
@@ -37,9 +71,24 @@ impl EmailClient {
             .await?
             .error_for_status()?;
             // .map_error(|error| {"Fail to send email".to_string()}"})?;
+        // the email was accepted whether or not the body can be read
+        Ok(response.json::<SendEmailResponse>().await.ok().map(|r| r.message_id))
+    }
+    // any HTTP response, whatever the status, means the provider can be reached
+    pub async fn check_reachable(&self) -> Result<(), reqwest::Error> {
+        self.http_client.head(&self.base_url).send().await?;
         Ok(())
     }
 }
+// W3C `traceparent` for the current span, so the provider call joins our trace
+fn trace_context_headers() -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+    headers
+}
 // implement SendEmailRequest structure
 #[derive(serde::Serialize)]
 #[serde(rename_all = "PascalCase")]
@@ -50,6 +99,11 @@ struct SendEmailRequest<'a> {
     html_content: &'a str,
     text_content: &'a str,
 }
+#[derive(serde::Deserialize)]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: String,
+}
 
 #[cfg(test)]
 mod tests {
@@ -62,6 +116,7 @@ mod tests {
     use wiremock::matchers::{any, header, header_exists, method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate, Request};
     use claim::{assert_ok, assert_err};
+    use tracing::Instrument;
 
     struct SendEmailBodyMatcher;
 
@@ -129,6 +184,29 @@ mod tests {
         //assert
         assert_ok!(result);
     }
+    // the provider's message id is returned when the response has one
+    #[tokio::test]
+    async fn send_message_returns_the_message_id() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "MessageID": "b7bc2f4a" })))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let client = email_client(mock_server.uri());
+
+        //act
+        let with_id = client.send_message(&email(), &subject(), &content(), &content()).await;
+        let without_id = client.send_message(&email(), &subject(), &content(), &content()).await;
+        //assert
+        assert_eq!(with_id.unwrap().as_deref(), Some("b7bc2f4a"));
+        assert_eq!(without_id.unwrap(), None);
+    }
     // server returns 500 if sending email returns error
     #[tokio::test]
     async fn send_email_returns_err_if_the_server_returns_500() {
@@ -145,6 +223,41 @@ mod tests {
         //assert
         assert_err!(result);
     }
+    // the trace context of the current span is propagated to the provider
+    #[tokio::test]
+    async fn send_email_propagates_the_trace_context() {
+        use opentelemetry::trace::TracerProvider;
+        use tracing::instrument::WithSubscriber;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let mock_server = MockServer::start().await;
+        Mock::given(header_exists("traceparent"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry::sdk::propagation::TraceContextPropagator::new(),
+        );
+        // the tracer only holds a weak reference, the provider must outlive the test
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber = tracing_subscriber::Registry::default()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let client = email_client(mock_server.uri());
+
+        let result = async {
+            let span = tracing::info_span!("Sending confirmation email");
+            client
+                .send_email(&email(), &subject(), &content(), &content())
+                .instrument(span)
+                .await
+        }
+        .with_subscriber(subscriber)
+        .await;
+
+        assert_ok!(result);
+    }
     // timeout case when sending email takes too long
     #[tokio::test]
     async fn send_email_times_out_if_the_server_takes_too_long() {
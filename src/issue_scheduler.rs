@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::email_client::EmailClient;
+use crate::router::{deliver_issue, LockWait, PreferenceCenter};
+use crate::shutdown::WorkerGuard;
+use crate::tracking::Tracker;
+
+// how often to look for issues that have come due
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Delivers scheduled newsletter issues once due, and resumes the ones a shutdown or crash interrupted.
+// Every instance runs one, the issue lock taken by `deliver_issue` keeps them from sending an issue twice.
+pub async fn run_issue_scheduler(
+    pool: PgPool,
+    email_client: EmailClient,
+    base_url: String,
+    preference_center: PreferenceCenter,
+    tracker: Tracker,
+    mut worker: WorkerGuard,
+) {
+    while !worker.is_triggered() {
+        if let Err(e) = deliver_due_issues(&pool, &email_client, &base_url, &preference_center, &tracker, &worker).await {
+            tracing::error!(error.cause_chain = ?e, "failed to look for due newsletter issues");
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = worker.triggered() => {}
+        }
+    }
+}
+
+#[tracing::instrument(name = "Delivering due newsletter issues", skip_all)]
+async fn deliver_due_issues(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    preference_center: &PreferenceCenter,
+    tracker: &Tracker,
+    worker: &WorkerGuard,
+) -> Result<(), anyhow::Error> {
+    let due = sqlx::query!(
+        r#"
+        SELECT id FROM newsletter_issues
+        WHERE status IN ('scheduled', 'sending') AND send_at <= now()
+        ORDER BY send_at, created_at
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the due issues")?;
+    for issue in due {
+        if worker.is_triggered() {
+            break;
+        }
+        // a failed issue is marked as such, the others still go out
+        let delivery = deliver_issue(
+            pool,
+            email_client,
+            base_url,
+            preference_center,
+            tracker,
+            issue.id,
+            LockWait::Skip,
+            || worker.is_triggered(),
+        )
+        .await;
+        if let Err(e) = delivery {
+            tracing::error!(error.cause_chain = ?e, issue_id = %issue.id, "failed to deliver a newsletter issue");
+        }
+    }
+    Ok(())
+}
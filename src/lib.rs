@@ -3,4 +3,22 @@ pub mod domain;
 pub mod router;
 pub mod startup;
 pub mod telemetry;
-pub mod email_client;
\ No newline at end of file
+pub mod email_client;
+pub mod metrics;
+pub mod shutdown;
+pub mod domain_verification;
+pub mod domain_policy;
+pub mod problem;
+pub mod signing;
+pub mod lists;
+pub mod segment;
+pub mod authentication;
+pub mod confirmation_mailer;
+pub mod csv_stream;
+pub mod suppression;
+pub mod sent_emails;
+pub mod consent;
+pub mod issue_scheduler;
+pub mod tracking;
+pub mod archive;
+pub mod template;
@@ -0,0 +1,45 @@
+use sqlx::{Executor, Postgres};
+use uuid::Uuid;
+
+// A newsletter a subscriber can join, identified in requests by its slug.
+#[derive(Debug, Clone)]
+pub struct MailingList {
+    pub id: Uuid,
+    pub slug: String,
+    pub name: String,
+}
+
+// The named list, or the default one when no slug is given.
+pub async fn find_list<'e>(
+    executor: impl Executor<'e, Database = Postgres>,
+    slug: Option<&str>,
+) -> Result<Option<MailingList>, sqlx::Error> {
+    sqlx::query_as!(
+        MailingList,
+        r#"
+        SELECT id, slug, name FROM lists
+        WHERE CASE WHEN $1::TEXT IS NULL THEN is_default ELSE slug = $1 END
+        "#,
+        slug
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+// Every list named in `slugs`, or `Err` with the first slug that doesn't exist.
+pub async fn find_lists<'e>(
+    executor: impl Executor<'e, Database = Postgres>,
+    slugs: &[String],
+) -> Result<Result<Vec<MailingList>, String>, sqlx::Error> {
+    let lists = sqlx::query_as!(
+        MailingList,
+        r#"SELECT id, slug, name FROM lists WHERE slug = ANY($1)"#,
+        slugs
+    )
+    .fetch_all(executor)
+    .await?;
+    match slugs.iter().find(|slug| !lists.iter().any(|l| &l.slug == *slug)) {
+        Some(unknown) => Ok(Err(unknown.clone())),
+        None => Ok(Ok(lists)),
+    }
+}
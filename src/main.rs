@@ -1,12 +1,42 @@
-use zero2rs::telemetry::{get_subscriber, init_subscriber};
-use zero2rs::configuration;
-use zero2rs::startup::Application;
+use secrecy::Secret;
+use zero2rs::authentication::create_user;
+use zero2rs::telemetry::{get_subscriber, init_subscriber, otlp_tracer, set_redaction_policy, shutdown_tracer};
+use zero2rs::configuration::{self, Settings};
+use zero2rs::startup::{get_connection_pool, Application};
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let subscriber = get_subscriber("zero2rs".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
     let config = configuration::get_configuration().expect("Fail to read configuration file.");
-    Application::build(config).await?.run_until_stopped().await?;
-    Ok(())
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => {}
+        ["create-admin", username] => return create_admin(&config, username).await,
+        _ => {
+            eprintln!("usage: zero2rs [create-admin <username>]");
+            std::process::exit(2);
+        }
+    }
+    set_redaction_policy(config.telemetry.redaction);
+    let tracer = otlp_tracer(&config.telemetry).expect("Failed to build the OTLP exporter");
+    let subscriber = get_subscriber("zero2rs".into(), "info".into(), std::io::stdout, tracer);
+    init_subscriber(subscriber);
+    let outcome = Application::build(config).await?.run_until_stopped().await?;
+    tracing::info!("shutdown complete: {:?}", outcome);
+    shutdown_tracer();
+    std::process::exit(outcome.exit_code());
+}
+
+// the password is read from the first line of stdin, so it stays out of the shell history
+async fn create_admin(config: &Settings, username: &str) -> std::io::Result<()> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = Secret::new(password.trim_end_matches(['\r', '\n']).to_string());
+    let pool = get_connection_pool(&config.database);
+    match create_user(&pool, username, password).await {
+        Ok(user_id) => {
+            println!("created {} ({})", username, user_id);
+            Ok(())
+        }
+        Err(e) => Err(std::io::Error::other(format!("{:#}", e))),
+    }
 }
@@ -0,0 +1,158 @@
+use std::future::Future;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+// Process-wide metrics, scraped through `GET /metrics`.
+// Labels must stay bounded: route patterns and outcomes, never emails or raw paths.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests_total: IntCounterVec,
+    pub http_request_duration_seconds: HistogramVec,
+    pub db_pool_connections: IntGauge,
+    pub db_pool_idle_connections: IntGauge,
+    pub db_pool_acquire_duration_seconds: Histogram,
+    pub subscriptions_created_total: IntCounter,
+    pub subscriptions_confirmed_total: IntCounter,
+    pub unsubscribes_total: IntCounter,
+    pub emails_sent_total: IntCounterVec,
+    pub email_send_duration_seconds: HistogramVec,
+    pub pending_confirmation_emails: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let http_requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled, by route pattern, method and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let http_request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency, by route pattern and method"),
+            &["route", "method"],
+        )
+        .unwrap();
+        let db_pool_connections = IntGauge::new("db_pool_connections", "Open Postgres connections in the pool").unwrap();
+        let db_pool_idle_connections = IntGauge::new("db_pool_idle_connections", "Idle Postgres connections in the pool").unwrap();
+        let db_pool_acquire_duration_seconds = Histogram::with_opts(HistogramOpts::new(
+            "db_pool_acquire_duration_seconds",
+            "Time spent waiting for a Postgres connection from the pool",
+        ))
+        .unwrap();
+        let subscriptions_created_total = IntCounter::new("subscriptions_created_total", "Subscriptions created").unwrap();
+        let subscriptions_confirmed_total = IntCounter::new("subscriptions_confirmed_total", "Subscriptions confirmed").unwrap();
+        let unsubscribes_total = IntCounter::new("unsubscribes_total", "Unsubscribe requests honoured").unwrap();
+        let emails_sent_total = IntCounterVec::new(
+            Opts::new("emails_sent_total", "Emails handed to the provider, by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let email_send_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("email_send_duration_seconds", "Email provider latency, by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let pending_confirmation_emails = IntGauge::new(
+            "pending_confirmation_emails",
+            "Confirmation emails queued and not sent yet",
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests_total.clone())).unwrap();
+        registry.register(Box::new(http_request_duration_seconds.clone())).unwrap();
+        registry.register(Box::new(db_pool_connections.clone())).unwrap();
+        registry.register(Box::new(db_pool_idle_connections.clone())).unwrap();
+        registry.register(Box::new(db_pool_acquire_duration_seconds.clone())).unwrap();
+        registry.register(Box::new(subscriptions_created_total.clone())).unwrap();
+        registry.register(Box::new(subscriptions_confirmed_total.clone())).unwrap();
+        registry.register(Box::new(unsubscribes_total.clone())).unwrap();
+        registry.register(Box::new(emails_sent_total.clone())).unwrap();
+        registry.register(Box::new(email_send_duration_seconds.clone())).unwrap();
+        registry.register(Box::new(pending_confirmation_emails.clone())).unwrap();
+
+        Self {
+            registry,
+            http_requests_total,
+            http_request_duration_seconds,
+            db_pool_connections,
+            db_pool_idle_connections,
+            db_pool_acquire_duration_seconds,
+            subscriptions_created_total,
+            subscriptions_confirmed_total,
+            unsubscribes_total,
+            emails_sent_total,
+            email_send_duration_seconds,
+            pending_confirmation_emails,
+        }
+    }
+
+    pub fn observe_http_request(&self, route: &str, method: &str, status: u16, started: Instant) {
+        let method = bounded_method(method);
+        self.http_requests_total
+            .with_label_values(&[route, method, &status.to_string()])
+            .inc();
+        self.http_request_duration_seconds
+            .with_label_values(&[route, method])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn observe_email_send(&self, outcome: &str, started: Instant) {
+        self.emails_sent_total.with_label_values(&[outcome]).inc();
+        self.email_send_duration_seconds
+            .with_label_values(&[outcome])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    // render every registered metric in the Prometheus text format
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+// time how long we wait on the pool, e.g. `observe_acquire(pool.begin()).await`; the metrics scrape
+// samples it too, for the queries that don't go through here
+pub async fn observe_acquire<F: Future>(acquire: F) -> F::Output {
+    let started = Instant::now();
+    let connection = acquire.await;
+    METRICS
+        .db_pool_acquire_duration_seconds
+        .observe(started.elapsed().as_secs_f64());
+    connection
+}
+
+// clients can send any method token, keep the label set closed
+fn bounded_method(method: &str) -> &str {
+    match method {
+        "GET" | "POST" | "PUT" | "PATCH" | "DELETE" | "HEAD" | "OPTIONS" => method,
+        _ => "OTHER",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bounded_method, METRICS};
+    use std::time::Instant;
+
+    #[test]
+    fn unknown_methods_share_a_single_label() {
+        assert_eq!(bounded_method("POST"), "POST");
+        assert_eq!(bounded_method("BREW"), "OTHER");
+    }
+
+    #[test]
+    fn observed_requests_are_encoded_in_the_text_format() {
+        METRICS.observe_http_request("/healthcheck", "GET", 200, Instant::now());
+        let text = METRICS.encode().unwrap();
+        assert!(text.contains(r#"http_requests_total{method="GET",route="/healthcheck",status="200"}"#));
+        assert!(text.contains("# TYPE http_request_duration_seconds histogram"));
+    }
+}
@@ -0,0 +1,95 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+use crate::domain::DomainError;
+use crate::telemetry::current_request_id;
+
+// Problem details for HTTP APIs (RFC 7807), the body of every error response.
+#[derive(serde::Serialize, Debug)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    // the `request_id` of the request's tracing span, to find the matching logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            code,
+            message: message.into(),
+            max: None,
+            suggestion: None,
+        }
+    }
+    pub fn from_domain(field: &'static str, e: &impl DomainError) -> Self {
+        Self {
+            max: e.max(),
+            ..Self::new(field, e.code(), e.to_string())
+        }
+    }
+    pub fn with_suggestion(mut self, suggestion: impl Into<String>) -> Self {
+        self.suggestion = Some(suggestion.into());
+        self
+    }
+}
+
+impl Problem {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: None,
+            request_id: current_request_id(),
+            errors: Vec::new(),
+        }
+    }
+    // a 400 listing what is wrong with each field
+    pub fn validation(errors: Vec<FieldError>) -> Self {
+        Self {
+            title: "Your request parameters didn't validate".to_string(),
+            errors,
+            ..Self::new(StatusCode::BAD_REQUEST)
+        }
+    }
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+    pub fn response(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        HttpResponse::build(status)
+            .content_type("application/problem+json")
+            .json(self)
+    }
+}
+
+// Rejections from actix extractors (malformed form or JSON bodies) as problem documents.
+// Deserializer messages can quote the submitted values, they are logged but not returned.
+pub fn extractor_error(e: impl std::fmt::Display + std::fmt::Debug + 'static) -> actix_web::Error {
+    tracing::debug!(error = %e, "request body rejected");
+    let response = Problem::new(StatusCode::BAD_REQUEST)
+        .with_detail("The request body could not be parsed")
+        .response();
+    actix_web::error::InternalError::from_response(e, response).into()
+}
@@ -0,0 +1,421 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use base64::Engine;
+use chrono::{DateTime, TimeZone as _, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::AdminUser;
+use crate::consent::{consent_history, ConsentEntry};
+use crate::domain::{CustomAttributes, Language, SubscriberEmail, SubscriberName, TimeZone};
+use crate::metrics::observe_acquire;
+use crate::problem::{FieldError, Problem};
+use crate::router::error_chain_fmt;
+
+pub(crate) const DEFAULT_PAGE_SIZE: i64 = 50;
+pub(crate) const MAX_PAGE_SIZE: i64 = 200;
+pub(crate) const STATUSES: [&str; 2] = ["pending_confirmation", "confirmed"];
+
+#[derive(thiserror::Error)]
+pub enum AdminError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+    #[error("There is no {0} with this id")]
+    NotFound(&'static str),
+    #[error("The request is not valid")]
+    ValidationError(Vec<FieldError>),
+}
+
+impl std::fmt::Debug for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AdminError::NotFound(_) => StatusCode::NOT_FOUND,
+            AdminError::ValidationError(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AdminError::ValidationError(errors) => Problem::validation(errors.clone()).response(),
+            AdminError::NotFound(_) => Problem::new(self.status_code()).with_detail(self.to_string()).response(),
+            AdminError::UnexpectedError(_) => Problem::new(self.status_code()).response(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, sqlx::FromRow)]
+pub struct SubscriberRecord {
+    pub(crate) id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    language: Option<String>,
+    timezone: Option<String>,
+    attributes: serde_json::Value,
+    flagged_reason: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ListParameters {
+    status: Option<String>,
+    // subscribed at or after
+    subscribed_after: Option<DateTime<Utc>>,
+    // subscribed strictly before
+    subscribed_before: Option<DateTime<Utc>>,
+    // matched against the canonical (lowercased) address
+    email_prefix: Option<String>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+struct SubscriberPage {
+    subscribers: Vec<SubscriberRecord>,
+    // pass back as `cursor` for the next page, `null` on the last one
+    next_cursor: Option<String>,
+}
+
+// Newest first. Pages are keyed on (subscribed_at, id), so inserts don't shift them.
+#[tracing::instrument(name = "Listing subscribers", skip(parameters, pool, admin), fields(admin = %admin.user_id))]
+pub async fn list_subscribers(
+    parameters: web::Query<ListParameters>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let parameters = parameters.into_inner();
+    let mut errors = Vec::new();
+    if let Some(status) = &parameters.status {
+        if !STATUSES.contains(&status.as_str()) {
+            errors.push(FieldError::new("status", "unknown_status", format!("The status must be one of {:?}", STATUSES)));
+        }
+    }
+    let cursor = parameters.cursor.as_deref().map(decode_cursor).transpose().unwrap_or_else(|_| {
+        errors.push(FieldError::new("cursor", "invalid_cursor", "The cursor is not one returned by this endpoint"));
+        None
+    });
+    if !errors.is_empty() {
+        return Err(AdminError::ValidationError(errors));
+    }
+    let limit = parameters.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    // only the filters in use make it into the query, so the planner can pick the matching index
+    let mut conditions = Vec::new();
+    let mut param = 0;
+    let mut next_param = || {
+        param += 1;
+        format!("${}", param)
+    };
+    if parameters.status.is_some() {
+        conditions.push(format!("status = {}", next_param()));
+    }
+    if parameters.subscribed_after.is_some() {
+        conditions.push(format!("subscribed_at >= {}", next_param()));
+    }
+    if parameters.subscribed_before.is_some() {
+        conditions.push(format!("subscribed_at < {}", next_param()));
+    }
+    let email_pattern = parameters.email_prefix.as_deref().map(|prefix| {
+        conditions.push(format!("email_canonical LIKE {}", next_param()));
+        format!("{}%", escape_like(&prefix.trim().to_lowercase()))
+    });
+    if cursor.is_some() {
+        let (at, id) = (next_param(), next_param());
+        conditions.push(format!("(subscribed_at, id) < ({}, {})", at, id));
+    }
+    let limit_param = next_param();
+    let sql = format!(
+        r#"
+        SELECT id, email, name, status, subscribed_at, language, timezone, attributes, flagged_reason
+        FROM subscriptions
+        {}
+        ORDER BY subscribed_at DESC, id DESC
+        LIMIT {}
+        "#,
+        if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        },
+        limit_param
+    );
+
+    let mut query = sqlx::query_as::<_, SubscriberRecord>(&sql);
+    if let Some(status) = &parameters.status {
+        query = query.bind(status);
+    }
+    if let Some(after) = parameters.subscribed_after {
+        query = query.bind(after);
+    }
+    if let Some(before) = parameters.subscribed_before {
+        query = query.bind(before);
+    }
+    if let Some(pattern) = &email_pattern {
+        query = query.bind(pattern);
+    }
+    if let Some((at, id)) = cursor {
+        query = query.bind(at).bind(id);
+    }
+    // one extra row tells whether there is a next page
+    let mut subscribers = query
+        .bind(limit + 1)
+        .fetch_all(pool.get_ref())
+        .await
+        .context("Failed to list the subscribers")?;
+    let next_cursor = if subscribers.len() as i64 > limit {
+        subscribers.truncate(limit as usize);
+        subscribers.last().map(|last| encode_cursor(last.subscribed_at, last.id))
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(SubscriberPage { subscribers, next_cursor }))
+}
+
+pub(crate) fn encode_cursor(at: DateTime<Utc>, id: Uuid) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!("{}.{}", at.timestamp_micros(), id))
+}
+
+pub(crate) fn decode_cursor(cursor: &str) -> Result<(DateTime<Utc>, Uuid), anyhow::Error> {
+    let decoded = String::from_utf8(base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(cursor)?)?;
+    let (micros, id) = decoded.split_once('.').context("Malformed cursor")?;
+    let micros: i64 = micros.parse()?;
+    let at = Utc
+        .timestamp_opt(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1000) as u32)
+        .single()
+        .context("Cursor timestamp out of range")?;
+    Ok((at, Uuid::parse_str(id)?))
+}
+
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[tracing::instrument(name = "Showing a subscriber", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn get_subscriber(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let record = sqlx::query_as!(
+        SubscriberRecord,
+        r#"
+        SELECT id, email, name, status, subscribed_at, language, timezone, attributes, flagged_reason
+        FROM subscriptions WHERE id = $1
+        "#,
+        id.into_inner()
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to fetch the subscriber")?
+    .ok_or(AdminError::NotFound("subscriber"))?;
+    Ok(HttpResponse::Ok().json(record))
+}
+
+#[derive(serde::Serialize)]
+struct ConsentLedger {
+    records: Vec<ConsentEntry>,
+}
+
+// The subscriber's consent records, oldest first: proof of when, where from and under which policy they opted in.
+#[tracing::instrument(name = "Showing a subscriber's consent records", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn get_consent(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let id = id.into_inner();
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query!(r#"SELECT id FROM subscriptions WHERE id = $1"#, id)
+        .fetch_optional(&mut transaction)
+        .await
+        .context("Failed to fetch the subscriber")?
+        .ok_or(AdminError::NotFound("subscriber"))?;
+    let records = consent_history(&mut transaction, id)
+        .await
+        .context("Failed to fetch the consent records")?;
+    Ok(HttpResponse::Ok().json(ConsentLedger { records }))
+}
+
+// Every field is optional, only the ones present are changed. An empty `language` or `timezone` clears it.
+// Unlike the preference centre, an email change applies at once without re-confirmation.
+#[derive(serde::Deserialize)]
+pub struct SubscriberUpdate {
+    name: Option<String>,
+    email: Option<String>,
+    status: Option<String>,
+    language: Option<String>,
+    timezone: Option<String>,
+    attributes: Option<serde_json::Value>,
+}
+
+#[tracing::instrument(name = "Updating a subscriber", skip(body, pool, admin), fields(admin = %admin.user_id))]
+pub async fn update_subscriber(
+    id: web::Path<Uuid>,
+    body: web::Json<SubscriberUpdate>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let id = id.into_inner();
+    let update = body.into_inner();
+    let mut errors = Vec::new();
+    let name = update.name.map(SubscriberName::parse).transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("name", &e));
+        None
+    });
+    let email = update.email.map(SubscriberEmail::parse).transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("email", &e));
+        None
+    });
+    // subscribers confirm through their link, on each list, and that is what their consent rests on
+    if update.status.is_some() {
+        errors.push(FieldError::new(
+            "status",
+            "read_only",
+            "The status changes through the confirmation and unsubscribe links",
+        ));
+    }
+    // `Some(None)` clears the value
+    let language = update.language.map(|l| match l.trim() {
+        "" => Ok(None),
+        l => Language::parse(l).map(Some),
+    });
+    let language = language.transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("language", &e));
+        None
+    });
+    let timezone = update.timezone.map(|t| match t.trim() {
+        "" => Ok(None),
+        t => TimeZone::parse(t).map(Some),
+    });
+    let timezone = timezone.transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("timezone", &e));
+        None
+    });
+    let attributes = update.attributes.map(CustomAttributes::parse).transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("attributes", &e));
+        None
+    });
+    if !errors.is_empty() {
+        return Err(AdminError::ValidationError(errors));
+    }
+
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let before = fetch_for_update(&mut transaction, id).await?;
+    if let Some(email) = &email {
+        let taken = sqlx::query!(
+            r#"SELECT id FROM subscriptions WHERE email_canonical = $1 AND id <> $2"#,
+            email.canonical(),
+            id
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .context("Failed to check whether the email is taken")?;
+        if taken.is_some() {
+            return Err(AdminError::ValidationError(vec![FieldError::new(
+                "email",
+                "already_subscribed",
+                "This email address is already subscribed",
+            )]));
+        }
+    }
+    let (set_language, language) = (language.is_some(), language.flatten());
+    let (set_timezone, timezone) = (timezone.is_some(), timezone.flatten());
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions SET
+            name = COALESCE($2, name),
+            email = COALESCE($3, email),
+            email_canonical = COALESCE($4, email_canonical),
+            language = CASE WHEN $5 THEN $6 ELSE language END,
+            timezone = CASE WHEN $7 THEN $8 ELSE timezone END,
+            attributes = COALESCE($9, attributes)
+        WHERE id = $1
+        "#,
+        id,
+        name.as_ref().map(|n| n.as_ref()),
+        email.as_ref().map(|e| e.as_ref()),
+        email.as_ref().map(|e| e.canonical()),
+        set_language,
+        language.as_ref().map(|l| l.as_ref()),
+        set_timezone,
+        timezone.as_ref().map(|t| t.as_ref()),
+        attributes.as_ref().map(|a| a.as_ref()),
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update the subscriber")?;
+    let after = fetch_for_update(&mut transaction, id).await?;
+    audit(&mut transaction, &admin, "update", &before, Some(&after)).await?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::Ok().json(after))
+}
+
+#[tracing::instrument(name = "Deleting a subscriber", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn delete_subscriber(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let id = id.into_inner();
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let before = fetch_for_update(&mut transaction, id).await?;
+    // tokens and list memberships go with it, the consent records stay until an erasure
+    sqlx::query!(r#"DELETE FROM subscriptions WHERE id = $1"#, id)
+        .execute(&mut transaction)
+        .await
+        .context("Failed to delete the subscriber")?;
+    audit(&mut transaction, &admin, "delete", &before, None).await?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn fetch_for_update(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+) -> Result<SubscriberRecord, AdminError> {
+    sqlx::query_as!(
+        SubscriberRecord,
+        r#"
+        SELECT id, email, name, status, subscribed_at, language, timezone, attributes, flagged_reason
+        FROM subscriptions WHERE id = $1 FOR UPDATE
+        "#,
+        id
+    )
+    .fetch_optional(transaction)
+    .await
+    .context("Failed to fetch the subscriber")?
+    .ok_or(AdminError::NotFound("subscriber"))
+}
+
+// written in the same transaction as the change it records
+async fn audit(
+    transaction: &mut Transaction<'_, Postgres>,
+    admin: &AdminUser,
+    action: &str,
+    before: &SubscriberRecord,
+    after: Option<&SubscriberRecord>,
+) -> Result<(), AdminError> {
+    let after = after.map(serde_json::to_value).transpose().context("Failed to serialize the subscriber")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO admin_audit_log (actor_id, action, subscriber_id, before, after)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        admin.user_id,
+        action,
+        before.id,
+        serde_json::to_value(before).context("Failed to serialize the subscriber")?,
+        after,
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to write the audit log")?;
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::time::SystemTime;
+
+use actix_web::http::header::{self, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::archive::{self, ArchivedIssue};
+use crate::problem::Problem;
+use crate::router::error_chain_fmt;
+use crate::startup::ApplicationBaseUrl;
+use crate::tracking::Tracker;
+
+// issues in the feed, the newest ones
+const FEED_SIZE: i64 = 20;
+// pages show issue content as written by admins: no scripts, frames or forms, images and inline styles
+// as newsletters use them
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; img-src https: data:; style-src 'unsafe-inline'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'";
+
+#[derive(thiserror::Error)]
+pub enum ArchiveError {
+    #[error("There is no archived issue at this address")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ArchiveError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ArchiveError::NotFound => StatusCode::NOT_FOUND,
+            ArchiveError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ArchiveError::NotFound => Problem::new(self.status_code()).with_detail(self.to_string()).response(),
+            ArchiveError::UnexpectedError(_) => Problem::new(self.status_code()).response(),
+        }
+    }
+}
+
+// Issues opted into the archive when published, once they have gone out, newest first. A resend to the failed
+// recipients leaves them listed, under their first date.
+async fn archived_issues(pool: &PgPool, limit: Option<i64>) -> Result<Vec<ArchivedIssue>, anyhow::Error> {
+    let issues = sqlx::query_as!(
+        ArchivedIssue,
+        r#"
+        SELECT id, title, archive_slug AS "slug!", html_content, published_at AS "published_at!"
+        FROM newsletter_issues
+        WHERE archive_slug IS NOT NULL AND published_at IS NOT NULL
+        ORDER BY published_at DESC, id
+        LIMIT $1
+        "#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the archived issues")?;
+    Ok(issues)
+}
+
+#[tracing::instrument(name = "Listing the archive", skip(request, pool))]
+pub async fn list_archive(request: HttpRequest, pool: web::Data<PgPool>) -> Result<HttpResponse, ArchiveError> {
+    let issues = archived_issues(&pool, None).await?;
+    let last_modified = issues.iter().map(|issue| issue.published_at).max();
+    Ok(cacheable(&request, "text/html; charset=utf-8", archive::index_page(&issues), last_modified))
+}
+
+#[tracing::instrument(name = "Showing an archived issue", skip(request, pool, tracker))]
+pub async fn show_archived_issue(
+    request: HttpRequest,
+    slug: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracker: web::Data<Tracker>,
+) -> Result<HttpResponse, ArchiveError> {
+    let issue = sqlx::query_as!(
+        ArchivedIssue,
+        r#"
+        SELECT id, title, archive_slug AS "slug!", html_content, published_at AS "published_at!"
+        FROM newsletter_issues
+        WHERE archive_slug = $1 AND published_at IS NOT NULL
+        "#,
+        slug.as_str()
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to fetch the archived issue")?
+    .ok_or(ArchiveError::NotFound)?;
+    let content = archive::public_content(&issue.html_content, &tracker);
+    let page = archive::issue_page(&issue, &content);
+    Ok(cacheable(&request, "text/html; charset=utf-8", page, Some(issue.published_at)))
+}
+
+#[tracing::instrument(name = "Serving the archive feed", skip(request, pool, tracker, base_url))]
+pub async fn archive_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    tracker: web::Data<Tracker>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, ArchiveError> {
+    let issues: Vec<_> = archived_issues(&pool, Some(FEED_SIZE))
+        .await?
+        .into_iter()
+        .map(|issue| {
+            let content = archive::public_content(&issue.html_content, &tracker);
+            (issue, content)
+        })
+        .collect();
+    let last_modified = issues.iter().map(|(issue, _)| issue.published_at).max();
+    let feed = archive::atom_feed(&issues, &base_url.0);
+    Ok(cacheable(&request, "application/atom+xml; charset=utf-8", feed, last_modified))
+}
+
+// Answers with `body`, or 304 Not Modified when the client's copy is current: by its ETag when
+// it sends If-None-Match, else by its date in If-Modified-Since.
+fn cacheable(
+    request: &HttpRequest,
+    content_type: &str,
+    body: String,
+    last_modified: Option<DateTime<Utc>>,
+) -> HttpResponse {
+    let etag = EntityTag::new_strong(hex::encode(&Sha256::digest(body.as_bytes())[..16]));
+    let not_modified = match request.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => match (request.get_header::<IfModifiedSince>(), last_modified) {
+            // HTTP dates are to the second
+            (Some(IfModifiedSince(since)), Some(modified)) => {
+                modified.timestamp() <= DateTime::<Utc>::from(SystemTime::from(since)).timestamp()
+            }
+            _ => false,
+        },
+    };
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header(header::ETag(etag))
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY));
+    if let Some(modified) = last_modified {
+        response.insert_header(header::LastModified(HttpDate::from(SystemTime::from(modified))));
+    }
+    if not_modified {
+        response.finish()
+    } else {
+        response.content_type(content_type).body(body)
+    }
+}
@@ -0,0 +1,189 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::AdminUser;
+use crate::metrics::observe_acquire;
+use crate::problem::FieldError;
+use crate::router::{decode_cursor, encode_cursor, find_issue, PublishError, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+#[derive(serde::Serialize)]
+struct DeliveryCounts {
+    total: i64,
+    queued: i64,
+    sent: i64,
+    failed: i64,
+    bounced: i64,
+}
+
+// opens and clicks recorded through tracking, `unique_` counts subscribers rather than events
+#[derive(serde::Serialize)]
+struct EngagementCounts {
+    opens: i64,
+    unique_opens: i64,
+    clicks: i64,
+    unique_clicks: i64,
+}
+
+#[derive(serde::Serialize)]
+struct FailedDelivery {
+    subscription_id: Uuid,
+    email: String,
+    // failed or bounced
+    status: String,
+    error: Option<String>,
+    attempts: i32,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct DeliveryReport {
+    issue_id: Uuid,
+    status: String,
+    counts: DeliveryCounts,
+    engagement: EngagementCounts,
+    failures: Vec<FailedDelivery>,
+    // pass back as `cursor` for the next page of failures, `null` on the last one
+    next_cursor: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeliveryReportParameters {
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+// How many recipients of an issue are at each delivery state and how many engaged with it,
+// and the ones it didn't reach, latest first.
+#[tracing::instrument(name = "Reporting on an issue's delivery", skip(parameters, pool, admin), fields(admin = %admin.user_id))]
+pub async fn get_delivery_report(
+    id: web::Path<Uuid>,
+    parameters: web::Query<DeliveryReportParameters>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    let cursor = parameters.cursor.as_deref().map(decode_cursor).transpose().map_err(|_| {
+        PublishError::ValidationError(vec![FieldError::new(
+            "cursor",
+            "invalid_cursor",
+            "The cursor is not one returned by this endpoint",
+        )])
+    })?;
+    let limit = parameters.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let issue = find_issue(pool.get_ref(), id).await?;
+    let counts = sqlx::query_as!(
+        DeliveryCounts,
+        r#"
+        SELECT
+            COUNT(*) AS "total!",
+            COUNT(*) FILTER (WHERE status = 'queued') AS "queued!",
+            COUNT(*) FILTER (WHERE status = 'sent') AS "sent!",
+            COUNT(*) FILTER (WHERE status = 'failed') AS "failed!",
+            COUNT(*) FILTER (WHERE status = 'bounced') AS "bounced!"
+        FROM issue_deliveries WHERE issue_id = $1
+        "#,
+        id
+    )
+    .fetch_one(pool.get_ref())
+    .await
+    .context("Failed to count the deliveries")?;
+    let engagement = sqlx::query_as!(
+        EngagementCounts,
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE kind = 'open') AS "opens!",
+            COUNT(DISTINCT subscription_id) FILTER (WHERE kind = 'open') AS "unique_opens!",
+            COUNT(*) FILTER (WHERE kind = 'click') AS "clicks!",
+            COUNT(DISTINCT subscription_id) FILTER (WHERE kind = 'click') AS "unique_clicks!"
+        FROM engagement_events WHERE issue_id = $1
+        "#,
+        id
+    )
+    .fetch_one(pool.get_ref())
+    .await
+    .context("Failed to count the opens and clicks")?;
+    let (cursor_at, cursor_id) = cursor.unzip();
+    // one extra row tells whether there is a next page
+    let mut failures = sqlx::query_as!(
+        FailedDelivery,
+        r#"
+        SELECT issue_deliveries.subscription_id, subscriptions.email, issue_deliveries.status,
+            issue_deliveries.error, issue_deliveries.attempts, issue_deliveries.updated_at
+        FROM issue_deliveries
+        JOIN subscriptions ON subscriptions.id = issue_deliveries.subscription_id
+        WHERE issue_deliveries.issue_id = $1 AND issue_deliveries.status IN ('failed', 'bounced')
+            AND ($2::timestamptz IS NULL OR (issue_deliveries.updated_at, issue_deliveries.subscription_id) < ($2, $3))
+        ORDER BY issue_deliveries.updated_at DESC, issue_deliveries.subscription_id DESC
+        LIMIT $4
+        "#,
+        id,
+        cursor_at,
+        cursor_id,
+        limit + 1
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to list the failed deliveries")?;
+    let next_cursor = if failures.len() as i64 > limit {
+        failures.truncate(limit as usize);
+        failures.last().map(|last| encode_cursor(last.updated_at, last.subscription_id))
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(DeliveryReport {
+        issue_id: id,
+        status: issue.status,
+        counts,
+        engagement,
+        failures,
+        next_cursor,
+    }))
+}
+
+// Queues the recipients an issue failed to reach again, the issue scheduler sends to them.
+// Bounced addresses are left alone, and so is everyone the issue already reached.
+#[tracing::instrument(name = "Resending an issue to its failed recipients", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn resend_failed(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    // serialises resends, a second one finds the issue sending again
+    let status = sqlx::query!(r#"SELECT status FROM newsletter_issues WHERE id = $1 FOR UPDATE"#, id)
+        .fetch_optional(&mut transaction)
+        .await
+        .context("Failed to lock the issue")?
+        .ok_or(PublishError::NotFound)?
+        .status;
+    if status != "sent" && status != "failed" {
+        return Err(PublishError::InvalidStatus(status));
+    }
+    let requeued = sqlx::query!(
+        r#"UPDATE issue_deliveries SET status = 'queued', updated_at = now() WHERE issue_id = $1 AND status = 'failed'"#,
+        id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to requeue the failed deliveries")?
+    .rows_affected();
+    if requeued > 0 {
+        sqlx::query!(
+            r#"
+            UPDATE newsletter_issues
+            SET status = 'sending', send_at = LEAST(send_at, now()), last_error = NULL, finished_at = NULL, updated_at = now()
+            WHERE id = $1
+            "#,
+            id
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to hand the issue back to the scheduler")?;
+    }
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "requeued": requeued })))
+}
@@ -0,0 +1,51 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+use crate::problem::Problem;
+use crate::tracking::{self, record_engagement, TrackedEmail, Tracker};
+
+// a transparent 1x1 GIF
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff,
+    0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02,
+    0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+// The open pixel. Answers with the image whatever the token, a broken image helps nobody.
+#[tracing::instrument(name = "Tracking an open", skip(token, pool, tracker))]
+pub async fn track_open(token: web::Path<String>, pool: web::Data<PgPool>, tracker: web::Data<Tracker>) -> HttpResponse {
+    if let Ok(email) = tracker.verify_open(&token) {
+        record(&pool, &tracker, &email, tracking::OPEN, None).await;
+    }
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(PIXEL)
+}
+
+// A tracked newsletter link: records the click and redirects to where the link pointed.
+// Only signed tokens redirect, so this can't send anyone anywhere else.
+#[tracing::instrument(name = "Tracking a click", skip(token, pool, tracker))]
+pub async fn track_click(token: web::Path<String>, pool: web::Data<PgPool>, tracker: web::Data<Tracker>) -> HttpResponse {
+    let (email, url) = match tracker.verify_click(&token) {
+        Ok(click) => click,
+        Err(e) => return Problem::new(StatusCode::NOT_FOUND).with_detail(e.to_string()).response(),
+    };
+    record(&pool, &tracker, &email, tracking::CLICK, Some(&url)).await;
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish()
+}
+
+// failing to record is logged, the reader still gets the image or their page
+async fn record(pool: &PgPool, tracker: &Tracker, email: &TrackedEmail, kind: &str, url: Option<&str>) {
+    // switched off since the email was sent
+    if !tracker.is_enabled() {
+        return;
+    }
+    if let Err(e) = record_engagement(pool, email, kind, url).await {
+        tracing::error!(error.cause_chain = ?e, issue_id = %email.issue_id, "failed to record an engagement event");
+    }
+}
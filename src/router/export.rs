@@ -0,0 +1,241 @@
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+
+use crate::authentication::AdminUser;
+use crate::metrics::observe_acquire;
+use crate::problem::FieldError;
+use crate::router::{AdminError, SubscriberRecord, STATUSES};
+
+// rows fetched from the cursor, and encoded into one chunk of the response, at a time
+const FETCH_SIZE: usize = 1000;
+// chunks encoded ahead of a slow client, bounds the memory an export can hold
+const BUFFERED_CHUNKS: usize = 4;
+const COLUMNS: [&str; 9] = [
+    "id",
+    "email",
+    "name",
+    "status",
+    "subscribed_at",
+    "language",
+    "timezone",
+    "attributes",
+    "flagged_reason",
+];
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ExportParameters {
+    #[serde(default)]
+    format: ExportFormat,
+    status: Option<String>,
+    // comma-separated, every column when absent
+    columns: Option<String>,
+}
+
+// Streams every subscriber, oldest first, as CSV (with a header row) or newline-delimited JSON.
+// Rows are read from a cursor in a read-only snapshot, so the export is consistent
+// and never holds more than a few batches in memory whatever the number of subscribers.
+#[tracing::instrument(name = "Exporting subscribers", skip(parameters, pool, admin), fields(admin = %admin.user_id))]
+pub async fn export_subscribers(
+    parameters: web::Query<ExportParameters>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let parameters = parameters.into_inner();
+    let mut errors = Vec::new();
+    if let Some(status) = &parameters.status {
+        if !STATUSES.contains(&status.as_str()) {
+            errors.push(FieldError::new("status", "unknown_status", format!("The status must be one of {:?}", STATUSES)));
+        }
+    }
+    let columns = parse_columns(parameters.columns.as_deref()).unwrap_or_else(|e| {
+        errors.push(e);
+        Vec::new()
+    });
+    if !errors.is_empty() {
+        return Err(AdminError::ValidationError(errors));
+    }
+    let encoder = Encoder {
+        format: parameters.format,
+        columns,
+    };
+
+    // opened before answering, so a failure here is still a 500 rather than a truncated body
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut transaction)
+        .await
+        .context("Failed to start the export snapshot")?;
+    sqlx::query(
+        r#"
+        DECLARE subscriber_export NO SCROLL CURSOR FOR
+        SELECT id, email, name, status, subscribed_at, language, timezone, attributes, flagged_reason
+        FROM subscriptions
+        WHERE $1::text IS NULL OR status = $1
+        ORDER BY subscribed_at, id
+        "#,
+    )
+    .bind(&parameters.status)
+    .execute(&mut transaction)
+    .await
+    .context("Failed to open the export cursor")?;
+
+    let (content_type, extension) = match encoder.format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "csv"),
+        ExportFormat::Ndjson => ("application/x-ndjson", "ndjson"),
+    };
+    let (sender, mut receiver) = mpsc::channel::<Result<Bytes, anyhow::Error>>(BUFFERED_CHUNKS);
+    if let Some(header) = encoder.header() {
+        let _ = sender.try_send(Ok(header));
+    }
+    tokio::spawn(async move {
+        let fetch = format!("FETCH FORWARD {} FROM subscriber_export", FETCH_SIZE);
+        loop {
+            let chunk = sqlx::query_as::<_, SubscriberRecord>(&fetch)
+                .fetch_all(&mut transaction)
+                .await
+                .context("Failed to fetch from the export cursor")
+                .and_then(|rows| match rows.as_slice() {
+                    [] => Ok(None),
+                    rows => encoder.encode(rows).map(Some),
+                });
+            let chunk = match chunk {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::error!(error.cause_chain = ?e, "subscriber export failed");
+                    // the connection is dropped, so the client sees a truncated body rather than a short export
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            };
+            if sender.send(Ok(chunk)).await.is_err() {
+                tracing::info!("subscriber export abandoned by the client");
+                return;
+            }
+        }
+        // the transaction is read-only, dropping it rolls back and closes the cursor
+    });
+    let body = futures_util::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, content_type))
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("subscribers.{}", extension))],
+        })
+        .streaming(body))
+}
+
+fn parse_columns(columns: Option<&str>) -> Result<Vec<&'static str>, FieldError> {
+    let columns = match columns {
+        Some(columns) if !columns.trim().is_empty() => columns,
+        _ => return Ok(COLUMNS.to_vec()),
+    };
+    let mut selected = Vec::new();
+    for column in columns.split(',').map(str::trim) {
+        let column = COLUMNS.iter().find(|c| **c == column).ok_or_else(|| {
+            FieldError::new("columns", "unknown_column", format!("The columns must be among {:?}", COLUMNS))
+        })?;
+        if !selected.contains(column) {
+            selected.push(*column);
+        }
+    }
+    Ok(selected)
+}
+
+struct Encoder {
+    format: ExportFormat,
+    columns: Vec<&'static str>,
+}
+
+impl Encoder {
+    fn header(&self) -> Option<Bytes> {
+        match self.format {
+            ExportFormat::Csv => Some(Bytes::from(self.columns.join(",") + "\r\n")),
+            ExportFormat::Ndjson => None,
+        }
+    }
+
+    fn encode(&self, rows: &[SubscriberRecord]) -> Result<Bytes, anyhow::Error> {
+        let mut out = String::new();
+        for row in rows {
+            let row = serde_json::to_value(row).context("Failed to serialize a subscriber")?;
+            match self.format {
+                ExportFormat::Csv => {
+                    let fields: Vec<String> = self.columns.iter().map(|c| csv_field(&row[c])).collect();
+                    out.push_str(&fields.join(","));
+                    out.push_str("\r\n");
+                }
+                ExportFormat::Ndjson => {
+                    let object: serde_json::Map<_, _> =
+                        self.columns.iter().map(|c| (c.to_string(), row[c].clone())).collect();
+                    out.push_str(&serde_json::Value::Object(object).to_string());
+                    out.push('\n');
+                }
+            }
+        }
+        Ok(Bytes::from(out))
+    }
+}
+
+// strings as they are, `null` as an empty field and anything else (the attributes) as JSON, quoted when needed.
+// A string a spreadsheet would take for a formula, e.g. a name like `=HYPERLINK(...)`, starts with a `'`.
+fn csv_field(value: &serde_json::Value) -> String {
+    let field = match value {
+        serde_json::Value::String(s) if s.starts_with(['=', '+', '-', '@', '\t', '\r']) => format!("'{}", s),
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => String::new(),
+        other => other.to_string(),
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_field, parse_columns};
+    use serde_json::json;
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field(&json!("anna")), "anna");
+        assert_eq!(csv_field(&json!(null)), "");
+        assert_eq!(csv_field(&json!("Doe, Jane")), "\"Doe, Jane\"");
+        assert_eq!(csv_field(&json!({"plan": "pro"})), "\"{\"\"plan\"\":\"\"pro\"\"}\"");
+        assert_eq!(csv_field(&json!("two\nlines")), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn csv_fields_are_never_read_as_formulas() {
+        assert_eq!(csv_field(&json!("=HYPERLINK(\"http://evil\")")), "\"'=HYPERLINK(\"\"http://evil\"\")\"");
+        assert_eq!(csv_field(&json!("+1")), "'+1");
+        assert_eq!(csv_field(&json!("-1")), "'-1");
+        assert_eq!(csv_field(&json!("@SUM(A1)")), "'@SUM(A1)");
+        assert_eq!(csv_field(&json!("\tcmd")), "'\tcmd");
+        assert_eq!(csv_field(&json!("\rcmd")), "\"'\rcmd\"");
+        // numbers in the attributes are JSON, not text
+        assert_eq!(csv_field(&json!(-1)), "-1");
+        assert_eq!(csv_field(&json!("a-b")), "a-b");
+    }
+
+    #[test]
+    fn columns_keep_the_requested_order_once() {
+        assert_eq!(parse_columns(Some("name, email,name")).unwrap(), ["name", "email"]);
+        assert_eq!(parse_columns(None).unwrap().len(), 9);
+        assert_eq!(parse_columns(Some("email,password")).unwrap_err().code, "unknown_column");
+    }
+}
@@ -0,0 +1,57 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use serde::de::DeserializeOwned;
+
+use crate::problem::Problem;
+
+// A request body accepted either form-encoded or as JSON, picked from the Content-Type.
+// Parse failures go through the `FormConfig`/`JsonConfig` error handlers registered in `startup`.
+pub struct FormOrJson<T>(pub T);
+
+#[derive(thiserror::Error, Debug)]
+pub enum BodyError {
+    #[error("Expected application/x-www-form-urlencoded or application/json, got {0}")]
+    UnsupportedMediaType(String),
+}
+
+impl ResponseError for BodyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            BodyError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        Problem::new(self.status_code()).with_detail(self.to_string()).response()
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for FormOrJson<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let mime = match req.mime_type() {
+            Ok(Some(mime)) => mime,
+            Ok(None) => return unsupported("no content type"),
+            Err(_) => return unsupported("an invalid content type"),
+        };
+        if mime.essence_str() == mime::APPLICATION_WWW_FORM_URLENCODED.essence_str() {
+            let form = web::Form::<T>::from_request(req, payload);
+            Box::pin(async move { Ok(FormOrJson(form.await?.into_inner())) })
+        } else if mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON) {
+            let json = web::Json::<T>::from_request(req, payload);
+            Box::pin(async move { Ok(FormOrJson(json.await?.into_inner())) })
+        } else {
+            unsupported(mime.essence_str())
+        }
+    }
+}
+
+fn unsupported<T: 'static>(content_type: &str) -> Pin<Box<dyn Future<Output = Result<T, actix_web::Error>>>> {
+    let error = BodyError::UnsupportedMediaType(content_type.to_string());
+    Box::pin(std::future::ready(Err(error.into())))
+}
@@ -0,0 +1,642 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::AdminUser;
+use crate::csv_stream::{CsvStream, RecordTooLarge};
+use crate::consent::{self, record_consents, ConsentRecord};
+use crate::domain::{CustomAttributes, DomainError, SubscriberEmail, SubscriberName};
+use crate::domain_policy::{DomainPolicy, PolicyDecision};
+use crate::lists::{find_list, MailingList};
+use crate::metrics::{observe_acquire, METRICS};
+use crate::problem::FieldError;
+use crate::router::{generate_subscription_token, AdminError};
+use crate::suppression::SuppressionList;
+
+// rows written per transaction
+const BATCH_SIZE: usize = 500;
+const DEFAULT_REPORT_PAGE_SIZE: i64 = 100;
+const MAX_REPORT_PAGE_SIZE: i64 = 1000;
+const OUTCOMES: [&str; 3] = ["accepted", "duplicate", "rejected"];
+// the report keeps what was submitted, cut to the longest valid address
+const MAX_REPORTED_EMAIL_LENGTH: usize = 320;
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ImportParameters {
+    // slug of the list to import into, the default list when absent
+    list: Option<String>,
+    // the operator asserts every row already consented, only then may rows be imported as `confirmed`
+    #[serde(default)]
+    prior_consent: bool,
+}
+
+#[derive(serde::Serialize, sqlx::FromRow)]
+pub struct ImportSummary {
+    id: Uuid,
+    list: String,
+    prior_consent: bool,
+    status: String,
+    accepted: i32,
+    duplicates: i32,
+    rejected: i32,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+// positions of the known columns in the header row, any other column is ignored
+struct Columns {
+    email: usize,
+    name: usize,
+    status: Option<usize>,
+    attributes: Option<usize>,
+}
+
+impl Columns {
+    fn from_header(header: &[String]) -> Result<Self, AdminError> {
+        let position = |column: &str| header.iter().position(|h| h.trim().eq_ignore_ascii_case(column));
+        let missing = |column: &str| {
+            AdminError::ValidationError(vec![FieldError::new(
+                "file",
+                "missing_column",
+                format!("The header row has no `{}` column", column),
+            )])
+        };
+        Ok(Self {
+            email: position("email").ok_or_else(|| missing("email"))?,
+            name: position("name").ok_or_else(|| missing("name"))?,
+            status: position("status"),
+            attributes: position("attributes"),
+        })
+    }
+}
+
+struct ValidRow {
+    id: Uuid,
+    email: SubscriberEmail,
+    name: SubscriberName,
+    confirmed: bool,
+    attributes: CustomAttributes,
+    flagged_reason: Option<&'static str>,
+}
+
+enum Outcome {
+    Accepted(ValidRow),
+    Duplicate(FieldError),
+    Rejected(FieldError),
+}
+
+struct ReportRow {
+    row_number: i64,
+    email: String,
+    outcome: Outcome,
+}
+
+impl ReportRow {
+    fn outcome(&self) -> &'static str {
+        match self.outcome {
+            Outcome::Accepted(_) => "accepted",
+            Outcome::Duplicate(_) => "duplicate",
+            Outcome::Rejected(_) => "rejected",
+        }
+    }
+    fn valid(&self) -> Option<&ValidRow> {
+        match &self.outcome {
+            Outcome::Accepted(valid) => Some(valid),
+            _ => None,
+        }
+    }
+    fn error(&self) -> Option<&FieldError> {
+        match &self.outcome {
+            Outcome::Accepted(_) => None,
+            Outcome::Duplicate(e) | Outcome::Rejected(e) => Some(e),
+        }
+    }
+}
+
+// Streams a CSV upload with an `email` and a `name` column, and optionally `status` and `attributes` (a JSON object).
+// Rows are validated like a subscription, written in batches, and reported on per row.
+// Imported rows are pending and get a confirmation email from the background mailer,
+// unless the operator passes `prior_consent=true` and the row's status is `confirmed`.
+// Addresses that are already subscribed are reported as duplicates and left untouched,
+// erased ones (see `SuppressionList`) are rejected.
+#[tracing::instrument(
+    name = "Importing subscribers",
+    skip(payload, parameters, pool, domain_policy, suppressions, admin),
+    fields(admin = %admin.user_id, job_id = tracing::field::Empty)
+)]
+pub async fn import_subscribers(
+    mut payload: web::Payload,
+    parameters: web::Query<ImportParameters>,
+    pool: web::Data<PgPool>,
+    domain_policy: web::Data<DomainPolicy>,
+    suppressions: web::Data<SuppressionList>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let list = find_list(pool.get_ref(), parameters.list.as_deref())
+        .await
+        .context("Failed to look the list up")?
+        .ok_or_else(|| {
+            AdminError::ValidationError(vec![FieldError::new("list", "unknown_list", "There is no list with this name")])
+        })?;
+
+    let mut stream = CsvStream::new();
+    let mut records = Vec::new();
+    let mut columns = None;
+    let mut import: Option<Import> = None;
+    loop {
+        let (fed, at_end) = match payload.next().await {
+            Some(Ok(chunk)) => (stream.feed(&chunk, &mut records).map_err(too_large), false),
+            Some(Err(e)) => (Err(anyhow::Error::new(e).context("Failed to read the upload").into()), false),
+            None => (stream.finish(&mut records).map_err(too_large), true),
+        };
+        if let Err(e) = fed {
+            return Err(match &import {
+                Some(import) => import.fail(&pool, e).await,
+                None => e,
+            });
+        }
+        for record in records.drain(..) {
+            let columns = match &columns {
+                Some(columns) => columns,
+                None => {
+                    columns = Some(Columns::from_header(&record)?);
+                    continue;
+                }
+            };
+            let import = match &mut import {
+                Some(import) => import,
+                None => {
+                    let job = Import::start(&pool, &admin, &list, parameters.prior_consent).await?;
+                    tracing::Span::current().record("job_id", tracing::field::display(job.id));
+                    import.insert(job)
+                }
+            };
+            if record.iter().all(|field| field.trim().is_empty()) {
+                continue;
+            }
+            import.push(record, columns, &domain_policy);
+            if import.pending.len() >= BATCH_SIZE {
+                if let Err(e) = import.flush(&pool, &suppressions).await {
+                    return Err(import.fail(&pool, e.into()).await);
+                }
+            }
+        }
+        if at_end {
+            break;
+        }
+    }
+    let mut import = match (columns, import) {
+        (_, Some(import)) => import,
+        (Some(_), None) => Import::start(&pool, &admin, &list, parameters.prior_consent).await?,
+        (None, None) => {
+            return Err(AdminError::ValidationError(vec![FieldError::new(
+                "file",
+                "empty_file",
+                "The file has no header row",
+            )]))
+        }
+    };
+    if let Err(e) = import.flush(&pool, &suppressions).await {
+        return Err(import.fail(&pool, e.into()).await);
+    }
+    import.complete(&pool).await?;
+    let summary = fetch_summary(&pool, import.id).await?;
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/admin/subscribers/imports/{}", import.id)))
+        .json(summary))
+}
+
+fn too_large(e: RecordTooLarge) -> AdminError {
+    AdminError::ValidationError(vec![FieldError {
+        max: Some(e.max()),
+        ..FieldError::new("file", "record_too_large", e.to_string())
+    }])
+}
+
+// A running import job, the rows validated since the last write are in `pending`.
+struct Import {
+    id: Uuid,
+    actor_id: Uuid,
+    list: MailingList,
+    prior_consent: bool,
+    rows: i64,
+    // canonical addresses seen earlier in the file
+    seen: HashSet<String>,
+    pending: Vec<ReportRow>,
+}
+
+impl Import {
+    async fn start(pool: &PgPool, admin: &AdminUser, list: &MailingList, prior_consent: bool) -> Result<Self, AdminError> {
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            r#"
+            INSERT INTO import_jobs (id, actor_id, list_id, prior_consent, status)
+            VALUES ($1, $2, $3, $4, 'running')
+            "#,
+            id,
+            admin.user_id,
+            list.id,
+            prior_consent,
+        )
+        .execute(pool)
+        .await
+        .context("Failed to record the import job")?;
+        Ok(Self {
+            id,
+            actor_id: admin.user_id,
+            list: list.clone(),
+            prior_consent,
+            rows: 0,
+            seen: HashSet::new(),
+            pending: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, record: Vec<String>, columns: &Columns, domain_policy: &DomainPolicy) {
+        self.rows += 1;
+        let field = |i: usize| record.get(i).map(|f| f.trim()).unwrap_or_default();
+        let email = field(columns.email).chars().take(MAX_REPORTED_EMAIL_LENGTH).collect();
+        let outcome = validate_row(
+            field(columns.email),
+            field(columns.name),
+            columns.status.map(field).unwrap_or_default(),
+            columns.attributes.map(field).unwrap_or_default(),
+            self.prior_consent,
+            domain_policy,
+        );
+        let outcome = match outcome {
+            Outcome::Accepted(row) if !self.seen.insert(row.email.canonical().to_string()) => {
+                Outcome::Duplicate(FieldError::new(
+                    "email",
+                    "duplicate_in_file",
+                    "This email address appears earlier in the file",
+                ))
+            }
+            outcome => outcome,
+        };
+        self.pending.push(ReportRow {
+            row_number: self.rows,
+            email,
+            outcome,
+        });
+    }
+
+    // writes the pending rows and their report in one transaction
+    async fn flush(&mut self, pool: &PgPool, suppressions: &SuppressionList) -> Result<(), anyhow::Error> {
+        let mut rows = std::mem::take(&mut self.pending);
+        let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+
+        // addresses erased on request are never imported again
+        let (positions, emails): (Vec<usize>, Vec<&SubscriberEmail>) = rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, row)| row.valid().map(|valid| (i, &valid.email)))
+            .unzip();
+        let suppressed = suppressions
+            .filter(&mut transaction, &emails)
+            .await
+            .context("Failed to check the suppression list")?;
+        for i in suppressed {
+            rows[positions[i]].outcome = Outcome::Rejected(FieldError::new(
+                "email",
+                "suppressed",
+                "This email address was erased on request and can't be imported",
+            ));
+        }
+
+        let valid: Vec<&ValidRow> = rows.iter().filter_map(ReportRow::valid).collect();
+        let status = |row: &ValidRow| if row.confirmed { "confirmed" } else { "pending_confirmation" };
+        // subscribers who were there already, e.g. on another list, keep their profile and join this list;
+        // the no-op update returns their id, `xmax` is only 0 for the rows inserted
+        let subscribers = sqlx::query!(
+            r#"
+            INSERT INTO subscriptions (id, email, email_canonical, name, subscribed_at, status, attributes, flagged_reason)
+            SELECT id, email, email_canonical, name, now(), status, attributes::jsonb, NULLIF(flagged_reason, '')
+            FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[])
+                AS input(id, email, email_canonical, name, status, attributes, flagged_reason)
+            ON CONFLICT (email_canonical) DO UPDATE SET email_canonical = EXCLUDED.email_canonical
+            RETURNING id, email_canonical, xmax = 0 AS "created!"
+            "#,
+            &valid.iter().map(|row| row.id).collect::<Vec<_>>(),
+            &valid.iter().map(|row| row.email.as_ref().to_string()).collect::<Vec<_>>(),
+            &valid.iter().map(|row| row.email.canonical().to_string()).collect::<Vec<_>>(),
+            &valid.iter().map(|row| row.name.as_ref().to_string()).collect::<Vec<_>>(),
+            &valid.iter().map(|row| status(row).to_string()).collect::<Vec<_>>(),
+            &valid.iter().map(|row| row.attributes.as_ref().to_string()).collect::<Vec<_>>(),
+            &valid.iter().map(|row| row.flagged_reason.unwrap_or_default().to_string()).collect::<Vec<_>>(),
+        )
+        .fetch_all(&mut transaction)
+        .await
+        .context("Failed to insert the imported subscribers")?;
+        let created = subscribers.iter().filter(|r| r.created).count();
+        let subscribers: HashMap<String, Uuid> = subscribers.into_iter().map(|r| (r.email_canonical, r.id)).collect();
+        for row in rows.iter_mut() {
+            if let Outcome::Accepted(valid) = &mut row.outcome {
+                valid.id = subscribers[valid.email.canonical()];
+            }
+        }
+
+        let valid: Vec<&ValidRow> = rows.iter().filter_map(ReportRow::valid).collect();
+        let joined: HashSet<Uuid> = sqlx::query!(
+            r#"
+            INSERT INTO list_memberships (list_id, subscription_id, status, confirmed_at)
+            SELECT $1, id, status, CASE WHEN status = 'confirmed' THEN now() END
+            FROM UNNEST($2::uuid[], $3::text[]) AS input(id, status)
+            ON CONFLICT (list_id, subscription_id) DO NOTHING
+            RETURNING subscription_id
+            "#,
+            self.list.id,
+            &valid.iter().map(|row| row.id).collect::<Vec<_>>(),
+            &valid.iter().map(|row| status(row).to_string()).collect::<Vec<_>>(),
+        )
+        .fetch_all(&mut transaction)
+        .await
+        .context("Failed to add the imported subscribers to the list")?
+        .into_iter()
+        .map(|r| r.subscription_id)
+        .collect();
+
+        for row in rows.iter_mut() {
+            if let Outcome::Accepted(valid) = &row.outcome {
+                if !joined.contains(&valid.id) {
+                    row.outcome = Outcome::Duplicate(FieldError::new(
+                        "email",
+                        "already_subscribed",
+                        "This email address is already on the list",
+                    ));
+                }
+            }
+        }
+        let (mut tokens, mut pending_ids, mut confirmed_ids) = (Vec::new(), Vec::new(), Vec::new());
+        for row in &rows {
+            if let Outcome::Accepted(valid) = &row.outcome {
+                if valid.confirmed {
+                    confirmed_ids.push(valid.id);
+                } else {
+                    tokens.push(generate_subscription_token());
+                    pending_ids.push(valid.id);
+                }
+            }
+        }
+        // sent by `run_confirmation_mailer` once committed
+        sqlx::query!(
+            r#"
+            INSERT INTO subscriptions_token (subscriptions_token, subscription_id, list_id, send_pending)
+            SELECT token, id, $1, true
+            FROM UNNEST($2::text[], $3::uuid[]) AS input(token, id)
+            "#,
+            self.list.id,
+            &tokens,
+            &pending_ids,
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to queue the confirmation emails")?;
+        // the consent is the operator's, on behalf of the subscribers. Those imported as pending
+        // have not consented yet, they do when they confirm.
+        record_consents(&mut transaction, &confirmed_ids, ConsentRecord {
+            event: consent::IMPORT,
+            list: Some(&self.list.slug),
+            source: Some(&format!("import:{}", self.id)),
+            policy_version: None,
+            origin: None,
+            actor_id: Some(self.actor_id),
+        })
+        .await
+        .context("Failed to record the consent")?;
+
+        let details = |detail: fn(&FieldError) -> String| {
+            rows.iter().map(|row| row.error().map(detail).unwrap_or_default()).collect::<Vec<_>>()
+        };
+        sqlx::query!(
+            r#"
+            INSERT INTO import_job_rows (job_id, row_number, email, outcome, field, reason, message)
+            SELECT $1, row_number, email, outcome, NULLIF(field, ''), NULLIF(reason, ''), NULLIF(message, '')
+            FROM UNNEST($2::int8[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[])
+                AS input(row_number, email, outcome, field, reason, message)
+            "#,
+            self.id,
+            &rows.iter().map(|row| row.row_number).collect::<Vec<_>>(),
+            &rows.iter().map(|row| row.email.clone()).collect::<Vec<_>>(),
+            &rows.iter().map(|row| row.outcome().to_string()).collect::<Vec<_>>(),
+            &details(|e| e.field.to_string()),
+            &details(|e| e.code.to_string()),
+            &details(|e| e.message.clone()),
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to write the import report")?;
+
+        let count = |outcome: &str| rows.iter().filter(|row| row.outcome() == outcome).count() as i32;
+        sqlx::query!(
+            r#"
+            UPDATE import_jobs
+            SET accepted = accepted + $2, duplicates = duplicates + $3, rejected = rejected + $4
+            WHERE id = $1
+            "#,
+            self.id,
+            count("accepted"),
+            count("duplicate"),
+            count("rejected"),
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to update the import job")?;
+        transaction.commit().await.context("Failed to commit SQL transaction")?;
+        METRICS.subscriptions_created_total.inc_by(created as u64);
+        Ok(())
+    }
+
+    async fn complete(&self, pool: &PgPool) -> Result<(), AdminError> {
+        self.finish(pool, "completed").await.context("Failed to complete the import job")?;
+        Ok(())
+    }
+
+    // batches written so far stay, the job is marked as failed
+    async fn fail(&self, pool: &PgPool, e: AdminError) -> AdminError {
+        if let Err(e) = self.finish(pool, "failed").await {
+            tracing::error!(error.cause_chain = ?e, "failed to mark the import job as failed");
+        }
+        e
+    }
+
+    async fn finish(&self, pool: &PgPool, status: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"UPDATE import_jobs SET status = $2, finished_at = now() WHERE id = $1"#,
+            self.id,
+            status,
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+fn validate_row(
+    email: &str,
+    name: &str,
+    status: &str,
+    attributes: &str,
+    prior_consent: bool,
+    domain_policy: &DomainPolicy,
+) -> Outcome {
+    let email = match SubscriberEmail::parse(email.to_string()) {
+        Ok(email) => email,
+        Err(e) => return Outcome::Rejected(FieldError::from_domain("email", &e)),
+    };
+    let name = match SubscriberName::parse(name.to_string()) {
+        Ok(name) => name,
+        Err(e) => return Outcome::Rejected(FieldError::from_domain("name", &e)),
+    };
+    let flagged_reason = match domain_policy.evaluate(email.domain()) {
+        PolicyDecision::Accept => None,
+        PolicyDecision::Flag(reason) => Some(reason),
+        PolicyDecision::Reject(code) => {
+            return Outcome::Rejected(FieldError::new(
+                "email",
+                code,
+                "Subscriptions from this email domain are not accepted",
+            ))
+        }
+    };
+    let confirmed = match status {
+        "" | "pending_confirmation" => false,
+        "confirmed" if prior_consent => true,
+        "confirmed" => {
+            return Outcome::Rejected(FieldError::new(
+                "status",
+                "consent_not_asserted",
+                "Rows can only be imported as confirmed with `prior_consent=true`",
+            ))
+        }
+        _ => {
+            return Outcome::Rejected(FieldError::new(
+                "status",
+                "unknown_status",
+                "The status must be `pending_confirmation` or `confirmed`",
+            ))
+        }
+    };
+    let attributes = if attributes.is_empty() {
+        serde_json::json!({})
+    } else {
+        match serde_json::from_str(attributes) {
+            Ok(attributes) => attributes,
+            Err(_) => {
+                return Outcome::Rejected(FieldError::new(
+                    "attributes",
+                    "invalid_json",
+                    "The attributes are not valid JSON",
+                ))
+            }
+        }
+    };
+    let attributes = match CustomAttributes::parse(attributes) {
+        Ok(attributes) => attributes,
+        Err(e) => return Outcome::Rejected(FieldError::new("attributes", e.code(), e.to_string())),
+    };
+    Outcome::Accepted(ValidRow {
+        id: Uuid::new_v4(),
+        email,
+        name,
+        confirmed,
+        attributes,
+        flagged_reason,
+    })
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ReportParameters {
+    outcome: Option<String>,
+    // the last `row_number` of the previous page
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+struct ImportReport {
+    #[serde(flatten)]
+    summary: ImportSummary,
+    rows: Vec<ReportEntry>,
+    // pass back as `after` for the next page, `null` on the last one
+    next_after: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+struct ReportEntry {
+    row_number: i64,
+    email: String,
+    outcome: String,
+    field: Option<String>,
+    reason: Option<String>,
+    message: Option<String>,
+}
+
+// The job's counts and its rows in file order, optionally only those with one `outcome`.
+#[tracing::instrument(name = "Showing an import report", skip(parameters, pool, admin), fields(admin = %admin.user_id))]
+pub async fn get_import(
+    id: web::Path<Uuid>,
+    parameters: web::Query<ReportParameters>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    if let Some(outcome) = &parameters.outcome {
+        if !OUTCOMES.contains(&outcome.as_str()) {
+            return Err(AdminError::ValidationError(vec![FieldError::new(
+                "outcome",
+                "unknown_outcome",
+                format!("The outcome must be one of {:?}", OUTCOMES),
+            )]));
+        }
+    }
+    let summary = fetch_summary(&pool, id.into_inner()).await?;
+    let limit = parameters.limit.unwrap_or(DEFAULT_REPORT_PAGE_SIZE).clamp(1, MAX_REPORT_PAGE_SIZE);
+    // one extra row tells whether there is a next page
+    let mut rows = sqlx::query_as!(
+        ReportEntry,
+        r#"
+        SELECT row_number, email, outcome, field, reason, message
+        FROM import_job_rows
+        WHERE job_id = $1 AND row_number > $2 AND ($3::text IS NULL OR outcome = $3)
+        ORDER BY row_number
+        LIMIT $4
+        "#,
+        summary.id,
+        parameters.after.unwrap_or(0),
+        parameters.outcome,
+        limit + 1,
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to fetch the import report")?;
+    let next_after = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|last| last.row_number)
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(ImportReport { summary, rows, next_after }))
+}
+
+async fn fetch_summary(pool: &PgPool, id: Uuid) -> Result<ImportSummary, AdminError> {
+    sqlx::query_as!(
+        ImportSummary,
+        r#"
+        SELECT import_jobs.id, lists.slug AS list, prior_consent, status, accepted, duplicates, rejected,
+            import_jobs.created_at, finished_at
+        FROM import_jobs JOIN lists ON lists.id = import_jobs.list_id
+        WHERE import_jobs.id = $1
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the import job")?
+    .ok_or(AdminError::NotFound("import job"))
+}
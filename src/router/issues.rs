@@ -0,0 +1,727 @@
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, LocalResult, NaiveDateTime, TimeZone as _, Utc};
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::archive::archive_slug;
+use crate::authentication::AdminUser;
+use crate::configuration::NewsletterSettings;
+use crate::domain::{SubscriberEmail, TimeZone};
+use crate::email_client::EmailClient;
+use crate::metrics::observe_acquire;
+use crate::problem::FieldError;
+use crate::router::{parse_content, render_email, validate_draft, Content, IssueDraft, PublishError};
+use crate::startup::ApplicationBaseUrl;
+use crate::telemetry::Sensitive;
+use crate::template::Recipient;
+
+const STATUSES: [&str; 7] = ["draft", "pending_approval", "scheduled", "sending", "sent", "cancelled", "failed"];
+const MAX_TEST_RECIPIENTS: usize = 10;
+
+// When a scheduled issue goes out.
+pub struct SendAt {
+    pub at: DateTime<Utc>,
+    // the zone it was given in, if any
+    pub timezone: Option<TimeZone>,
+}
+
+impl SendAt {
+    // `send_at` is RFC 3339, or a local time like `2023-10-30T09:00` in `timezone`,
+    // so "Monday 09:00 in Paris" stays 09:00 whichever side of a DST change Monday is.
+    pub fn parse(send_at: &str, timezone: Option<&str>, now: DateTime<Utc>) -> Result<Self, FieldError> {
+        let timezone = timezone
+            .filter(|tz| !tz.trim().is_empty())
+            .map(TimeZone::parse)
+            .transpose()
+            .map_err(|e| FieldError::from_domain("timezone", &e))?;
+        let send_at = send_at.trim();
+        let at = match DateTime::parse_from_rfc3339(send_at) {
+            Ok(at) => at.with_timezone(&Utc),
+            Err(_) => {
+                let local = NaiveDateTime::parse_from_str(send_at, "%Y-%m-%dT%H:%M:%S")
+                    .or_else(|_| NaiveDateTime::parse_from_str(send_at, "%Y-%m-%dT%H:%M"))
+                    .map_err(|_| {
+                        FieldError::new(
+                            "send_at",
+                            "invalid_datetime",
+                            "The send time must be RFC 3339, or a local time like 2023-10-30T09:00 with a timezone",
+                        )
+                    })?;
+                let tz = timezone.as_ref().ok_or_else(|| {
+                    FieldError::new("timezone", "missing_timezone", "A send time without an offset needs a timezone")
+                })?;
+                match tz.tz().from_local_datetime(&local) {
+                    LocalResult::Single(at) => at.with_timezone(&Utc),
+                    // the clocks go back, the first of the two
+                    LocalResult::Ambiguous(earliest, _) => earliest.with_timezone(&Utc),
+                    LocalResult::None => {
+                        return Err(FieldError::new(
+                            "send_at",
+                            "nonexistent_local_time",
+                            "This local time is skipped when the clocks go forward",
+                        ))
+                    }
+                }
+            }
+        };
+        if at <= now {
+            return Err(FieldError::new("send_at", "in_the_past", "The send time has already passed"));
+        }
+        Ok(Self { at, timezone })
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct IssueRecord {
+    id: Uuid,
+    title: String,
+    html_content: String,
+    plain_content: String,
+    lists: Vec<String>,
+    segment: Option<serde_json::Value>,
+    pub(crate) status: String,
+    revision: i32,
+    send_at: Option<DateTime<Utc>>,
+    timezone: Option<String>,
+    last_error: Option<String>,
+    created_by: Option<Uuid>,
+    publish_requested_by: Option<Uuid>,
+    approved_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    // set when published to the public archive
+    archive_slug: Option<String>,
+}
+
+pub async fn find_issue<'e>(executor: impl PgExecutor<'e>, id: Uuid) -> Result<IssueRecord, PublishError> {
+    sqlx::query_as!(
+        IssueRecord,
+        r#"
+        SELECT id, title, html_content, plain_content, lists, segment, status, revision, send_at, timezone, last_error,
+            created_by, publish_requested_by, approved_by, created_at, started_at, finished_at, archive_slug
+        FROM newsletter_issues WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(executor)
+    .await
+    .context("Failed to fetch the issue")?
+    .ok_or(PublishError::NotFound)
+}
+
+// The status a new issue is stored with, and when it goes out.
+pub struct IssueState<'a> {
+    status: &'static str,
+    send_at: Option<DateTime<Utc>>,
+    timezone: Option<&'a str>,
+}
+
+impl<'a> IssueState<'a> {
+    pub fn draft() -> Self {
+        Self { status: "draft", send_at: None, timezone: None }
+    }
+    pub fn scheduled(schedule: &'a SendAt) -> Self {
+        Self {
+            status: "scheduled",
+            send_at: Some(schedule.at),
+            timezone: schedule.timezone.as_ref().map(|tz| tz.as_ref()),
+        }
+    }
+    // delivered by the request that created it
+    pub fn sending() -> Self {
+        Self { status: "sending", send_at: Some(Utc::now()), timezone: None }
+    }
+}
+
+// stores the issue with its content as the first revision
+pub async fn insert_issue(
+    pool: &PgPool,
+    issue: &IssueDraft,
+    state: IssueState<'_>,
+    created_by: Option<Uuid>,
+) -> Result<Uuid, anyhow::Error> {
+    let id = Uuid::new_v4();
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues
+            (id, title, html_content, plain_content, lists, segment, status, send_at, timezone, created_by, started_at,
+             publish_requested_by)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, CASE WHEN $7 = 'sending' THEN now() END,
+            -- published as it is created, by its author
+            CASE WHEN $7 <> 'draft' THEN $10::uuid END)
+        "#,
+        id,
+        issue.title,
+        issue.content.html,
+        issue.content.plain,
+        &issue.lists,
+        issue.segment,
+        state.status,
+        state.send_at,
+        state.timezone,
+        created_by,
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to store the newsletter issue")?;
+    insert_revision(&mut transaction, id, 1, issue, created_by).await?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(id)
+}
+
+async fn insert_revision(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+    revision: i32,
+    issue: &IssueDraft,
+    edited_by: Option<Uuid>,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issue_revisions (issue_id, revision, title, html_content, plain_content, lists, segment, edited_by)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        issue_id,
+        revision,
+        issue.title,
+        issue.content.html,
+        issue.content.plain,
+        &issue.lists,
+        issue.segment,
+        edited_by,
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to store the issue revision")?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+pub struct IssueFilter {
+    status: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct IssueList {
+    issues: Vec<IssueRecord>,
+}
+
+// Every issue, or only those with `status`, in sending order; drafts last.
+#[tracing::instrument(name = "Listing newsletter issues", skip(filter, pool, admin), fields(admin = %admin.user_id))]
+pub async fn list_issues(
+    filter: web::Query<IssueFilter>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    if let Some(status) = &filter.status {
+        if !STATUSES.contains(&status.as_str()) {
+            return Err(PublishError::ValidationError(vec![FieldError::new(
+                "status",
+                "unknown_status",
+                format!("The status must be one of {:?}", STATUSES),
+            )]));
+        }
+    }
+    let issues = sqlx::query_as!(
+        IssueRecord,
+        r#"
+        SELECT id, title, html_content, plain_content, lists, segment, status, revision, send_at, timezone, last_error,
+            created_by, publish_requested_by, approved_by, created_at, started_at, finished_at, archive_slug
+        FROM newsletter_issues
+        WHERE $1::text IS NULL OR status = $1
+        ORDER BY send_at NULLS LAST, created_at
+        "#,
+        filter.status
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to list the issues")?;
+    Ok(HttpResponse::Ok().json(IssueList { issues }))
+}
+
+#[tracing::instrument(name = "Showing a newsletter issue", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn get_issue(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    Ok(HttpResponse::Ok().json(find_issue(pool.get_ref(), id.into_inner()).await?))
+}
+
+// Creates an issue as a draft, nothing is sent until it is published.
+#[tracing::instrument(name = "Creating a newsletter draft", skip(body, pool, admin), fields(admin = %admin.user_id))]
+pub async fn create_draft(
+    body: web::Json<IssueDraft>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    validate_draft(&pool, &body).await?;
+    let id = insert_issue(&pool, &body, IssueState::draft(), Some(admin.user_id)).await?;
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/newsletter/issues/{}", id)))
+        .json(find_issue(pool.get_ref(), id).await?))
+}
+
+// Replaces the content of a draft, keeping the previous version as a revision.
+#[tracing::instrument(name = "Editing a newsletter draft", skip(body, pool, admin), fields(admin = %admin.user_id))]
+pub async fn edit_draft(
+    id: web::Path<Uuid>,
+    body: web::Json<IssueDraft>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    validate_draft(&pool, &body).await?;
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let revision = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET title = $2, html_content = $3, plain_content = $4, lists = $5, segment = $6,
+            revision = revision + 1, updated_at = now()
+        WHERE id = $1 AND status = 'draft'
+        RETURNING revision
+        "#,
+        id,
+        body.title,
+        body.content.html,
+        body.content.plain,
+        &body.lists,
+        body.segment,
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to update the draft")?;
+    let revision = match revision {
+        Some(row) => row.revision,
+        None => return Err(status_conflict(&pool, id).await),
+    };
+    insert_revision(&mut transaction, id, revision, &body, Some(admin.user_id)).await?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::Ok().json(find_issue(pool.get_ref(), id).await?))
+}
+
+#[derive(serde::Serialize)]
+struct RevisionRecord {
+    revision: i32,
+    title: String,
+    html_content: String,
+    plain_content: String,
+    lists: Vec<String>,
+    segment: Option<serde_json::Value>,
+    edited_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct RevisionList {
+    revisions: Vec<RevisionRecord>,
+}
+
+// The content of every version of an issue, oldest first.
+#[tracing::instrument(name = "Listing the revisions of an issue", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn list_revisions(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    find_issue(pool.get_ref(), id).await?;
+    let revisions = sqlx::query_as!(
+        RevisionRecord,
+        r#"
+        SELECT revision, title, html_content, plain_content, lists, segment, edited_by, created_at
+        FROM newsletter_issue_revisions WHERE issue_id = $1 ORDER BY revision
+        "#,
+        id
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to list the revisions")?;
+    Ok(HttpResponse::Ok().json(RevisionList { revisions }))
+}
+
+// a revision of the issue, the latest when `revision` is None
+async fn find_revision(pool: &PgPool, id: Uuid, revision: Option<i32>) -> Result<RevisionRecord, PublishError> {
+    sqlx::query_as!(
+        RevisionRecord,
+        r#"
+        SELECT revision, title, html_content, plain_content, lists, segment, edited_by, created_at
+        FROM newsletter_issue_revisions
+        WHERE issue_id = $1 AND revision = COALESCE($2, (SELECT revision FROM newsletter_issues WHERE id = $1))
+        "#,
+        id,
+        revision
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the revision")?
+    .ok_or(PublishError::NotFound)
+}
+
+// stands in for a subscriber's unsubscribe link in previews and test sends
+fn sample_unsubscribe_link(base_url: &str) -> String {
+    format!("{}/subscriptions/unsubscribe?token=preview", base_url)
+}
+
+// The revision as a subscriber without a name or attributes would get it, so placeholders show their defaults.
+fn render_sample(revision: &RevisionRecord, base_url: &str) -> Result<(String, String), PublishError> {
+    let (html, plain) = parse_content(&revision.html_content, &revision.plain_content)?;
+    let unsubscribe_link = sample_unsubscribe_link(base_url);
+    let recipient = Recipient {
+        name: "",
+        email: "",
+        unsubscribe_url: &unsubscribe_link,
+        attributes: &serde_json::Value::Null,
+    };
+    Ok(render_email(&html, &plain, &recipient))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreviewParameters {
+    revision: Option<i32>,
+}
+
+#[derive(serde::Serialize)]
+struct Preview {
+    revision: i32,
+    subject: String,
+    html: String,
+    plain: String,
+}
+
+// The email as a subscriber would get it, for the latest revision or `?revision=`.
+#[tracing::instrument(name = "Previewing a newsletter issue", skip(parameters, pool, base_url, admin), fields(admin = %admin.user_id))]
+pub async fn preview_issue(
+    id: web::Path<Uuid>,
+    parameters: web::Query<PreviewParameters>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let revision = find_revision(&pool, id.into_inner(), parameters.revision).await?;
+    let (html, plain) = render_sample(&revision, &base_url.0)?;
+    Ok(HttpResponse::Ok().json(Preview {
+        revision: revision.revision,
+        subject: revision.title,
+        html,
+        plain,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct TestSend {
+    recipients: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct TestSendOutcome {
+    revision: i32,
+    sent: usize,
+}
+
+// Sends the latest revision to a few internal addresses, whatever the issue's status.
+// The subject is marked as a test and nothing is recorded against subscribers.
+#[tracing::instrument(
+    name = "Test-sending a newsletter issue",
+    skip(body, pool, email_client, base_url, settings, admin),
+    fields(admin = %admin.user_id)
+)]
+pub async fn test_send_issue(
+    id: web::Path<Uuid>,
+    body: web::Json<TestSend>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<NewsletterSettings>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let recipients = parse_test_recipients(&body.recipients, &settings.internal_domains)
+        .map_err(PublishError::ValidationError)?;
+    let revision = find_revision(&pool, id.into_inner(), None).await?;
+    let (html, plain) = render_sample(&revision, &base_url.0)?;
+    let subject = format!("[Test] {}", revision.title);
+    for recipient in &recipients {
+        email_client
+            .send_email(recipient, &subject, &html, &plain)
+            .await
+            .with_context(|| format!("Failed to send the test email to {}", Sensitive(recipient)))?;
+    }
+    Ok(HttpResponse::Ok().json(TestSendOutcome {
+        revision: revision.revision,
+        sent: recipients.len(),
+    }))
+}
+
+fn parse_test_recipients(recipients: &[String], internal_domains: &[String]) -> Result<Vec<SubscriberEmail>, Vec<FieldError>> {
+    if recipients.is_empty() {
+        return Err(vec![FieldError::new("recipients", "empty", "At least one recipient is needed")]);
+    }
+    if recipients.len() > MAX_TEST_RECIPIENTS {
+        return Err(vec![FieldError {
+            max: Some(MAX_TEST_RECIPIENTS),
+            ..FieldError::new(
+                "recipients",
+                "too_many",
+                format!("A test goes to at most {} recipients", MAX_TEST_RECIPIENTS),
+            )
+        }]);
+    }
+    let mut parsed = Vec::new();
+    let mut errors = Vec::new();
+    for recipient in recipients {
+        match SubscriberEmail::parse(recipient.clone()) {
+            Err(e) => errors.push(FieldError::from_domain("recipients", &e)),
+            Ok(email) if !is_internal(&email, internal_domains) => errors.push(FieldError::new(
+                "recipients",
+                "not_internal",
+                format!("Test sends only go to {:?}", internal_domains),
+            )),
+            Ok(email) => parsed.push(email),
+        }
+    }
+    if errors.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(errors)
+    }
+}
+
+fn is_internal(email: &SubscriberEmail, internal_domains: &[String]) -> bool {
+    let domain = email.as_ref().rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+    internal_domains.is_empty() || internal_domains.iter().any(|internal| internal.eq_ignore_ascii_case(domain))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PublishRequest {
+    // right away when absent, see `SendAt`
+    #[serde(default)]
+    send_at: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+    // also publish it to the public archive once sent
+    #[serde(default)]
+    archive: bool,
+}
+
+// Publishes a draft: it is scheduled for `send_at`, or right away, unless approval is required,
+// in which case it waits for a second admin to approve it.
+#[tracing::instrument(name = "Publishing a newsletter issue", skip(body, pool, settings, admin), fields(admin = %admin.user_id))]
+pub async fn publish_issue(
+    id: web::Path<Uuid>,
+    body: web::Json<PublishRequest>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<NewsletterSettings>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    let schedule = match &body.send_at {
+        Some(send_at) => Some(
+            SendAt::parse(send_at, body.timezone.as_deref(), Utc::now())
+                .map_err(|e| PublishError::ValidationError(vec![e]))?,
+        ),
+        None => None,
+    };
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let issue = sqlx::query!(
+        r#"
+        SELECT status, title, html_content, plain_content, lists, segment
+        FROM newsletter_issues WHERE id = $1 FOR UPDATE
+        "#,
+        id
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to fetch the issue")?
+    .ok_or(PublishError::NotFound)?;
+    if issue.status != "draft" {
+        return Err(PublishError::InvalidStatus(issue.status));
+    }
+    // a list may have gone since the draft was written
+    let draft = IssueDraft {
+        title: issue.title,
+        content: Content {
+            html: issue.html_content,
+            plain: issue.plain_content,
+        },
+        lists: issue.lists,
+        segment: issue.segment,
+    };
+    validate_draft(&pool, &draft).await?;
+    let (status, send_at) = match (settings.require_approval, &schedule) {
+        (true, schedule) => ("pending_approval", schedule.as_ref().map(|s| s.at)),
+        (false, Some(schedule)) => ("scheduled", Some(schedule.at)),
+        // the scheduler picks it up within a second
+        (false, None) => ("scheduled", Some(Utc::now())),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, send_at = $3, timezone = $4, publish_requested_by = $5, archive_slug = $6, updated_at = now()
+        WHERE id = $1
+        "#,
+        id,
+        status,
+        send_at,
+        schedule.as_ref().and_then(|s| s.timezone.as_ref()).map(|tz| tz.as_ref()),
+        admin.user_id,
+        body.archive.then(|| archive_slug(&draft.title, id)),
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to publish the issue")?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::Accepted().json(find_issue(pool.get_ref(), id).await?))
+}
+
+// Approves an issue awaiting approval, by another admin than the one who published it.
+// It goes out at the time it was published for, or right away if that has passed.
+#[tracing::instrument(name = "Approving a newsletter issue", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn approve_issue(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let issue = sqlx::query!(
+        r#"SELECT status, publish_requested_by FROM newsletter_issues WHERE id = $1 FOR UPDATE"#,
+        id
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to fetch the issue")?
+    .ok_or(PublishError::NotFound)?;
+    if issue.status != "pending_approval" {
+        return Err(PublishError::InvalidStatus(issue.status));
+    }
+    if issue.publish_requested_by == Some(admin.user_id) {
+        return Err(PublishError::SelfApproval);
+    }
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'scheduled', approved_by = $2, send_at = GREATEST(COALESCE(send_at, now()), now()), updated_at = now()
+        WHERE id = $1
+        "#,
+        id,
+        admin.user_id,
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to approve the issue")?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::Ok().json(find_issue(pool.get_ref(), id).await?))
+}
+
+#[derive(serde::Deserialize)]
+pub struct Reschedule {
+    send_at: String,
+    #[serde(default)]
+    timezone: Option<String>,
+}
+
+// Moves a scheduled issue to another time; one that has started sending can't be moved.
+#[tracing::instrument(name = "Rescheduling a newsletter issue", skip(body, pool, admin), fields(admin = %admin.user_id))]
+pub async fn reschedule_issue(
+    id: web::Path<Uuid>,
+    body: web::Json<Reschedule>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    let schedule = SendAt::parse(&body.send_at, body.timezone.as_deref(), Utc::now())
+        .map_err(|e| PublishError::ValidationError(vec![e]))?;
+    // the scheduler only claims issues that are due, so once this commits it waits for the new time
+    let rescheduled = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET send_at = $2, timezone = $3, updated_at = now()
+        WHERE id = $1 AND status = 'scheduled'
+        "#,
+        id,
+        schedule.at,
+        schedule.timezone.as_ref().map(|tz| tz.as_ref()),
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to reschedule the issue")?;
+    if rescheduled.rows_affected() == 0 {
+        return Err(status_conflict(&pool, id).await);
+    }
+    Ok(HttpResponse::Ok().json(find_issue(pool.get_ref(), id).await?))
+}
+
+// Cancels an issue that hasn't started sending, it stays listed as `cancelled`.
+#[tracing::instrument(name = "Cancelling a newsletter issue", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn cancel_issue(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let id = id.into_inner();
+    let cancelled = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET status = 'cancelled', finished_at = now(), updated_at = now()
+        WHERE id = $1 AND status IN ('draft', 'pending_approval', 'scheduled')
+        "#,
+        id
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to cancel the issue")?;
+    if cancelled.rows_affected() == 0 {
+        return Err(status_conflict(&pool, id).await);
+    }
+    Ok(HttpResponse::Ok().json(find_issue(pool.get_ref(), id).await?))
+}
+
+// why an issue couldn't be changed: it doesn't exist or its status doesn't allow it
+async fn status_conflict(pool: &PgPool, id: Uuid) -> PublishError {
+    match find_issue(pool, id).await {
+        Ok(issue) => PublishError::InvalidStatus(issue.status),
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SendAt;
+    use chrono::{DateTime, Utc};
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn local_times_are_read_in_the_timezone() {
+        let now = utc("2023-10-20T12:00:00Z");
+        // summer time on the first, winter time on the second
+        let summer = SendAt::parse("2023-10-23T09:00", Some("Europe/Paris"), now).unwrap();
+        let winter = SendAt::parse("2023-10-30T09:00:00", Some("Europe/Paris"), now).unwrap();
+        assert_eq!(summer.at, utc("2023-10-23T07:00:00Z"));
+        assert_eq!(winter.at, utc("2023-10-30T08:00:00Z"));
+        assert_eq!(winter.timezone.unwrap().as_ref(), "Europe/Paris");
+    }
+
+    #[test]
+    fn an_explicit_offset_needs_no_timezone() {
+        let now = utc("2023-10-20T12:00:00Z");
+        let send_at = SendAt::parse("2023-10-30T09:00:00-04:00", None, now).unwrap();
+        assert_eq!(send_at.at, utc("2023-10-30T13:00:00Z"));
+    }
+
+    #[test]
+    fn unusable_send_times_are_rejected() {
+        let now = utc("2023-10-20T12:00:00Z");
+        let code = |send_at: &str, timezone: Option<&str>| SendAt::parse(send_at, timezone, now).err().unwrap().code;
+        assert_eq!(code("2023-10-30T09:00", None), "missing_timezone");
+        assert_eq!(code("2023-10-30T09:00", Some("Mars/Olympus")), "unknown_timezone");
+        assert_eq!(code("next monday", Some("Europe/Paris")), "invalid_datetime");
+        assert_eq!(code("2023-10-19T09:00:00Z", None), "in_the_past");
+        // 02:30 doesn't exist in Paris on the last Sunday of March
+        assert_eq!(code("2024-03-31T02:30", Some("Europe/Paris")), "nonexistent_local_time");
+    }
+}
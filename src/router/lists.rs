@@ -0,0 +1,125 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::AdminUser;
+use crate::problem::FieldError;
+use crate::router::AdminError;
+
+const MAX_SLUG_LENGTH: usize = 64;
+const MAX_NAME_LENGTH: usize = 256;
+
+#[derive(serde::Serialize)]
+pub struct ListRecord {
+    slug: String,
+    name: String,
+    is_default: bool,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewList {
+    slug: String,
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ListUpdate {
+    name: String,
+}
+
+#[tracing::instrument(name = "Listing the mailing lists", skip(pool, admin), fields(admin = %admin.user_id))]
+pub async fn list_lists(pool: web::Data<PgPool>, admin: AdminUser) -> Result<HttpResponse, AdminError> {
+    let lists = sqlx::query_as!(ListRecord, r#"SELECT slug, name, is_default, created_at FROM lists ORDER BY slug"#)
+        .fetch_all(pool.get_ref())
+        .await
+        .context("Failed to fetch the lists")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "lists": lists })))
+}
+
+// The slug is how subscription forms, newsletters and unsubscribe links name the list, so it
+// can't be changed once the list exists; the name can.
+#[tracing::instrument(name = "Creating a mailing list", skip(body, pool, admin), fields(admin = %admin.user_id))]
+pub async fn create_list(
+    body: web::Json<NewList>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let NewList { slug, name } = body.into_inner();
+    let errors: Vec<FieldError> = [check_slug(&slug), check_name(&name)].into_iter().flatten().collect();
+    if !errors.is_empty() {
+        return Err(AdminError::ValidationError(errors));
+    }
+    let list = sqlx::query_as!(
+        ListRecord,
+        r#"
+        INSERT INTO lists (id, slug, name) VALUES ($1, $2, $3)
+        ON CONFLICT (slug) DO NOTHING
+        RETURNING slug, name, is_default, created_at
+        "#,
+        Uuid::new_v4(),
+        slug,
+        name.trim()
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to create the list")?
+    .ok_or_else(|| {
+        AdminError::ValidationError(vec![FieldError::new("slug", "slug_taken", "There is already a list with this slug")])
+    })?;
+    Ok(HttpResponse::Created().json(list))
+}
+
+#[tracing::instrument(name = "Renaming a mailing list", skip(body, pool, admin), fields(admin = %admin.user_id))]
+pub async fn rename_list(
+    slug: web::Path<String>,
+    body: web::Json<ListUpdate>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let ListUpdate { name } = body.into_inner();
+    if let Some(error) = check_name(&name) {
+        return Err(AdminError::ValidationError(vec![error]));
+    }
+    let list = sqlx::query_as!(
+        ListRecord,
+        r#"UPDATE lists SET name = $2 WHERE slug = $1 RETURNING slug, name, is_default, created_at"#,
+        slug.as_str(),
+        name.trim()
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to rename the list")?
+    .ok_or(AdminError::NotFound("list"))?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+// lowercase letters, digits and dashes, so it reads the same in a form and in a URL
+fn check_slug(slug: &str) -> Option<FieldError> {
+    let valid = slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if slug.is_empty() || !valid || slug.starts_with('-') || slug.ends_with('-') {
+        Some(FieldError::new("slug", "invalid_slug", "Lowercase letters, digits and dashes, e.g. engineering-notes"))
+    } else if slug.len() > MAX_SLUG_LENGTH {
+        Some(FieldError {
+            max: Some(MAX_SLUG_LENGTH),
+            ..FieldError::new("slug", "too_long", format!("At most {} characters", MAX_SLUG_LENGTH))
+        })
+    } else {
+        None
+    }
+}
+
+fn check_name(name: &str) -> Option<FieldError> {
+    if name.trim().is_empty() {
+        Some(FieldError::new("name", "empty", "The name can't be empty"))
+    } else if name.trim().chars().count() > MAX_NAME_LENGTH {
+        Some(FieldError {
+            max: Some(MAX_NAME_LENGTH),
+            ..FieldError::new("name", "too_long", format!("At most {} characters", MAX_NAME_LENGTH))
+        })
+    } else {
+        None
+    }
+}
@@ -0,0 +1,36 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+use crate::metrics::{observe_acquire, METRICS};
+
+// Prometheus scrape endpoint.
+// Pool and pending-confirmation gauges are sampled here, everything else is updated where it happens.
+// Queries run straight on the pool don't time their wait, so the wait for the connection counting
+// the pending confirmations is a sample of it, once per scrape.
+#[tracing::instrument(name = "Render metrics", skip(pool))]
+pub async fn metrics(pool: web::Data<PgPool>) -> HttpResponse {
+    METRICS.db_pool_connections.set(pool.size() as i64);
+    METRICS.db_pool_idle_connections.set(pool.num_idle() as i64);
+    match count_pending_confirmations(&pool).await {
+        Ok(pending) => METRICS.pending_confirmation_emails.set(pending),
+        Err(e) => tracing::warn!(error.cause_chain = ?e, "failed to count pending confirmations"),
+    }
+    match METRICS.encode() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "failed to encode metrics");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// the confirmation emails queued for the background mailer
+async fn count_pending_confirmations(pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let mut connection = observe_acquire(pool.acquire()).await?;
+    let row = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions_token WHERE send_pending"#)
+        .fetch_one(&mut connection)
+        .await?;
+    Ok(row.count)
+}
@@ -2,8 +2,36 @@ mod health_check;
 mod subscriptions;
 mod subscriptions_confirm;
 mod newsletter;
+mod readiness;
+mod metrics;
+mod extractors;
+mod preferences;
+mod admin;
+mod import;
+mod export;
+mod privacy;
+mod issues;
+mod deliveries;
+mod webhooks;
+mod engagement;
+mod archive;
+mod lists;
 
 pub use health_check::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
-pub use newsletter::*;
\ No newline at end of file
+pub use newsletter::*;
+pub use readiness::*;
+pub use metrics::*;
+pub use extractors::*;
+pub use preferences::*;
+pub use admin::*;
+pub use import::*;
+pub use export::*;
+pub use privacy::*;
+pub use issues::*;
+pub use deliveries::*;
+pub use webhooks::*;
+pub use engagement::*;
+pub use archive::*;
+pub use lists::*;
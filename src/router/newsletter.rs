@@ -1,29 +1,84 @@
+use crate::authentication::AdminUser;
 use crate::email_client::EmailClient;
+use crate::lists::{find_list, find_lists, MailingList};
+use crate::metrics::observe_acquire;
+use crate::router::PreferenceCenter;
+use crate::segment::{Segment, SqlFilter};
+use crate::configuration::NewsletterSettings;
+use crate::router::{find_issue, insert_issue, IssueState, SendAt};
+use crate::sent_emails::record_issue_sent;
+use crate::startup::ApplicationBaseUrl;
+use crate::problem::{FieldError, Problem};
+use crate::shutdown::ShutdownListener;
+use crate::telemetry::Sensitive;
+use crate::template::{escape_html, Escape, Recipient, Template};
+use crate::tracking::{TrackedEmail, Tracker};
 use crate::{domain::SubscriberEmail, router::error_chain_fmt};
+use actix_web::http::header;
 use actix_web::ResponseError;
 use actix_web::{web, HttpResponse};
 use anyhow::Context;
-use sqlx::PgPool;
+use chrono::Utc;
+use sqlx::{PgPool, Row};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use uuid::Uuid;
 // define a struct called BodyData that contains both title and content fields.
 #[derive(serde::Deserialize)]
 pub struct BodyData {
-    title: String,
-    content: Content,
+    #[serde(flatten)]
+    issue: IssueDraft,
+    // send later rather than now: RFC 3339, or a local time like `2023-10-30T09:00` in `timezone`
+    #[serde(default)]
+    send_at: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+}
+// What an issue sends, as published directly or written as a draft.
+#[derive(serde::Deserialize)]
+pub struct IssueDraft {
+    pub(crate) title: String,
+    pub(crate) content: Content,
+    // slugs of the lists to send to, the default list when empty
+    #[serde(default)]
+    pub(crate) lists: Vec<String>,
+    // only send to the subscribers matching this filter, see `Segment`
+    #[serde(default)]
+    pub(crate) segment: Option<serde_json::Value>,
 }
 // define Content struct that contains plain text and html text.
 #[derive(serde::Deserialize)]
 pub struct Content {
-    plain: String,
-    html: String,
+    pub(crate) plain: String,
+    pub(crate) html: String,
 }
-struct ConfirmedSubscriber {
+pub struct ConfirmedSubscriber {
+    id: Uuid,
     email: SubscriberEmail,
+    // the targeted lists this subscriber is on
+    lists: Vec<String>,
+    tracking_opt_out: bool,
+    // for the placeholders of the content
+    name: String,
+    attributes: serde_json::Value,
 }
 // define some error types
 #[derive(thiserror::Error)]
 pub enum PublishError {
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
+    #[error("The newsletter is not valid")]
+    ValidationError(Vec<FieldError>),
+    #[error("The server is shutting down, delivery was interrupted")]
+    ShuttingDown,
+    #[error("There is no issue with this id")]
+    NotFound,
+    #[error("The issue is {0}, which doesn't allow this")]
+    InvalidStatus(String),
+    #[error("Issues must be approved by another admin than the one who published them")]
+    SelfApproval,
+    #[error("Publishing requires a second admin's approval, create an issue and publish it instead")]
+    ApprovalRequired,
 }
 
 impl std::fmt::Debug for PublishError {
@@ -36,68 +91,524 @@ impl ResponseError for PublishError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             PublishError::UnexpectedError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            PublishError::ValidationError(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            PublishError::ShuttingDown => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
+            PublishError::NotFound => actix_web::http::StatusCode::NOT_FOUND,
+            PublishError::InvalidStatus(_) => actix_web::http::StatusCode::CONFLICT,
+            PublishError::SelfApproval | PublishError::ApprovalRequired => actix_web::http::StatusCode::FORBIDDEN,
         }
     }
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::new(self.status_code())
+        match self {
+            PublishError::ValidationError(errors) => Problem::validation(errors.clone()).response(),
+            PublishError::UnexpectedError(_) => Problem::new(self.status_code()).response(),
+            _ => Problem::new(self.status_code()).with_detail(self.to_string()).response(),
+        }
+    }
+}
+
+impl PublishError {
+    // for a delivery that can't answer with a problem, e.g. the issue's `last_error`
+    fn into_cause(self) -> anyhow::Error {
+        match self {
+            PublishError::UnexpectedError(e) => e,
+            PublishError::ValidationError(errors) => {
+                anyhow::anyhow!(errors.into_iter().map(|e| e.message).collect::<Vec<_>>().join("; "))
+            }
+            other => anyhow::anyhow!(other.to_string()),
+        }
     }
 }
 
 // publish_newsletter function
-// return 200 OK if newsletter is sent successfully.
-#[tracing::instrument(name = "Publish a newsletter", skip(_body, pool, email_client))]
+// return 200 OK if newsletter is sent successfully, or 202 Accepted when it is scheduled with `send_at`.
+// when a shutdown starts mid-send we finish the current email and stop with a 503,
+// the issue scheduler sends the rest after the restart.
+// someone on several of the targeted lists gets a single copy.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "Publish a newsletter",
+    skip(body, pool, email_client, shutdown, base_url, preference_center, tracker, settings, admin),
+    fields(admin = %admin.user_id)
+)]
 pub async fn publish_newsletter(
-    _body: web::Json<BodyData>,
+    body: web::Json<BodyData>,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
+    shutdown: web::Data<ShutdownListener>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    preference_center: web::Data<PreferenceCenter>,
+    tracker: web::Data<Tracker>,
+    settings: web::Data<NewsletterSettings>,
+    admin: AdminUser,
 ) -> Result<HttpResponse, PublishError> {
-    let subscribers = get_confirmed_subscribers(&pool).await?;
-    for subscriber in subscribers {
+    // there is nobody to approve a newsletter sent this way
+    if settings.require_approval {
+        return Err(PublishError::ApprovalRequired);
+    }
+    validate_draft(&pool, &body.issue).await?;
+    let schedule = match &body.send_at {
+        Some(send_at) => Some(
+            SendAt::parse(send_at, body.timezone.as_deref(), Utc::now())
+                .map_err(|e| PublishError::ValidationError(vec![e]))?,
+        ),
+        None => None,
+    };
+    // sent right away unless scheduled, in which case the scheduler picks it up once due
+    let state = match &schedule {
+        Some(schedule) => IssueState::scheduled(schedule),
+        None => IssueState::sending(),
+    };
+    let issue_id = insert_issue(&pool, &body.issue, state, Some(admin.user_id)).await?;
+    if schedule.is_some() {
+        let issue = find_issue(pool.get_ref(), issue_id).await?;
+        return Ok(HttpResponse::Accepted()
+            .insert_header((header::LOCATION, format!("/newsletter/issues/{}", issue_id)))
+            .json(issue));
+    }
+    let delivery = deliver_issue(
+        &pool,
+        &email_client,
+        &base_url.0,
+        &preference_center,
+        &tracker,
+        issue_id,
+        LockWait::Block,
+        || shutdown.is_triggered(),
+    )
+    .await?;
+    match delivery {
+        Delivery::Interrupted => Err(PublishError::ShuttingDown),
+        Delivery::Sent | Delivery::Skipped => Ok(HttpResponse::Ok().finish()),
+    }
+}
+
+// the title, lists and segment of an issue, when it is created, edited and published
+pub(crate) async fn validate_draft(pool: &PgPool, issue: &IssueDraft) -> Result<(), PublishError> {
+    if issue.title.trim().is_empty() {
+        return Err(PublishError::ValidationError(vec![FieldError::new(
+            "title",
+            "empty",
+            "The newsletter title is empty",
+        )]));
+    }
+    parse_content(&issue.content.html, &issue.content.plain)?;
+    segment_filter(issue.segment.as_ref())?;
+    target_lists(pool, &issue.lists).await?;
+    Ok(())
+}
+
+// the placeholders of the HTML and plain content, see `Template`
+pub(crate) fn parse_content(html: &str, plain: &str) -> Result<(Template, Template), PublishError> {
+    match (Template::parse(html), Template::parse(plain)) {
+        (Ok(html), Ok(plain)) => Ok((html, plain)),
+        (html, plain) => {
+            let errors = [("content.html", html.err()), ("content.plain", plain.err())]
+                .into_iter()
+                .filter_map(|(field, e)| e.map(|e| FieldError::from_domain(field, &e)))
+                .collect();
+            Err(PublishError::ValidationError(errors))
+        }
+    }
+}
+
+// the email as sent to a subscriber, HTML and plain text: the issue's content with their unsubscribe
+// link, at the end unless the content already places it
+pub(crate) fn render_email(html: &Template, plain: &Template, recipient: &Recipient) -> (String, String) {
+    let mut rendered = (html.render(recipient, Escape::Html), plain.render(recipient, Escape::Plain));
+    if !html.uses_unsubscribe_url() {
+        rendered.0.push_str(&format!("<p><a href=\"{}\">Unsubscribe</a></p>", escape_html(recipient.unsubscribe_url)));
+    }
+    if !plain.uses_unsubscribe_url() {
+        rendered.1.push_str(&format!("\n\nUnsubscribe: {}", recipient.unsubscribe_url));
+    }
+    rendered
+}
+
+// How `deliver_issue` takes the issue's lock.
+pub enum LockWait {
+    // wait for whoever holds it to finish
+    Block,
+    // give up at once, someone else is delivering it
+    Skip,
+}
+
+pub enum Delivery {
+    Sent,
+    // a shutdown started, the rest is sent after the restart
+    Interrupted,
+    // someone else is delivering the issue, or it isn't due or was already sent
+    Skipped,
+}
+
+// namespace of the advisory locks held while delivering an issue, the issue id is the other key
+const ISSUE_LOCK: i32 = 1045;
+
+// Sends a due issue to the recipients it hasn't reached yet. A Postgres advisory lock on the issue is held
+// throughout, so several instances (or a request and the scheduler) never deliver it at the same time;
+// it is released by Postgres if this instance dies. An issue that fails is marked `failed`, an interrupted one
+// stays `sending` and is resumed by whoever takes the lock next.
+#[allow(clippy::too_many_arguments)]
+pub async fn deliver_issue(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    preference_center: &PreferenceCenter,
+    tracker: &Tracker,
+    issue_id: Uuid,
+    wait: LockWait,
+    should_stop: impl Fn() -> bool,
+) -> Result<Delivery, anyhow::Error> {
+    // nothing is written through this transaction, it only scopes the lock
+    let mut lock = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let locked = match wait {
+        LockWait::Block => {
+            sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext($2))")
+                .bind(ISSUE_LOCK)
+                .bind(issue_id.to_string())
+                .execute(&mut lock)
+                .await
+                .context("Failed to lock the issue")?;
+            true
+        }
+        LockWait::Skip => sqlx::query_scalar!(
+            r#"SELECT pg_try_advisory_xact_lock($1, hashtext($2)) AS "locked!""#,
+            ISSUE_LOCK,
+            issue_id.to_string()
+        )
+        .fetch_one(&mut lock)
+        .await
+        .context("Failed to lock the issue")?,
+    };
+    if !locked {
+        return Ok(Delivery::Skipped);
+    }
+    let issue = sqlx::query_as!(
+        IssueContent,
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'sending', started_at = COALESCE(started_at, now()), updated_at = now()
+        WHERE id = $1 AND status IN ('scheduled', 'sending') AND send_at <= now()
+        RETURNING title, html_content, plain_content, lists, segment
+        "#,
+        issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to claim the issue")?;
+    let issue = match issue {
+        Some(issue) => issue,
+        None => return Ok(Delivery::Skipped),
+    };
+
+    let delivery = send_issue(pool, email_client, base_url, preference_center, tracker, issue_id, &issue, should_stop).await;
+    match &delivery {
+        Ok(Delivery::Sent) => {
+            sqlx::query!(
+                r#"
+                UPDATE newsletter_issues
+                SET status = 'sent', finished_at = now(), published_at = COALESCE(published_at, now()), updated_at = now()
+                WHERE id = $1
+                "#,
+                issue_id
+            )
+            .execute(pool)
+            .await
+            .context("Failed to mark the issue as sent")?;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            sqlx::query!(
+                r#"
+                UPDATE newsletter_issues
+                SET status = 'failed', last_error = $2, finished_at = now(), published_at = COALESCE(published_at, now()),
+                    updated_at = now()
+                WHERE id = $1
+                "#,
+                issue_id,
+                format!("{:#}", e)
+            )
+            .execute(pool)
+            .await
+            .context("Failed to mark the issue as failed")?;
+        }
+    }
+    lock.rollback().await.context("Failed to release the issue lock")?;
+    delivery
+}
+
+struct IssueContent {
+    title: String,
+    html_content: String,
+    plain_content: String,
+    lists: Vec<String>,
+    segment: Option<serde_json::Value>,
+}
+
+// Queues the recipients the first time, then sends to those still queued. A recipient the provider refuses
+// is marked `failed` with the reason and the others still go out; the issue fails once they all had their turn.
+#[allow(clippy::too_many_arguments)]
+async fn send_issue(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    preference_center: &PreferenceCenter,
+    tracker: &Tracker,
+    issue_id: Uuid,
+    issue: &IssueContent,
+    should_stop: impl Fn() -> bool,
+) -> Result<Delivery, anyhow::Error> {
+    // validated when the issue was created, but a list may have gone since
+    let filter = segment_filter(issue.segment.as_ref()).map_err(PublishError::into_cause)?;
+    let lists = target_lists(pool, &issue.lists).await.map_err(PublishError::into_cause)?;
+    let (html_template, plain_template) =
+        parse_content(&issue.html_content, &issue.plain_content).map_err(PublishError::into_cause)?;
+    let mut recipients = Vec::new();
+    for subscriber in get_confirmed_subscribers(pool, &lists, &filter).await? {
         match subscriber {
-            Ok(subscriber) => {
-                let _result = email_client
-                    .send_email(
-                        &subscriber.email,
-                        &_body.title,
-                        &_body.content.plain,
-                        &_body.content.html,
-                    )
-                    .await
-                    .with_context(|| {
-                        format!("Failed to send email to {}", subscriber.email.as_ref())
-                    })?;
-            }
+            Ok(subscriber) => recipients.push(subscriber),
             Err(e) => {
                 // comment error.cause_chain = ?e
                 tracing::warn!(error.cause_chain = ?e, "skipping invalid subscriber email");
             }
         }
     }
-    Ok(HttpResponse::Ok().finish())
+    let ids: Vec<Uuid> = recipients.iter().map(|r| r.id).collect();
+    queue_recipients(pool, issue_id, &ids).await?;
+    let queued: HashSet<Uuid> = sqlx::query!(
+        r#"SELECT subscription_id FROM issue_deliveries WHERE issue_id = $1 AND status = 'queued'"#,
+        issue_id
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the queued recipients")?
+    .into_iter()
+    .map(|row| row.subscription_id)
+    .collect();
+
+    let (mut attempted, mut failed) = (0, 0);
+    let mut first_error = None;
+    for subscriber in recipients.iter().filter(|r| queued.contains(&r.id)) {
+        if should_stop() {
+            return Ok(Delivery::Interrupted);
+        }
+        // on a single list, the link only leaves that one
+        let list = match subscriber.lists.as_slice() {
+            [list] => Some(list.as_str()),
+            _ => None,
+        };
+        let unsubscribe_link = preference_center.unsubscribe_link(base_url, subscriber.id, list);
+        // links are tracked before the placeholders are filled in, so that no personal value ends up in a token
+        let html_template = match tracker.is_enabled() && !subscriber.tracking_opt_out {
+            true => {
+                let email = TrackedEmail { issue_id, subscription_id: subscriber.id };
+                Cow::Owned(
+                    Template::parse(&tracker.instrument(&issue.html_content, base_url, &email))
+                        .context("Failed to read the tracked content")?,
+                )
+            }
+            false => Cow::Borrowed(&html_template),
+        };
+        let recipient = Recipient {
+            name: &subscriber.name,
+            email: subscriber.email.as_ref(),
+            unsubscribe_url: &unsubscribe_link,
+            attributes: &subscriber.attributes,
+        };
+        let (html, plain) = render_email(&html_template, &plain_template, &recipient);
+        attempted += 1;
+        match email_client.send_message(&subscriber.email, &issue.title, &html, &plain).await {
+            Ok(message_id) => record_delivery(pool, issue_id, subscriber.id, &issue.title, message_id).await?,
+            Err(e) => {
+                let e = anyhow::Error::from(e)
+                    .context(format!("Failed to send email to {}", Sensitive(&subscriber.email)));
+                tracing::warn!(error.cause_chain = ?e, issue_id = %issue_id, "failed to send a newsletter email");
+                record_failure(pool, issue_id, subscriber.id, &e).await?;
+                failed += 1;
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    // whoever is still queued isn't a recipient anymore, e.g. they unsubscribed before a resend
+    sqlx::query!(r#"DELETE FROM issue_deliveries WHERE issue_id = $1 AND status = 'queued'"#, issue_id)
+        .execute(pool)
+        .await
+        .context("Failed to drop the recipients that left")?;
+    match first_error {
+        Some(e) => Err(e.context(format!("{} of {} recipients could not be sent to", failed, attempted))),
+        None => Ok(Delivery::Sent),
+    }
 }
-// get confirmed subscriber from database
-// return a vector of ConfirmedSubscriber
-pub async fn get_confirmed_subscribers(
+
+// Only on the first delivery: a resumed one goes on with the same recipients,
+// and a resend only retries those that failed.
+async fn queue_recipients(pool: &PgPool, issue_id: Uuid, subscription_ids: &[Uuid]) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (issue_id, subscription_id)
+        SELECT $1, id FROM UNNEST($2::uuid[]) AS id
+        WHERE NOT EXISTS (SELECT 1 FROM issue_deliveries WHERE issue_id = $1)
+        "#,
+        issue_id,
+        subscription_ids
+    )
+    .execute(pool)
+    .await
+    .context("Failed to queue the recipients")?;
+    Ok(())
+}
+
+async fn record_delivery(
     pool: &PgPool,
-) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
-    let confirmed_subscribers = sqlx::query!(
+    issue_id: Uuid,
+    subscription_id: Uuid,
+    subject: &str,
+    message_id: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query!(
         r#"
-        SELECT email
+        UPDATE issue_deliveries
+        SET status = 'sent', error = NULL, message_id = $3, attempts = attempts + 1, updated_at = now()
+        WHERE issue_id = $1 AND subscription_id = $2
+        "#,
+        issue_id,
+        subscription_id,
+        message_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to record the delivery")?;
+    record_issue_sent(&mut transaction, subscription_id, issue_id, subject)
+        .await
+        .context("Failed to record the newsletter email")?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(())
+}
+
+async fn record_failure(
+    pool: &PgPool,
+    issue_id: Uuid,
+    subscription_id: Uuid,
+    error: &anyhow::Error,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_deliveries SET status = 'failed', error = $3, attempts = attempts + 1, updated_at = now()
+        WHERE issue_id = $1 AND subscription_id = $2
+        "#,
+        issue_id,
+        subscription_id,
+        format!("{:#}", error)
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record the failed delivery")?;
+    Ok(())
+}
+#[derive(serde::Deserialize)]
+pub struct DryRunData {
+    #[serde(default)]
+    lists: Vec<String>,
+    #[serde(default)]
+    segment: Option<serde_json::Value>,
+}
+
+// How many subscribers a newsletter with these `lists` and `segment` would go to, without sending it.
+#[tracing::instrument(name = "Counting newsletter recipients", skip(body, pool, admin), fields(admin = %admin.user_id))]
+pub async fn count_recipients(
+    body: web::Json<DryRunData>,
+    pool: web::Data<PgPool>,
+    admin: AdminUser,
+) -> Result<HttpResponse, PublishError> {
+    let filter = segment_filter(body.segment.as_ref())?;
+    let lists = target_lists(&pool, &body.lists).await?;
+    let list_ids: Vec<Uuid> = lists.iter().map(|l| l.id).collect();
+    let sql = recipients_sql("COUNT(DISTINCT subscriptions.id)", &filter);
+    let recipients: i64 = filter
+        .bind(sqlx::query(&sql).bind(&list_ids))
+        .fetch_one(pool.get_ref())
+        .await
+        .context("Failed to count the recipients")?
+        .try_get(0)
+        .context("Failed to read the recipient count")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "recipients": recipients })))
+}
+
+fn segment_filter(segment: Option<&serde_json::Value>) -> Result<SqlFilter, PublishError> {
+    match segment {
+        // $1 is the list ids
+        Some(segment) => Segment::parse(segment)
+            .map(|segment| segment.to_sql(2))
+            .map_err(|e| PublishError::ValidationError(vec![FieldError::from_domain("segment", &e)])),
+        None => Ok(SqlFilter::everything()),
+    }
+}
+
+// confirmed members of the lists in $1 matching `filter`
+fn recipients_sql(select: &str, filter: &SqlFilter) -> String {
+    format!(
+        r#"
+        SELECT {}
         FROM subscriptions
-        WHERE status = 'confirmed'
+        JOIN list_memberships ON list_memberships.subscription_id = subscriptions.id
+        JOIN lists ON lists.id = list_memberships.list_id
+        WHERE list_memberships.status = 'confirmed' AND list_memberships.list_id = ANY($1) AND {}
         "#,
+        select, filter.clause
     )
-    .fetch_all(pool)
-    .await?
-    // comment: what is the difference between query and query_as?
-    // query_as! is a macro that allows you to specify the type of the result set
-    // A variant of query which takes a path to an explicitly defined struct as the output type.
-    // Note: use query_as! to specify the type of the result set, when add some fields to the struct
-    .into_iter()
-    .map(|row| match SubscriberEmail::parse(row.email) {
-        Ok(email) => Ok(ConfirmedSubscriber { email }),
-        Err(e) => Err(anyhow::anyhow!(e)),
-    })
-    .collect();
+}
+
+async fn target_lists(pool: &PgPool, slugs: &[String]) -> Result<Vec<MailingList>, PublishError> {
+    if slugs.is_empty() {
+        let list = find_list(pool, None)
+            .await
+            .context("Failed to look the default list up")?
+            .context("There is no default list")?;
+        return Ok(vec![list]);
+    }
+    find_lists(pool, slugs)
+        .await
+        .context("Failed to look the lists up")?
+        .map_err(|unknown| {
+            PublishError::ValidationError(vec![FieldError::new(
+                "lists",
+                "unknown_list",
+                format!("There is no list named {:?}", unknown),
+            )])
+        })
+}
+
+// get confirmed subscriber from database
+// return a vector of ConfirmedSubscriber, one per subscriber however many of `lists` they are on
+// the segment makes the query dynamic, so it can't be checked by `query!` at compile time
+pub async fn get_confirmed_subscribers(
+    pool: &PgPool,
+    lists: &[MailingList],
+    filter: &SqlFilter,
+) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
+    let list_ids: Vec<Uuid> = lists.iter().map(|l| l.id).collect();
+    let sql = recipients_sql(
+        "subscriptions.id, subscriptions.email, subscriptions.name, subscriptions.attributes, subscriptions.tracking_opt_out, \
+         array_agg(lists.slug) AS lists",
+        filter,
+    ) + "GROUP BY subscriptions.id";
+    let confirmed_subscribers = filter
+        .bind(sqlx::query(&sql).bind(&list_ids))
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| {
+            let email = SubscriberEmail::parse(row.try_get("email")?)?;
+            Ok(ConfirmedSubscriber {
+                id: row.try_get("id")?,
+                email,
+                lists: row.try_get("lists")?,
+                tracking_opt_out: row.try_get("tracking_opt_out")?,
+                name: row.try_get("name")?,
+                attributes: row.try_get("attributes")?,
+            })
+        })
+        .collect();
     Ok(confirmed_subscribers)
 }
@@ -0,0 +1,427 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::configuration::PreferencesSettings;
+use crate::consent::{self, record_consent, ConsentRecord, RequestOrigin};
+use crate::domain::{CustomAttributes, Language, SubscriberEmail, SubscriberName, TimeZone};
+use crate::domain_policy::{DomainPolicy, PolicyDecision};
+use crate::email_client::EmailClient;
+use crate::sent_emails::{self, record_sent};
+use crate::lists::find_list;
+use crate::metrics::{observe_acquire, METRICS};
+use crate::problem::{FieldError, Problem};
+use crate::router::{error_chain_fmt, generate_subscription_token, FormOrJson};
+use crate::signing::{LinkError, LinkSigner};
+use crate::startup::ApplicationBaseUrl;
+use crate::telemetry::Sensitive;
+
+const LINK_PURPOSE: &str = "preferences";
+const UNSUBSCRIBE_PURPOSE: &str = "unsubscribe";
+
+// Issues and checks the signed links that open the preference centre or unsubscribe.
+#[derive(Clone)]
+pub struct PreferenceCenter {
+    signer: LinkSigner,
+    link_ttl: chrono::Duration,
+    unsubscribe_link_ttl: chrono::Duration,
+    topics: Vec<String>,
+}
+
+impl PreferenceCenter {
+    pub fn new(signer: LinkSigner, settings: &PreferencesSettings) -> Self {
+        Self {
+            signer,
+            link_ttl: settings.link_ttl(),
+            unsubscribe_link_ttl: settings.unsubscribe_link_ttl(),
+            topics: settings.topics.clone(),
+        }
+    }
+    pub fn link(&self, base_url: &str, subscriber_id: Uuid) -> String {
+        let token = self.signer.sign(LINK_PURPOSE, subscriber_id, Utc::now() + self.link_ttl);
+        format!("{}/subscriptions/preferences?token={}", base_url, token)
+    }
+    // from `list` only, or from every list when `None`
+    pub fn unsubscribe_link(&self, base_url: &str, subscriber_id: Uuid, list: Option<&str>) -> String {
+        let token = self
+            .signer
+            .sign(UNSUBSCRIBE_PURPOSE, subscriber_id, Utc::now() + self.unsubscribe_link_ttl);
+        match list {
+            Some(list) => format!(
+                "{}/subscriptions/unsubscribe?token={}&list={}",
+                base_url,
+                token,
+                utf8_percent_encode(list, NON_ALPHANUMERIC)
+            ),
+            None => format!("{}/subscriptions/unsubscribe?token={}", base_url, token),
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+    #[error(transparent)]
+    InvalidLink(#[from] LinkError),
+    #[error("The preferences are not valid")]
+    ValidationError(Vec<FieldError>),
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PreferencesError::InvalidLink(_) => StatusCode::UNAUTHORIZED,
+            PreferencesError::ValidationError(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PreferencesError::ValidationError(errors) => Problem::validation(errors.clone()).response(),
+            PreferencesError::InvalidLink(e) => Problem::new(self.status_code()).with_detail(e.to_string()).response(),
+            PreferencesError::UnexpectedError(_) => Problem::new(self.status_code()).response(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct LinkRequest {
+    #[serde(default)]
+    email: String,
+}
+
+// Email a preference centre link. Always 200, so it can't be used to find out who is subscribed.
+#[tracing::instrument(
+    name = "Sending a preference centre link",
+    skip(body, pool, email_client, base_url, preference_center),
+    fields(subscriber_email = %Sensitive(&body.0.email))
+)]
+pub async fn request_preferences_link(
+    body: FormOrJson<LinkRequest>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    preference_center: web::Data<PreferenceCenter>,
+) -> Result<HttpResponse, PreferencesError> {
+    let email = match SubscriberEmail::parse(body.0.email) {
+        Ok(email) => email,
+        Err(e) => return Err(PreferencesError::ValidationError(vec![FieldError::from_domain("email", &e)])),
+    };
+    let subscriber_id = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE email_canonical = $1"#,
+        email.canonical()
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to look the subscriber up")?;
+    if let Some(row) = subscriber_id {
+        let link = preference_center.link(&base_url.0, row.id);
+        let subject = "Manage your subscription";
+        email_client
+            .send_email(
+                &email,
+                subject,
+                &format!("Click <a href=\"{}\">here</a> to manage your subscription.", link),
+                &format!("Visit {} to manage your subscription.", link),
+            )
+            .await
+            .context("Failed to send the preference centre link")?;
+        record_sent(pool.get_ref(), row.id, sent_emails::PREFERENCES_LINK, subject)
+            .await
+            .context("Failed to record the preference centre link")?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(serde::Deserialize)]
+pub struct LinkParameters {
+    token: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct Preferences {
+    email: String,
+    name: String,
+    status: String,
+    language: Option<String>,
+    timezone: Option<String>,
+    attributes: serde_json::Value,
+    topics: Vec<String>,
+    // no opens or clicks of newsletters are recorded
+    tracking_opt_out: bool,
+    // waiting for the subscriber to confirm the new address
+    pending_email: Option<String>,
+}
+
+#[tracing::instrument(name = "Showing the preference centre", skip(parameters, pool, preference_center))]
+pub async fn get_preferences(
+    parameters: web::Query<LinkParameters>,
+    pool: web::Data<PgPool>,
+    preference_center: web::Data<PreferenceCenter>,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = preference_center.signer.verify(LINK_PURPOSE, &parameters.token)?;
+    let preferences = load_preferences(&pool, subscriber_id).await?;
+    Ok(HttpResponse::Ok().json(preferences))
+}
+
+// Every field is optional, only the ones present are changed.
+// An empty `language` or `timezone` clears it; `attributes` and `topics` are replaced as a whole.
+#[derive(serde::Deserialize)]
+pub struct PreferencesUpdate {
+    name: Option<String>,
+    email: Option<String>,
+    language: Option<String>,
+    timezone: Option<String>,
+    attributes: Option<serde_json::Value>,
+    topics: Option<Vec<String>>,
+    tracking_opt_out: Option<bool>,
+}
+
+#[tracing::instrument(
+    name = "Updating preferences",
+    skip(parameters, body, pool, email_client, base_url, preference_center, domain_policy)
+)]
+pub async fn update_preferences(
+    parameters: web::Query<LinkParameters>,
+    body: web::Json<PreferencesUpdate>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    preference_center: web::Data<PreferenceCenter>,
+    domain_policy: web::Data<DomainPolicy>,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = preference_center.signer.verify(LINK_PURPOSE, &parameters.token)?;
+    let update = body.into_inner();
+    let mut errors = Vec::new();
+    let name = update.name.map(SubscriberName::parse).transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("name", &e));
+        None
+    });
+    let email = update.email.map(SubscriberEmail::parse).transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("email", &e));
+        None
+    });
+    if let Some(email) = &email {
+        if let PolicyDecision::Reject(code) = domain_policy.evaluate(email.domain()) {
+            errors.push(FieldError::new("email", code, "Subscriptions from this email domain are not accepted"));
+        }
+    }
+    // `Some(None)` clears the value
+    let language = update.language.map(|l| match l.trim() {
+        "" => Ok(None),
+        l => Language::parse(l).map(Some),
+    });
+    let language = language.transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("language", &e));
+        None
+    });
+    let timezone = update.timezone.map(|t| match t.trim() {
+        "" => Ok(None),
+        t => TimeZone::parse(t).map(Some),
+    });
+    let timezone = timezone.transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("timezone", &e));
+        None
+    });
+    let attributes = update.attributes.map(CustomAttributes::parse).transpose().unwrap_or_else(|e| {
+        errors.push(FieldError::from_domain("attributes", &e));
+        None
+    });
+    if let Some(topics) = &update.topics {
+        if let Some(unknown) = topics.iter().find(|t| !preference_center.topics.contains(t)) {
+            errors.push(FieldError::new("topics", "unknown_topic", format!("There is no topic named {:?}", unknown)));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(PreferencesError::ValidationError(errors));
+    }
+
+    let (set_language, language) = (language.is_some(), language.flatten());
+    let (set_timezone, timezone) = (timezone.is_some(), timezone.flatten());
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET
+            name = COALESCE($2, name),
+            language = CASE WHEN $3 THEN $4 ELSE language END,
+            timezone = CASE WHEN $5 THEN $6 ELSE timezone END,
+            attributes = COALESCE($7, attributes),
+            topics = COALESCE($8, topics),
+            tracking_opt_out = COALESCE($9, tracking_opt_out)
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        name.as_ref().map(|n| n.as_ref()),
+        set_language,
+        language.as_ref().map(|l| l.as_ref()),
+        set_timezone,
+        timezone.as_ref().map(|t| t.as_ref()),
+        attributes.as_ref().map(|a| a.as_ref()),
+        update.topics.as_deref(),
+        update.tracking_opt_out,
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update the preferences")?;
+    if updated.rows_affected() == 0 {
+        // the subscriber was deleted after the link was sent
+        return Err(LinkError::Invalid.into());
+    }
+    if let Some(email) = email {
+        request_email_change(&mut transaction, &email_client, &base_url.0, subscriber_id, &email).await?;
+    }
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+
+    let preferences = load_preferences(&pool, subscriber_id).await?;
+    Ok(HttpResponse::Ok().json(preferences))
+}
+
+// The new address only replaces the current one once it is confirmed through `confirm`.
+async fn request_email_change(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    email_client: &EmailClient,
+    base_url: &str,
+    subscriber_id: Uuid,
+    email: &SubscriberEmail,
+) -> Result<(), PreferencesError> {
+    let taken = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE email_canonical = $1 AND id <> $2"#,
+        email.canonical(),
+        subscriber_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to check whether the email is taken")?;
+    if taken.is_some() {
+        return Err(PreferencesError::ValidationError(vec![FieldError::new(
+            "email",
+            "already_subscribed",
+            "This email address is already subscribed",
+        )]));
+    }
+    let token = generate_subscription_token();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions_token (subscriptions_token, subscription_id, new_email, new_email_canonical)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        token,
+        subscriber_id,
+        email.as_ref(),
+        email.canonical(),
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store the email change token")?;
+    let confirmation_link = format!("{}/subscriptions/confirm?subscription_token={}", base_url, token);
+    let subject = "Confirm your new email address";
+    email_client
+        .send_email(
+            email,
+            subject,
+            &format!("Click <a href=\"{}\">here</a> to confirm your new email address.", confirmation_link),
+            &format!("Visit {} to confirm your new email address.", confirmation_link),
+        )
+        .await
+        .context("Failed to send the email change confirmation")?;
+    record_sent(&mut *transaction, subscriber_id, sent_emails::EMAIL_CHANGE, subject)
+        .await
+        .context("Failed to record the email change confirmation")?;
+    Ok(())
+}
+
+async fn load_preferences(pool: &PgPool, subscriber_id: Uuid) -> Result<Preferences, PreferencesError> {
+    let preferences = sqlx::query_as!(
+        Preferences,
+        r#"
+        SELECT email, name, status, language, timezone, attributes, topics, tracking_opt_out,
+            (SELECT new_email FROM subscriptions_token
+             WHERE subscription_id = subscriptions.id AND new_email IS NOT NULL
+             ORDER BY created_at DESC LIMIT 1) AS pending_email
+        FROM subscriptions WHERE id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to load the preferences")?;
+    preferences.ok_or(PreferencesError::InvalidLink(LinkError::Invalid))
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    token: String,
+    // every list when missing
+    list: Option<String>,
+}
+
+// The link at the bottom of every newsletter. Answers GET for clicks and POST for one-click
+// unsubscribe from mail clients; unsubscribing twice is not an error.
+#[tracing::instrument(name = "Unsubscribing", skip(parameters, pool, preference_center, origin))]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    preference_center: web::Data<PreferenceCenter>,
+    origin: RequestOrigin,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = preference_center.signer.verify(UNSUBSCRIBE_PURPOSE, &parameters.token)?;
+    let list_id = match parameters.list.as_deref() {
+        Some(slug) => {
+            let list = find_list(pool.get_ref(), Some(slug))
+                .await
+                .context("Failed to look the list up")?
+                .ok_or_else(|| {
+                    PreferencesError::ValidationError(vec![FieldError::new(
+                        "list",
+                        "unknown_list",
+                        "There is no list with this name",
+                    )])
+                })?;
+            Some(list.id)
+        }
+        None => None,
+    };
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let unsubscribed = sqlx::query!(
+        r#"
+        UPDATE list_memberships SET status = 'unsubscribed', unsubscribed_at = now()
+        FROM lists
+        WHERE lists.id = list_id AND subscription_id = $1 AND ($2::uuid IS NULL OR list_id = $2) AND status <> 'unsubscribed'
+        RETURNING lists.slug
+        "#,
+        subscriber_id,
+        list_id
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to unsubscribe")?;
+    // one entry per list left
+    for list in &unsubscribed {
+        record_consent(&mut transaction, subscriber_id, ConsentRecord {
+            event: consent::UNSUBSCRIBE,
+            list: Some(&list.slug),
+            source: None,
+            policy_version: None,
+            origin: Some(&origin),
+            actor_id: None,
+        })
+        .await
+        .context("Failed to record the withdrawal of consent")?;
+    }
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    if !unsubscribed.is_empty() {
+        METRICS.unsubscribes_total.inc();
+    }
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,378 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::AdminUser;
+use crate::consent::{consent_history, ConsentEntry};
+use crate::domain::SubscriberEmail;
+use crate::metrics::observe_acquire;
+use crate::problem::FieldError;
+use crate::router::{AdminError, SubscriberRecord};
+use crate::suppression::SuppressionList;
+
+// an erased address in import reports
+const ERASED: &str = "[erased]";
+
+#[derive(serde::Deserialize)]
+pub struct DataSubject {
+    email: String,
+}
+
+impl DataSubject {
+    fn parse(self) -> Result<SubscriberEmail, AdminError> {
+        SubscriberEmail::parse(self.email).map_err(|e| AdminError::ValidationError(vec![FieldError::from_domain("email", &e)]))
+    }
+}
+
+// Everything stored about an address. Confirmation tokens are credentials, so only their metadata is included.
+#[derive(serde::Serialize)]
+struct SubjectAccessReport {
+    email: String,
+    generated_at: DateTime<Utc>,
+    subscriber: Option<SubscriberRecord>,
+    topics: Vec<String>,
+    lists: Vec<MembershipEntry>,
+    tokens: Vec<TokenEntry>,
+    sent_emails: Vec<SentEmailEntry>,
+    imports: Vec<ImportEntry>,
+    audit_log: Vec<AuditEntry>,
+    consent: Vec<ConsentEntry>,
+    engagement: Vec<EngagementEntry>,
+    suppressed: bool,
+}
+
+#[derive(serde::Serialize)]
+struct MembershipEntry {
+    list: String,
+    status: String,
+    created_at: DateTime<Utc>,
+    confirmed_at: Option<DateTime<Utc>>,
+    unsubscribed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+struct TokenEntry {
+    // confirms joining this list, or
+    list: Option<String>,
+    // changing the subscriber's address to this one
+    new_email: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct SentEmailEntry {
+    kind: String,
+    subject: String,
+    sent_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct EngagementEntry {
+    issue_id: Uuid,
+    // open or click
+    kind: String,
+    url: Option<String>,
+    occurred_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct ImportEntry {
+    job_id: Uuid,
+    row_number: i64,
+    outcome: String,
+    reason: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct AuditEntry {
+    action: String,
+    actor_id: Uuid,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+    created_at: DateTime<Utc>,
+}
+
+// A data subject access request: a JSON document of everything held about `email`.
+// The request itself is audited.
+#[tracing::instrument(name = "Answering a data subject access request", skip(body, pool, suppressions, admin), fields(admin = %admin.user_id))]
+pub async fn subject_access(
+    body: web::Json<DataSubject>,
+    pool: web::Data<PgPool>,
+    suppressions: web::Data<SuppressionList>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let email = body.into_inner().parse()?;
+    // a snapshot, so the sections agree with each other
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut transaction)
+        .await
+        .context("Failed to start the access snapshot")?;
+
+    let subscriber = sqlx::query_as::<_, SubscriberRecord>(
+        r#"
+        SELECT id, email, name, status, subscribed_at, language, timezone, attributes, flagged_reason
+        FROM subscriptions WHERE email_canonical = $1
+        "#,
+    )
+    .bind(email.canonical())
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to fetch the subscriber")?;
+    let subscriber_id = subscriber.as_ref().map(|s| s.id);
+    let topics = sqlx::query!(r#"SELECT topics FROM subscriptions WHERE id = $1"#, subscriber_id)
+        .fetch_optional(&mut transaction)
+        .await
+        .context("Failed to fetch the topics")?
+        .map(|row| row.topics)
+        .unwrap_or_default();
+    let lists = sqlx::query_as!(
+        MembershipEntry,
+        r#"
+        SELECT lists.slug AS list, status, list_memberships.created_at, confirmed_at, unsubscribed_at
+        FROM list_memberships JOIN lists ON lists.id = list_memberships.list_id
+        WHERE subscription_id = $1
+        ORDER BY list_memberships.created_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch the list memberships")?;
+    // also the pending changes of another subscriber's address to this one
+    let tokens = sqlx::query_as!(
+        TokenEntry,
+        r#"
+        SELECT lists.slug AS "list?", new_email, subscriptions_token.created_at
+        FROM subscriptions_token LEFT JOIN lists ON lists.id = subscriptions_token.list_id
+        WHERE subscription_id = $1 OR new_email_canonical = $2
+        ORDER BY subscriptions_token.created_at
+        "#,
+        subscriber_id,
+        email.canonical()
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch the tokens")?;
+    let sent_emails = sqlx::query_as!(
+        SentEmailEntry,
+        r#"SELECT kind, subject, sent_at FROM sent_emails WHERE subscription_id = $1 ORDER BY sent_at, id"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch the send history")?;
+    let imports = sqlx::query_as!(
+        ImportEntry,
+        r#"
+        SELECT job_id, row_number, outcome, reason, import_jobs.created_at
+        FROM import_job_rows JOIN import_jobs ON import_jobs.id = import_job_rows.job_id
+        WHERE lower(import_job_rows.email) IN ($1, lower($2))
+        ORDER BY import_jobs.created_at, row_number
+        "#,
+        email.canonical(),
+        email.as_ref()
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch the import rows")?;
+    let audit_log = sqlx::query_as!(
+        AuditEntry,
+        r#"
+        SELECT action, actor_id, before, after, created_at
+        FROM admin_audit_log
+        WHERE subscriber_id = $1 OR subject_hash = $2
+            -- also about a subscriber deleted since, or who had this address before changing it
+            OR lower(before->>'email') IN ($3, lower($4)) OR lower(after->>'email') IN ($3, lower($4))
+        ORDER BY created_at, id
+        "#,
+        subscriber_id,
+        suppressions.hash(&email),
+        email.canonical(),
+        email.as_ref()
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch the audit log")?;
+    // the ledger outlives subscribers deleted by an admin
+    let mut consent = Vec::new();
+    for id in subscriber_id.into_iter().chain(deleted_subscribers(&mut transaction, &email).await?) {
+        consent.extend(
+            consent_history(&mut transaction, id)
+                .await
+                .context("Failed to fetch the consent records")?,
+        );
+    }
+    consent.sort_by_key(|entry| entry.occurred_at);
+    let engagement = sqlx::query_as!(
+        EngagementEntry,
+        r#"SELECT issue_id, kind, url, occurred_at FROM engagement_events WHERE subscription_id = $1 ORDER BY occurred_at, id"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch the opens and clicks")?;
+    let suppressed = suppressions
+        .contains(&mut transaction, &email)
+        .await
+        .context("Failed to check the suppression list")?;
+
+    audit_request(&mut transaction, &admin, "subject_access", subscriber_id, &suppressions.hash(&email)).await?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::Ok().json(SubjectAccessReport {
+        email: email.as_ref().to_string(),
+        generated_at: Utc::now(),
+        subscriber,
+        topics,
+        lists,
+        tokens,
+        sent_emails,
+        imports,
+        audit_log,
+        consent,
+        engagement,
+        suppressed,
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct ErasureOutcome {
+    // whether there was a subscriber with this address
+    subscriber_erased: bool,
+    suppressed: bool,
+}
+
+// Erases `email`: the subscriber and everything attached to it is deleted, the address is removed from
+// import reports and from the audit entries that hold it, and a hash of it goes on the suppression list
+// so it isn't imported again. Works, and is audited, whether or not the address is subscribed.
+#[tracing::instrument(name = "Erasing a data subject", skip(body, pool, suppressions, admin), fields(admin = %admin.user_id))]
+pub async fn erase_subject(
+    body: web::Json<DataSubject>,
+    pool: web::Data<PgPool>,
+    suppressions: web::Data<SuppressionList>,
+    admin: AdminUser,
+) -> Result<HttpResponse, AdminError> {
+    let email = body.into_inner().parse()?;
+    let mut transaction = observe_acquire(pool.begin()).await.context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber_id = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE email_canonical = $1 FOR UPDATE"#,
+        email.canonical()
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look the subscriber up")?
+    .map(|row| row.id);
+
+    // the consent records of the subscriber, and of any deleted earlier, only go with an erasure
+    let mut consent_subjects = deleted_subscribers(&mut transaction, &email).await?;
+    consent_subjects.extend(subscriber_id);
+    sqlx::query("SET LOCAL consent_records.erasure = 'on'")
+        .execute(&mut transaction)
+        .await
+        .context("Failed to allow the erasure of consent records")?;
+    sqlx::query!(r#"DELETE FROM consent_records WHERE subscription_id = ANY($1)"#, &consent_subjects)
+        .execute(&mut transaction)
+        .await
+        .context("Failed to erase the consent records")?;
+    // the entries stay, so what was done and by whom is still on record. Entries with the address
+    // may be about a subscriber deleted since, or one who changed to another address.
+    sqlx::query!(
+        r#"
+        UPDATE admin_audit_log SET before = NULL, after = NULL, redacted_at = now()
+        WHERE redacted_at IS NULL AND (
+            subscriber_id = $1
+            OR lower(before->>'email') IN ($2, lower($3)) OR lower(after->>'email') IN ($2, lower($3))
+        )
+        "#,
+        subscriber_id,
+        email.canonical(),
+        email.as_ref()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to redact the audit log")?;
+    if let Some(subscriber_id) = subscriber_id {
+        // tokens, list memberships and the send history go with it
+        sqlx::query!(r#"DELETE FROM subscriptions WHERE id = $1"#, subscriber_id)
+            .execute(&mut transaction)
+            .await
+            .context("Failed to delete the subscriber")?;
+    }
+    sqlx::query!(
+        r#"DELETE FROM subscriptions_token WHERE new_email_canonical = $1"#,
+        email.canonical()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete the pending email changes")?;
+    sqlx::query!(
+        r#"UPDATE import_job_rows SET email = $3 WHERE lower(email) IN ($1, lower($2))"#,
+        email.canonical(),
+        email.as_ref(),
+        ERASED,
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to erase the address from import reports")?;
+    suppressions
+        .add(&mut transaction, &email, "erasure")
+        .await
+        .context("Failed to add the address to the suppression list")?;
+
+    audit_request(&mut transaction, &admin, "erasure", subscriber_id, &suppressions.hash(&email)).await?;
+    transaction.commit().await.context("Failed to commit SQL transaction")?;
+    Ok(HttpResponse::Ok().json(ErasureOutcome {
+        subscriber_erased: subscriber_id.is_some(),
+        suppressed: true,
+    }))
+}
+
+// Subscribers with this address that an admin deleted, as their audit entries tell until they are redacted.
+async fn deleted_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Vec<Uuid>, AdminError> {
+    let ids = sqlx::query!(
+        r#"
+        SELECT DISTINCT subscriber_id AS "id!" FROM admin_audit_log
+        WHERE action = 'delete' AND lower(before->>'email') IN ($1, lower($2))
+            AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE subscriptions.id = admin_audit_log.subscriber_id)
+        "#,
+        email.canonical(),
+        email.as_ref()
+    )
+    .fetch_all(transaction)
+    .await
+    .context("Failed to look the deleted subscribers up")?
+    .into_iter()
+    .map(|row| row.id)
+    .collect();
+    Ok(ids)
+}
+
+// keyed by the address hash, the entry itself holds no personal data
+async fn audit_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    admin: &AdminUser,
+    action: &str,
+    subscriber_id: Option<Uuid>,
+    subject_hash: &str,
+) -> Result<(), AdminError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO admin_audit_log (actor_id, action, subscriber_id, subject_hash)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        admin.user_id,
+        action,
+        subscriber_id,
+        subject_hash,
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to write the audit log")?;
+    Ok(())
+}
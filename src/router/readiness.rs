@@ -0,0 +1,96 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+use crate::configuration::ReadinessSettings;
+use crate::email_client::EmailClient;
+
+#[derive(serde::Serialize)]
+pub struct ReadinessReport {
+    status: &'static str,
+    checks: Vec<CheckReport>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CheckReport {
+    component: &'static str,
+    required: bool,
+    status: &'static str,
+    latency_ms: u128,
+}
+
+// readiness probe, unlike `/healthcheck` this fails with a 503 when a required dependency is down.
+// It is public, so why a check failed only goes to the logs: driver errors name hosts and users.
+#[tracing::instrument(name = "Readiness check", skip(pool, email_client, settings))]
+pub async fn readiness(
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    settings: web::Data<ReadinessSettings>,
+) -> HttpResponse {
+    let timeout = settings.check_timeout();
+    let mut checks = vec![
+        run_check("database", true, timeout, check_database(&pool)).await,
+        run_check("migrations", true, timeout, check_migrations(&pool)).await,
+    ];
+    if settings.check_email_provider {
+        checks.push(run_check("email_provider", false, timeout, email_client.check_reachable()).await);
+    }
+    let ready = checks.iter().all(|c| !c.required || c.status == "up");
+    let report = ReadinessReport {
+        status: if ready { "ready" } else { "unavailable" },
+        checks,
+    };
+    if ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+async fn run_check<F, E>(component: &'static str, required: bool, timeout: Duration, check: F) -> CheckReport
+where
+    F: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    let start = Instant::now();
+    let error = match tokio::time::timeout(timeout, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("timed out after {}ms", timeout.as_millis())),
+    };
+    if let Some(e) = &error {
+        tracing::warn!(component, error = %e, "readiness check failed");
+    }
+    CheckReport {
+        component,
+        required,
+        status: if error.is_none() { "up" } else { "down" },
+        latency_ms: start.elapsed().as_millis(),
+    }
+}
+
+async fn check_database(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+    Ok(())
+}
+
+// the schema is ready when the latest applied migration is the latest one we ship
+async fn check_migrations(pool: &PgPool) -> Result<(), anyhow::Error> {
+    let expected = sqlx::migrate!("./migrations")
+        .iter()
+        .map(|m| m.version)
+        .max()
+        .unwrap_or_default();
+    let applied: Option<i64> = sqlx::query_scalar(
+        "SELECT MAX(version) FROM _sqlx_migrations WHERE success",
+    )
+    .fetch_one(pool)
+    .await?;
+    match applied {
+        Some(applied) if applied == expected => Ok(()),
+        Some(applied) => Err(anyhow::anyhow!("expected migration {}, found {}", expected, applied)),
+        None => Err(anyhow::anyhow!("expected migration {}, none applied", expected)),
+    }
+}
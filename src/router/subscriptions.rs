@@ -5,14 +5,32 @@ use uuid::Uuid;
 use chrono::Utc;
 use rand::{thread_rng, Rng};
 use unicode_segmentation::UnicodeSegmentation;
-use crate::{domain::{SubscriberName, NewSubscriber, SubscriberEmail}, email_client::{EmailClient}, startup::ApplicationBaseUrl};
+use crate::{domain::{Language, SubscriberName, NewSubscriber, SubscriberEmail, TimeZone}, email_client::{EmailClient}, startup::ApplicationBaseUrl};
+use crate::metrics::{observe_acquire, METRICS};
+use crate::telemetry::Sensitive;
+use crate::domain_verification::{DomainRejection, DomainVerification};
+use crate::domain_policy::{DomainPolicy, PolicyDecision};
+use crate::problem::{FieldError, Problem};
+use crate::router::FormOrJson;
+use crate::lists::find_list;
+use crate::sent_emails::{self, record_sent};
+use crate::consent::{self, record_consent, ConsentPolicy, ConsentRecord, RequestOrigin};
+use crate::template::escape_html;
+
+const MAX_CONSENT_FIELD_LENGTH: usize = 100;
 
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
-    #[error("{0}")]
-    ValidationError(String),
+    // one entry per invalid field, each with a machine-readable code, e.g. `disposable_domain`
+    #[error("The subscriber details are not valid")]
+    ValidationError(Vec<FieldError>),
+    #[error(transparent)]
+    RejectedDomain(#[from] DomainRejection),
+    // another request subscribed the same address at the same moment
+    #[error("This email address is being subscribed by another request, try again")]
+    Conflict,
 }
 impl std::fmt::Debug for SubscribeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -35,70 +53,201 @@ impl ResponseError for SubscribeError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             SubscribeError::ValidationError(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            SubscribeError::RejectedDomain(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            SubscribeError::Conflict => actix_web::http::StatusCode::CONFLICT,
             SubscribeError::UnexpectedError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::new(self.status_code())
+        match self {
+            SubscribeError::ValidationError(errors) => Problem::validation(errors.clone()).response(),
+            // tell the user what is wrong so they can fix a typo
+            SubscribeError::RejectedDomain(rejection) => {
+                let mut error = FieldError::new("email", rejection.code(), rejection.to_string());
+                if let Some(suggestion) = rejection.suggestion() {
+                    error = error.with_suggestion(suggestion);
+                }
+                Problem::validation(vec![error]).response()
+            }
+            SubscribeError::Conflict => Problem::new(self.status_code()).with_detail(self.to_string()).response(),
+            SubscribeError::UnexpectedError(_) => Problem::new(self.status_code()).response(),
+        }
     }
-} 
+}
 
+// missing fields are treated as empty, so they are reported like any other invalid field
 #[derive(serde::Deserialize, Debug)]
 pub struct FormData {
+    #[serde(default)]
     email: String,
-    name: String
+    #[serde(default)]
+    name: String,
+    // optional profile fields, empty values are ignored
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+    // slug of the list to join, the default list when missing
+    #[serde(default)]
+    list: Option<String>,
+    // recorded as proof of consent: which signup form, and which privacy policy it showed
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    policy_version: Option<String>,
+    // set when resubmitting after a suggested domain fix was declined, the domain is still looked up
+    #[serde(default)]
+    keep_domain: bool,
 }
 
-impl TryFrom<web::Form<FormData>> for NewSubscriber {
-    type Error = String;
-    /// Performs the conversion.
-    fn try_from(form: web::Form<FormData>) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(form.0.name)?;
-        let email = SubscriberEmail::parse(form.0.email)?;
-        Ok(NewSubscriber { email, name })
+impl TryFrom<FormData> for NewSubscriber {
+    type Error = Vec<FieldError>;
+    /// Performs the conversion, reporting every invalid field rather than the first one.
+    fn try_from(form: FormData) -> Result<Self, Self::Error> {
+        parse_subscriber(form)
     }
 
 }
 
-pub fn parse_subscriber(form: web::Form<FormData>) -> Result<NewSubscriber, String> {
-    let name = SubscriberName::parse(form.0.name)?;
-    let email = SubscriberEmail::parse(form.0.email)?;
-    Ok(NewSubscriber { email, name })
+pub fn parse_subscriber(form: FormData) -> Result<NewSubscriber, Vec<FieldError>> {
+    let name = SubscriberName::parse(form.name);
+    let email = SubscriberEmail::parse(form.email);
+    let language = non_empty(form.language).map(|l| Language::parse(&l)).transpose();
+    let timezone = non_empty(form.timezone).map(|t| TimeZone::parse(&t)).transpose();
+    match (name, email, language, timezone) {
+        (Ok(name), Ok(email), Ok(language), Ok(timezone)) => Ok(NewSubscriber { email, name, language, timezone }),
+        (name, email, language, timezone) => {
+            let mut errors = Vec::new();
+            if let Err(e) = name {
+                errors.push(FieldError::from_domain("name", &e));
+            }
+            if let Err(e) = email {
+                errors.push(FieldError::from_domain("email", &e));
+            }
+            if let Err(e) = language {
+                errors.push(FieldError::from_domain("language", &e));
+            }
+            if let Err(e) = timezone {
+                errors.push(FieldError::from_domain("timezone", &e));
+            }
+            Err(errors)
+        }
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.trim().is_empty())
 }
 // async fn subscribe(_req: HttpRequest) -> HttpResponse {
+// the body can be form-encoded or JSON, see `FormOrJson`
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(form, pool, email_client, base_url, domain_verification, domain_policy, consent_policy, origin),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_email = %Sensitive(&form.0.email),
+        subscriber_name = %Sensitive(&form.0.name)
     )
 )]
-pub async fn subscribe(form: web::Form<FormData>, pool: web::Data<PgPool>, email_client: web::Data<EmailClient>
-    , base_url: web::Data<ApplicationBaseUrl>) -> Result<HttpResponse, SubscribeError> {
-    let new_subscriber = form.try_into().map_err(|e| SubscribeError::ValidationError(e))?; 
-    let mut transaction =  pool.begin()
+pub async fn subscribe(form: FormOrJson<FormData>, pool: web::Data<PgPool>, email_client: web::Data<EmailClient>
+    , base_url: web::Data<ApplicationBaseUrl>, domain_verification: web::Data<DomainVerification>
+    , domain_policy: web::Data<DomainPolicy>, consent_policy: web::Data<ConsentPolicy>, origin: RequestOrigin) -> Result<HttpResponse, SubscribeError> {
+    let FormOrJson(mut form) = form;
+    let list_slug = non_empty(form.list.take());
+    let source = non_empty(form.source.take());
+    let policy_version = non_empty(form.policy_version.take());
+    let keep_domain = form.keep_domain;
+    let too_long: Vec<FieldError> = [("source", &source), ("policy_version", &policy_version)]
+        .into_iter()
+        .filter(|(_, value)| value.as_ref().is_some_and(|v| v.chars().count() > MAX_CONSENT_FIELD_LENGTH))
+        .map(|(field, _)| FieldError {
+            max: Some(MAX_CONSENT_FIELD_LENGTH),
+            ..FieldError::new(field, "too_long", format!("At most {} characters", MAX_CONSENT_FIELD_LENGTH))
+        })
+        .collect();
+    if !too_long.is_empty() {
+        return Err(SubscribeError::ValidationError(too_long));
+    }
+    let new_subscriber: NewSubscriber = form.try_into().map_err(SubscribeError::ValidationError)?;
+    let mut flagged_reason = match domain_policy.evaluate(new_subscriber.email.domain()) {
+        PolicyDecision::Accept => None,
+        PolicyDecision::Flag(reason) => {
+            tracing::info!(reason, "subscription flagged by the domain policy");
+            Some(reason)
+        }
+        PolicyDecision::Reject(code) => {
+            return Err(SubscribeError::ValidationError(vec![FieldError::new(
+                "email",
+                code,
+                "Subscriptions from this email domain are not accepted",
+            )]))
+        }
+    };
+    if flagged_reason.is_none() && new_subscriber.name.is_confusable() {
+        tracing::info!("subscription flagged, the name mixes lookalike scripts");
+        flagged_reason = Some("confusable_name");
+    }
+    domain_verification.check(&new_subscriber.email, keep_domain).await?;
+    let mut transaction = observe_acquire(pool.begin())
         .await
         .context("Failed to acquire a Postgres connection from the pool")?;
 
-    // trying to access the data field by using from.0.name instead of from reference
-    let subscriber_id =  insert_subscriber(&mut transaction, &new_subscriber)
+    let list = find_list(&mut transaction, list_slug.as_deref())
+        .await
+        .context("Failed to look the list up")?
+        .ok_or_else(|| {
+            SubscribeError::ValidationError(vec![FieldError::new("list", "unknown_list", "There is no list with this name")])
+        })?;
+
+    // joining another list keeps the existing subscriber and their profile
+    let subscriber_id = match find_subscriber(&mut transaction, &new_subscriber.email)
+        .await
+        .context("Failed to look the subscriber up")?
+    {
+        Some(subscriber_id) => subscriber_id,
+        None => insert_subscriber(&mut transaction, &new_subscriber, flagged_reason)
+            .await
+            .map_err(|e| match e {
+                // the lookup above can't lock a row that doesn't exist yet
+                sqlx::Error::Database(e) if e.code().as_deref() == Some(UNIQUE_VIOLATION) => SubscribeError::Conflict,
+                e => anyhow::Error::new(e).context("Failed to insert new subscriber in the database").into(),
+            })?,
+    };
+    let joined = join_list(&mut transaction, list.id, subscriber_id)
+        .await
+        .context("Failed to add the subscriber to the list")?;
+    // the same answer as for a new subscriber, so the form can't tell who is on the list
+    if !joined {
+        return Ok(HttpResponse::Ok().finish());
+    }
+    record_consent(&mut transaction, subscriber_id, ConsentRecord {
+        event: consent::SUBSCRIBE,
+        list: Some(&list.slug),
+        source: source.as_deref(),
+        policy_version: Some(policy_version.as_deref().unwrap_or(&consent_policy.policy_version)),
+        origin: Some(&origin),
+        actor_id: None,
+    })
     .await
-    .context("Failed to insert new subscriber in the database")?;
+    .context("Failed to record the consent")?;
     let subscription_token = generate_subscription_token();
 
-    store_token(&mut transaction, subscriber_id, &subscription_token)
+    store_token(&mut transaction, subscriber_id, list.id, &subscription_token)
     .await
     .context("Failed to store subscription token in the database")?;
 
-    send_confirmation_email(email_client, new_subscriber, &base_url.0, &subscription_token)
+    send_confirmation_email(&email_client, &new_subscriber.email, &list.name, &base_url.0, &subscription_token)
     .await
     .context("Failed to send confirmation email")?;
+    record_sent(&mut transaction, subscriber_id, sent_emails::CONFIRMATION, CONFIRMATION_SUBJECT)
+    .await
+    .context("Failed to record the confirmation email")?;
     
 
     transaction.commit()
     .await
     .context("Failed to commit SQL transaction")?;
+    METRICS.subscriptions_created_total.inc();
 
     Ok(HttpResponse::Ok().finish())
 }
@@ -114,48 +263,88 @@ pub fn is_valid_name(s: &str) -> bool {
     name = "Saving new subscriber details in the database",
     skip(transaction, new_subscripber)
 )]
-pub async fn insert_subscriber(transaction: &mut Transaction<'_, Postgres>, new_subscripber: &NewSubscriber) -> Result<Uuid, sqlx::Error> {
+pub async fn insert_subscriber(transaction: &mut Transaction<'_, Postgres>, new_subscripber: &NewSubscriber, flagged_reason: Option<&str>) -> Result<Uuid, sqlx::Error> {
     let uid = Uuid::new_v4();
     sqlx::query!(
         r#"
-        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-        VALUES ($1, $2, $3, $4, 'pending_confirmation')
+        INSERT INTO subscriptions (id, email, email_canonical, name, subscribed_at, status, flagged_reason, language, timezone)
+        VALUES ($1, $2, $3, $4, $5, 'pending_confirmation', $6, $7, $8)
         "#,
         uid,
         new_subscripber.email.as_ref(),
+        new_subscripber.email.canonical(),
         new_subscripber.name.as_ref(),
         Utc::now(),
+        flagged_reason,
+        new_subscripber.language.as_ref().map(|l| l.as_ref()),
+        new_subscripber.timezone.as_ref().map(|t| t.as_ref()),
     )
     .execute(transaction)
     .await
     .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
+        // the database error detail may echo the row back, only log the message
+        tracing::error!("Failed to execute query: {}", e);
         e
     })?;
     Ok(uid)
 }
+async fn find_subscriber(transaction: &mut Transaction<'_, Postgres>, email: &SubscriberEmail) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE email_canonical = $1 FOR UPDATE"#,
+        email.canonical()
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|r| r.id))
+}
+
+// pending until confirmed, `false` when already confirmed on the list. Someone who unsubscribed
+// can join again, and someone still pending joins anew to get another confirmation email.
+async fn join_list(transaction: &mut Transaction<'_, Postgres>, list_id: Uuid, subscriber_id: Uuid) -> Result<bool, sqlx::Error> {
+    let joined = sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (list_id, subscription_id, status)
+        VALUES ($1, $2, 'pending_confirmation')
+        ON CONFLICT (list_id, subscription_id) DO UPDATE
+        SET status = 'pending_confirmation', created_at = now(), confirmed_at = NULL, unsubscribed_at = NULL
+        WHERE list_memberships.status IN ('unsubscribed', 'pending_confirmation')
+        "#,
+        list_id,
+        subscriber_id,
+    )
+    .execute(transaction)
+    .await?
+    .rows_affected();
+    Ok(joined == 1)
+}
+
+pub const CONFIRMATION_SUBJECT: &str = "Welcome";
+// the Postgres SQLSTATE
+const UNIQUE_VIOLATION: &str = "23505";
+
 #[tracing::instrument(
     name = "Sending confirmation email",
-    skip(email_client, new_subscripber, base_url, subscription_token),
+    skip(email_client, email, list_name, base_url, subscription_token),
 )]
 pub async fn send_confirmation_email(
-    email_client: web::Data<EmailClient>,
-    new_subscripber: NewSubscriber,
+    email_client: &EmailClient,
+    email: &SubscriberEmail,
+    list_name: &str,
     base_url: &str,
     subscription_token: &str,
 )-> Result<(), reqwest::Error>{
     let confirmation_link = format!("{}/subscriptions/confirm?subscription_token={}", base_url, subscription_token);
     // todo uuid for confirmed link
     email_client.send_email(
-        &new_subscripber.email,
-        "Welcome", 
-        &format!("welcome to our newsletter! <br /> Click <a href=\"{}\">here</a> to confirm your subscription.", confirmation_link),
-        &format!("welcome to our newsletter! \n Visit {} to confirm your subscription.", confirmation_link)
+        email,
+        CONFIRMATION_SUBJECT,
+        &format!("welcome to {}! <br /> Click <a href=\"{}\">here</a> to confirm your subscription.", escape_html(list_name), confirmation_link),
+        &format!("welcome to {}! \n Visit {} to confirm your subscription.", list_name, confirmation_link)
     )
     .await
 }
 
-fn generate_subscription_token() -> String {
+pub(crate) fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     let token: String = std::iter::repeat(())
         .map(|()| rng.sample(rand::distributions::Alphanumeric))
@@ -165,19 +354,21 @@ fn generate_subscription_token() -> String {
     token
 }
 
-async fn store_token(transaction: &mut Transaction<'_, Postgres>, subscriber_id: Uuid, subscription_token: &str) -> Result<(), sqlx::Error> {
+async fn store_token(transaction: &mut Transaction<'_, Postgres>, subscriber_id: Uuid, list_id: Uuid, subscription_token: &str) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"
-        INSERT INTO subscriptions_token (subscriptions_token, subscription_id)
-        VALUES ($1, $2)
+        INSERT INTO subscriptions_token (subscriptions_token, subscription_id, list_id)
+        VALUES ($1, $2, $3)
         "#,
         subscription_token,
         subscriber_id,
+        list_id,
     )
     .execute(transaction)
     .await
     .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
+        // the database error detail may echo the row back, only log the message
+        tracing::error!("Failed to execute query: {}", e);
         e
     })?;
     Ok(())
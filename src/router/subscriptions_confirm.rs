@@ -1,56 +1,154 @@
-use actix_web::{web, HttpResponse, web::Query};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError, web::Query};
+use anyhow::Context;
 use sqlx::PgPool;
 use uuid::Uuid;
+use crate::consent::{self, record_consent, ConsentRecord, RequestOrigin};
+use crate::metrics::{observe_acquire, METRICS};
+use crate::problem::Problem;
+use crate::router::error_chain_fmt;
 
 #[derive(serde::Deserialize)]
 pub struct Parameters {
     subscription_token: String,
 }
 
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+    #[error("This confirmation link is not valid or has already been used")]
+    UnknownToken,
+    // someone subscribed with the new address in the meantime
+    #[error("The new email address is already subscribed")]
+    EmailTaken,
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ConfirmError::UnknownToken => StatusCode::BAD_REQUEST,
+            ConfirmError::EmailTaken => StatusCode::CONFLICT,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ConfirmError::UnexpectedError(_) => Problem::new(self.status_code()).response(),
+            _ => Problem::new(self.status_code()).with_detail(self.to_string()).response(),
+        }
+    }
+}
+
 #[tracing::instrument(
     name = "Confirm a pending subscriber",
-    skip(parameters, pool),
+    skip(parameters, pool, origin),
 )]
-pub async fn confirm(parameters: Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let id = match get_subscriber_id(&pool, &parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(e) => {
-            tracing::error!("Failed to query database: {:?}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
+pub async fn confirm(parameters: Query<Parameters>, pool: web::Data<PgPool>, origin: RequestOrigin) -> Result<HttpResponse, ConfirmError> {
+    let token = get_token(&pool, &parameters.subscription_token)
+        .await
+        .context("Failed to fetch the confirmation token")?;
 
-    match id {
-        Some(id) => {
-            if confirm_subscriber(&pool, id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
+    match token {
+        // requested from the preference centre
+        Some(ConfirmationToken { subscription_id, new_email: Some(new_email), new_email_canonical: Some(new_email_canonical), .. }) => {
+            let changed = change_email(&pool, subscription_id, &parameters.subscription_token, &new_email, &new_email_canonical)
+                .await
+                .context("Failed to change the email")?;
+            if !changed {
+                return Err(ConfirmError::EmailTaken);
             }
-            HttpResponse::Ok().finish()
+            Ok(HttpResponse::Ok().finish())
+        }
+        Some(ConfirmationToken { subscription_id: id, list_id: Some(list_id), .. }) => {
+            confirm_subscriber(&pool, id, list_id, &origin)
+                .await
+                .context("Failed to confirm the subscriber")?;
+            METRICS.subscriptions_confirmed_total.inc();
+            Ok(HttpResponse::Ok().finish())
         }
-        None => HttpResponse::BadRequest().finish(),
+        _ => Err(ConfirmError::UnknownToken),
     }
-    
 }
 
-// get subscriber id from token 
-pub async fn get_subscriber_id(pool: &PgPool, subscription_token: &str) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscription_id FROM subscriptions_token WHERE subscriptions_token = $1"#,
+pub struct ConfirmationToken {
+    pub subscription_id: Uuid,
+    pub new_email: Option<String>,
+    pub new_email_canonical: Option<String>,
+    pub list_id: Option<Uuid>,
+}
+
+async fn get_token(pool: &PgPool, subscription_token: &str) -> Result<Option<ConfirmationToken>, sqlx::Error> {
+    sqlx::query_as!(
+        ConfirmationToken,
+        r#"SELECT subscription_id, new_email, new_email_canonical, list_id FROM subscriptions_token WHERE subscriptions_token = $1"#,
         subscription_token
     )
     .fetch_optional(pool)
+    .await
+}
+
+// swap in the new address and burn the token, `false` if the address was taken meanwhile
+async fn change_email(pool: &PgPool, id: Uuid, token: &str, email: &str, email_canonical: &str) -> Result<bool, sqlx::Error> {
+    let mut transaction = observe_acquire(pool.begin()).await?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET email = $2, email_canonical = $3
+        WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE email_canonical = $3 AND id <> $1)
+        "#,
+        id,
+        email,
+        email_canonical
+    )
+    .execute(&mut transaction)
     .await?
-    .map(|s| s.subscription_id);
-    Ok(result)
+    .rows_affected();
+    sqlx::query!(r#"DELETE FROM subscriptions_token WHERE subscriptions_token = $1"#, token)
+        .execute(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(updated == 1)
 }
 
-// confirm subscriber, update status form pending to confirmed in subscriptions table
-async fn confirm_subscriber(pool: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
+// confirm the membership of the token's list, the subscriber counts as confirmed once any list is
+async fn confirm_subscriber(pool: &PgPool, id: Uuid, list_id: Uuid, origin: &RequestOrigin) -> Result<(), sqlx::Error> {
+    let mut transaction = observe_acquire(pool.begin()).await?;
+    let confirmed = sqlx::query!(
+        r#"
+        UPDATE list_memberships SET status = 'confirmed', confirmed_at = now()
+        FROM lists
+        WHERE lists.id = list_id AND list_id = $1 AND subscription_id = $2 AND status = 'pending_confirmation'
+        RETURNING lists.slug
+        "#,
+        list_id,
+        id
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    // a token clicked twice is only recorded once
+    if let Some(confirmed) = confirmed {
+        record_consent(&mut transaction, id, ConsentRecord {
+            event: consent::CONFIRM,
+            list: Some(&confirmed.slug),
+            source: None,
+            policy_version: None,
+            origin: Some(origin),
+            actor_id: None,
+        })
+        .await?;
+    }
     sqlx::query!(
         r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
         id
     )
-    .execute(pool)
+    .execute(&mut transaction)
     .await?;
+    transaction.commit().await?;
     Ok(())
 }
\ No newline at end of file
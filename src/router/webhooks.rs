@@ -0,0 +1,96 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::problem::Problem;
+use crate::router::error_chain_fmt;
+
+// What the email provider sends in `X-Webhook-Token` to prove a webhook call is theirs.
+pub struct WebhookToken(Secret<String>);
+
+impl WebhookToken {
+    pub fn new(token: Secret<String>) -> Self {
+        Self(token)
+    }
+
+    // compares digests, so the time taken doesn't tell how much of the token was right
+    fn matches(&self, presented: &str) -> bool {
+        Sha256::digest(presented.as_bytes()) == Sha256::digest(self.0.expose_secret().as_bytes())
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum WebhookError {
+    #[error("The webhook token is missing or wrong")]
+    Unauthorized,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::Unauthorized => StatusCode::UNAUTHORIZED,
+            WebhookError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        Problem::new(self.status_code()).response()
+    }
+}
+
+// A bounce as reported by Postmark, the fields we use.
+#[derive(serde::Deserialize)]
+pub struct BounceReport {
+    #[serde(rename = "MessageID")]
+    message_id: String,
+    // e.g. HardBounce, SoftBounce
+    #[serde(rename = "Type", default)]
+    kind: Option<String>,
+    #[serde(rename = "Description", default)]
+    description: Option<String>,
+}
+
+// Marks the newsletter email the bounce is about as `bounced`. Bounces of other emails, such as
+// confirmations, are acknowledged and ignored, so the provider doesn't retry them.
+#[tracing::instrument(name = "Recording a bounce", skip(request, body, pool, token), fields(message_id = %body.message_id))]
+pub async fn record_bounce(
+    request: HttpRequest,
+    body: web::Json<BounceReport>,
+    pool: web::Data<PgPool>,
+    token: web::Data<WebhookToken>,
+) -> Result<HttpResponse, WebhookError> {
+    let presented = request.headers().get("X-Webhook-Token").and_then(|value| value.to_str().ok());
+    if !presented.is_some_and(|presented| token.matches(presented)) {
+        return Err(WebhookError::Unauthorized);
+    }
+    let reason = match (&body.kind, &body.description) {
+        (Some(kind), Some(description)) => format!("{}: {}", kind, description),
+        (Some(kind), None) => kind.clone(),
+        (None, description) => description.clone().unwrap_or_else(|| "Bounced".to_string()),
+    };
+    let bounced = sqlx::query!(
+        r#"
+        UPDATE issue_deliveries SET status = 'bounced', error = $2, updated_at = now()
+        WHERE message_id = $1 AND status = 'sent'
+        "#,
+        body.message_id,
+        reason
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to record the bounce")?;
+    if bounced.rows_affected() == 0 {
+        tracing::info!("the bounce is not about a newsletter email we sent");
+    }
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,453 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::{Map, Value};
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+use sqlx::Postgres;
+
+use crate::domain::{DomainError, Language, TimeZone};
+
+const MAX_DEPTH: usize = 8;
+const MAX_CONDITIONS: usize = 50;
+const MAX_VALUES: usize = 100;
+const MAX_ATTRIBUTE_KEY_LENGTH: usize = 64;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SegmentError {
+    #[error("A segment is a {{\"field\", \"op\", \"value\"}} condition or an \"and\", \"or\" or \"not\" group")]
+    InvalidExpression,
+    #[error("An \"and\" or \"or\" group needs at least one expression")]
+    EmptyGroup,
+    #[error("Unknown field {0:?}, expected subscribed_at, language, timezone or attributes.<name>")]
+    UnknownField(String),
+    #[error("The field {field:?} does not support the {op:?} operator")]
+    UnsupportedOperator { field: String, op: String },
+    #[error("The value for {0:?} is missing or has the wrong type")]
+    InvalidValue(String),
+    #[error("The segment has more than {max} conditions")]
+    TooManyConditions { max: usize },
+    #[error("The segment is nested more than {max} levels deep")]
+    TooDeep { max: usize },
+}
+
+impl DomainError for SegmentError {
+    fn code(&self) -> &'static str {
+        match self {
+            SegmentError::InvalidExpression => "invalid_segment",
+            SegmentError::EmptyGroup => "empty_group",
+            SegmentError::UnknownField(_) => "unknown_field",
+            SegmentError::UnsupportedOperator { .. } => "unsupported_operator",
+            SegmentError::InvalidValue(_) => "invalid_value",
+            SegmentError::TooManyConditions { .. } => "too_many_conditions",
+            SegmentError::TooDeep { .. } => "too_deep",
+        }
+    }
+    fn max(&self) -> Option<usize> {
+        match self {
+            SegmentError::TooManyConditions { max } | SegmentError::TooDeep { max } => Some(*max),
+            _ => None,
+        }
+    }
+}
+
+// A filter over `subscriptions`, e.g.
+// `{"and": [{"field": "language", "op": "eq", "value": "de"}, {"not": {"field": "attributes.plan", "op": "exists"}}]}`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    And(Vec<Segment>),
+    Or(Vec<Segment>),
+    Not(Box<Segment>),
+    Condition(Condition),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    SubscribedAt(Comparison, DateTime<Utc>),
+    Language(TextTest<Language>),
+    Timezone(TextTest<TimeZone>),
+    Attribute(String, AttributeTest),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextTest<T> {
+    Eq(T),
+    Ne(T),
+    In(Vec<T>),
+    Exists(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeTest {
+    Eq(Value),
+    Ne(Value),
+    In(Vec<Value>),
+    // numbers only, anything else doesn't match
+    Compare(Comparison, Value),
+    Exists(bool),
+}
+
+impl Segment {
+    pub fn parse(expression: &Value) -> Result<Segment, SegmentError> {
+        let mut conditions = 0;
+        parse_node(expression, 1, &mut conditions)
+    }
+
+    // The segment as a SQL boolean expression whose placeholders start at `$first_param`.
+    // Only field names known here reach the SQL text, every value is a bound parameter.
+    pub fn to_sql(&self, first_param: usize) -> SqlFilter {
+        let mut filter = SqlFilter {
+            clause: String::new(),
+            args: Vec::new(),
+            first_param,
+        };
+        filter.clause = filter.compile(self);
+        filter
+    }
+}
+
+fn parse_node(expression: &Value, depth: usize, conditions: &mut usize) -> Result<Segment, SegmentError> {
+    if depth > MAX_DEPTH {
+        return Err(SegmentError::TooDeep { max: MAX_DEPTH });
+    }
+    let object = expression.as_object().ok_or(SegmentError::InvalidExpression)?;
+    if object.contains_key("field") {
+        *conditions += 1;
+        if *conditions > MAX_CONDITIONS {
+            return Err(SegmentError::TooManyConditions { max: MAX_CONDITIONS });
+        }
+        return parse_condition(object).map(Segment::Condition);
+    }
+    let (operator, operand) = match object.iter().next() {
+        Some(entry) if object.len() == 1 => entry,
+        _ => return Err(SegmentError::InvalidExpression),
+    };
+    let group = |operand: &Value, conditions: &mut usize| {
+        let children = operand.as_array().ok_or(SegmentError::InvalidExpression)?;
+        if children.is_empty() {
+            return Err(SegmentError::EmptyGroup);
+        }
+        children
+            .iter()
+            .map(|child| parse_node(child, depth + 1, conditions))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    match operator.as_str() {
+        "and" => group(operand, conditions).map(Segment::And),
+        "or" => group(operand, conditions).map(Segment::Or),
+        "not" => Ok(Segment::Not(Box::new(parse_node(operand, depth + 1, conditions)?))),
+        _ => Err(SegmentError::InvalidExpression),
+    }
+}
+
+fn parse_condition(object: &Map<String, Value>) -> Result<Condition, SegmentError> {
+    if object.keys().any(|key| !["field", "op", "value"].contains(&key.as_str())) {
+        return Err(SegmentError::InvalidExpression);
+    }
+    let field = object["field"].as_str().ok_or(SegmentError::InvalidExpression)?;
+    let op = object.get("op").and_then(Value::as_str).ok_or(SegmentError::InvalidExpression)?;
+    let value = object.get("value");
+    let unsupported = || SegmentError::UnsupportedOperator {
+        field: field.to_string(),
+        op: op.to_string(),
+    };
+    let invalid = || SegmentError::InvalidValue(field.to_string());
+    match field {
+        "subscribed_at" => {
+            let comparison = parse_comparison(op).ok_or_else(unsupported)?;
+            let at = value.and_then(Value::as_str).and_then(parse_timestamp).ok_or_else(invalid)?;
+            Ok(Condition::SubscribedAt(comparison, at))
+        }
+        "language" => {
+            let test = parse_text_test(op, value, |s| Language::parse(s).ok()).ok_or_else(unsupported)?;
+            Ok(Condition::Language(test.ok_or_else(invalid)?))
+        }
+        "timezone" => {
+            let test = parse_text_test(op, value, |s| TimeZone::parse(s).ok()).ok_or_else(unsupported)?;
+            Ok(Condition::Timezone(test.ok_or_else(invalid)?))
+        }
+        _ => {
+            let key = field
+                .strip_prefix("attributes.")
+                .filter(|key| !key.is_empty() && key.chars().count() <= MAX_ATTRIBUTE_KEY_LENGTH)
+                .ok_or_else(|| SegmentError::UnknownField(field.to_string()))?;
+            let test = match (op, value) {
+                ("eq", Some(value)) => AttributeTest::Eq(value.clone()),
+                ("ne", Some(value)) => AttributeTest::Ne(value.clone()),
+                ("in", Some(Value::Array(values))) if !values.is_empty() && values.len() <= MAX_VALUES => {
+                    AttributeTest::In(values.clone())
+                }
+                ("exists", value) => AttributeTest::Exists(parse_exists(value).ok_or_else(invalid)?),
+                ("eq" | "ne" | "in", _) => return Err(invalid()),
+                (op, value) => match (parse_comparison(op), value) {
+                    (Some(comparison), Some(value)) if value.is_number() => AttributeTest::Compare(comparison, value.clone()),
+                    (Some(_), _) => return Err(invalid()),
+                    (None, _) => return Err(unsupported()),
+                },
+            };
+            Ok(Condition::Attribute(key.to_string(), test))
+        }
+    }
+}
+
+fn parse_comparison(op: &str) -> Option<Comparison> {
+    match op {
+        "lt" | "before" => Some(Comparison::Lt),
+        "lte" => Some(Comparison::Lte),
+        "gt" | "after" => Some(Comparison::Gt),
+        "gte" => Some(Comparison::Gte),
+        _ => None,
+    }
+}
+
+// RFC 3339, or a plain date meaning midnight UTC
+fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(s) {
+        return Some(at.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(DateTime::from_utc(date.and_hms_opt(0, 0, 0)?, Utc))
+}
+
+// `exists` alone means true
+fn parse_exists(value: Option<&Value>) -> Option<bool> {
+    match value {
+        None => Some(true),
+        Some(value) => value.as_bool(),
+    }
+}
+
+// `None` for an unsupported operator, `Some(None)` for a bad value
+fn parse_text_test<T>(op: &str, value: Option<&Value>, parse: impl Fn(&str) -> Option<T>) -> Option<Option<TextTest<T>>> {
+    let one = || value.and_then(Value::as_str).and_then(&parse);
+    let test = match op {
+        "eq" => one().map(TextTest::Eq),
+        "ne" => one().map(TextTest::Ne),
+        "in" => value
+            .and_then(Value::as_array)
+            .filter(|values| !values.is_empty() && values.len() <= MAX_VALUES)
+            .and_then(|values| values.iter().map(|v| v.as_str().and_then(&parse)).collect::<Option<Vec<_>>>())
+            .map(TextTest::In),
+        "exists" => parse_exists(value).map(TextTest::Exists),
+        _ => return None,
+    };
+    Some(test)
+}
+
+// A bound parameter of a compiled segment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlArg {
+    Text(String),
+    TextArray(Vec<String>),
+    Timestamp(DateTime<Utc>),
+    Json(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlFilter {
+    pub clause: String,
+    pub args: Vec<SqlArg>,
+    first_param: usize,
+}
+
+impl SqlFilter {
+    // matches every subscriber, for sends without a segment
+    pub fn everything() -> Self {
+        Self {
+            clause: "TRUE".to_string(),
+            args: Vec::new(),
+            first_param: 1,
+        }
+    }
+
+    pub fn bind<'q>(&'q self, mut query: Query<'q, Postgres, PgArguments>) -> Query<'q, Postgres, PgArguments> {
+        for arg in &self.args {
+            query = match arg {
+                SqlArg::Text(value) => query.bind(value),
+                SqlArg::TextArray(values) => query.bind(values),
+                SqlArg::Timestamp(value) => query.bind(value),
+                SqlArg::Json(value) => query.bind(value),
+            };
+        }
+        query
+    }
+
+    fn param(&mut self, arg: SqlArg) -> String {
+        let cast = match arg {
+            SqlArg::Text(_) => "text",
+            SqlArg::TextArray(_) => "text[]",
+            SqlArg::Timestamp(_) => "timestamptz",
+            SqlArg::Json(_) => "jsonb",
+        };
+        self.args.push(arg);
+        format!("${}::{}", self.first_param + self.args.len() - 1, cast)
+    }
+
+    fn compile(&mut self, segment: &Segment) -> String {
+        match segment {
+            Segment::And(children) => self.join(children, " AND "),
+            Segment::Or(children) => self.join(children, " OR "),
+            Segment::Not(child) => format!("(NOT {})", self.compile(child)),
+            // NULL counts as false, so that NOT keeps its meaning
+            Segment::Condition(condition) => format!("COALESCE({}, false)", self.condition(condition)),
+        }
+    }
+
+    fn join(&mut self, children: &[Segment], separator: &str) -> String {
+        let children: Vec<_> = children.iter().map(|child| self.compile(child)).collect();
+        format!("({})", children.join(separator))
+    }
+
+    fn condition(&mut self, condition: &Condition) -> String {
+        match condition {
+            Condition::SubscribedAt(comparison, at) => {
+                format!("subscriptions.subscribed_at {} {}", operator(*comparison), self.param(SqlArg::Timestamp(*at)))
+            }
+            // "de" also matches regional variants such as "de-AT"
+            Condition::Language(test) => self.text_test("subscriptions.language", test, |column, tag| {
+                format!("({column} = {tag} OR starts_with({column}, {tag} || '-'))")
+            }),
+            Condition::Timezone(test) => {
+                self.text_test("subscriptions.timezone", test, |column, name| format!("{column} = {name}"))
+            }
+            Condition::Attribute(key, test) => {
+                let key = self.param(SqlArg::Text(key.clone()));
+                let attribute = format!("(subscriptions.attributes -> {})", key);
+                match test {
+                    AttributeTest::Eq(value) => format!("{} = {}", attribute, self.param(SqlArg::Json(value.clone()))),
+                    AttributeTest::Ne(value) => {
+                        format!("NOT COALESCE({} = {}, false)", attribute, self.param(SqlArg::Json(value.clone())))
+                    }
+                    AttributeTest::In(values) => {
+                        let values: Vec<_> = values.iter().map(|v| self.param(SqlArg::Json(v.clone()))).collect();
+                        format!("{} IN ({})", attribute, values.join(", "))
+                    }
+                    AttributeTest::Compare(comparison, value) => format!(
+                        "(jsonb_typeof({attribute}) = 'number' AND {attribute} {} {})",
+                        operator(*comparison),
+                        self.param(SqlArg::Json(value.clone()))
+                    ),
+                    AttributeTest::Exists(true) => format!("subscriptions.attributes ? {}", key),
+                    AttributeTest::Exists(false) => format!("NOT (subscriptions.attributes ? {})", key),
+                }
+            }
+        }
+    }
+
+    fn text_test<T: AsRef<str>>(
+        &mut self,
+        column: &str,
+        test: &TextTest<T>,
+        equals: impl Fn(&str, &str) -> String,
+    ) -> String {
+        match test {
+            TextTest::Eq(value) => equals(column, &self.param(SqlArg::Text(value.as_ref().to_string()))),
+            TextTest::Ne(value) => format!(
+                "NOT COALESCE({}, false)",
+                equals(column, &self.param(SqlArg::Text(value.as_ref().to_string())))
+            ),
+            TextTest::In(values) => {
+                let values = self.param(SqlArg::TextArray(values.iter().map(|v| v.as_ref().to_string()).collect()));
+                format!("EXISTS (SELECT 1 FROM unnest({}) AS candidate WHERE {})", values, equals(column, "candidate"))
+            }
+            TextTest::Exists(true) => format!("{} IS NOT NULL", column),
+            TextTest::Exists(false) => format!("{} IS NULL", column),
+        }
+    }
+}
+
+fn operator(comparison: Comparison) -> &'static str {
+    match comparison {
+        Comparison::Lt => "<",
+        Comparison::Lte => "<=",
+        Comparison::Gt => ">",
+        Comparison::Gte => ">=",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Segment, SegmentError, SqlArg};
+    use claim::assert_err;
+    use serde_json::json;
+
+    #[test]
+    fn a_segment_compiles_to_placeholders_only() {
+        let segment = Segment::parse(&json!({"and": [
+            {"field": "language", "op": "eq", "value": "DE"},
+            {"not": {"field": "attributes.plan", "op": "eq", "value": "free'; DROP TABLE subscriptions; --"}}
+        ]}))
+        .unwrap();
+
+        let filter = segment.to_sql(2);
+
+        assert_eq!(
+            filter.clause,
+            "(COALESCE((subscriptions.language = $2::text OR starts_with(subscriptions.language, $2::text || '-')), false) \
+             AND (NOT COALESCE((subscriptions.attributes -> $3::text) = $4::jsonb, false)))"
+        );
+        assert!(!filter.clause.contains("DROP"));
+        assert_eq!(
+            filter.args,
+            [
+                SqlArg::Text("de".to_string()),
+                SqlArg::Text("plan".to_string()),
+                SqlArg::Json(json!("free'; DROP TABLE subscriptions; --")),
+            ]
+        );
+    }
+
+    #[test]
+    fn dates_and_timestamps_are_accepted() {
+        for value in ["2023-06-01", "2023-06-01T00:00:00Z", "2023-06-01T02:00:00+02:00"] {
+            let segment = Segment::parse(&json!({"field": "subscribed_at", "op": "after", "value": value})).unwrap();
+            assert_eq!(
+                segment.to_sql(1).args,
+                [SqlArg::Timestamp("2023-06-01T00:00:00Z".parse().unwrap())],
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_fields_and_operators_are_rejected() {
+        let cases = [
+            (json!({"field": "email", "op": "eq", "value": "a@b.com"}), "unknown_field"),
+            (json!({"field": "attributes.", "op": "exists"}), "unknown_field"),
+            (json!({"field": "language", "op": "gt", "value": "de"}), "unsupported_operator"),
+            (json!({"field": "subscribed_at", "op": "eq", "value": "2023-01-01"}), "unsupported_operator"),
+            (json!({"field": "timezone", "op": "eq", "value": "Mars/Olympus"}), "invalid_value"),
+            (json!({"field": "attributes.seats", "op": "gt", "value": "3"}), "invalid_value"),
+            (json!({"field": "language", "op": "eq", "value": "de", "or": []}), "invalid_segment"),
+            (json!({"xor": []}), "invalid_segment"),
+            (json!({"or": []}), "empty_group"),
+            (json!("language = 'de'"), "invalid_segment"),
+        ];
+        for (expression, code) in cases {
+            let error = assert_err!(Segment::parse(&expression));
+            assert_eq!(crate::domain::DomainError::code(&error), code, "{}", expression);
+        }
+    }
+
+    #[test]
+    fn segments_are_bounded() {
+        let mut deep = json!({"field": "language", "op": "exists"});
+        for _ in 0..8 {
+            deep = json!({ "not": deep });
+        }
+        assert_eq!(assert_err!(Segment::parse(&deep)), SegmentError::TooDeep { max: 8 });
+
+        let wide: Vec<_> = (0..51).map(|_| json!({"field": "language", "op": "exists"})).collect();
+        assert_eq!(
+            assert_err!(Segment::parse(&json!({ "or": wide }))),
+            SegmentError::TooManyConditions { max: 50 }
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+// what an email sent to a subscriber was for, stored as `sent_emails.kind`
+pub const CONFIRMATION: &str = "confirmation";
+pub const NEWSLETTER: &str = "newsletter";
+pub const PREFERENCES_LINK: &str = "preferences_link";
+pub const EMAIL_CHANGE: &str = "email_change";
+
+// The send history of a subscriber, returned by data subject access requests.
+pub async fn record_sent<'e>(
+    executor: impl PgExecutor<'e>,
+    subscription_id: Uuid,
+    kind: &str,
+    subject: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO sent_emails (subscription_id, kind, subject) VALUES ($1, $2, $3)"#,
+        subscription_id,
+        kind,
+        subject,
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// a newsletter email, tied to its issue
+pub async fn record_issue_sent<'e>(
+    executor: impl PgExecutor<'e>,
+    subscription_id: Uuid,
+    issue_id: Uuid,
+    subject: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO sent_emails (subscription_id, kind, subject, issue_id) VALUES ($1, $2, $3, $4)"#,
+        subscription_id,
+        NEWSLETTER,
+        subject,
+        issue_id,
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
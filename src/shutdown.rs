@@ -0,0 +1,246 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+// Outcome of a shutdown, mapped to the process exit code by main.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    // every in-flight request and worker finished within the grace period
+    Clean,
+    // the grace period elapsed and the remaining work was dropped
+    Forced,
+}
+
+impl ShutdownOutcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShutdownOutcome::Clean => 0,
+            ShutdownOutcome::Forced => 2,
+        }
+    }
+}
+
+// Shutdown coordinator, owned by `Application`.
+// Handlers get a `ShutdownListener` to check whether they should stop early,
+// background workers register through `worker()` and are waited on when draining.
+pub struct Shutdown {
+    notify: Arc<watch::Sender<bool>>,
+    listener: watch::Receiver<bool>,
+    in_flight: InFlightRequests,
+    workers_tx: mpsc::Sender<()>,
+    workers_rx: mpsc::Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (notify, listener) = watch::channel(false);
+        let (workers_tx, workers_rx) = mpsc::channel(1);
+        Self {
+            notify: Arc::new(notify),
+            listener,
+            in_flight: InFlightRequests::new(),
+            workers_tx,
+            workers_rx,
+        }
+    }
+    pub fn listener(&self) -> ShutdownListener {
+        ShutdownListener {
+            receiver: self.listener.clone(),
+            in_flight: self.in_flight.clone(),
+        }
+    }
+    // the returned guard must be held by the worker until it has finished its current item
+    pub fn worker(&self) -> WorkerGuard {
+        WorkerGuard {
+            listener: self.listener(),
+            _alive: self.workers_tx.clone(),
+        }
+    }
+    pub fn trigger(&self) {
+        let _ = self.notify.send(true);
+    }
+    // a cloneable handle that can start the shutdown from elsewhere, e.g. tests
+    pub fn trigger_handle(&self) -> ShutdownTrigger {
+        ShutdownTrigger(self.notify.clone())
+    }
+    // resolves once no request is being handled
+    pub async fn wait_for_requests(&self) {
+        self.in_flight.idle().await
+    }
+    // resolves once every `WorkerGuard` has been dropped
+    pub async fn wait_for_workers(self) {
+        let Shutdown { workers_tx, mut workers_rx, .. } = self;
+        drop(workers_tx);
+        // nothing is ever sent on this channel, recv returns None when the last sender is gone
+        let _ = workers_rx.recv().await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownTrigger(Arc<watch::Sender<bool>>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        let _ = self.0.send(true);
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownListener {
+    receiver: watch::Receiver<bool>,
+    in_flight: InFlightRequests,
+}
+
+impl ShutdownListener {
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+    pub async fn triggered(&mut self) {
+        while !*self.receiver.borrow() {
+            if self.receiver.changed().await.is_err() {
+                // the coordinator is gone, nothing will ever trigger us
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+    // held by the HTTP layer for as long as a request is being handled
+    pub fn request_started(&self) -> RequestGuard {
+        self.in_flight.0.send_modify(|n| *n += 1);
+        RequestGuard(self.in_flight.clone())
+    }
+}
+
+// Count of requests being handled.
+// actix-server lets a worker exit as soon as the accept loop is gone, so we
+// wait for this to reach zero ourselves before stopping the server.
+#[derive(Clone)]
+struct InFlightRequests(Arc<watch::Sender<usize>>);
+
+impl InFlightRequests {
+    fn new() -> Self {
+        Self(Arc::new(watch::channel(0).0))
+    }
+    async fn idle(&self) {
+        let mut count = self.0.subscribe();
+        while *count.borrow_and_update() != 0 {
+            if count.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub struct RequestGuard(InFlightRequests);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0 .0.send_modify(|n| *n -= 1);
+    }
+}
+
+pub struct WorkerGuard {
+    listener: ShutdownListener,
+    _alive: mpsc::Sender<()>,
+}
+
+impl WorkerGuard {
+    pub fn is_triggered(&self) -> bool {
+        self.listener.is_triggered()
+    }
+    pub async fn triggered(&mut self) {
+        self.listener.triggered().await
+    }
+}
+
+// resolves on SIGTERM or ctrl-c
+pub async fn termination_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install ctrl-c handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// run `drain` to completion unless the grace period elapses first
+pub async fn drain_within<F: std::future::Future<Output = ()>>(grace_period: Duration, drain: F) -> ShutdownOutcome {
+    match tokio::time::timeout(grace_period, drain).await {
+        Ok(()) => ShutdownOutcome::Clean,
+        Err(_) => ShutdownOutcome::Forced,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{drain_within, Shutdown, ShutdownOutcome};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn listeners_observe_the_trigger() {
+        let shutdown = Shutdown::new();
+        let mut listener = shutdown.listener();
+        assert!(!listener.is_triggered());
+        shutdown.trigger();
+        listener.triggered().await;
+        assert!(listener.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn requests_are_waited_on_until_their_guard_is_dropped() {
+        let shutdown = Shutdown::new();
+        let guard = shutdown.listener().request_started();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(guard);
+        });
+        let outcome = drain_within(Duration::from_secs(2), shutdown.wait_for_requests()).await;
+        assert_eq!(outcome, ShutdownOutcome::Clean);
+    }
+
+    #[tokio::test]
+    async fn drain_is_clean_when_workers_finish_their_item_in_time() {
+        let shutdown = Shutdown::new();
+        let mut worker = shutdown.worker();
+        tokio::spawn(async move {
+            worker.triggered().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(worker);
+        });
+        shutdown.trigger();
+        let outcome = drain_within(Duration::from_secs(2), shutdown.wait_for_workers()).await;
+        assert_eq!(outcome, ShutdownOutcome::Clean);
+    }
+
+    #[tokio::test]
+    async fn drain_is_forced_when_a_worker_outlives_the_grace_period() {
+        let shutdown = Shutdown::new();
+        let worker = shutdown.worker();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(worker);
+        });
+        shutdown.trigger();
+        let outcome = drain_within(Duration::from_millis(100), shutdown.wait_for_workers()).await;
+        assert_eq!(outcome, ShutdownOutcome::Forced);
+        assert_ne!(outcome.exit_code(), ShutdownOutcome::Clean.exit_code());
+    }
+}
@@ -1,19 +1,49 @@
-use crate::configuration::DatabaseSettings;
+use crate::configuration::{DatabaseSettings, NewsletterSettings, ReadinessSettings};
 use crate::configuration::Settings;
+use crate::confirmation_mailer::run_confirmation_mailer;
+use crate::issue_scheduler::run_issue_scheduler;
+use crate::domain_policy::DomainPolicy;
+use crate::domain_verification::DomainVerification;
 use crate::email_client::EmailClient;
-use crate::router::{health_check, publish_newsletter, subscribe, confirm};
-use actix_web::{dev::Server, web, App, HttpServer};
+use crate::metrics::METRICS;
+use crate::problem::extractor_error;
+use crate::router::{
+    approve_issue, cancel_issue, confirm, create_draft, edit_draft, count_recipients, delete_subscriber, erase_subject, export_subscribers, get_consent, get_import, get_issue, get_preferences, get_subscriber, import_subscribers, health_check, list_issues, list_subscribers,
+    metrics, publish_newsletter, readiness, request_preferences_link, subject_access, subscribe, unsubscribe, update_preferences,
+    list_revisions, preview_issue, publish_issue, reschedule_issue, test_send_issue, update_subscriber, PreferenceCenter,
+    get_delivery_report, record_bounce, resend_failed, track_click, track_open, WebhookToken,
+    archive_feed, list_archive, show_archived_issue, create_list, list_lists, rename_list,
+};
+use crate::signing::LinkSigner;
+use crate::suppression::SuppressionList;
+use crate::tracking::Tracker;
+use crate::consent::ConsentPolicy;
+use crate::telemetry::with_request_id;
+use crate::shutdown::{drain_within, termination_signal, Shutdown, ShutdownListener, ShutdownOutcome, ShutdownTrigger};
+use actix_web::dev::{Server, Service};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, App, HttpMessage, HttpServer};
 use secrecy::ExposeSecret;
 use sqlx::PgPool;
 use sqlx::postgres::PgPoolOptions;
 use std::net::TcpListener;
-use tracing_actix_web::TracingLogger;
+use tracing_actix_web::{RequestId, TracingLogger};
 pub struct Application {
     port: u16,
     server: Server,
+    shutdown: Shutdown,
+    grace_period: std::time::Duration,
 }
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
+        let domain_verification = DomainVerification::from_settings(&configuration.domain_verification)?;
+        Self::build_with_domain_verification(configuration, domain_verification).await
+    }
+    // lets tests swap the DNS resolver for an `InMemoryDomainVerifier`
+    pub async fn build_with_domain_verification(
+        configuration: Settings,
+        domain_verification: DomainVerification,
+    ) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
         let address = format!("127.0.0.1:{}", configuration.application.port);
         // something different
@@ -25,50 +55,274 @@ impl Application {
             .sender()
             .expect("invalid email address");
         let timeout = configuration.email_client.timeout();
+        let webhook_token = WebhookToken::new(configuration.email_client.webhook_token);
         let email_client = EmailClient::new(
             configuration.email_client.base_url,
             sender_email,
             configuration.email_client.authorization_token,
             timeout,
         );
-        let server = run(listener, connection_pool, email_client, configuration.application.base_url)?;
+        let shutdown = Shutdown::new();
+        let grace_period = configuration.application.shutdown_grace_period();
+        let suppressions = SuppressionList::new(configuration.application.suppression_key.clone());
+        let tracker = Tracker::new(
+            LinkSigner::new(configuration.application.hmac_secret.clone()),
+            configuration.newsletter.track_engagement,
+        );
+        let preference_center = PreferenceCenter::new(
+            LinkSigner::new(configuration.application.hmac_secret),
+            &configuration.preferences,
+        );
+        tokio::spawn(run_confirmation_mailer(
+            connection_pool.clone(),
+            email_client.clone(),
+            configuration.application.base_url.clone(),
+            shutdown.worker(),
+        ));
+        tokio::spawn(run_issue_scheduler(
+            connection_pool.clone(),
+            email_client.clone(),
+            configuration.application.base_url.clone(),
+            preference_center.clone(),
+            tracker.clone(),
+            shutdown.worker(),
+        ));
+        let server = run(
+            listener,
+            connection_pool,
+            email_client,
+            configuration.application.base_url,
+            configuration.readiness,
+            domain_verification,
+            DomainPolicy::from_settings(&configuration.domain_policy)?,
+            preference_center,
+            tracker,
+            suppressions,
+            ConsentPolicy::from_settings(&configuration.consent)?,
+            configuration.newsletter,
+            webhook_token,
+            shutdown.listener(),
+            grace_period,
+        )?;
         Ok(Self {
             port,
             server,
+            shutdown,
+            grace_period,
         })
     }
     pub fn port(&self) -> u16 {
         self.port
     }
-    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+    pub fn shutdown_trigger(&self) -> ShutdownTrigger {
+        self.shutdown.trigger_handle()
+    }
+    // Serve until SIGTERM/ctrl-c (or a `ShutdownTrigger`), then drain:
+    // stop accepting connections, let in-flight requests and workers finish
+    // within the grace period, and report whether we had to force it.
+    pub async fn run_until_stopped(self) -> Result<ShutdownOutcome, std::io::Error> {
+        let Application { server, shutdown, grace_period, .. } = self;
+        let handle = server.handle();
+        let mut server = tokio::spawn(server);
+        let mut listener = shutdown.listener();
+        tokio::select! {
+            result = &mut server => {
+                // the server stopped on its own, there is nothing left to drain
+                result.map_err(std::io::Error::other)??;
+                return Ok(ShutdownOutcome::Clean);
+            }
+            _ = termination_signal() => {
+                tracing::info!("termination signal received, shutting down");
+            }
+            _ = listener.triggered() => {
+                tracing::info!("shutdown requested, shutting down");
+            }
+        }
+        shutdown.trigger();
+        let drain = async {
+            handle.pause().await;
+            shutdown.wait_for_requests().await;
+            handle.stop(true).await;
+            let _ = (&mut server).await;
+            shutdown.wait_for_workers().await;
+        };
+        let outcome = drain_within(grace_period, drain).await;
+        if outcome == ShutdownOutcome::Forced {
+            tracing::warn!("grace period of {:?} elapsed, forcing shutdown", grace_period);
+            handle.stop(false).await;
+        }
+        Ok(outcome)
     }
 }
 // Note: think deeply
 
 pub struct ApplicationBaseUrl(pub String);
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     pool: PgPool,
     email_client: EmailClient,
     base_url: String,
+    readiness_settings: ReadinessSettings,
+    domain_verification: DomainVerification,
+    domain_policy: DomainPolicy,
+    preference_center: PreferenceCenter,
+    tracker: Tracker,
+    suppressions: SuppressionList,
+    consent_policy: ConsentPolicy,
+    newsletter_settings: NewsletterSettings,
+    webhook_token: WebhookToken,
+    shutdown: ShutdownListener,
+    grace_period: std::time::Duration,
 ) -> Result<Server, std::io::Error> {
     let pool = web::Data::new(pool);
     let email_client = web::Data::new(email_client);
     let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let readiness_settings = web::Data::new(readiness_settings);
+    let domain_verification = web::Data::new(domain_verification);
+    let domain_policy = web::Data::new(domain_policy);
+    let preference_center = web::Data::new(preference_center);
+    let tracker = web::Data::new(tracker);
+    let suppressions = web::Data::new(suppressions);
+    let consent_policy = web::Data::new(consent_policy);
+    let newsletter_settings = web::Data::new(newsletter_settings);
+    let webhook_token = web::Data::new(webhook_token);
+    let shutdown = web::Data::new(shutdown);
     let server = HttpServer::new(move || {
+        let in_flight = shutdown.clone();
         App::new()
+            .wrap_fn(move |req, srv| {
+                let guard = in_flight.request_started();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    drop(guard);
+                    response
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let started = std::time::Instant::now();
+                let method = req.method().to_string();
+                // the matched pattern keeps the label bounded, e.g. no tokens from the path
+                let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    // an error is still answered, with the status of its error response
+                    let status = match &response {
+                        Ok(response) => response.status(),
+                        Err(e) => e.as_response_error().status_code(),
+                    };
+                    METRICS.observe_http_request(&route, &method, status.as_u16(), started);
+                    response
+                }
+            })
+            // runs inside `TracingLogger`, so the root span and its `RequestId` already exist
+            .wrap_fn(|req, srv| {
+                let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+                let response = srv.call(req);
+                async move {
+                    let request_id = match request_id {
+                        Some(request_id) => request_id,
+                        None => return response.await,
+                    };
+                    let mut response = with_request_id(request_id.clone(), response).await?;
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        response.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+                    }
+                    Ok(response)
+                }
+            })
             .wrap(TracingLogger::default())
             // .route("/{name}", web::get().to(greet))
             .route("/healthcheck", web::get().to(health_check))
+            .route("/readyz", web::get().to(readiness))
+            .route("/metrics", web::get().to(metrics))
             .route("/subscriptions", web::post().to(subscribe))
             .route("/subscriptions/confirm", web::get().to(confirm))
+            .route("/subscriptions/preferences/link", web::post().to(request_preferences_link))
+            .service(
+                web::resource("/subscriptions/unsubscribe")
+                    .route(web::get().to(unsubscribe))
+                    .route(web::post().to(unsubscribe)),
+            )
+            .service(
+                web::resource("/subscriptions/preferences")
+                    .route(web::get().to(get_preferences))
+                    .route(web::patch().to(update_preferences)),
+            )
             .route("/newsletter", web::post().to(publish_newsletter))
+            .route("/newsletter/dry-run", web::post().to(count_recipients))
+            .service(
+                web::resource("/newsletter/issues")
+                    .route(web::get().to(list_issues))
+                    .route(web::post().to(create_draft)),
+            )
+            .service(
+                web::resource("/newsletter/issues/{id}")
+                    .route(web::get().to(get_issue))
+                    .route(web::put().to(edit_draft))
+                    .route(web::patch().to(reschedule_issue)),
+            )
+            .route("/newsletter/issues/{id}/revisions", web::get().to(list_revisions))
+            .route("/newsletter/issues/{id}/preview", web::get().to(preview_issue))
+            .route("/newsletter/issues/{id}/test", web::post().to(test_send_issue))
+            .route("/newsletter/issues/{id}/publish", web::post().to(publish_issue))
+            .route("/newsletter/issues/{id}/approve", web::post().to(approve_issue))
+            .route("/newsletter/issues/{id}/cancel", web::post().to(cancel_issue))
+            .route("/webhooks/bounces", web::post().to(record_bounce))
+            .route("/t/o/{token}", web::get().to(track_open))
+            .route("/t/c/{token}", web::get().to(track_click))
+            .route("/archive", web::get().to(list_archive))
+            .route("/archive/{slug}", web::get().to(show_archived_issue))
+            .route("/feed.xml", web::get().to(archive_feed))
+            .service(
+                web::scope("/admin")
+                    .route("/subscribers", web::get().to(list_subscribers))
+                    // before `/subscribers/{id}`, which would answer a POST here with a 405
+                    .route("/subscribers/import", web::post().to(import_subscribers))
+                    .route("/subscribers/export", web::get().to(export_subscribers))
+                    .route("/subscribers/imports/{id}", web::get().to(get_import))
+                    .route("/privacy/access", web::post().to(subject_access))
+                    .route("/privacy/erasure", web::post().to(erase_subject))
+                    .route("/subscribers/{id}/consent", web::get().to(get_consent))
+                    .route("/issues/{id}/report", web::get().to(get_delivery_report))
+                    .route("/issues/{id}/resend-failed", web::post().to(resend_failed))
+                    .service(
+                        web::resource("/lists")
+                            .route(web::get().to(list_lists))
+                            .route(web::post().to(create_list)),
+                    )
+                    .route("/lists/{slug}", web::patch().to(rename_list))
+                    .service(
+                        web::resource("/subscribers/{id}")
+                            .route(web::get().to(get_subscriber))
+                            .route(web::patch().to(update_subscriber))
+                            .route(web::delete().to(delete_subscriber)),
+                    ),
+            )
+            .app_data(web::FormConfig::default().error_handler(|e, _| extractor_error(e)))
+            .app_data(web::JsonConfig::default().error_handler(|e, _| extractor_error(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| extractor_error(e)))
             .app_data(pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(readiness_settings.clone())
+            .app_data(domain_verification.clone())
+            .app_data(domain_policy.clone())
+            .app_data(preference_center.clone())
+            .app_data(tracker.clone())
+            .app_data(suppressions.clone())
+            .app_data(consent_policy.clone())
+            .app_data(newsletter_settings.clone())
+            .app_data(webhook_token.clone())
+            .app_data(shutdown.clone())
     })
+    // signals are handled by `Application::run_until_stopped`
+    .disable_signals()
+    // backstop only, the grace period is enforced by `run_until_stopped`
+    .shutdown_timeout(grace_period.as_secs() + 1)
     .listen(listener)?
     .run();
     Ok(server)
@@ -0,0 +1,87 @@
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::PgExecutor;
+
+use crate::domain::SubscriberEmail;
+
+// Addresses that were erased on request. Only a keyed hash of the canonical address is kept,
+// so an address can be recognised when it comes back but not recovered from the table.
+#[derive(Clone)]
+pub struct SuppressionList {
+    key: Secret<String>,
+}
+
+impl SuppressionList {
+    pub fn new(key: Secret<String>) -> Self {
+        Self { key }
+    }
+
+    pub fn hash(&self, email: &SubscriberEmail) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        // the prefix keeps these hashes apart from anything else keyed the same way
+        mac.update(format!("suppression:{}", email.canonical()).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    pub async fn add<'e>(
+        &self,
+        executor: impl PgExecutor<'e>,
+        email: &SubscriberEmail,
+        reason: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"INSERT INTO suppressions (email_hash, reason) VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
+            self.hash(email),
+            reason,
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn contains<'e>(&self, executor: impl PgExecutor<'e>, email: &SubscriberEmail) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query!(r#"SELECT email_hash FROM suppressions WHERE email_hash = $1"#, self.hash(email))
+            .fetch_optional(executor)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    // the subset of `emails` that is suppressed, as indices into it
+    pub async fn filter<'e>(
+        &self,
+        executor: impl PgExecutor<'e>,
+        emails: &[&SubscriberEmail],
+    ) -> Result<Vec<usize>, sqlx::Error> {
+        let hashes: Vec<String> = emails.iter().map(|email| self.hash(email)).collect();
+        let suppressed: Vec<String> = sqlx::query!(
+            r#"SELECT email_hash FROM suppressions WHERE email_hash = ANY($1)"#,
+            &hashes
+        )
+        .fetch_all(executor)
+        .await?
+        .into_iter()
+        .map(|row| row.email_hash)
+        .collect();
+        Ok((0..hashes.len()).filter(|&i| suppressed.contains(&hashes[i])).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SuppressionList;
+    use crate::domain::SubscriberEmail;
+    use secrecy::Secret;
+
+    #[test]
+    fn the_hash_is_keyed_and_ignores_the_case_of_the_local_part() {
+        let list = SuppressionList::new(Secret::new("key".to_string()));
+        let other = SuppressionList::new(Secret::new("another key".to_string()));
+        let email = SubscriberEmail::parse("Anna@gmail.com".to_string()).unwrap();
+        let lowercase = SubscriberEmail::parse("anna@gmail.com".to_string()).unwrap();
+        assert_eq!(list.hash(&email), list.hash(&lowercase));
+        assert_ne!(list.hash(&email), other.hash(&email));
+        assert!(!list.hash(&email).contains("anna"));
+    }
+}
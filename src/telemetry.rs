@@ -1,19 +1,132 @@
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
 use tracing::subscriber::{Subscriber, set_global_default};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry, fmt::MakeWriter};
 use tracing_log::LogTracer;
-pub fn get_subscriber<Sink>(name: String, env_filter: String, sink: Sink) -> impl Subscriber + Send + Sync
+
+use crate::configuration::TelemetrySettings;
+
+mod redaction;
+pub use redaction::*;
+
+// `tracer` adds an OpenTelemetry layer next to the bunyan JSON logs, see `otlp_tracer`.
+pub fn get_subscriber<Sink>(name: String, env_filter: String, sink: Sink, tracer: Option<Tracer>) -> impl Subscriber + Send + Sync
     where Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
     let env_filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    // personal data is redacted from everything written to the sink, see `RedactionPolicy`
+    let formatting_layer = BunyanFormattingLayer::new(name, RedactingMakeWriter(sink));
+    let otel_layer = tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
     Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
         .with(formatting_layer)
+        .with(otel_layer)
 }
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("failed to initialize log tracer");
+    // W3C `traceparent` is extracted from incoming requests and injected into outgoing ones
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
     set_global_default(subscriber).expect("Failed to set subscriber");
-}
\ No newline at end of file
+}
+
+// Build a tracer exporting spans over OTLP/HTTP, `None` when no endpoint is configured.
+// Spans are exported in batches on the tokio runtime, so this must be called from within one.
+pub fn otlp_tracer(settings: &TelemetrySettings) -> Result<Option<Tracer>, opentelemetry::trace::TraceError> {
+    let endpoint = match &settings.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let exporter = SpanExporterBuilder::from(
+        opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .with_timeout(settings.export_timeout()),
+    )
+    .build_span_exporter()?;
+    // personal data is scrubbed from spans before they are exported, see `RedactingExporter`
+    let provider = trace::TracerProvider::builder()
+        .with_batch_exporter(RedactingExporter(exporter), opentelemetry::runtime::Tokio)
+        .with_config(trace::config().with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            settings.service_name.clone(),
+        )])))
+        .build();
+    let tracer = provider.versioned_tracer("opentelemetry-otlp", Some(env!("CARGO_PKG_VERSION")), None);
+    // registered globally, so `shutdown_tracer` flushes it
+    let _ = opentelemetry::global::set_tracer_provider(provider);
+    Ok(Some(tracer))
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Run a request handler with its `request_id`, the one recorded on the root span by `TracingLogger`.
+pub async fn with_request_id<F: std::future::Future>(request_id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(request_id, f).await
+}
+
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// flush the spans still buffered by the batch exporter
+pub fn shutdown_tracer() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_subscriber, otlp_tracer, shutdown_tracer};
+    use crate::configuration::TelemetrySettings;
+    use tracing::instrument::WithSubscriber;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_to_the_collector() {
+        // a local stand-in for the OpenTelemetry collector
+        let collector = MockServer::start().await;
+        Mock::given(path("/v1/traces"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1..)
+            .mount(&collector)
+            .await;
+        let settings = TelemetrySettings {
+            otlp_endpoint: Some(format!("{}/v1/traces", collector.uri())),
+            service_name: "test".into(),
+            export_timeout_milliseconds: 1000,
+            redaction: Default::default(),
+        };
+        let tracer = otlp_tracer(&settings).unwrap();
+        let subscriber = get_subscriber("test".into(), "info".into(), std::io::sink, tracer);
+
+        async {
+            let span = tracing::info_span!("Adding a new subscriber");
+            let _guard = span.enter();
+            tracing::info!("inside the span");
+        }
+        .with_subscriber(subscriber)
+        .await;
+        tokio::task::spawn_blocking(shutdown_tracer).await.unwrap();
+    }
+
+    #[test]
+    fn no_tracer_is_built_without_an_endpoint() {
+        let settings = TelemetrySettings {
+            otlp_endpoint: None,
+            service_name: "test".into(),
+            export_timeout_milliseconds: 1000,
+            redaction: Default::default(),
+        };
+        assert!(otlp_tracer(&settings).unwrap().is_none());
+    }
+}
@@ -0,0 +1,244 @@
+use once_cell::sync::{Lazy, OnceCell};
+use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry::sdk::trace::{EvictedHashMap, EvictedQueue};
+use opentelemetry::{Array, KeyValue, Value};
+use regex::bytes::Regex;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::io::Write;
+use tracing_subscriber::fmt::MakeWriter;
+use unicode_segmentation::UnicodeSegmentation;
+
+// How personal data (email addresses, names) shows up in logs and traces.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionPolicy {
+    // replaced entirely by `[REDACTED]`
+    Full,
+    // replaced by a stable digest, so records about the same person can still be correlated
+    Hashed,
+    // first character kept, e.g. `u***@example.com`
+    #[default]
+    Masked,
+}
+
+impl RedactionPolicy {
+    pub fn redact(&self, value: &str) -> String {
+        match self {
+            RedactionPolicy::Full => "[REDACTED]".to_string(),
+            RedactionPolicy::Hashed => {
+                let digest = format!("{:x}", Sha256::digest(value.trim().to_lowercase().as_bytes()));
+                format!("sha256:{}", &digest[..16])
+            }
+            RedactionPolicy::Masked => match value.rsplit_once('@') {
+                Some((local, domain)) => format!("{}@{}", mask(local), domain),
+                None => mask(value),
+            },
+        }
+    }
+}
+
+fn mask(value: &str) -> String {
+    match value.graphemes(true).next() {
+        Some(first) => format!("{}***", first),
+        None => "***".to_string(),
+    }
+}
+
+static POLICY: OnceCell<RedactionPolicy> = OnceCell::new();
+
+// set once at startup, before any log is written; later calls are ignored
+pub fn set_redaction_policy(policy: RedactionPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+pub fn redaction_policy() -> RedactionPolicy {
+    POLICY.get().copied().unwrap_or_default()
+}
+
+// Marks a span or event field as personal data, redacted with the global policy when recorded:
+// `fields(subscriber_email = %Sensitive(&form.email))`
+pub struct Sensitive<T>(pub T);
+
+impl<T: AsRef<str>> std::fmt::Display for Sensitive<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&redaction_policy().redact(self.0.as_ref()))
+    }
+}
+
+impl<T: AsRef<str>> std::fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+static EMAIL_ADDRESS: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"[A-Za-z0-9.!#$%&'+/=?^_`{|}~-]+@[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)+").unwrap()
+});
+
+// Last line of defence for values that were not marked `Sensitive`, e.g. database errors
+// echoing a row back: email addresses found in the formatted output are redacted too.
+pub fn scrub(line: &[u8], policy: RedactionPolicy) -> Vec<u8> {
+    EMAIL_ADDRESS
+        .replace_all(line, |captures: &regex::bytes::Captures| {
+            policy.redact(&String::from_utf8_lossy(&captures[0])).into_bytes()
+        })
+        .into_owned()
+}
+
+// Wraps the sink of the formatting layer so every record goes through `scrub`.
+pub struct RedactingMakeWriter<M>(pub M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+pub struct RedactingWriter<W>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    // the bunyan layer hands over a whole record at once, so addresses are never split
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write_all(&scrub(buf, redaction_policy()))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// `scrub` for a single value
+pub fn scrub_str(value: &str, policy: RedactionPolicy) -> String {
+    String::from_utf8_lossy(&scrub(value.as_bytes(), policy)).into_owned()
+}
+
+// Wraps the span exporter so every span name, attribute and event goes through `scrub` before
+// it leaves the process, as the sink of the formatting layer does for the JSON logs.
+#[derive(Debug)]
+pub struct RedactingExporter<E>(pub E);
+
+#[async_trait::async_trait]
+impl<E: SpanExporter> SpanExporter for RedactingExporter<E> {
+    async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+        let policy = redaction_policy();
+        self.0.export(batch.into_iter().map(|span| redact_span(span, policy)).collect()).await
+    }
+    fn shutdown(&mut self) {
+        self.0.shutdown()
+    }
+}
+
+fn redact_span(mut span: SpanData, policy: RedactionPolicy) -> SpanData {
+    span.name = Cow::Owned(scrub_str(&span.name, policy));
+    span.status_message = Cow::Owned(scrub_str(&span.status_message, policy));
+    let mut attributes = EvictedHashMap::new(span.attributes.len() as u32, span.attributes.len());
+    for (key, value) in span.attributes.into_iter() {
+        attributes.insert(KeyValue::new(key, redact_value(value, policy)));
+    }
+    span.attributes = attributes;
+    let mut events = EvictedQueue::new(span.events.len() as u32);
+    events.extend(span.events.into_iter().map(|mut event| {
+        event.name = Cow::Owned(scrub_str(&event.name, policy));
+        for attribute in event.attributes.iter_mut() {
+            attribute.value = redact_value(attribute.value.clone(), policy);
+        }
+        event
+    }));
+    span.events = events;
+    span
+}
+
+fn redact_value(value: Value, policy: RedactionPolicy) -> Value {
+    match value {
+        Value::String(value) => Value::String(Cow::Owned(scrub_str(&value, policy))),
+        Value::Array(Array::String(values)) => Value::Array(Array::String(
+            values.iter().map(|value| Cow::Owned(scrub_str(value, policy))).collect(),
+        )),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact_span, scrub, RedactionPolicy};
+    use opentelemetry::sdk::export::trace::SpanData;
+    use opentelemetry::sdk::trace::{EvictedHashMap, EvictedQueue};
+    use opentelemetry::trace::{Event, SpanContext, SpanId, SpanKind, StatusCode};
+    use opentelemetry::KeyValue;
+    use std::time::SystemTime;
+
+    #[test]
+    fn masked_policy_keeps_the_first_character_and_the_domain() {
+        let policy = RedactionPolicy::Masked;
+        assert_eq!(policy.redact("ursula@example.com"), "u***@example.com");
+        assert_eq!(policy.redact("Ursula Le Guin"), "U***");
+        assert_eq!(policy.redact(""), "***");
+    }
+
+    #[test]
+    fn hashed_policy_is_stable_and_does_not_leak_the_value() {
+        let policy = RedactionPolicy::Hashed;
+        let hashed = policy.redact("ursula@example.com");
+        assert!(hashed.starts_with("sha256:"));
+        assert!(!hashed.contains("ursula"));
+        assert_eq!(hashed, policy.redact("Ursula@Example.com"));
+        assert_ne!(hashed, policy.redact("le_guin@example.com"));
+    }
+
+    #[test]
+    fn full_policy_hides_everything() {
+        assert_eq!(RedactionPolicy::Full.redact("ursula@example.com"), "[REDACTED]");
+    }
+
+    #[test]
+    fn email_addresses_are_scrubbed_from_formatted_records() {
+        let line = br#"{"msg":"Key (email)=(ursula@example.com) already exists."}"#;
+        let scrubbed = String::from_utf8(scrub(line, RedactionPolicy::Masked)).unwrap();
+        assert_eq!(scrubbed, r#"{"msg":"Key (email)=(u***@example.com) already exists."}"#);
+    }
+
+    #[test]
+    fn already_masked_addresses_are_left_alone() {
+        let line = br#"{"subscriber_email":"u***@example.com"}"#;
+        assert_eq!(scrub(line, RedactionPolicy::Masked), line.to_vec());
+    }
+
+    #[test]
+    fn exported_spans_are_scrubbed() {
+        let mut attributes = EvictedHashMap::new(8, 8);
+        attributes.insert(KeyValue::new("exception.message", "Key (email)=(ursula@example.com) already exists."));
+        attributes.insert(KeyValue::new("http.status_code", 500i64));
+        let mut events = EvictedQueue::new(8);
+        events.extend(vec![Event::new(
+            "sending to ursula@example.com",
+            SystemTime::now(),
+            vec![KeyValue::new("error.cause_chain", "rejected ursula@example.com")],
+            0,
+        )]);
+        let span = SpanData {
+            span_context: SpanContext::empty_context(),
+            parent_span_id: SpanId::INVALID,
+            span_kind: SpanKind::Internal,
+            name: "Adding a new subscriber".into(),
+            start_time: SystemTime::now(),
+            end_time: SystemTime::now(),
+            attributes,
+            events,
+            links: EvictedQueue::new(0),
+            status_code: StatusCode::Error,
+            status_message: "ursula@example.com bounced".into(),
+            resource: None,
+            instrumentation_lib: Default::default(),
+        };
+
+        let redacted = redact_span(span, RedactionPolicy::Masked);
+
+        let exported = format!("{:?}", redacted);
+        assert!(!exported.contains("ursula@"));
+        assert!(exported.contains("u***@example.com"));
+        assert!(exported.contains("500"));
+    }
+}
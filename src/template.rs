@@ -0,0 +1,303 @@
+use once_cell::sync::Lazy;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+
+use crate::domain::DomainError;
+
+const MAX_ATTRIBUTE_KEY_LENGTH: usize = 64;
+// the content so far ends inside a link's value, with what of it is already written captured
+static URL_ATTRIBUTE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)\b(?:href|src|action)\s*=\s*["']?([^"'\s>]*)$"#).unwrap());
+// everything but the unreserved characters, so a value stays one piece of the URL
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    #[error("A {{{{ placeholder is not closed with }}}}")]
+    UnclosedPlaceholder,
+    #[error("Unknown variable {0:?}, expected name, email, unsubscribe_url or attributes.<name>")]
+    UnknownVariable(String),
+    #[error("Unknown filter {0:?}, expected default: \"text\"")]
+    UnknownFilter(String),
+    #[error("The default is a quoted text, e.g. default: \"there\"")]
+    InvalidDefault,
+}
+
+impl DomainError for TemplateError {
+    fn code(&self) -> &'static str {
+        match self {
+            TemplateError::UnclosedPlaceholder => "unclosed_placeholder",
+            TemplateError::UnknownVariable(_) => "unknown_variable",
+            TemplateError::UnknownFilter(_) => "unknown_filter",
+            TemplateError::InvalidDefault => "invalid_default",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Variable {
+    Name,
+    Email,
+    UnsubscribeUrl,
+    Attribute(String),
+}
+
+// Where a placeholder sits in HTML content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Text,
+    // the start of an href, src or action value: the value is the link
+    UrlStart,
+    // further into such a value, e.g. a query parameter
+    UrlRest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Placeholder { variable: Variable, default: Option<String>, context: Context },
+}
+
+// Newsletter content with placeholders filled in per subscriber, e.g.
+// `Hi {{ name }}`, `{{ unsubscribe_url }}` or `{{ attributes.company | default: "there" }}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Part>);
+
+// How values are written into the content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    Html,
+    Plain,
+}
+
+// What the placeholders stand for, for one subscriber.
+pub struct Recipient<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+    pub unsubscribe_url: &'a str,
+    // a JSON object, see `CustomAttributes`
+    pub attributes: &'a serde_json::Value,
+}
+
+impl Template {
+    pub fn parse(content: &str) -> Result<Template, TemplateError> {
+        let mut parts = Vec::new();
+        let mut rest = content;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find("}}").ok_or(TemplateError::UnclosedPlaceholder)? + start;
+            let written = &content[..content.len() - rest.len() + start];
+            parts.push(parse_placeholder(&rest[start + 2..end], context(written))?);
+            rest = &rest[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Template(parts))
+    }
+
+    // Whether the content places the unsubscribe link itself.
+    pub fn uses_unsubscribe_url(&self) -> bool {
+        self.0
+            .iter()
+            .any(|part| matches!(part, Part::Placeholder { variable: Variable::UnsubscribeUrl, .. }))
+    }
+
+    // Values are escaped as `escape` says, the text around them is left as written. A value that is
+    // missing or empty is replaced by the default, if there is one. In HTML, a value that starts a
+    // link must be an http(s) URL, or the default is used, and one further into a link is
+    // percent-encoded; the unsubscribe link is ours and goes in as it is.
+    pub fn render(&self, recipient: &Recipient, escape: Escape) -> String {
+        let mut rendered = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Placeholder { variable, default, context } => {
+                    let value = match variable {
+                        Variable::Name => Some(recipient.name.to_string()),
+                        Variable::Email => Some(recipient.email.to_string()),
+                        Variable::UnsubscribeUrl => Some(recipient.unsubscribe_url.to_string()),
+                        Variable::Attribute(key) => match recipient.attributes.get(key) {
+                            None | Some(serde_json::Value::Null) => None,
+                            Some(serde_json::Value::String(value)) => Some(value.clone()),
+                            Some(value) => Some(value.to_string()),
+                        },
+                    };
+                    let context = match variable {
+                        Variable::UnsubscribeUrl => Context::Text,
+                        _ => *context,
+                    };
+                    let usable = |value: &String| match (escape, context) {
+                        (Escape::Html, Context::UrlStart) => is_web_url(value),
+                        _ => !value.is_empty(),
+                    };
+                    let value = value.filter(usable).or_else(|| default.clone().filter(usable)).unwrap_or_default();
+                    match (escape, context) {
+                        (Escape::Html, Context::UrlRest) => {
+                            rendered.push_str(&escape_html(&utf8_percent_encode(&value, URL_COMPONENT).to_string()))
+                        }
+                        (Escape::Html, _) => rendered.push_str(&escape_html(&value)),
+                        (Escape::Plain, _) => rendered.push_str(&value),
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+// `written` is the content before the placeholder
+fn context(written: &str) -> Context {
+    match URL_ATTRIBUTE.captures(written) {
+        Some(captures) if captures[1].is_empty() => Context::UrlStart,
+        Some(_) => Context::UrlRest,
+        None => Context::Text,
+    }
+}
+
+fn is_web_url(value: &str) -> bool {
+    let value = value.trim_start().to_ascii_lowercase();
+    value.starts_with("https://") || value.starts_with("http://")
+}
+
+// `inner` is what is between `{{` and `}}`
+fn parse_placeholder(inner: &str, context: Context) -> Result<Part, TemplateError> {
+    let (variable, filter) = match inner.split_once('|') {
+        Some((variable, filter)) => (variable.trim(), Some(filter.trim())),
+        None => (inner.trim(), None),
+    };
+    let variable = match variable {
+        "name" => Variable::Name,
+        "email" => Variable::Email,
+        "unsubscribe_url" => Variable::UnsubscribeUrl,
+        _ => match variable.strip_prefix("attributes.") {
+            Some(key) if !key.is_empty() && key.chars().count() <= MAX_ATTRIBUTE_KEY_LENGTH => {
+                Variable::Attribute(key.to_string())
+            }
+            _ => return Err(TemplateError::UnknownVariable(variable.to_string())),
+        },
+    };
+    let default = match filter {
+        Some(filter) => {
+            let (name, argument) = filter.split_once(':').unwrap_or((filter, ""));
+            if name.trim() != "default" {
+                return Err(TemplateError::UnknownFilter(name.trim().to_string()));
+            }
+            Some(parse_quoted(argument.trim()).ok_or(TemplateError::InvalidDefault)?)
+        }
+        None => None,
+    };
+    Ok(Part::Placeholder { variable, default, context })
+}
+
+// "text" or 'text', without escapes
+fn parse_quoted(argument: &str) -> Option<String> {
+    let quote = argument.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let text = argument[1..].strip_suffix(quote)?;
+    (!text.contains(quote)).then(|| text.to_string())
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_html, Escape, Recipient, Template, TemplateError};
+    use claim::assert_err;
+    use serde_json::json;
+
+    fn recipient(attributes: &serde_json::Value) -> Recipient<'_> {
+        Recipient {
+            name: "Tom & Jerry",
+            email: "tom@example.com",
+            unsubscribe_url: "https://example.com/unsubscribe?token=a&list=b",
+            attributes,
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let attributes = json!({"company": "<Acme>", "seats": 3, "empty": ""});
+        let template = Template::parse(
+            "Hi {{name}}, {{ attributes.company }} has {{ attributes.seats }} seats{{attributes.empty|default:'!'}} \
+             {{ attributes.plan | default: \"Free\" }} <a href=\"{{ unsubscribe_url }}\">",
+        )
+        .unwrap();
+
+        assert_eq!(
+            template.render(&recipient(&attributes), Escape::Html),
+            "Hi Tom &amp; Jerry, &lt;Acme&gt; has 3 seats! Free \
+             <a href=\"https://example.com/unsubscribe?token=a&amp;list=b\">"
+        );
+        assert_eq!(
+            template.render(&recipient(&attributes), Escape::Plain),
+            "Hi Tom & Jerry, <Acme> has 3 seats! Free <a href=\"https://example.com/unsubscribe?token=a&list=b\">"
+        );
+    }
+
+    #[test]
+    fn content_without_placeholders_is_unchanged() {
+        let content = "<p>Nothing {to} fill } in</p>";
+        assert_eq!(Template::parse(content).unwrap().render(&recipient(&json!({})), Escape::Html), content);
+    }
+
+    #[test]
+    fn malformed_placeholders_are_rejected() {
+        let cases = [
+            ("Hi {{ name", TemplateError::UnclosedPlaceholder),
+            ("Hi {{ first_name }}", TemplateError::UnknownVariable("first_name".to_string())),
+            ("Hi {{ }}", TemplateError::UnknownVariable("".to_string())),
+            ("Hi {{ attributes. }}", TemplateError::UnknownVariable("attributes.".to_string())),
+            ("Hi {{ name | upcase }}", TemplateError::UnknownFilter("upcase".to_string())),
+            ("Hi {{ name | default: there }}", TemplateError::InvalidDefault),
+            ("Hi {{ name | default: \"a\" \"b\" }}", TemplateError::InvalidDefault),
+        ];
+        for (content, error) in cases {
+            assert_eq!(assert_err!(Template::parse(content)), error, "{}", content);
+        }
+    }
+
+    #[test]
+    fn values_in_links_stay_links() {
+        let attributes = json!({"site": "javascript:alert(1)", "home": "https://acme.example/?a=1&b=2", "ref": "a&b=c d"});
+        let template = Template::parse(
+            "<a href=\"{{ attributes.site }}\">x</a><a href='{{ attributes.site | default: \"https://example.com\" }}'>x</a>\
+             <a HREF={{ attributes.home }}>x</a><img src=\"https://example.com/?ref={{ attributes.ref }}\">\
+             <form action=\"{{ name }}\"></form> {{ attributes.site }}",
+        )
+        .unwrap();
+
+        assert_eq!(
+            template.render(&recipient(&attributes), Escape::Html),
+            "<a href=\"\">x</a><a href='https://example.com'>x</a>\
+             <a HREF=https://acme.example/?a=1&amp;b=2>x</a><img src=\"https://example.com/?ref=a%26b%3Dc%20d\">\
+             <form action=\"\"></form> javascript:alert(1)"
+        );
+        assert!(template.render(&recipient(&attributes), Escape::Plain).contains("href=\"javascript:alert(1)\""));
+    }
+
+    #[test]
+    fn the_unsubscribe_url_is_found() {
+        assert!(Template::parse("<a href=\"{{ unsubscribe_url }}\">Leave</a>").unwrap().uses_unsubscribe_url());
+        assert!(!Template::parse("Hi {{ name }}").unwrap().uses_unsubscribe_url());
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(escape_html(r#"<a href="x">Tom & Jerry's</a>"#), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;");
+    }
+}
@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+use crate::signing::{LinkError, LinkSigner};
+
+const OPEN_PURPOSE: &str = "track_open";
+const CLICK_PURPOSE: &str = "track_click";
+
+// what an engagement event is, stored as `engagement_events.kind`
+pub const OPEN: &str = "open";
+pub const CLICK: &str = "click";
+
+// the `href` of an anchor, quoted either way
+static ANCHOR_HREF: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)(<a\b[^>]*?\shref\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap());
+static BODY_END: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)</body\s*>").unwrap());
+
+// A newsletter email as sent to one subscriber.
+pub struct TrackedEmail {
+    pub issue_id: Uuid,
+    pub subscription_id: Uuid,
+}
+
+// Rewrites the links of newsletter emails through `/t/c/` and adds an open pixel from `/t/o/`.
+// Tokens are signed, so the click endpoint only redirects to links we put in a newsletter.
+#[derive(Clone)]
+pub struct Tracker {
+    signer: LinkSigner,
+    enabled: bool,
+}
+
+impl Tracker {
+    pub fn new(signer: LinkSigner, enabled: bool) -> Self {
+        Self { signer, enabled }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // the HTML unchanged when tracking is off
+    pub fn instrument<'a>(&self, html: &'a str, base_url: &str, email: &TrackedEmail) -> Cow<'a, str> {
+        if !self.enabled {
+            return Cow::Borrowed(html);
+        }
+        let recipient = format!("{} {}", email.issue_id, email.subscription_id);
+        let mut tracked = ANCHOR_HREF
+            .replace_all(html, |captures: &Captures| {
+                let href = captures.get(2).or_else(|| captures.get(3)).map_or("", |m| m.as_str());
+                let url = href.trim().replace("&amp;", "&");
+                // mailto:, anchors and the like are left alone, and so are links with placeholders,
+                // which are only known once filled in for the subscriber
+                if !(url.starts_with("https://") || url.starts_with("http://")) || url.contains("{{") {
+                    return captures[0].to_string();
+                }
+                let token = self.signer.sign_payload(CLICK_PURPOSE, &format!("{} {}", recipient, url));
+                format!("{}\"{}/t/c/{}\"", &captures[1], base_url, token)
+            })
+            .into_owned();
+        let pixel = format!(
+            "<img src=\"{}/t/o/{}\" width=\"1\" height=\"1\" alt=\"\">",
+            base_url,
+            self.signer.sign_payload(OPEN_PURPOSE, &recipient)
+        );
+        match BODY_END.find_iter(&tracked).last().map(|end| end.start()) {
+            Some(end) => tracked.insert_str(end, &pixel),
+            None => tracked.push_str(&pixel),
+        }
+        Cow::Owned(tracked)
+    }
+
+    pub fn verify_open(&self, token: &str) -> Result<TrackedEmail, LinkError> {
+        let payload = self.signer.verify_payload(OPEN_PURPOSE, token)?;
+        parse_recipient(&payload)
+    }
+
+    // the email the link was in, and where it goes
+    pub fn verify_click(&self, token: &str) -> Result<(TrackedEmail, String), LinkError> {
+        let payload = self.signer.verify_payload(CLICK_PURPOSE, token)?;
+        let mut parts = payload.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(issue_id), Some(subscription_id), Some(url)) => {
+                Ok((parse_recipient(&format!("{} {}", issue_id, subscription_id))?, url.to_string()))
+            }
+            _ => Err(LinkError::Invalid),
+        }
+    }
+}
+
+fn parse_recipient(payload: &str) -> Result<TrackedEmail, LinkError> {
+    let (issue_id, subscription_id) = payload.split_once(' ').ok_or(LinkError::Invalid)?;
+    Ok(TrackedEmail {
+        issue_id: Uuid::parse_str(issue_id).map_err(|_| LinkError::Invalid)?,
+        subscription_id: Uuid::parse_str(subscription_id).map_err(|_| LinkError::Invalid)?,
+    })
+}
+
+// Nothing is recorded for subscribers who opted out, or who were erased since.
+pub async fn record_engagement<'e>(
+    executor: impl PgExecutor<'e>,
+    email: &TrackedEmail,
+    kind: &str,
+    url: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO engagement_events (issue_id, subscription_id, kind, url)
+        SELECT newsletter_issues.id, subscriptions.id, $3, $4
+        FROM subscriptions, newsletter_issues
+        WHERE subscriptions.id = $2 AND NOT subscriptions.tracking_opt_out AND newsletter_issues.id = $1
+        "#,
+        email.issue_id,
+        email.subscription_id,
+        kind,
+        url,
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TrackedEmail, Tracker};
+    use crate::signing::LinkSigner;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn tracker(enabled: bool) -> Tracker {
+        Tracker::new(LinkSigner::new(Secret::new("key".to_string())), enabled)
+    }
+
+    fn email() -> TrackedEmail {
+        TrackedEmail { issue_id: Uuid::new_v4(), subscription_id: Uuid::new_v4() }
+    }
+
+    #[test]
+    fn web_links_go_through_the_click_endpoint() {
+        let email = email();
+        let html = r#"<p><a class="x" href="https://example.com/?a=1&amp;b=2">one</a> <a href='mailto:me@example.com'>two</a></p>"#;
+
+        let tracked = tracker(true).instrument(html, "http://base", &email);
+
+        let token = tracked.split("/t/c/").nth(1).unwrap().split('"').next().unwrap();
+        let (clicked, url) = tracker(true).verify_click(token).unwrap();
+        assert_eq!(url, "https://example.com/?a=1&b=2");
+        assert_eq!(clicked.subscription_id, email.subscription_id);
+        assert!(tracked.contains(r#"<a class="x" href="http://base/t/c/"#));
+        assert!(tracked.contains("href='mailto:me@example.com'"));
+    }
+
+    #[test]
+    fn the_open_pixel_goes_before_the_end_of_the_body() {
+        let email = email();
+
+        let tracked = tracker(true).instrument("<html><body><p>Hi</p></BODY></html>", "http://base", &email);
+
+        assert!(tracked.ends_with("\"></BODY></html>"));
+        let token = tracked.split("/t/o/").nth(1).unwrap().split('"').next().unwrap();
+        assert_eq!(tracker(true).verify_open(token).unwrap().issue_id, email.issue_id);
+        // an open token doesn't work as a click token
+        assert!(tracker(true).verify_click(token).is_err());
+    }
+
+    #[test]
+    fn links_with_placeholders_are_left_alone() {
+        let html = r#"<a href="https://example.com/?ref={{ email }}">link</a>"#;
+        assert!(tracker(true).instrument(html, "http://base", &email()).starts_with(html));
+    }
+
+    #[test]
+    fn nothing_changes_when_tracking_is_off() {
+        let html = r#"<a href="https://example.com">link</a>"#;
+        assert_eq!(tracker(false).instrument(html, "http://base", &email()), html);
+    }
+}
@@ -0,0 +1,223 @@
+use reqwest::Method;
+use secrecy::{ExposeSecret, Secret};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2rs::authentication::create_user;
+use crate::helpers::{spawn_app, TestApp};
+
+// subscribes carol, anna and bernd in that order, carol is confirmed
+async fn create_subscribers(app: &TestApp) {
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    for body in ["name=anna&email=Anna%40gmail.com", "name=bernd&email=bernd%40gmail.com"] {
+        app.post_subscriptions(body).await.error_for_status().unwrap();
+    }
+}
+
+async fn list(app: &TestApp, query: &str) -> serde_json::Value {
+    let response = app.admin(Method::GET, &format!("/subscribers?{}", query)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200, "{}", query);
+    response.json().await.unwrap()
+}
+
+fn names(page: &serde_json::Value) -> Vec<String> {
+    page["subscribers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+async fn subscriber_id(app: &TestApp, name: &str) -> Uuid {
+    sqlx::query!("SELECT id FROM subscriptions WHERE name = $1", name)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .id
+}
+
+#[tokio::test]
+async fn admin_requests_must_be_authenticated() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/admin/subscribers", app.address)).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(response.headers()["WWW-Authenticate"], r#"Basic realm="admin""#);
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/subscribers", app.address))
+        .basic_auth(&app.test_user.username, Some("wrong password"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = reqwest::Client::new()
+        .delete(format!("{}/admin/subscribers/{}", app.address, Uuid::new_v4()))
+        .basic_auth("nobody", Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn subscribers_are_listed_newest_first_one_page_at_a_time() {
+    let app = spawn_app().await;
+    create_subscribers(&app).await;
+
+    let first = list(&app, "limit=2").await;
+    assert_eq!(names(&first), ["bernd", "anna"]);
+    let cursor = first["next_cursor"].as_str().unwrap();
+
+    let second = list(&app, &format!("limit=2&cursor={}", cursor)).await;
+    assert_eq!(names(&second), ["carol"]);
+    assert_eq!(second["next_cursor"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered_and_searched() {
+    let app = spawn_app().await;
+    create_subscribers(&app).await;
+
+    assert_eq!(names(&list(&app, "status=confirmed").await), ["carol"]);
+    assert_eq!(names(&list(&app, "status=pending_confirmation").await), ["bernd", "anna"]);
+    assert_eq!(names(&list(&app, "email_prefix=ANN").await), ["anna"]);
+    // LIKE wildcards are matched literally
+    assert!(names(&list(&app, "email_prefix=%25").await).is_empty());
+    assert_eq!(names(&list(&app, "subscribed_after=2000-01-01T00:00:00Z").await).len(), 3);
+    assert!(names(&list(&app, "subscribed_before=2000-01-01T00:00:00Z").await).is_empty());
+}
+
+#[tokio::test]
+async fn invalid_list_parameters_are_rejected() {
+    let app = spawn_app().await;
+
+    for (query, code) in [("status=gone", "unknown_status"), ("cursor=garbage", "invalid_cursor")] {
+        let response = app.admin(Method::GET, &format!("/subscribers?{}", query)).send().await.unwrap();
+
+        assert_eq!(response.status().as_u16(), 400, "{}", query);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["errors"][0]["code"], code);
+    }
+    let response = app.admin(Method::GET, "/subscribers?subscribed_after=yesterday").send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_subscriber_can_be_fetched_by_id() {
+    let app = spawn_app().await;
+    create_subscribers(&app).await;
+    let id = subscriber_id(&app, "anna").await;
+
+    let response = app.admin(Method::GET, &format!("/subscribers/{}", id)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email"], "Anna@gmail.com");
+    assert_eq!(body["status"], "pending_confirmation");
+
+    let response = app.admin(Method::GET, &format!("/subscribers/{}", Uuid::new_v4())).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn updates_are_validated_and_audited() {
+    let app = spawn_app().await;
+    create_subscribers(&app).await;
+    let id = subscriber_id(&app, "anna").await;
+
+    let response = app
+        .admin(Method::PATCH, &format!("/subscribers/{}", id))
+        .json(&serde_json::json!({"name": "Anna <script>", "email": "bernd@gmail.com"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    let response = app
+        .admin(Method::PATCH, &format!("/subscribers/{}", id))
+        .json(&serde_json::json!({"email": "BERND@gmail.com"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "already_subscribed");
+
+    // confirming someone would skip their double opt-in
+    let response = app
+        .admin(Method::PATCH, &format!("/subscribers/{}", id))
+        .json(&serde_json::json!({"status": "confirmed"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "read_only");
+
+    let response = app
+        .admin(Method::PATCH, &format!("/subscribers/{}", id))
+        .json(&serde_json::json!({"name": "Anna Schmidt", "language": "de"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["name"], "Anna Schmidt");
+    assert_eq!(body["status"], "pending_confirmation");
+
+    let audit = sqlx::query!("SELECT actor_id, action, subscriber_id, before, after FROM admin_audit_log")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(audit.len(), 1);
+    assert_eq!(audit[0].actor_id, app.test_user.user_id);
+    assert_eq!(audit[0].action, "update");
+    assert_eq!(audit[0].subscriber_id, Some(id));
+    assert_eq!(audit[0].before.as_ref().unwrap()["name"], "anna");
+    assert_eq!(audit[0].after.as_ref().unwrap()["name"], "Anna Schmidt");
+    assert_eq!(audit[0].after.as_ref().unwrap()["language"], "de");
+}
+
+#[tokio::test]
+async fn deleting_a_subscriber_is_audited() {
+    let app = spawn_app().await;
+    create_subscribers(&app).await;
+    let id = subscriber_id(&app, "carol").await;
+
+    let response = app.admin(Method::DELETE, &format!("/subscribers/{}", id)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+
+    let response = app.admin(Method::GET, &format!("/subscribers/{}", id)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    let audit = sqlx::query!("SELECT action, before, after FROM admin_audit_log")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(audit.action, "delete");
+    assert_eq!(audit.before.unwrap()["email"], "carol@gmail.com");
+    assert!(audit.after.is_none());
+}
+
+#[tokio::test]
+async fn operators_created_from_the_command_line_can_use_the_admin_api() {
+    let app = spawn_app().await;
+    let password = Secret::new("correct horse battery staple".to_string());
+
+    create_user(&app.connection_pool, "operator", password.clone()).await.unwrap();
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/subscribers", app.address))
+        .basic_auth("operator", Some(password.expose_secret()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(create_user(&app.connection_pool, "operator", password).await.is_err());
+}
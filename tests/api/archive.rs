@@ -0,0 +1,216 @@
+use reqwest::Method;
+use wiremock::matchers::{body_partial_json, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{newsletter_body, spawn_app, spawn_app_configured, TestApp};
+
+// tracking is on, to check none of it shows in the archive
+async fn archive_app() -> TestApp {
+    let app = spawn_app_configured(|c| c.newsletter.track_engagement = true).await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app
+}
+
+fn newsletter(title: &str) -> serde_json::Value {
+    newsletter_body(title, r#"<p><a href="https://example.com/article">Read it</a></p>"#, "Read it at https://example.com/article")
+}
+
+// writes the issue as a draft and publishes it, opted into the archive or not, then waits for it
+// to be sent; returns its archive slug
+async fn publish(app: &TestApp, title: &str, archive: bool) -> Option<String> {
+    let id = app.create_draft(&newsletter(title)).await;
+    app.issues(Method::POST, &format!("/{}/publish", id))
+        .json(&serde_json::json!({ "archive": archive }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.wait_for_issue_status(&id, "sent").await["archive_slug"].as_str().map(str::to_string)
+}
+
+async fn get(app: &TestApp, path: &str) -> reqwest::Response {
+    reqwest::get(format!("{}{}", app.address, path)).await.unwrap()
+}
+
+#[tokio::test]
+async fn only_issues_published_to_the_archive_are_listed() {
+    let app = archive_app().await;
+    let slug = publish(&app, "Public <news>", true).await.unwrap();
+    assert_eq!(publish(&app, "Members only", false).await, None);
+
+    let response = get(&app, "/archive").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "text/html; charset=utf-8");
+    let page = response.text().await.unwrap();
+    assert!(page.contains(&format!(r#"<a href="/archive/{}">Public &lt;news&gt;</a>"#, slug)));
+    assert!(slug.starts_with("public-news-"));
+    assert!(!page.contains("Members only"));
+}
+
+#[tokio::test]
+async fn archived_issues_have_no_tracking_or_unsubscribe_links() {
+    let app = archive_app().await;
+    let slug = publish(&app, "Public news", true).await.unwrap();
+    // what carol got was tracked
+    let requests = app.email_server.received_requests().await.unwrap();
+    let sent: serde_json::Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert!(sent["HtmlContent"].as_str().unwrap().contains("/t/c/"));
+
+    let response = get(&app, &format!("/archive/{}", slug)).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Security-Policy"].to_str().unwrap().starts_with("default-src 'none'"));
+    let page = response.text().await.unwrap();
+    assert!(page.contains("<title>Public news</title>"));
+    assert!(page.contains(r#"<a href="https://example.com/article">Read it</a>"#));
+    assert!(!page.contains("/t/c/"));
+    assert!(!page.contains("/t/o/"));
+    assert!(!page.contains("unsubscribe"));
+    assert_eq!(get(&app, "/archive/no-such-issue").await.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn unpublished_and_unsent_issues_are_not_archived() {
+    let app = archive_app().await;
+    let draft: serde_json::Value = app
+        .issues(Method::POST, "")
+        .json(&newsletter("Draft news"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let id = draft["id"].as_str().unwrap();
+    assert!(draft["archive_slug"].is_null());
+
+    // scheduled for later, not sent yet
+    let published: serde_json::Value = app
+        .issues(Method::POST, &format!("/{}/publish", id))
+        .json(&serde_json::json!({ "send_at": "2999-01-01T09:00:00Z", "archive": true }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let slug = published["archive_slug"].as_str().unwrap();
+
+    assert_eq!(get(&app, &format!("/archive/{}", slug)).await.status().as_u16(), 404);
+    assert!(!get(&app, "/archive").await.text().await.unwrap().contains("Draft news"));
+}
+
+#[tokio::test]
+async fn newsletters_published_in_one_request_are_never_archived() {
+    let app = archive_app().await;
+    let mut body = newsletter("Public news");
+    body["archive"] = serde_json::json!(true);
+
+    app.post_newsletters(&body).await.error_for_status().unwrap();
+
+    let archived = sqlx::query!("SELECT archive_slug FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .archive_slug;
+    assert_eq!(archived, None);
+    assert!(!get(&app, "/archive").await.text().await.unwrap().contains("Public news"));
+}
+
+#[tokio::test]
+async fn the_feed_lists_archived_issues() {
+    let app = archive_app().await;
+    let slug = publish(&app, "Public & news", true).await.unwrap();
+
+    let response = get(&app, "/feed.xml").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "application/atom+xml; charset=utf-8");
+    let feed = response.text().await.unwrap();
+    assert!(feed.contains(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#));
+    assert!(feed.contains("<title>Public &amp; news</title>"));
+    assert!(feed.contains(&format!("/archive/{}\"/>", slug)));
+    assert!(feed.contains("&lt;a href=&quot;https://example.com/article&quot;&gt;"));
+    assert!(!feed.contains("/t/c/"));
+}
+
+#[tokio::test]
+async fn resending_to_failed_recipients_keeps_the_issue_archived_under_its_first_date() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    app.create_confirmed_subscription("name=dave&email=dave%40gmail.com").await;
+    // dave's provider is down for the first attempt only
+    Mock::given(path("/email"))
+        .and(body_partial_json(serde_json::json!({ "To": "dave@gmail.com" })))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let id = app.create_draft(&newsletter("Public news")).await;
+    app.issues(Method::POST, &format!("/{}/publish", id))
+        .json(&serde_json::json!({ "archive": true }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let slug = app.wait_for_issue_status(&id, "failed").await["archive_slug"].as_str().unwrap().to_string();
+    // carol got it, so it is out and archived
+    assert_eq!(get(&app, &format!("/archive/{}", slug)).await.status().as_u16(), 200);
+    let first_sent = sqlx::query!("SELECT published_at, finished_at FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+
+    app.admin(Method::POST, &format!("/issues/{}/resend-failed", id)).send().await.unwrap().error_for_status().unwrap();
+    app.wait_for_issue_status(&id, "sent").await;
+
+    let resent = sqlx::query!("SELECT published_at, finished_at FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(resent.published_at, first_sent.published_at);
+    assert!(resent.finished_at > first_sent.finished_at);
+    let page = get(&app, &format!("/archive/{}", slug)).await.text().await.unwrap();
+    let published_at = first_sent.published_at.unwrap().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    assert!(page.contains(&format!(r#"<time datetime="{}">"#, published_at)));
+    assert!(get(&app, "/archive").await.text().await.unwrap().contains("Public news"));
+}
+
+#[tokio::test]
+async fn pages_are_revalidated_with_etags_and_dates() {
+    let app = archive_app().await;
+    publish(&app, "Public news", true).await;
+    let first = get(&app, "/archive").await;
+    let etag = first.headers()["ETag"].to_str().unwrap().to_string();
+    let last_modified = first.headers()["Last-Modified"].to_str().unwrap().to_string();
+    assert!(first.headers()["Cache-Control"].to_str().unwrap().starts_with("public"));
+    let client = reqwest::Client::new();
+    let url = format!("{}/archive", app.address);
+
+    let by_etag = client.get(&url).header("If-None-Match", &etag).send().await.unwrap();
+    let by_date = client.get(&url).header("If-Modified-Since", &last_modified).send().await.unwrap();
+    let stale = client.get(&url).header("If-None-Match", "\"something-else\"").send().await.unwrap();
+
+    assert_eq!(by_etag.status().as_u16(), 304);
+    assert_eq!(by_etag.headers()["ETag"], etag.as_str());
+    assert_eq!(by_date.status().as_u16(), 304);
+    assert_eq!(stale.status().as_u16(), 200);
+
+    // a new issue changes the archive
+    publish(&app, "More news", true).await;
+    let changed = client.get(&url).header("If-None-Match", &etag).send().await.unwrap();
+    assert_eq!(changed.status().as_u16(), 200);
+    assert_ne!(changed.headers()["ETag"], etag.as_str());
+}
@@ -0,0 +1,264 @@
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{spawn_app, TestApp};
+
+async fn subscriber_id(app: &TestApp, email: &str) -> uuid::Uuid {
+    sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .id
+}
+
+async fn consent_records(app: &TestApp, id: uuid::Uuid) -> Vec<serde_json::Value> {
+    let response = app.admin(Method::GET, &format!("/subscribers/{}/consent", id)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["records"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn subscribing_and_confirming_are_recorded_with_their_origin() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("User-Agent", "signup-test/1.0")
+        .form(&[
+            ("name", "le guin"),
+            ("email", "ursula_le_guin@gmail.com"),
+            ("source", "footer-form"),
+            ("policy_version", "2023-09-01"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let link = app.get_confirmation_links(email_request).html;
+    let client = reqwest::Client::builder().user_agent("mail-client/2.0").build().unwrap();
+    client.get(link.clone()).send().await.unwrap().error_for_status().unwrap();
+    // a second click doesn't confirm again
+    client.get(link).send().await.unwrap().error_for_status().unwrap();
+
+    let records = consent_records(&app, subscriber_id(&app, "ursula_le_guin@gmail.com").await).await;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["event"], "subscribe");
+    assert_eq!(records[0]["list"], "newsletter");
+    assert_eq!(records[0]["source"], "footer-form");
+    assert_eq!(records[0]["policy_version"], "2023-09-01");
+    assert_eq!(records[0]["ip"], "127.0.0.1");
+    assert_eq!(records[0]["user_agent"], "signup-test/1.0");
+    assert_eq!(records[1]["event"], "confirm");
+    assert_eq!(records[1]["list"], "newsletter");
+    assert_eq!(records[1]["user_agent"], "mail-client/2.0");
+    assert!(records[1]["occurred_at"].as_str().unwrap() >= records[0]["occurred_at"].as_str().unwrap());
+}
+
+#[tokio::test]
+async fn the_configured_policy_version_is_recorded_by_default() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+
+    let records = consent_records(&app, subscriber_id(&app, "carol@gmail.com").await).await;
+
+    assert_eq!(records[0]["policy_version"], "2023-10-01");
+    assert!(records[0]["source"].is_null());
+}
+
+#[tokio::test]
+async fn forwarded_for_headers_from_untrusted_peers_are_ignored() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("X-Forwarded-For", "203.0.113.7")
+        .form(&[("name", "carol"), ("email", "carol@gmail.com")])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let records = consent_records(&app, subscriber_id(&app, "carol@gmail.com").await).await;
+    assert_eq!(records[0]["ip"], "127.0.0.1");
+}
+
+#[tokio::test]
+async fn overlong_consent_fields_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "carol",
+            "email": "carol@gmail.com",
+            "source": "x".repeat(101),
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "source");
+    assert_eq!(body["errors"][0]["code"], "too_long");
+}
+
+#[tokio::test]
+async fn consent_records_cannot_be_changed() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+
+    let update = sqlx::query!("UPDATE consent_records SET policy_version = 'forged'")
+        .execute(&app.connection_pool)
+        .await;
+    let delete = sqlx::query!("DELETE FROM consent_records").execute(&app.connection_pool).await;
+
+    assert!(update.is_err());
+    assert!(delete.is_err());
+}
+
+#[tokio::test]
+async fn imports_are_recorded_as_the_operators_consent() {
+    let app = spawn_app().await;
+    app.import_subscribers("prior_consent=true", "email,name,status\ncarol@gmail.com,carol,confirmed\n")
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let records = consent_records(&app, subscriber_id(&app, "carol@gmail.com").await).await;
+
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["event"], "import");
+    assert_eq!(records[0]["list"], "newsletter");
+    assert!(records[0]["source"].as_str().unwrap().starts_with("import:"));
+    assert_eq!(records[0]["actor_id"], app.test_user.user_id.to_string());
+}
+
+#[tokio::test]
+async fn subscribers_imported_as_pending_have_not_consented_yet() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.import_subscribers("", "email,name\ncarol@gmail.com,carol\n")
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let records = consent_records(&app, subscriber_id(&app, "carol@gmail.com").await).await;
+
+    assert!(records.is_empty());
+}
+
+#[tokio::test]
+async fn deleting_a_subscriber_keeps_the_consent_records_until_the_address_is_erased() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    let id = subscriber_id(&app, "carol@gmail.com").await;
+    app.admin(Method::DELETE, &format!("/subscribers/{}", id))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let count = || async {
+        sqlx::query!(r#"SELECT count(*) AS "count!" FROM consent_records WHERE subscription_id = $1"#, id)
+            .fetch_one(&app.connection_pool)
+            .await
+            .unwrap()
+            .count
+    };
+    assert_eq!(count().await, 2);
+    let report: serde_json::Value = app
+        .admin(Method::POST, "/privacy/access")
+        .json(&serde_json::json!({ "email": "carol@gmail.com" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["consent"].as_array().unwrap().len(), 2);
+
+    app.admin(Method::POST, "/privacy/erasure")
+        .json(&serde_json::json!({ "email": "carol@gmail.com" }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(count().await, 0);
+}
+
+#[tokio::test]
+async fn unsubscribing_is_recorded_once() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(&serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "plain": "Newsletter content", "html": "<p>Newsletter content</p>" },
+        "lists": ["newsletter"],
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    let requests = app.email_server.received_requests().await.unwrap();
+    let link = app.get_confirmation_links(requests.last().unwrap()).html;
+
+    reqwest::get(link.clone()).await.unwrap().error_for_status().unwrap();
+    reqwest::get(link).await.unwrap().error_for_status().unwrap();
+
+    let records = consent_records(&app, subscriber_id(&app, "carol@gmail.com").await).await;
+    let events: Vec<_> = records.iter().map(|e| e["event"].as_str().unwrap()).collect();
+    assert_eq!(events, ["subscribe", "confirm", "unsubscribe"]);
+    assert_eq!(records[2]["list"], "newsletter");
+}
+
+#[tokio::test]
+async fn consent_records_require_authentication_and_an_existing_subscriber() {
+    let app = spawn_app().await;
+    let path = format!("/subscribers/{}/consent", uuid::Uuid::new_v4());
+
+    let anonymous = reqwest::get(format!("{}/admin{}", app.address, path)).await.unwrap();
+    let missing = app.admin(Method::GET, &path).send().await.unwrap();
+
+    assert_eq!(anonymous.status().as_u16(), 401);
+    assert_eq!(missing.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_access_report_includes_the_consent_records() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+
+    let report: serde_json::Value = app
+        .admin(Method::POST, "/privacy/access")
+        .json(&serde_json::json!({ "email": "carol@gmail.com" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let events: Vec<_> = report["consent"].as_array().unwrap().iter().map(|e| e["event"].as_str().unwrap()).collect();
+    assert_eq!(events, ["subscribe", "confirm"]);
+}
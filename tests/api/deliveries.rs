@@ -0,0 +1,185 @@
+use reqwest::Method;
+use secrecy::Secret;
+use uuid::Uuid;
+use wiremock::matchers::{body_partial_json, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{newsletter, spawn_app, spawn_app_configured, TestApp};
+
+// the provider refuses every email to `email`
+async fn refuse_emails_to(app: &TestApp, email: &str) {
+    Mock::given(path("/email"))
+        .and(body_partial_json(serde_json::json!({ "To": email })))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn accept_emails(app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+// publishes the newsletter and returns its issue id
+async fn publish(app: &TestApp) -> String {
+    app.post_newsletters(&newsletter()).await;
+    app.latest_issue_id().await
+}
+
+async fn report(app: &TestApp, id: &str, query: &str) -> serde_json::Value {
+    let response = app.admin(Method::GET, &format!("/issues/{}/report{}", id, query)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    response.json().await.unwrap()
+}
+
+async fn newsletters_sent_to(app: &TestApp, email: &str) -> usize {
+    app.recipients_of("Newsletter title").await.iter().filter(|to| *to == email).count()
+}
+
+#[tokio::test]
+async fn a_failing_recipient_doesnt_stop_the_others() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    app.create_confirmed_subscription("name=dave&email=dave%40gmail.com").await;
+    app.create_confirmed_subscription("name=erin&email=erin%40gmail.com").await;
+    refuse_emails_to(&app, "carol@gmail.com").await;
+    refuse_emails_to(&app, "dave@gmail.com").await;
+    accept_emails(&app).await;
+
+    let id = publish(&app).await;
+
+    assert_eq!(newsletters_sent_to(&app, "erin@gmail.com").await, 1);
+    let report = report(&app, &id, "").await;
+    assert_eq!(report["status"], "failed");
+    assert_eq!(report["counts"], serde_json::json!({ "total": 3, "queued": 0, "sent": 1, "failed": 2, "bounced": 0 }));
+    let failures = report["failures"].as_array().unwrap();
+    assert_eq!(failures.len(), 2);
+    assert!(failures.iter().all(|f| f["status"] == "failed" && f["attempts"] == 1));
+    assert!(failures[0]["error"].as_str().unwrap().contains("Failed to send email"));
+    assert!(app.issue(&id).await["last_error"].as_str().unwrap().starts_with("2 of 3 recipients could not be sent to"));
+}
+
+#[tokio::test]
+async fn failures_are_reported_a_page_at_a_time() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    app.create_confirmed_subscription("name=dave&email=dave%40gmail.com").await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    let id = publish(&app).await;
+
+    let first = report(&app, &id, "?limit=1").await;
+    let cursor = first["next_cursor"].as_str().unwrap();
+    let second = report(&app, &id, &format!("?limit=1&cursor={}", cursor)).await;
+    let invalid = app.admin(Method::GET, &format!("/issues/{}/report?cursor=garbage", id)).send().await.unwrap();
+
+    let mut emails = vec![
+        first["failures"][0]["email"].as_str().unwrap(),
+        second["failures"][0]["email"].as_str().unwrap(),
+    ];
+    emails.sort();
+    assert_eq!(emails, ["carol@gmail.com", "dave@gmail.com"]);
+    assert!(second["next_cursor"].is_null());
+    assert_eq!(invalid.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn resending_only_retries_the_failed_recipients() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    app.create_confirmed_subscription("name=dave&email=dave%40gmail.com").await;
+    // carol's provider is down for the first attempt only
+    Mock::given(path("/email"))
+        .and(body_partial_json(serde_json::json!({ "To": "carol@gmail.com" })))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    accept_emails(&app).await;
+    let id = publish(&app).await;
+
+    let response = app.admin(Method::POST, &format!("/issues/{}/resend-failed", id)).send().await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["requeued"], 1);
+    app.wait_for_issue_status(&id, "sent").await;
+    let report = report(&app, &id, "").await;
+    assert_eq!(report["status"], "sent");
+    assert_eq!(report["counts"]["sent"], 2);
+    assert_eq!(report["counts"]["failed"], 0);
+    // one refused, one delivered
+    assert_eq!(newsletters_sent_to(&app, "carol@gmail.com").await, 2);
+    assert_eq!(newsletters_sent_to(&app, "dave@gmail.com").await, 1);
+    let again = app.admin(Method::POST, &format!("/issues/{}/resend-failed", id)).send().await.unwrap();
+    let body: serde_json::Value = again.json().await.unwrap();
+    assert_eq!(body["requeued"], 0);
+}
+
+#[tokio::test]
+async fn only_finished_issues_can_be_resent() {
+    let app = spawn_app().await;
+    let draft = app.create_draft(&newsletter()).await;
+
+    let unfinished = app.admin(Method::POST, &format!("/issues/{}/resend-failed", draft)).send().await.unwrap();
+    let missing = app.admin(Method::POST, &format!("/issues/{}/resend-failed", Uuid::new_v4())).send().await.unwrap();
+    let anonymous = reqwest::get(format!("{}/admin/issues/{}/report", app.address, Uuid::new_v4())).await.unwrap();
+
+    assert_eq!(unfinished.status().as_u16(), 409);
+    assert_eq!(missing.status().as_u16(), 404);
+    assert_eq!(anonymous.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn bounces_reported_by_the_provider_are_recorded() {
+    let app = spawn_app_configured(|c| c.email_client.webhook_token = Secret::new("bounce-token".to_string())).await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "MessageID": "msg-carol" })))
+        .mount(&app.email_server)
+        .await;
+    let id = publish(&app).await;
+    let bounce = serde_json::json!({
+        "RecordType": "Bounce",
+        "MessageID": "msg-carol",
+        "Type": "HardBounce",
+        "Description": "The server was unable to deliver your message",
+        "Email": "carol@gmail.com",
+    });
+    let webhook = |token: &str| {
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/bounces", app.address))
+            .header("X-Webhook-Token", token)
+    };
+
+    let forged = webhook("guess").json(&bounce).send().await.unwrap();
+    let unknown = webhook("bounce-token")
+        .json(&serde_json::json!({ "MessageID": "msg-confirmation", "Type": "HardBounce" }))
+        .send()
+        .await
+        .unwrap();
+    let genuine = webhook("bounce-token").json(&bounce).send().await.unwrap();
+
+    assert_eq!(forged.status().as_u16(), 401);
+    assert_eq!(unknown.status().as_u16(), 200);
+    assert_eq!(genuine.status().as_u16(), 200);
+    let report = report(&app, &id, "").await;
+    assert_eq!(report["counts"]["sent"], 0);
+    assert_eq!(report["counts"]["bounced"], 1);
+    assert_eq!(report["failures"][0]["status"], "bounced");
+    assert_eq!(report["failures"][0]["error"], "HardBounce: The server was unable to deliver your message");
+    // bounced addresses aren't retried
+    let resend: serde_json::Value = app
+        .admin(Method::POST, &format!("/issues/{}/resend-failed", id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(resend["requeued"], 0);
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use reqwest::Method;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{newsletter_body, spawn_app, spawn_app_configured};
+
+async fn json(request: reqwest::RequestBuilder) -> (u16, serde_json::Value) {
+    let response = request.send().await.unwrap();
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap_or_default())
+}
+
+#[tokio::test]
+async fn issues_require_an_admin() {
+    let app = spawn_app().await;
+
+    let list = reqwest::get(format!("{}/newsletter/issues", app.address)).await.unwrap();
+    let create = reqwest::Client::new()
+        .post(format!("{}/newsletter/issues", app.address))
+        .json(&newsletter_body("Title", "<p>Content</p>", "Newsletter content"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(list.status().as_u16(), 401);
+    assert_eq!(create.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn drafts_keep_their_revision_history_and_are_not_sent() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let id = app.create_draft(&newsletter_body("First title", "<p>First</p>", "Newsletter content")).await;
+
+    let (status, edited) = json(app.issues(Method::PUT, &format!("/{}", id)).json(&newsletter_body("Second title", "<p>Second</p>", "Newsletter content"))).await;
+
+    assert_eq!(status, 200);
+    assert_eq!(edited["status"], "draft");
+    assert_eq!(edited["revision"], 2);
+    assert_eq!(edited["title"], "Second title");
+    assert_eq!(edited["created_by"], app.test_user.user_id.to_string());
+    let (_, history) = json(app.issues(Method::GET, &format!("/{}/revisions", id))).await;
+    let titles: Vec<_> = history["revisions"].as_array().unwrap().iter().map(|r| r["title"].as_str().unwrap()).collect();
+    assert_eq!(titles, ["First title", "Second title"]);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+}
+
+#[tokio::test]
+async fn invalid_drafts_are_rejected() {
+    let app = spawn_app().await;
+
+    let mut unknown_list = newsletter_body("Title", "<p>Content</p>", "Newsletter content");
+    unknown_list["lists"] = serde_json::json!(["gossip"]);
+    let (empty_title, _) = json(app.issues(Method::POST, "").json(&newsletter_body(" ", "<p>Content</p>", "Newsletter content"))).await;
+    let (unknown, problem) = json(app.issues(Method::POST, "").json(&unknown_list)).await;
+
+    assert_eq!(empty_title, 400);
+    assert_eq!(unknown, 400);
+    assert_eq!(problem["errors"][0]["code"], "unknown_list");
+}
+
+#[tokio::test]
+async fn previews_render_the_email_of_any_revision() {
+    let app = spawn_app().await;
+    let id = app.create_draft(&newsletter_body("First title", "<p>First</p>", "Newsletter content")).await;
+    app.issues(Method::PUT, &format!("/{}", id))
+        .json(&newsletter_body("Second title", "<p>Second</p>", "Newsletter content"))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let (status, latest) = json(app.issues(Method::GET, &format!("/{}/preview", id))).await;
+    let (_, first) = json(app.issues(Method::GET, &format!("/{}/preview?revision=1", id))).await;
+    let (missing, _) = json(app.issues(Method::GET, &format!("/{}/preview?revision=9", id))).await;
+
+    assert_eq!(status, 200);
+    assert_eq!(latest["revision"], 2);
+    assert_eq!(latest["subject"], "Second title");
+    assert!(latest["html"].as_str().unwrap().starts_with("<p>Second</p>"));
+    assert!(latest["html"].as_str().unwrap().contains("Unsubscribe"));
+    assert!(latest["plain"].as_str().unwrap().contains("Unsubscribe: "));
+    assert_eq!(first["subject"], "First title");
+    assert_eq!(missing, 404);
+}
+
+#[tokio::test]
+async fn test_sends_only_go_to_the_internal_addresses_given() {
+    let app = spawn_app_configured(|c| c.newsletter.internal_domains = vec!["example.com".into()]).await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let id = app.create_draft(&newsletter_body("Newsletter title", "<p>Content</p>", "Newsletter content")).await;
+
+    let (rejected, problem) = json(
+        app.issues(Method::POST, &format!("/{}/test", id))
+            .json(&serde_json::json!({ "recipients": ["editor@example.com", "carol@gmail.com"] })),
+    )
+    .await;
+    assert_eq!(rejected, 400);
+    assert_eq!(problem["errors"][0]["code"], "not_internal");
+
+    let (status, outcome) = json(
+        app.issues(Method::POST, &format!("/{}/test", id))
+            .json(&serde_json::json!({ "recipients": ["editor@example.com", "Reviewer@Example.com"] })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(outcome["sent"], 2);
+    assert_eq!(app.recipients_of("[Test] Newsletter title").await, ["editor@example.com", "Reviewer@example.com"]);
+    assert!(app.recipients_of("Newsletter title").await.is_empty());
+    let (_, issue) = json(app.issues(Method::GET, &format!("/{}", id))).await;
+    assert_eq!(issue["status"], "draft");
+}
+
+#[tokio::test]
+async fn published_drafts_are_delivered_and_can_no_longer_be_edited() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let id = app.create_draft(&newsletter_body("Newsletter title", "<p>Content</p>", "Newsletter content")).await;
+
+    let (status, published) = json(app.issues(Method::POST, &format!("/{}/publish", id)).json(&serde_json::json!({}))).await;
+
+    assert_eq!(status, 202);
+    assert_eq!(published["status"], "scheduled");
+    app.wait_for_issue_status(&id, "sent").await;
+    assert_eq!(app.recipients_of("Newsletter title").await, ["carol@gmail.com"]);
+    let (edit, _) = json(app.issues(Method::PUT, &format!("/{}", id)).json(&newsletter_body("Too late", "<p>Content</p>", "Newsletter content"))).await;
+    let (again, _) = json(app.issues(Method::POST, &format!("/{}/publish", id)).json(&serde_json::json!({}))).await;
+    assert_eq!(edit, 409);
+    assert_eq!(again, 409);
+}
+
+#[tokio::test]
+async fn publishing_waits_for_a_second_admin_when_approval_is_required() {
+    let app = spawn_app_configured(|c| c.newsletter.require_approval = true).await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let reviewer = app.add_admin().await;
+    let id = app.create_draft(&newsletter_body("Newsletter title", "<p>Content</p>", "Newsletter content")).await;
+
+    let (_, published) = json(app.issues(Method::POST, &format!("/{}/publish", id)).json(&serde_json::json!({}))).await;
+    assert_eq!(published["status"], "pending_approval");
+    let (self_approval, _) = json(app.issues(Method::POST, &format!("/{}/approve", id))).await;
+    assert_eq!(self_approval, 403);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(app.recipients_of("Newsletter title").await.is_empty());
+
+    let (status, approved) =
+        json(app.admin_request(&reviewer, Method::POST, &format!("/newsletter/issues/{}/approve", id))).await;
+
+    assert_eq!(status, 200);
+    assert_eq!(approved["approved_by"], reviewer.user_id.to_string());
+    assert_eq!(approved["publish_requested_by"], app.test_user.user_id.to_string());
+    app.wait_for_issue_status(&id, "sent").await;
+    assert_eq!(app.recipients_of("Newsletter title").await, ["carol@gmail.com"]);
+}
+
+#[tokio::test]
+async fn newsletters_cannot_skip_a_required_approval() {
+    let app = spawn_app_configured(|c| c.newsletter.require_approval = true).await;
+
+    let response = app.post_newsletters(&newsletter_body("Newsletter title", "<p>Content</p>", "Newsletter content")).await;
+
+    assert_eq!(response.status().as_u16(), 403);
+}
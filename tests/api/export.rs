@@ -0,0 +1,93 @@
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{spawn_app, TestApp};
+
+async fn export(app: &TestApp, query: &str) -> reqwest::Response {
+    app.admin(Method::GET, &format!("/subscribers/export?{}", query)).send().await.unwrap()
+}
+
+#[tokio::test]
+async fn exporting_requires_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/admin/subscribers/export", app.address)).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn csv_exports_have_the_selected_columns_oldest_first() {
+    let app = spawn_app().await;
+    let csv = "email,name,status\nanna@gmail.com,\"Doe, Anna\",confirmed\nbernd@gmail.com,bernd,confirmed\n";
+    app.import_subscribers("prior_consent=true", csv).await.error_for_status().unwrap();
+    sqlx::query!("UPDATE subscriptions SET subscribed_at = subscribed_at - interval '1 day' WHERE name = 'bernd'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    let response = export(&app, "format=csv&columns=name,email").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "text/csv; charset=utf-8");
+    assert!(response.headers()["Content-Disposition"].to_str().unwrap().contains("subscribers.csv"));
+    assert_eq!(
+        response.text().await.unwrap(),
+        "name,email\r\nbernd,bernd@gmail.com\r\n\"Doe, Anna\",anna@gmail.com\r\n"
+    );
+}
+
+#[tokio::test]
+async fn ndjson_exports_can_be_filtered_by_status() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=anna&email=anna%40gmail.com").await.error_for_status().unwrap();
+
+    let response = export(&app, "format=ndjson&status=confirmed&columns=email,status,attributes").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "application/x-ndjson");
+    let body = response.text().await.unwrap();
+    let lines: Vec<serde_json::Value> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(
+        lines,
+        [serde_json::json!({ "email": "carol@gmail.com", "status": "confirmed", "attributes": {} })]
+    );
+}
+
+#[tokio::test]
+async fn exports_larger_than_one_fetch_are_complete() {
+    let app = spawn_app().await;
+    let csv: String = std::iter::once("email,name,status\n".to_string())
+        .chain((0..2500).map(|i| format!("user{}@gmail.com,user {},confirmed\n", i, i)))
+        .collect();
+    app.import_subscribers("prior_consent=true", &csv).await.error_for_status().unwrap();
+
+    let response = export(&app, "columns=email").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response.text().await.unwrap();
+    let mut emails: Vec<&str> = body.lines().skip(1).collect();
+    assert_eq!(emails.len(), 2500);
+    emails.sort_unstable();
+    emails.dedup();
+    assert_eq!(emails.len(), 2500);
+}
+
+#[tokio::test]
+async fn unknown_columns_and_formats_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = export(&app, "columns=email,password").await;
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["errors"][0]["code"], "unknown_column");
+
+    let response = export(&app, "format=xml").await;
+    assert_eq!(response.status().as_u16(), 400);
+}
@@ -1,3 +1,5 @@
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
 use crate::helpers::spawn_app;
 
 #[tokio::test]
@@ -15,3 +17,106 @@ async fn health_check() {
 }
 
 
+
+#[tokio::test]
+async fn readyz_reports_every_required_dependency_up() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!("{}/readyz", app.address))
+        .await
+        .expect("Failed to get");
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ready");
+    let checks = body["checks"].as_array().unwrap();
+    for component in ["database", "migrations"] {
+        let check = checks.iter().find(|c| c["component"] == component).unwrap();
+        assert_eq!(check["status"], "up");
+        assert!(check["latency_ms"].is_u64());
+    }
+}
+
+#[tokio::test]
+async fn readyz_returns_503_when_migrations_are_behind() {
+    let app = spawn_app().await;
+    sqlx::query("DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(&format!("{}/readyz", app.address))
+        .await
+        .expect("Failed to get");
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let migrations = body["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|c| c["component"] == "migrations")
+        .unwrap()
+        .clone();
+    assert_eq!(migrations["status"], "down");
+    // the reason is logged, not exposed
+    assert!(migrations.get("error").is_none());
+}
+
+#[tokio::test]
+async fn metrics_are_exposed_in_the_prometheus_text_format() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    let response = reqwest::get(&format!("{}/metrics", app.address))
+        .await
+        .expect("Failed to get");
+
+    assert!(response.status().is_success());
+    let body = response.text().await.unwrap();
+    assert!(body.contains(r#"http_requests_total{method="POST",route="/subscriptions",status="200"}"#));
+    assert!(body.contains("subscriptions_created_total"));
+    assert!(body.contains(r#"emails_sent_total{outcome="success"}"#));
+    assert!(body.contains("db_pool_connections"));
+    assert!(body.contains("pending_confirmation_emails"));
+    // metrics must never carry subscriber data
+    assert!(!body.contains("ursula_le_guin"));
+}
+
+#[tokio::test]
+async fn pending_confirmation_emails_are_the_queued_ones() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    // sent straight away, the subscriber still has to click
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    // queued for the background mailer
+    app.import_subscribers("", "email,name\ncarol@gmail.com,carol\ndave@gmail.com,dave\n")
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let body = reqwest::get(&format!("{}/metrics", app.address)).await.unwrap().text().await.unwrap();
+
+    assert!(body.lines().any(|line| line == "pending_confirmation_emails 2"));
+    assert!(body.lines().any(|line| line.starts_with("db_pool_acquire_duration_seconds_count ")));
+}
+
+#[tokio::test]
+async fn failed_requests_are_counted_with_their_status() {
+    let app = spawn_app().await;
+    let anonymous = reqwest::get(&format!("{}/admin/subscribers", app.address)).await.unwrap();
+    assert_eq!(anonymous.status().as_u16(), 401);
+
+    let body = reqwest::get(&format!("{}/metrics", app.address)).await.unwrap().text().await.unwrap();
+
+    assert!(body.contains(r#"http_requests_total{method="GET",route="/admin/subscribers",status="401"}"#));
+}
@@ -1,9 +1,14 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
 use secrecy::ExposeSecret;
+use sqlx::migrate::Migrator;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
 use wiremock::MockServer;
-use zero2rs::configuration::{self, DatabaseSettings};
+use zero2rs::configuration::{self, DatabaseSettings, Settings};
+use zero2rs::domain_verification::DomainVerification;
+use zero2rs::shutdown::{ShutdownOutcome, ShutdownTrigger};
 use zero2rs::startup::{get_connection_pool, Application};
 use zero2rs::telemetry::{get_subscriber, init_subscriber};
 
@@ -11,11 +16,49 @@ pub struct ConfirmationLinks {
     pub html: reqwest::Url,
     pub plain_text: reqwest::Url,
 }
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+    async fn store(&self, pool: &PgPool) {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        // cheap parameters, tests don't need a strong hash
+        let password_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(15000, 2, 1, None).unwrap(),
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash,
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
 pub struct TestApp {
     pub address: String,
     pub connection_pool: PgPool,
     pub port: u16,
     pub email_server: MockServer,
+    pub shutdown: ShutdownTrigger,
+    pub server: tokio::task::JoinHandle<Result<ShutdownOutcome, std::io::Error>>,
+    pub test_user: TestUser,
 }
 impl TestApp {
     pub async fn post_subscriptions(&self, body: &str) -> reqwest::Response {
@@ -29,6 +72,14 @@ impl TestApp {
             .await
             .expect("Failed to post")
     }
+    pub async fn post_subscriptions_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", self.address))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to post")
+    }
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body = serde_json::from_slice::<serde_json::Value>(&email_request.body).unwrap();
 
@@ -45,34 +96,182 @@ impl TestApp {
                 .expect("Failed to set port");
             confirmation_link
         };
-        let html_link = get_link(&body["HtmlContent"].as_str().unwrap());
-        let plain_link = get_link(&body["TextContent"].as_str().unwrap());
+        let html_link = get_link(body["HtmlContent"].as_str().unwrap());
+        let plain_link = get_link(body["TextContent"].as_str().unwrap());
         ConfirmationLinks {
             html: html_link,
             plain_text: plain_link,
         }
     }
-    // post_newsletters, para: &self, body: json
+    // the preference centre link emailed to `email`, assuming it is the last email sent
+    pub async fn preferences_link(&self, email: &str) -> reqwest::Url {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions/preferences/link", self.address))
+            .json(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to post");
+        assert_eq!(response.status().as_u16(), 200);
+        let requests = self.email_server.received_requests().await.unwrap();
+        self.get_confirmation_links(requests.last().unwrap()).html
+    }
+    pub async fn patch_preferences(&self, link: &reqwest::Url, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .patch(link.clone())
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to patch")
+    }
+    pub async fn create_list(&self, slug: &str, name: &str) {
+        self.admin(reqwest::Method::POST, "/lists")
+            .json(&serde_json::json!({ "slug": slug, "name": name }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .error_for_status()
+            .expect("Failed to create the list");
+    }
+    // subscribe with the form `body` and click the confirmation link
+    pub async fn create_confirmed_subscription(&self, body: &str) {
+        let _mock_guard = wiremock::Mock::given(wiremock::matchers::path("/email"))
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount_as_scoped(&self.email_server)
+            .await;
+        self.post_subscriptions(body).await.error_for_status().unwrap();
+        let requests = self.email_server.received_requests().await.unwrap();
+        let confirmation_links = self.get_confirmation_links(requests.last().unwrap());
+        reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+    }
+    // post_newsletters, para: &self, body: json, authenticated as the test user
     pub async fn post_newsletters(&self, body: &serde_json::Value) -> reqwest::Response {
-        let client = reqwest::Client::new();
-        let url = format!("{}/newsletters", self.address);
-        client.post(&url).json(&body).send().await.unwrap()
+        self.admin_request(&self.test_user, reqwest::Method::POST, "/newsletter")
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+    // the issue as `GET /newsletter/issues/{id}` returns it
+    pub async fn issue(&self, id: &str) -> serde_json::Value {
+        self.issues(reqwest::Method::GET, &format!("/{}", id))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+    // the newest issue, e.g. the one `post_newsletters` just published
+    pub async fn latest_issue_id(&self) -> String {
+        let listed: serde_json::Value =
+            self.issues(reqwest::Method::GET, "").send().await.unwrap().json().await.unwrap();
+        listed["issues"][0]["id"].as_str().unwrap().to_string()
+    }
+    // writes `body` as a draft and returns its id
+    pub async fn create_draft(&self, body: &serde_json::Value) -> String {
+        let response = self.issues(reqwest::Method::POST, "").json(body).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 201);
+        let issue: serde_json::Value = response.json().await.unwrap();
+        issue["id"].as_str().unwrap().to_string()
+    }
+    // the scheduler and deliveries run in the background, polls until the issue gets to `status`
+    pub async fn wait_for_issue_status(&self, id: &str, status: &str) -> serde_json::Value {
+        for _ in 0..100 {
+            let issue = self.issue(id).await;
+            if issue["status"] == status {
+                return issue;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        panic!("issue {} never became {}", id, status);
+    }
+    // who got an email with `subject`, once per email
+    pub async fn recipients_of(&self, subject: &str) -> Vec<String> {
+        self.email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|r| serde_json::from_slice::<serde_json::Value>(&r.body).ok())
+            .filter(|b| b["Subject"] == subject)
+            .map(|b| b["To"].as_str().unwrap().to_string())
+            .collect()
+    }
+    // a request to `/admin{path}` authenticated as the test user
+    pub fn admin(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.admin_request(&self.test_user, method, &format!("/admin{}", path))
+    }
+    // a request to `/newsletter/issues{path}` authenticated as the test user
+    pub fn issues(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.admin_request(&self.test_user, method, &format!("/newsletter/issues{}", path))
+    }
+    pub fn admin_request(&self, user: &TestUser, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        reqwest::Client::new()
+            .request(method, format!("{}{}", self.address, path))
+            .basic_auth(&user.username, Some(&user.password))
+    }
+    // another admin besides the test user
+    pub async fn add_admin(&self) -> TestUser {
+        let user = TestUser::generate();
+        user.store(&self.connection_pool).await;
+        user
+    }
+    pub async fn import_subscribers(&self, query: &str, csv: &str) -> reqwest::Response {
+        self.admin(reqwest::Method::POST, &format!("/subscribers/import?{}", query))
+            .header("Content-Type", "text/csv")
+            .body(csv.to_string())
+            .send()
+            .await
+            .unwrap()
+    }
+    pub async fn post_dry_run(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.admin_request(&self.test_user, reqwest::Method::POST, "/newsletter/dry-run")
+            .json(body)
+            .send()
+            .await
+            .unwrap()
     }
 
 }
+// a newsletter as `post_newsletters` and drafts take it
+pub fn newsletter_body(title: &str, html: &str, plain: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "content": { "html": html, "plain": plain },
+    })
+}
+pub fn newsletter() -> serde_json::Value {
+    newsletter_body("Newsletter title", "<p>Newsletter content</p>", "Newsletter content")
+}
+pub fn newsletter_for(lists: &[&str]) -> serde_json::Value {
+    let mut body = newsletter();
+    body["lists"] = serde_json::json!(lists);
+    body
+}
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "debug".to_string();
     let test_name = "test".to_string();
 
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(test_name, default_filter_level, std::io::stdout);
+        let subscriber = get_subscriber(test_name, default_filter_level, std::io::stdout, None);
         init_subscriber(subscriber);
     } else {
-        let subscriber = get_subscriber(test_name, default_filter_level, std::io::sink);
+        let subscriber = get_subscriber(test_name, default_filter_level, std::io::sink, None);
         init_subscriber(subscriber);
     }
 });
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with_domain_verification(DomainVerification(None)).await
+}
+pub async fn spawn_app_with_domain_verification(domain_verification: DomainVerification) -> TestApp {
+    spawn_app_with(domain_verification, |_| {}).await
+}
+// an app with `configure` applied to the test configuration
+pub async fn spawn_app_configured(configure: impl FnOnce(&mut Settings)) -> TestApp {
+    spawn_app_with(DomainVerification(None), configure).await
+}
+async fn spawn_app_with(domain_verification: DomainVerification, configure: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
     let email_server = MockServer::start().await;
@@ -82,26 +281,41 @@ pub async fn spawn_app() -> TestApp {
         c.database.database_name = Uuid::new_v4().to_string();
         c.application.port = 0;
         c.email_client.base_url = email_server.uri();
+        configure(&mut c);
         c
     };
 
     configure_database(&configuration.database).await;
-    let app = Application::build(configuration.clone())
+    let app = Application::build_with_domain_verification(configuration.clone(), domain_verification)
         .await
         .expect("Failed to build app");
     let address = format!("http://127.0.0.1:{}", app.port());
     let application_port = app.port();
-    let _ = tokio::spawn(app.run_until_stopped());
-    TestApp {
+    let shutdown = app.shutdown_trigger();
+    let server = tokio::spawn(app.run_until_stopped());
+    let test_app = TestApp {
         address,
         connection_pool: get_connection_pool(&configuration.database),
         port: application_port,
         email_server,
-    }
+        shutdown,
+        server,
+        test_user: TestUser::generate(),
+    };
+    test_app.test_user.store(&test_app.connection_pool).await;
+    test_app
 }
 async fn configure_database(config: &DatabaseSettings) -> PgPool {
+    let connection_pool = create_database(config).await;
+    sqlx::migrate!("./migrations")
+        .run(&connection_pool)
+        .await
+        .expect("Failed to migrate");
+    connection_pool
+}
+async fn create_database(config: &DatabaseSettings) -> PgPool {
     let mut connection =
-        PgConnection::connect(&config.connection_database_without_db().expose_secret())
+        PgConnection::connect(config.connection_database_without_db().expose_secret())
             .await
             .expect("Failed to connect to database");
     connection
@@ -109,12 +323,21 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
         .await
         .expect("Failed to create database");
 
-    let connection_pool = PgPool::connect(&config.connection_database().expose_secret())
-        .await
-        .expect("Failed to connect to database");
-    sqlx::migrate!("./migrations")
-        .run(&connection_pool)
+    PgPool::connect(config.connection_database().expose_secret())
         .await
-        .expect("Failed to migrate");
+        .expect("Failed to connect to database")
+}
+// a new database with the migrations before `version` applied, to seed rows as they were then;
+// `sqlx::migrate!` applies the rest
+pub async fn database_migrated_until(version: i64) -> PgPool {
+    let mut config = configuration::get_configuration().expect("failed to get configuration").database;
+    config.database_name = Uuid::new_v4().to_string();
+    let connection_pool = create_database(&config).await;
+    let all = sqlx::migrate!("./migrations");
+    let before = Migrator {
+        migrations: all.iter().filter(|m| m.version < version).cloned().collect::<Vec<_>>().into(),
+        ignore_missing: false,
+    };
+    before.run(&connection_pool).await.expect("Failed to migrate");
     connection_pool
 }
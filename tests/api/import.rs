@@ -0,0 +1,309 @@
+use std::time::Duration;
+
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{spawn_app, TestApp};
+
+async fn import(app: &TestApp, query: &str, csv: impl Into<reqwest::Body>) -> reqwest::Response {
+    app.admin(Method::POST, &format!("/subscribers/import?{}", query))
+        .header("Content-Type", "text/csv")
+        .body(csv)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn report(app: &TestApp, id: &str, query: &str) -> serde_json::Value {
+    let response = app
+        .admin(Method::GET, &format!("/subscribers/imports/{}?{}", id, query))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    response.json().await.unwrap()
+}
+
+// the mailer sends in the background, poll until it caught up
+async fn wait_for_emails(app: &TestApp, count: usize) -> Vec<wiremock::Request> {
+    for _ in 0..50 {
+        let requests = app.email_server.received_requests().await.unwrap();
+        if requests.len() >= count {
+            return requests;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("expected {} emails", count);
+}
+
+#[tokio::test]
+async fn importing_requires_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/subscribers/import", app.address))
+        .body("email,name\nanna@gmail.com,anna\n")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    let count = sqlx::query!("SELECT count(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(count, 0);
+}
+
+#[tokio::test]
+async fn the_report_lists_accepted_duplicate_and_rejected_rows() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let csv = "\
+Name,Email,Status
+anna,anna@gmail.com,
+carol,Carol@gmail.com,
+anna again,ANNA@gmail.com,
+bernd,bernd-at-gmail.com,
+dora,dora@gmail.com,confirmed
+";
+
+    let response = import(&app, "", csv).await;
+
+    assert_eq!(response.status().as_u16(), 201);
+    let location = response.headers()["Location"].to_str().unwrap().to_string();
+    let summary: serde_json::Value = response.json().await.unwrap();
+    let id = summary["id"].as_str().unwrap();
+    assert_eq!(location, format!("/admin/subscribers/imports/{}", id));
+    assert_eq!(summary["status"], "completed");
+    assert_eq!(summary["list"], "newsletter");
+    assert_eq!((summary["accepted"].as_i64(), summary["duplicates"].as_i64(), summary["rejected"].as_i64()), (Some(1), Some(2), Some(2)));
+
+    let all = report(&app, id, "").await;
+    let rows: Vec<_> = all["rows"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|row| (row["row_number"].as_i64().unwrap(), row["outcome"].as_str().unwrap(), row["reason"].as_str()))
+        .collect();
+    assert_eq!(
+        rows,
+        [
+            (1, "accepted", None),
+            (2, "duplicate", Some("already_subscribed")),
+            (3, "duplicate", Some("duplicate_in_file")),
+            (4, "rejected", Some("missing_at")),
+            (5, "rejected", Some("consent_not_asserted")),
+        ]
+    );
+    let rejected = report(&app, id, "outcome=rejected").await;
+    assert_eq!(rejected["rows"].as_array().unwrap().len(), 2);
+    assert_eq!(rejected["rows"][0]["email"], "bernd-at-gmail.com");
+    assert_eq!(rejected["rows"][0]["field"], "email");
+
+    // carol keeps her subscription
+    let carol = sqlx::query!("SELECT name, status FROM subscriptions WHERE email_canonical = 'carol@gmail.com'")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!((carol.name.as_str(), carol.status.as_str()), ("carol", "confirmed"));
+}
+
+#[tokio::test]
+async fn imported_pending_subscribers_get_a_confirmation_email() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = import(&app, "", "email,name\nanna@gmail.com,anna\n").await;
+    assert_eq!(response.status().as_u16(), 201);
+
+    let requests = wait_for_emails(&app, 1).await;
+    let confirmation_link = app.get_confirmation_links(&requests[0]).html;
+    reqwest::get(confirmation_link).await.unwrap().error_for_status().unwrap();
+    let status = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .status;
+    assert_eq!(status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribers_of_another_list_join_the_imported_list() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    app.create_list("engineering", "Engineering notes").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = import(&app, "list=engineering", "email,name\nCarol@gmail.com,someone else\n").await;
+
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!((summary["accepted"].as_i64(), summary["duplicates"].as_i64()), (Some(1), Some(0)));
+    let requests = wait_for_emails(&app, 2).await;
+    let confirmation_link = app.get_confirmation_links(&requests[1]).html;
+    reqwest::get(confirmation_link).await.unwrap().error_for_status().unwrap();
+    let memberships = sqlx::query!(
+        r#"
+        SELECT subscriptions.name, lists.slug, list_memberships.status
+        FROM list_memberships
+        JOIN subscriptions ON subscriptions.id = list_memberships.subscription_id
+        JOIN lists ON lists.id = list_memberships.list_id
+        ORDER BY lists.slug
+        "#
+    )
+    .fetch_all(&app.connection_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|m| (m.name, m.slug, m.status))
+    .collect::<Vec<_>>();
+    let membership = |list: &str| ("carol".to_string(), list.to_string(), "confirmed".to_string());
+    assert_eq!(memberships, [membership("engineering"), membership("newsletter")]);
+
+    let response = import(&app, "list=engineering", "email,name\ncarol@gmail.com,carol\n").await;
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!((summary["accepted"].as_i64(), summary["duplicates"].as_i64()), (Some(0), Some(1)));
+}
+
+#[tokio::test]
+async fn failed_confirmation_emails_are_retried_later_then_given_up_on() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let response = import(&app, "", "email,name\nanna@gmail.com,anna\n").await;
+    assert_eq!(response.status().as_u16(), 201);
+    wait_for_emails(&app, 1).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    // not tried again straight away
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 1);
+    let queued = sqlx::query!(
+        "SELECT send_pending, send_attempts, next_attempt_at > now() AS \"later!\" FROM subscriptions_token"
+    )
+    .fetch_one(&app.connection_pool)
+    .await
+    .unwrap();
+    assert!(queued.send_pending);
+    assert_eq!(queued.send_attempts, 1);
+    assert!(queued.later);
+
+    // the last attempt is due
+    sqlx::query!("UPDATE subscriptions_token SET send_attempts = 4, next_attempt_at = now()")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+    wait_for_emails(&app, 2).await;
+    for _ in 0..50 {
+        let queued = sqlx::query!("SELECT send_pending, send_attempts, send_failed_at FROM subscriptions_token")
+            .fetch_one(&app.connection_pool)
+            .await
+            .unwrap();
+        if !queued.send_pending {
+            assert_eq!(queued.send_attempts, 5);
+            assert!(queued.send_failed_at.is_some());
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the confirmation was not given up on");
+}
+
+#[tokio::test]
+async fn asserting_prior_consent_skips_double_opt_in() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let csv = "email,name,status,attributes\nanna@gmail.com,anna,confirmed,\"{\"\"plan\"\": \"\"pro\"\"}\"\n";
+
+    let response = import(&app, "prior_consent=true", csv).await;
+
+    assert_eq!(response.status().as_u16(), 201);
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(summary["prior_consent"], true);
+    let saved = sqlx::query!(
+        r#"
+        SELECT subscriptions.status AS "status!", attributes AS "attributes!", list_memberships.status AS "membership!"
+        FROM subscriptions JOIN list_memberships ON list_memberships.subscription_id = subscriptions.id
+        "#
+    )
+    .fetch_one(&app.connection_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    assert_eq!(saved.membership, "confirmed");
+    assert_eq!(saved.attributes, serde_json::json!({ "plan": "pro" }));
+    let queued = sqlx::query!("SELECT count(*) AS \"count!\" FROM subscriptions_token")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(queued, 0);
+}
+
+#[tokio::test]
+async fn large_files_are_imported_in_batches_and_reported_page_by_page() {
+    let app = spawn_app().await;
+    let csv: String = std::iter::once("email,name,status\n".to_string())
+        .chain((0..1200).map(|i| format!("user{}@gmail.com,user {},confirmed\n", i, i)))
+        .collect();
+
+    let response = import(&app, "prior_consent=true", csv).await;
+
+    assert_eq!(response.status().as_u16(), 201);
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(summary["accepted"], 1200);
+    let id = summary["id"].as_str().unwrap();
+    let first = report(&app, id, "limit=1000").await;
+    assert_eq!(first["rows"].as_array().unwrap().len(), 1000);
+    let second = report(&app, id, &format!("limit=1000&after={}", first["next_after"])).await;
+    assert_eq!(second["rows"].as_array().unwrap().len(), 200);
+    assert_eq!(second["rows"][199]["email"], "user1199@gmail.com");
+    assert_eq!(second["next_after"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn a_file_without_the_required_columns_is_rejected() {
+    let app = spawn_app().await;
+
+    for (csv, code) in [("", "empty_file"), ("email,full_name\nanna@gmail.com,anna\n", "missing_column")] {
+        let response = import(&app, "", csv).await;
+
+        assert_eq!(response.status().as_u16(), 400);
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["errors"][0]["field"], "file");
+        assert_eq!(problem["errors"][0]["code"], code);
+    }
+}
+
+#[tokio::test]
+async fn importing_into_an_unknown_list_is_rejected() {
+    let app = spawn_app().await;
+
+    let response = import(&app, "list=nope", "email,name\nanna@gmail.com,anna\n").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["errors"][0]["code"], "unknown_list");
+}
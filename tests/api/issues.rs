@@ -0,0 +1,281 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use reqwest::Method;
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{newsletter, spawn_app, TestApp};
+
+fn scheduled_newsletter(send_at: &str, timezone: Option<&str>) -> serde_json::Value {
+    let mut body = newsletter();
+    body["send_at"] = serde_json::json!(send_at);
+    body["timezone"] = serde_json::json!(timezone);
+    body
+}
+
+// an issue that is already due, as left behind by an instance that stopped while delivering it
+async fn insert_due_issue(app: &TestApp, id: Uuid, status: &str) {
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (id, title, html_content, plain_content, lists, status, send_at)
+        VALUES ($1, 'Newsletter title', '<p>Newsletter content</p>', 'Newsletter content', '{}', $2, now())
+        "#,
+        id,
+        status
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn scheduling_and_managing_issues_require_an_admin() {
+    let app = spawn_app().await;
+    let scheduled: serde_json::Value = app
+        .post_newsletters(&scheduled_newsletter("2999-01-01T09:00:00Z", None))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = scheduled["id"].as_str().unwrap();
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("{}/newsletter/issues/{}{}", app.address, id, path);
+
+    let requests = [
+        client.post(format!("{}/newsletter", app.address)).json(&scheduled_newsletter("2999-01-01T09:00:00Z", None)),
+        client.get(format!("{}/newsletter/issues", app.address)),
+        client.get(url("")),
+        client.patch(url("")).json(&serde_json::json!({ "send_at": "2999-02-01T09:00:00Z" })),
+        client.post(url("/cancel")),
+    ];
+
+    for request in requests {
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 401, "{}", response.url());
+    }
+    let issue = app.issue(id).await;
+    assert_eq!(issue["status"], "scheduled");
+    assert_eq!(issue["send_at"], "2999-01-01T09:00:00Z");
+    let count = sqlx::query!(r#"SELECT count(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(count, 1);
+}
+
+#[tokio::test]
+async fn scheduled_issues_are_delivered_once_due() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let send_at = (Utc::now() + chrono::Duration::seconds(2)).to_rfc3339();
+
+    let response = app.post_newsletters(&scheduled_newsletter(&send_at, None)).await;
+
+    assert_eq!(response.status().as_u16(), 202);
+    let location = response.headers()["Location"].to_str().unwrap().to_string();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "scheduled");
+    assert_eq!(location, format!("/newsletter/issues/{}", body["id"].as_str().unwrap()));
+    assert!(app.recipients_of("Newsletter title").await.is_empty());
+
+    let sent = app.wait_for_issue_status(body["id"].as_str().unwrap(), "sent").await;
+    assert!(sent["finished_at"].is_string());
+    assert_eq!(app.recipients_of("Newsletter title").await, ["carol@gmail.com"]);
+}
+
+#[tokio::test]
+async fn local_send_times_are_kept_in_their_timezone() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_newsletters(&scheduled_newsletter("2999-10-28T09:00", Some("Europe/Paris")))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["timezone"], "Europe/Paris");
+    assert_eq!(body["send_at"], "2999-10-28T08:00:00Z");
+    let listed: serde_json::Value = app
+        .issues(Method::GET, "?status=scheduled")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed["issues"][0]["id"], body["id"]);
+}
+
+#[tokio::test]
+async fn unusable_send_times_are_rejected() {
+    let app = spawn_app().await;
+    let cases = [
+        (scheduled_newsletter("2999-10-28T09:00", None), "missing_timezone"),
+        (scheduled_newsletter("2000-01-01T09:00:00Z", None), "in_the_past"),
+        (scheduled_newsletter("tomorrow", Some("Europe/Paris")), "invalid_datetime"),
+    ];
+
+    for (body, code) in cases {
+        let response = app.post_newsletters(&body).await;
+
+        assert_eq!(response.status().as_u16(), 400, "{}", code);
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["errors"][0]["code"], code);
+    }
+    let listed: serde_json::Value = app.issues(Method::GET, "").send().await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(listed["issues"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn scheduled_issues_can_be_rescheduled_and_cancelled() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let send_at = (Utc::now() + chrono::Duration::seconds(2)).to_rfc3339();
+    let body: serde_json::Value = app
+        .post_newsletters(&scheduled_newsletter(&send_at, None))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = body["id"].as_str().unwrap();
+
+    let rescheduled = app
+        .issues(Method::PATCH, &format!("/{}", id))
+        .json(&serde_json::json!({ "send_at": "2999-01-01T09:00", "timezone": "America/New_York" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(rescheduled.status().as_u16(), 200);
+    let rescheduled: serde_json::Value = rescheduled.json().await.unwrap();
+    assert_eq!(rescheduled["send_at"], "2999-01-01T14:00:00Z");
+
+    let cancelled = app
+        .issues(Method::POST, &format!("/{}/cancel", id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cancelled.status().as_u16(), 200);
+    assert_eq!(app.issue(id).await["status"], "cancelled");
+
+    // past scheduling, and for issues that don't exist
+    let again = app
+        .issues(Method::POST, &format!("/{}/cancel", id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(again.status().as_u16(), 409);
+    let missing = app
+        .issues(Method::POST, &format!("/{}/cancel", Uuid::new_v4()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(missing.status().as_u16(), 404);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+}
+
+#[tokio::test]
+async fn published_newsletters_are_recorded_as_sent_issues() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let mut body = scheduled_newsletter("", None);
+    body.as_object_mut().unwrap().remove("send_at");
+
+    let response = app.post_newsletters(&body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let listed: serde_json::Value = app
+        .issues(Method::GET, "?status=sent")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed["issues"].as_array().unwrap().len(), 1);
+    let reschedule = app
+        .issues(Method::PATCH, &format!("/{}", listed["issues"][0]["id"].as_str().unwrap()))
+        .json(&serde_json::json!({ "send_at": "2999-01-01T09:00:00Z" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reschedule.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn interrupted_deliveries_resume_without_sending_twice() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    app.create_confirmed_subscription("name=dave&email=dave%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    // hold the issue's lock, as another instance delivering it would
+    let id = Uuid::new_v4();
+    let mut lock = app.connection_pool.begin().await.unwrap();
+    sqlx::query("SELECT pg_advisory_xact_lock(1045, hashtext($1))")
+        .bind(id.to_string())
+        .execute(&mut lock)
+        .await
+        .unwrap();
+    insert_due_issue(&app, id, "sending").await;
+    // both were queued, and carol was sent to before it stopped
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (issue_id, subscription_id, status)
+        SELECT $1, id, CASE WHEN email = 'carol@gmail.com' THEN 'sent' ELSE 'queued' END FROM subscriptions
+        "#,
+        id
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(app.recipients_of("Newsletter title").await.is_empty());
+    assert_eq!(app.issue(&id.to_string()).await["status"], "sending");
+
+    // the other instance dies
+    lock.rollback().await.unwrap();
+    app.wait_for_issue_status(&id.to_string(), "sent").await;
+    assert_eq!(app.recipients_of("Newsletter title").await, ["dave@gmail.com"]);
+}
+
+#[tokio::test]
+async fn issues_that_fail_are_marked_with_the_error() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    let id = Uuid::new_v4();
+    insert_due_issue(&app, id, "scheduled").await;
+
+    let failed = app.wait_for_issue_status(&id.to_string(), "failed").await;
+
+    assert!(failed["last_error"].as_str().unwrap().contains("Failed to send email"));
+}
@@ -0,0 +1,104 @@
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::spawn_app;
+
+#[tokio::test]
+async fn lists_are_created_and_renamed_by_admins() {
+    let app = spawn_app().await;
+
+    let response = app
+        .admin(Method::POST, "/lists")
+        .json(&serde_json::json!({ "slug": "engineering", "name": "Engineering notes" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+    let created: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(created["slug"], "engineering");
+    assert_eq!(created["is_default"], false);
+
+    let response = app
+        .admin(Method::PATCH, "/lists/engineering")
+        .json(&serde_json::json!({ "name": "Engineering" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let listed: serde_json::Value = app.admin(Method::GET, "/lists").send().await.unwrap().json().await.unwrap();
+    let lists: Vec<_> = listed["lists"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| (l["slug"].as_str().unwrap(), l["name"].as_str().unwrap()))
+        .collect();
+    assert_eq!(lists, [("engineering", "Engineering"), ("newsletter", "Newsletter")]);
+}
+
+#[tokio::test]
+async fn invalid_or_taken_slugs_are_rejected() {
+    let app = spawn_app().await;
+    let cases = [
+        (serde_json::json!({ "slug": "R&D", "name": "Research" }), "slug", "invalid_slug"),
+        (serde_json::json!({ "slug": "-rd", "name": "Research" }), "slug", "invalid_slug"),
+        (serde_json::json!({ "slug": "rd", "name": "  " }), "name", "empty"),
+        (serde_json::json!({ "slug": "newsletter", "name": "Another" }), "slug", "slug_taken"),
+    ];
+    for (body, field, code) in cases {
+        let response = app.admin(Method::POST, "/lists").json(&body).send().await.unwrap();
+
+        assert_eq!(response.status().as_u16(), 400, "{}", body);
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["errors"][0]["field"], field, "{}", body);
+        assert_eq!(problem["errors"][0]["code"], code, "{}", body);
+    }
+    let response = app
+        .admin(Method::PATCH, "/lists/no-such-list")
+        .json(&serde_json::json!({ "name": "Anything" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn lists_can_only_be_managed_by_admins() {
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let created = client
+        .post(format!("{}/admin/lists", app.address))
+        .json(&serde_json::json!({ "slug": "engineering", "name": "Engineering notes" }))
+        .send()
+        .await
+        .unwrap();
+    let renamed = client
+        .patch(format!("{}/admin/lists/newsletter", app.address))
+        .json(&serde_json::json!({ "name": "Renamed" }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(created.status().as_u16(), 401);
+    assert_eq!(renamed.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_list_name_is_escaped_in_the_confirmation_email() {
+    let app = spawn_app().await;
+    app.create_list("research", "<b>R&D</b>").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=research").await;
+
+    let request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert!(body["HtmlContent"].as_str().unwrap().starts_with("welcome to &lt;b&gt;R&amp;D&lt;/b&gt;!"));
+    assert!(body["TextContent"].as_str().unwrap().starts_with("welcome to <b>R&D</b>!"));
+}
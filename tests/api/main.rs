@@ -2,4 +2,20 @@ mod helpers;
 mod health_check;
 mod subscriptions;
 mod subscriptions_confirm;
-mod newsletter;
\ No newline at end of file
+mod newsletter;
+mod shutdown;
+mod preferences;
+mod unsubscribe;
+mod admin;
+mod import;
+mod export;
+mod privacy;
+mod consent;
+mod issues;
+mod drafts;
+mod deliveries;
+mod tracking;
+mod archive;
+mod personalisation;
+mod lists;
+mod migrations;
@@ -0,0 +1,58 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+use zero2rs::domain::SubscriberEmail;
+use crate::helpers::database_migrated_until;
+
+async fn insert_subscriber(pool: &PgPool, email: &str, status: &str, days_ago: i32) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status) \
+         VALUES ($1, $2, 'le guin', now() - make_interval(days => $3), $4)",
+    )
+    .bind(id)
+    .bind(email)
+    .bind(days_ago)
+    .bind(status)
+    .execute(pool)
+    .await
+    .unwrap();
+    id
+}
+
+#[tokio::test]
+async fn addresses_differing_in_case_are_merged_when_the_canonical_form_is_added() {
+    let pool = database_migrated_until(20231020090000).await;
+    let pending = insert_subscriber(&pool, "foo@example.com", "pending_confirmation", 1).await;
+    let confirmed = insert_subscriber(&pool, "Foo@Example.COM", "confirmed", 2).await;
+    sqlx::query("INSERT INTO subscriptions_token (subscriptions_token, subscription_id) VALUES ('token', $1)")
+        .bind(pending)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let older = insert_subscriber(&pool, "ana@Bücher.example", "pending_confirmation", 3).await;
+    insert_subscriber(&pool, "ANA@xn--bcher-kva.example", "pending_confirmation", 1).await;
+    insert_subscriber(&pool, "q@例え.テスト", "confirmed", 1).await;
+
+    sqlx::migrate!("./migrations").run(&pool).await.expect("Failed to migrate");
+
+    let rows: Vec<(Uuid, String, String)> =
+        sqlx::query_as("SELECT id, email, email_canonical FROM subscriptions ORDER BY email_canonical")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    let canonical = |email: &str| SubscriberEmail::parse(email.to_string()).unwrap().canonical().to_string();
+    assert_eq!(
+        rows,
+        [
+            (older, "ana@Bücher.example".to_string(), canonical("ana@Bücher.example")),
+            (confirmed, "Foo@Example.COM".to_string(), canonical("Foo@Example.COM")),
+            (rows[2].0, "q@例え.テスト".to_string(), canonical("q@例え.テスト")),
+        ]
+    );
+    // the merged subscriber can still use the link it was sent
+    let token_owner: Uuid = sqlx::query_scalar("SELECT subscription_id FROM subscriptions_token")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(token_owner, confirmed);
+}
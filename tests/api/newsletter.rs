@@ -1,8 +1,6 @@
-use std::path;
-
-use wiremock::matchers::any;
+use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
-use crate::helpers::{spawn_app, TestApp, ConfirmationLinks};
+use crate::helpers::{newsletter_for, spawn_app, TestApp, ConfirmationLinks};
 
 #[tokio::test]
 async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
@@ -18,7 +16,7 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
     let newsletter_request_body = serde_json::json!({
         "title": "Newsletter title",
         "content": {
-            "text": "Newsletter content",
+            "plain": "Newsletter content",
             "html": "<p>Newsletter content</p>",
         }
     });
@@ -28,12 +26,12 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
     assert_eq!(response.status().as_u16(), 200);
 
 }
-async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks{
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks{
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
     // we need to send email in subscription api successfully.
     // so we need a mock server to response.
     // response for public sending email api(as usual exteranl api)
-    let _mock_guard = Mock::given(path::Path::new("/email"))
+    let _mock_guard = Mock::given(path("/email"))
         .and(method("POST"))
         .respond_with(ResponseTemplate::new(200))
         .named("Create unconfirmed subscriber")
@@ -42,9 +40,9 @@ async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks{
         .await;
     app.post_subscriptions(body).await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    app.get_confirmation_links(&email_request)
+    app.get_confirmation_links(email_request)
 }
-async fn create_confirmed_subscriber(app: &TestApp) {
+pub async fn create_confirmed_subscriber(app: &TestApp) {
     let confirmation_link = create_unconfirmed_subscriber(app).await.html;
     reqwest::get(confirmation_link)
         .await
@@ -60,7 +58,7 @@ pub async fn newsletters_are_delivered_to_confirmed_subscribers() {
 
     // response for newsletter api
     Mock::given(path("/email"))
-        .and(matcher::method("POST"))
+        .and(method("POST"))
         .respond_with(ResponseTemplate::new(200))
         .expect(1)
         .mount(&app.email_server)
@@ -69,7 +67,7 @@ pub async fn newsletters_are_delivered_to_confirmed_subscribers() {
     let newsletter_request_body = serde_json::json!({
         "title": "Newsletter title",
         "content": {
-            "text": "Newsletter content",
+            "plain": "Newsletter content",
             "html": "<p>Newsletter content</p>",
         }
     });
@@ -79,6 +77,61 @@ pub async fn newsletters_are_delivered_to_confirmed_subscribers() {
     assert_eq!(response.status().as_u16(), 200);
 }
 
+#[tokio::test]
+async fn newsletters_are_only_published_by_admins_who_are_recorded_as_their_authors() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "plain": "Newsletter content", "html": "<p>Newsletter content</p>" },
+    });
+
+    let anonymous = reqwest::Client::new()
+        .post(format!("{}/newsletter", app.address))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    let published = app.post_newsletters(&body).await;
+
+    assert_eq!(anonymous.status().as_u16(), 401);
+    assert_eq!(published.status().as_u16(), 200);
+    let issue = sqlx::query!("SELECT created_by, publish_requested_by FROM newsletter_issues")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(issue.created_by, Some(app.test_user.user_id));
+    assert_eq!(issue.publish_requested_by, Some(app.test_user.user_id));
+}
+
+#[tokio::test]
+async fn newsletter_emails_carry_the_html_and_plain_content_in_their_own_fields() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(&serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "plain": "Newsletter content", "html": "<p>Newsletter content</p>" }
+    }))
+    .await;
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert!(body["HtmlContent"].as_str().unwrap().starts_with("<p>Newsletter content</p>"));
+    assert!(body["TextContent"].as_str().unwrap().starts_with("Newsletter content"));
+}
+
 // test case for invalid input for newsletter api
 #[tokio::test]
 pub async fn newsletter_return_400_for_invalid_data() {
@@ -89,7 +142,7 @@ pub async fn newsletter_return_400_for_invalid_data() {
         (
             serde_json::json!({
                 "content": {
-                    "text": "Newsletter content",
+                    "plain": "Newsletter content",
                     "html": "<p>Newsletter content</p>",
                 }
             }),
@@ -105,7 +158,7 @@ pub async fn newsletter_return_400_for_invalid_data() {
             serde_json::json!({
                 "title": "",
                 "content": {
-                    "text": "Newsletter content",
+                    "plain": "Newsletter content",
                     "html": "<p>Newsletter content</p>",
                 }
             }),
@@ -122,3 +175,148 @@ pub async fn newsletter_return_400_for_invalid_data() {
         );
     }
 }
+
+#[tokio::test]
+async fn newsletters_are_only_delivered_to_the_targeted_lists() {
+    let app = spawn_app().await;
+    app.create_list("engineering", "Engineering notes").await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(&newsletter_for(&["engineering"])).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn subscribers_on_several_targeted_lists_get_a_single_copy() {
+    let app = spawn_app().await;
+    app.create_list("engineering", "Engineering notes").await;
+    create_confirmed_subscriber(&app).await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com&list=engineering")
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(&newsletter_for(&["newsletter", "engineering"])).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn newsletters_to_an_unknown_list_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app.post_newsletters(&newsletter_for(&["gossip"])).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "unknown_list");
+}
+
+// three confirmed subscribers: a German-speaking pro customer, an Austrian, an English speaker
+async fn create_segmentable_subscribers(app: &TestApp) {
+    app.create_confirmed_subscription("name=anna&email=anna%40gmail.com&language=de").await;
+    app.create_confirmed_subscription("name=bernd&email=bernd%40gmail.com&language=de-AT").await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com&language=en").await;
+    sqlx::query!(r#"UPDATE subscriptions SET attributes = '{"plan": "pro", "seats": 12}' WHERE name = 'anna'"#)
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn the_dry_run_counts_the_subscribers_matching_a_segment() {
+    let app = spawn_app().await;
+    create_segmentable_subscribers(&app).await;
+    let cases = [
+        (serde_json::json!(null), 3),
+        (serde_json::json!({"field": "language", "op": "eq", "value": "de"}), 2),
+        (serde_json::json!({"not": {"field": "language", "op": "eq", "value": "de"}}), 1),
+        (
+            serde_json::json!({"and": [
+                {"field": "language", "op": "in", "value": ["de", "fr"]},
+                {"field": "attributes.plan", "op": "eq", "value": "pro"}
+            ]}),
+            1,
+        ),
+        (serde_json::json!({"field": "attributes.seats", "op": "gte", "value": 10}), 1),
+        (serde_json::json!({"field": "attributes.plan", "op": "exists", "value": false}), 2),
+        (serde_json::json!({"field": "subscribed_at", "op": "after", "value": "2000-01-01"}), 3),
+        (serde_json::json!({"field": "attributes.plan", "op": "eq", "value": "x' OR '1'='1"}), 0),
+    ];
+
+    for (segment, expected) in cases {
+        let response = app.post_dry_run(&serde_json::json!({ "segment": segment })).await;
+
+        assert_eq!(response.status().as_u16(), 200, "{}", segment);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["recipients"], expected, "{}", segment);
+    }
+}
+
+#[tokio::test]
+async fn invalid_segments_are_rejected_with_a_reason() {
+    let app = spawn_app().await;
+
+    let segment = serde_json::json!({"field": "email", "op": "eq", "value": "anna@gmail.com"});
+    let response = app.post_dry_run(&serde_json::json!({ "segment": segment })).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "segment");
+    assert_eq!(body["errors"][0]["code"], "unknown_field");
+}
+
+#[tokio::test]
+async fn the_dry_run_is_only_for_admins() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletter/dry-run", app.address))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert!(response.json::<serde_json::Value>().await.unwrap().get("recipients").is_none());
+}
+
+#[tokio::test]
+async fn segmented_newsletters_are_only_delivered_to_matching_subscribers() {
+    let app = spawn_app().await;
+    create_segmentable_subscribers(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let mut body = newsletter_for(&[]);
+    body["segment"] = serde_json::json!({"field": "language", "op": "eq", "value": "de"});
+    let response = app.post_newsletters(&body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let recipients: Vec<_> = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter_map(|r| serde_json::from_slice::<serde_json::Value>(&r.body).ok())
+        .filter(|b| b["Subject"] == "Newsletter title")
+        .map(|b| b["To"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(recipients.len(), 2);
+    assert!(!recipients.contains(&"carol@gmail.com".to_string()));
+}
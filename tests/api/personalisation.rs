@@ -0,0 +1,101 @@
+use reqwest::Method;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{newsletter_body, spawn_app, TestApp};
+
+// the HTML and plain text `email` got
+async fn received_by(app: &TestApp, email: &str) -> (String, String) {
+    let requests = app.email_server.received_requests().await.unwrap();
+    let body = requests
+        .iter()
+        .filter_map(|r| serde_json::from_slice::<serde_json::Value>(&r.body).ok())
+        .find(|b| b["Subject"] == "Newsletter title" && b["To"] == email)
+        .unwrap_or_else(|| panic!("nothing was sent to {}", email));
+    (body["HtmlContent"].as_str().unwrap().to_string(), body["TextContent"].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn placeholders_are_filled_in_for_each_subscriber() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    app.create_confirmed_subscription("name=dave&email=dave%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let link = app.preferences_link("carol@gmail.com").await;
+    let updated = app
+        .patch_preferences(&link, &serde_json::json!({ "attributes": { "company": "Acme <Labs>", "site": "javascript:alert(1)" } }))
+        .await;
+    assert_eq!(updated.status().as_u16(), 200);
+
+    app.post_newsletters(&newsletter_body(
+        "Newsletter title",
+        r#"<p>Hi {{ name }} from {{ attributes.company | default: "there" }}</p><a href="{{ attributes.site }}">Site</a><a href="{{ unsubscribe_url }}">Leave</a>"#,
+        "Hi {{ name }} from {{ attributes.company | default: \"there\" }}, leave at {{ unsubscribe_url }}",
+    ))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    let (carol_html, carol_plain) = received_by(&app, "carol@gmail.com").await;
+    let (dave_html, _) = received_by(&app, "dave@gmail.com").await;
+    assert!(carol_html.starts_with("<p>Hi carol from Acme &lt;Labs&gt;</p>"));
+    assert!(carol_plain.starts_with("Hi carol from Acme <Labs>, leave at http"));
+    assert!(dave_html.starts_with("<p>Hi dave from there</p>"));
+    assert!(carol_html.contains(r#"<a href="">Site</a><a href="http://127.0.0.1/subscriptions/unsubscribe?token="#));
+    // the content places the unsubscribe link, so it isn't added again at the end
+    assert_eq!(carol_html.matches("/subscriptions/unsubscribe").count(), 1);
+    assert_eq!(carol_plain.matches("/subscriptions/unsubscribe").count(), 1);
+}
+
+#[tokio::test]
+async fn unknown_variables_are_rejected_before_anything_is_sent() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(&newsletter_body("Newsletter title", "<p>Hi {{ first_name }}</p>", "Hi {{ name | upcase }}"))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["errors"][0]["field"], "content.html");
+    assert_eq!(problem["errors"][0]["code"], "unknown_variable");
+    assert_eq!(problem["errors"][1]["field"], "content.plain");
+    assert_eq!(problem["errors"][1]["code"], "unknown_filter");
+    let listed: serde_json::Value = app.issues(Method::GET, "").send().await.unwrap().json().await.unwrap();
+    assert!(listed["issues"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn previews_show_the_defaults() {
+    let app = spawn_app().await;
+    let draft: serde_json::Value = app
+        .issues(Method::POST, "")
+        .json(&newsletter_body("Newsletter title", r#"<p>Hi {{ name | default: "reader" }}</p>"#, "Hi {{ name | default: \"reader\" }}"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let preview: serde_json::Value = app
+        .issues(Method::GET, &format!("/{}/preview", draft["id"].as_str().unwrap()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert!(preview["html"].as_str().unwrap().starts_with("<p>Hi reader</p>"));
+    assert!(preview["plain"].as_str().unwrap().starts_with("Hi reader\n"));
+}
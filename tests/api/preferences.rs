@@ -0,0 +1,195 @@
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{spawn_app, TestApp};
+
+fn mount_email(app: &TestApp) -> impl std::future::Future<Output = ()> + '_ {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+}
+
+#[tokio::test]
+async fn the_emailed_link_opens_the_preference_centre() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    mount_email(&app).await;
+
+    let link = app.preferences_link("Ursula_Le_Guin@gmail.com").await;
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(body["name"], "le guin");
+    assert_eq!(body["status"], "confirmed");
+    assert_eq!(body["attributes"], serde_json::json!({}));
+    assert_eq!(body["topics"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn requesting_a_link_for_an_unknown_email_sends_nothing() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/preferences/link", app.address))
+        .form(&[("email", "nobody@gmail.com")])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn tampered_or_missing_tokens_are_rejected() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    mount_email(&app).await;
+    let link = app.preferences_link("ursula_le_guin@gmail.com").await;
+    let token = link.query_pairs().find(|(k, _)| k == "token").unwrap().1.to_string();
+    let (_, rest) = token.split_once('.').unwrap();
+    let forged = format!("{}/subscriptions/preferences?token={}.{}", app.address, uuid::Uuid::new_v4(), rest);
+
+    let response = reqwest::get(forged).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+
+    let response = reqwest::get(format!("{}/subscriptions/preferences", app.address)).await.unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn the_profile_can_be_edited() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    mount_email(&app).await;
+    let link = app.preferences_link("ursula_le_guin@gmail.com").await;
+
+    let body = serde_json::json!({
+        "name": "Ursula K. Le Guin",
+        "language": "EN-gb",
+        "timezone": "Europe/London",
+        "attributes": {"company": "Earthsea", "seats": 3},
+        "topics": ["announcements", "events"]
+    });
+    let response = app.patch_preferences(&link, &body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["name"], "Ursula K. Le Guin");
+    assert_eq!(body["language"], "en-GB");
+    assert_eq!(body["timezone"], "Europe/London");
+    assert_eq!(body["attributes"]["company"], "Earthsea");
+    assert_eq!(body["topics"], serde_json::json!(["announcements", "events"]));
+
+    // fields left out are kept, an empty language clears it
+    let response = app.patch_preferences(&link, &serde_json::json!({"language": ""})).await;
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["language"], serde_json::Value::Null);
+    assert_eq!(body["name"], "Ursula K. Le Guin");
+}
+
+#[tokio::test]
+async fn invalid_edits_are_rejected_without_changing_anything() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    mount_email(&app).await;
+    let link = app.preferences_link("ursula_le_guin@gmail.com").await;
+
+    let body = serde_json::json!({
+        "name": "Ursula",
+        "timezone": "Mars/Olympus",
+        "attributes": ["not", "an", "object"],
+        "topics": ["gossip"]
+    });
+    let response = app.patch_preferences(&link, &body).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let codes: Vec<_> = body["errors"].as_array().unwrap().iter().map(|e| e["code"].clone()).collect();
+    assert_eq!(codes, ["unknown_timezone", "not_an_object", "unknown_topic"]);
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn an_email_change_only_applies_once_the_new_address_is_confirmed() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    mount_email(&app).await;
+    let link = app.preferences_link("ursula_le_guin@gmail.com").await;
+
+    let response = app
+        .patch_preferences(&link, &serde_json::json!({"email": "ursula@le-guin.com"}))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(body["pending_email"], "ursula@le-guin.com");
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let confirmation = requests.last().unwrap();
+    let sent: serde_json::Value = serde_json::from_slice(&confirmation.body).unwrap();
+    assert_eq!(sent["To"], "ursula@le-guin.com");
+    let confirmation_links = app.get_confirmation_links(confirmation);
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let body: serde_json::Value = reqwest::get(link).await.unwrap().json().await.unwrap();
+    assert_eq!(body["email"], "ursula@le-guin.com");
+    assert_eq!(body["pending_email"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn an_email_change_to_a_subscribed_address_is_rejected() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    mount_email(&app).await;
+    app.post_subscriptions("name=ged&email=sparrowhawk%40gmail.com").await;
+    let link = app.preferences_link("ursula_le_guin@gmail.com").await;
+
+    let response = app
+        .patch_preferences(&link, &serde_json::json!({"email": "Sparrowhawk@gmail.com"}))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "already_subscribed");
+}
+
+#[tokio::test]
+async fn confirming_an_email_change_to_an_address_taken_meanwhile_is_a_409_problem() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    mount_email(&app).await;
+    let link = app.preferences_link("ursula_le_guin@gmail.com").await;
+    app.patch_preferences(&link, &serde_json::json!({"email": "sparrowhawk@gmail.com"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    let requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_links = app.get_confirmation_links(requests.last().unwrap());
+    app.post_subscriptions("name=ged&email=sparrowhawk%40gmail.com").await.error_for_status().unwrap();
+
+    let response = reqwest::get(confirmation_links.html.clone()).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], 409);
+    // the token is used up
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+}
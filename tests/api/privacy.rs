@@ -0,0 +1,188 @@
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{spawn_app, TestApp};
+
+async fn privacy_request(app: &TestApp, action: &str, email: &str) -> reqwest::Response {
+    app.admin(Method::POST, &format!("/privacy/{}", action))
+        .json(&serde_json::json!({ "email": email }))
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn subject_access(app: &TestApp, email: &str) -> serde_json::Value {
+    let response = privacy_request(app, "access", email).await;
+    assert_eq!(response.status().as_u16(), 200);
+    response.json().await.unwrap()
+}
+
+async fn import_row(app: &TestApp, email: &str) -> serde_json::Value {
+    let response = app
+        .import_subscribers("prior_consent=true", &format!("email,name,status\n{},carol,confirmed\n", email))
+        .await;
+    let id = response.json::<serde_json::Value>().await.unwrap()["id"].as_str().unwrap().to_string();
+    let report: serde_json::Value = app
+        .admin(Method::GET, &format!("/subscribers/imports/{}", id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    report["rows"][0].clone()
+}
+
+#[tokio::test]
+async fn privacy_requests_must_be_authenticated() {
+    let app = spawn_app().await;
+
+    for action in ["access", "erasure"] {
+        let response = reqwest::Client::new()
+            .post(format!("{}/admin/privacy/{}", app.address, action))
+            .json(&serde_json::json!({ "email": "carol@gmail.com" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+    }
+}
+
+#[tokio::test]
+async fn the_access_report_covers_the_subscription_tokens_send_history_and_audit_log() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.preferences_link("carol@gmail.com").await;
+    let id = subject_access(&app, "carol@gmail.com").await["subscriber"]["id"].as_str().unwrap().to_string();
+    app.admin(Method::PATCH, &format!("/subscribers/{}", id))
+        .json(&serde_json::json!({ "name": "Carol" }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let report = subject_access(&app, "Carol@gmail.com").await;
+
+    assert_eq!(report["subscriber"]["name"], "Carol");
+    assert_eq!(report["lists"][0]["list"], "newsletter");
+    assert_eq!(report["lists"][0]["status"], "confirmed");
+    assert_eq!(report["tokens"].as_array().unwrap().len(), 1);
+    assert!(report["tokens"][0].get("subscriptions_token").is_none());
+    let kinds: Vec<_> = report["sent_emails"].as_array().unwrap().iter().map(|e| e["kind"].as_str().unwrap()).collect();
+    assert_eq!(kinds, ["confirmation", "preferences_link"]);
+    let actions: Vec<_> = report["audit_log"].as_array().unwrap().iter().map(|e| e["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, ["subject_access", "update"]);
+    assert_eq!(report["audit_log"][1]["before"]["name"], "carol");
+    assert_eq!(report["suppressed"], false);
+}
+
+#[tokio::test]
+async fn erasure_deletes_the_subscriber_redacts_the_audit_log_and_suppresses_the_address() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    let id = subject_access(&app, "carol@gmail.com").await["subscriber"]["id"].as_str().unwrap().to_string();
+    app.admin(Method::PATCH, &format!("/subscribers/{}", id))
+        .json(&serde_json::json!({ "name": "Carol" }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = privacy_request(&app, "erasure", "carol@gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let outcome: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(outcome, serde_json::json!({ "subscriber_erased": true, "suppressed": true }));
+    let remaining = sqlx::query!(r#"SELECT (SELECT count(*) FROM subscriptions) AS "subscriptions!", (SELECT count(*) FROM sent_emails) AS "sent_emails!""#)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!((remaining.subscriptions, remaining.sent_emails), (0, 0));
+    let audit = sqlx::query!("SELECT action, before, after, subject_hash FROM admin_audit_log ORDER BY id")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+    let actions: Vec<_> = audit.iter().map(|entry| entry.action.as_str()).collect();
+    assert_eq!(actions, ["subject_access", "update", "erasure"]);
+    assert!(audit.iter().all(|entry| entry.before.is_none() && entry.after.is_none()));
+    assert!(!audit[2].subject_hash.as_ref().unwrap().contains("carol"));
+
+    assert_eq!(import_row(&app, "Carol@gmail.com").await["reason"], "suppressed");
+    let report = subject_access(&app, "carol@gmail.com").await;
+    assert_eq!(report["subscriber"], serde_json::Value::Null);
+    assert_eq!(report["suppressed"], true);
+}
+
+#[tokio::test]
+async fn the_audit_entries_of_a_deleted_subscriber_are_reported_and_redacted() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    let id = subject_access(&app, "carol@gmail.com").await["subscriber"]["id"].as_str().unwrap().to_string();
+    app.admin(Method::DELETE, &format!("/subscribers/{}", id))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let report = subject_access(&app, "Carol@gmail.com").await;
+    let actions: Vec<_> = report["audit_log"].as_array().unwrap().iter().map(|e| e["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, ["subject_access", "delete"]);
+    assert_eq!(report["audit_log"][1]["before"]["email"], "carol@gmail.com");
+
+    let outcome: serde_json::Value = privacy_request(&app, "erasure", "carol@gmail.com").await.json().await.unwrap();
+
+    assert_eq!(outcome["subscriber_erased"], false);
+    let audit = sqlx::query!("SELECT action, before, redacted_at FROM admin_audit_log WHERE action = 'delete'")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert!(audit.before.is_none());
+    assert!(audit.redacted_at.is_some());
+}
+
+#[tokio::test]
+async fn an_address_can_be_erased_before_it_is_ever_subscribed() {
+    let app = spawn_app().await;
+
+    let response = privacy_request(&app, "erasure", "erin@gmail.com").await;
+
+    let outcome: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(outcome["subscriber_erased"], false);
+    assert_eq!(import_row(&app, "erin@gmail.com").await["reason"], "suppressed");
+    // other addresses are unaffected
+    assert_eq!(import_row(&app, "dora@gmail.com").await["outcome"], "accepted");
+}
+
+#[tokio::test]
+async fn erasure_removes_the_address_from_import_reports() {
+    let app = spawn_app().await;
+    assert_eq!(import_row(&app, "dora@gmail.com").await["outcome"], "accepted");
+
+    privacy_request(&app, "erasure", "dora@gmail.com").await.error_for_status().unwrap();
+
+    let emails: Vec<String> = sqlx::query!("SELECT email FROM import_job_rows")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.email)
+        .collect();
+    assert_eq!(emails, ["[erased]"]);
+}
+
+#[tokio::test]
+async fn privacy_requests_need_a_valid_email() {
+    let app = spawn_app().await;
+
+    let response = privacy_request(&app, "erasure", "not-an-email").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
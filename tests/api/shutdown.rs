@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2rs::shutdown::ShutdownOutcome;
+use crate::helpers::spawn_app;
+use crate::newsletter::create_confirmed_subscriber;
+
+#[tokio::test]
+async fn in_flight_requests_complete_during_a_graceful_shutdown() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // a slow email provider keeps the newsletter request in flight
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "plain": "Newsletter content",
+            "html": "<p>Newsletter content</p>",
+        }
+    });
+    let request = app.post_newsletters(&newsletter_request_body);
+    // shut down once the handler is waiting on the provider
+    let trigger = async {
+        while app.email_server.received_requests().await.unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        app.shutdown.trigger();
+    };
+    let (response, _) = tokio::join!(request, trigger);
+
+    assert_eq!(response.status().as_u16(), 200);
+    let outcome = app.server.await.unwrap().unwrap();
+    assert_eq!(outcome, ShutdownOutcome::Clean);
+}
+
+#[tokio::test]
+async fn new_connections_are_refused_after_shutdown() {
+    let app = spawn_app().await;
+    app.shutdown.trigger();
+    let outcome = app.server.await.unwrap().unwrap();
+    assert_eq!(outcome, ShutdownOutcome::Clean);
+
+    let result = reqwest::get(&format!("{}/healthcheck", app.address)).await;
+    assert!(result.is_err());
+}
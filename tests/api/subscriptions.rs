@@ -1,4 +1,6 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with_domain_verification};
+use std::sync::Arc;
+use zero2rs::domain_verification::{DomainVerification, InMemoryDomainVerifier};
 use wiremock::{Mock, ResponseTemplate};
 use wiremock::matchers::{method, path};
 #[tokio::test]
@@ -26,7 +28,7 @@ async fn subscribe_persists_the_new_subscriber(){
         .mount(&app.email_server)
         .await;
 
-    app.post_subscriptions(body.into()).await;
+    app.post_subscriptions(body).await;
 
     let saved = sqlx::query!("SELECT email, name, status FROM subscriptions",)
         .fetch_one(&app.connection_pool)
@@ -38,6 +40,59 @@ async fn subscribe_persists_the_new_subscriber(){
     assert_eq!(saved.status, "pending_confirmation");
 }
 
+#[tokio::test]
+async fn subscribe_normalises_the_email_and_resends_the_confirmation_to_a_differently_cased_duplicate() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40Gmail.COM").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    // still pending: another confirmation email, answered as for a new subscriber
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 2);
+
+    let saved = sqlx::query!("SELECT email, email_canonical FROM subscriptions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "Ursula_Le_Guin@gmail.com");
+    assert_eq!(saved[0].email_canonical, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_409_when_the_same_address_is_subscribed_concurrently() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    // another request's insert, not committed yet when ours looks the address up
+    let mut other = app.connection_pool.begin().await.unwrap();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, email_canonical, name, subscribed_at, status)
+        VALUES (gen_random_uuid(), 'Ursula_Le_Guin@gmail.com', 'ursula_le_guin@gmail.com', 'le guin', now(), 'pending_confirmation')"#
+    )
+    .execute(&mut other)
+    .await
+    .unwrap();
+
+    let (response, _) = tokio::join!(app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com"), async {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        other.commit().await.unwrap();
+    });
+
+    assert_eq!(response.status().as_u16(), 409);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["status"], 409);
+}
+
 #[tokio::test]
 async fn subscribe_returns_a_400_when_fields_is_invalid(){
     let app = spawn_app().await;
@@ -81,7 +136,7 @@ pub async fn subscribe_sends_a_confirmation_email_for_valid_data() {
         .mount(&app.email_server)
         .await;
     dbg!(&app.email_server.uri());
-    app.post_subscriptions(body.into()).await;
+    app.post_subscriptions(body).await;
 }
 #[tokio::test]
 pub async fn subscribe_sends_a_confirmation_email_with_a_link(){
@@ -92,10 +147,10 @@ pub async fn subscribe_sends_a_confirmation_email_with_a_link(){
         .respond_with(ResponseTemplate::new(200))
         .mount(&app.email_server)
         .await;
-    app.post_subscriptions(body.into()).await;
+    app.post_subscriptions(body).await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
     // let confirmation_links = app.get_confirmation_links(&email_request);
     // assert_eq!(confirmation_links.html, confirmation_links.plain_text);
@@ -107,6 +162,287 @@ pub async fn subscribe_fails_when_the_database_is_unavailable(){
     let app = spawn_app().await;
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
     sqlx::query!("ALTER TABLE subscriptions DROP COLUMN email").execute(&app.connection_pool).await.unwrap();
-    let response = app.post_subscriptions(body.into()).await;
+    let response = app.post_subscriptions(body).await;
     assert_eq!(response.status().as_u16(), 500);
 }
+
+#[tokio::test]
+async fn subscribe_suggests_a_fix_for_a_misspelled_domain() {
+    let verifier = InMemoryDomainVerifier::new(["gmail.com", "gmial.com"]);
+    let app = spawn_app_with_domain_verification(DomainVerification(Some(Arc::new(verifier)))).await;
+
+    let response = app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmial.com").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["suggestion"], "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribe_keeps_the_domain_when_the_suggestion_is_declined() {
+    let verifier = InMemoryDomainVerifier::new(["gmail.com", "gmial.com"]);
+    let app = spawn_app_with_domain_verification(DomainVerification(Some(Arc::new(verifier)))).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmial.com&keep_domain=true").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email FROM subscriptions").fetch_one(&app.connection_pool).await.unwrap();
+    assert_eq!(saved.email, "ursula_le_guin@gmial.com");
+}
+
+#[tokio::test]
+async fn subscribe_rejects_a_domain_without_mail_records() {
+    let verifier = InMemoryDomainVerifier::new(["gmail.com"]);
+    let app = spawn_app_with_domain_verification(DomainVerification(Some(Arc::new(verifier)))).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions("name=le%20guin&email=ursula%40no-mail.example").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn subscribe_rejects_a_disposable_domain_with_a_reason_code() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions("name=le%20guin&email=ursula%40mailinator.com").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "email");
+    assert_eq!(body["errors"][0]["code"], "disposable_domain");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_problem_document_listing_every_invalid_field() {
+    let app = spawn_app().await;
+
+    let response = app.post_subscriptions(&format!("name={}&email=ursula%40", "a".repeat(257))).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+    let request_id = response.headers()["x-request-id"].to_str().unwrap().to_owned();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], 400);
+    assert_eq!(body["request_id"], request_id.as_str());
+    assert_eq!(body["errors"][0]["field"], "name");
+    assert_eq!(body["errors"][0]["code"], "too_long");
+    assert_eq!(body["errors"][0]["max"], 256);
+    assert_eq!(body["errors"][1]["field"], "email");
+    assert_eq!(body["errors"][1]["code"], "missing_domain");
+}
+
+#[tokio::test]
+async fn subscribe_errors_do_not_echo_the_submitted_values() {
+    let app = spawn_app().await;
+
+    let response = app.post_subscriptions("name=%3Cscript%3E&email=ursula%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body = response.text().await.unwrap();
+    assert!(!body.contains("script"));
+    assert!(!body.contains("ursula"));
+}
+
+#[tokio::test]
+async fn malformed_bodies_are_rejected_with_a_problem_document() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/json")
+        .body(r#"{"name": "le guin", "email": "#)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+    assert!(response.headers().contains_key("x-request-id"));
+}
+
+#[tokio::test]
+async fn subscribe_accepts_a_json_body() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let body = serde_json::json!({"name": "le guin", "email": "ursula_le_guin@gmail.com"});
+    let response = app.post_subscriptions_json(&body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn subscribe_reports_invalid_json_fields_like_form_fields() {
+    let app = spawn_app().await;
+
+    let response = app.post_subscriptions_json(&serde_json::json!({"email": "ursula_le_guin@gmail.com"})).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "name");
+    assert_eq!(body["errors"][0]["code"], "empty");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_415_for_unsupported_media_types() {
+    let app = spawn_app().await;
+
+    for content_type in [Some("text/plain"), Some("multipart/form-data; boundary=x"), None] {
+        let mut request = reqwest::Client::new()
+            .post(format!("{}/subscriptions", app.address))
+            .body("name=le%20guin&email=ursula_le_guin%40gmail.com");
+        if let Some(content_type) = content_type {
+            request = request.header("Content-Type", content_type);
+        }
+        let response = request.send().await.unwrap();
+
+        assert_eq!(response.status().as_u16(), 415, "content type {:?}", content_type);
+        assert_eq!(response.headers()["content-type"], "application/problem+json");
+    }
+}
+
+#[tokio::test]
+async fn subscribe_flags_a_name_impersonating_with_lookalike_letters() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // "Аdmin" starting with a Cyrillic capital A
+    let response = app.post_subscriptions("name=%D0%90dmin&email=ursula_le_guin%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT flagged_reason FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.flagged_reason.as_deref(), Some("confusable_name"));
+}
+
+#[tokio::test]
+async fn subscribe_stores_the_optional_language_and_timezone() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let body = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com",
+        "language": "pt_br",
+        "timezone": "America/Sao_Paulo"
+    });
+    let response = app.post_subscriptions_json(&body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT language, timezone FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.language.as_deref(), Some("pt-BR"));
+    assert_eq!(saved.timezone.as_deref(), Some("America/Sao_Paulo"));
+}
+
+#[tokio::test]
+async fn subscribe_rejects_an_unknown_timezone() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&timezone=Mars%2FOlympus")
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "timezone");
+    assert_eq!(body["errors"][0]["code"], "unknown_timezone");
+}
+
+#[tokio::test]
+async fn subscribe_to_an_unknown_list_is_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=gossip")
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "list");
+    assert_eq!(body["errors"][0]["code"], "unknown_list");
+}
+
+#[tokio::test]
+async fn joining_a_second_list_is_confirmed_separately() {
+    let app = spawn_app().await;
+    app.create_list("engineering", "Engineering notes").await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=engineering")
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let memberships = sqlx::query!(
+        r#"SELECT lists.slug, list_memberships.status FROM list_memberships
+        JOIN lists ON lists.id = list_memberships.list_id ORDER BY lists.slug"#
+    )
+    .fetch_all(&app.connection_pool)
+    .await
+    .unwrap();
+    let memberships: Vec<_> = memberships.iter().map(|m| (m.slug.as_str(), m.status.as_str())).collect();
+    assert_eq!(
+        memberships,
+        [("engineering", "pending_confirmation"), ("newsletter", "confirmed")]
+    );
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.len(), 1);
+
+    // already confirmed on the list: the same answer, and no email
+    let sent = app.email_server.received_requests().await.unwrap().len();
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=newsletter")
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), sent);
+}
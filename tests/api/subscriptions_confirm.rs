@@ -21,9 +21,9 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .respond_with(ResponseTemplate::new(200))
         .mount(&app.email_server)
         .await;
-    let _response = app.post_subscriptions(body.into()).await;
+    let _response = app.post_subscriptions(body).await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     assert_eq!(confirmation_links.html.host_str().unwrap(), "127.0.0.1");
     let response = reqwest::get(confirmation_links.html).await.unwrap();
     assert_eq!(response.status().as_u16(), 200);
@@ -39,9 +39,9 @@ pub async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .respond_with(ResponseTemplate::new(200))
         .mount(&app.email_server)
         .await;
-    app.post_subscriptions(body.into()).await;
+    app.post_subscriptions(body).await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     reqwest::get(confirmation_links.html).await.unwrap();
     let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
         .fetch_one(&app.connection_pool)
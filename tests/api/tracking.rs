@@ -0,0 +1,151 @@
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{newsletter_body, spawn_app, spawn_app_configured, TestApp};
+
+async fn tracked_app() -> TestApp {
+    let app = spawn_app_configured(|c| c.newsletter.track_engagement = true).await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app
+}
+
+// publishes a newsletter with a link, returns the issue id and the HTML carol got
+async fn publish(app: &TestApp) -> (String, String) {
+    app.post_newsletters(&newsletter_body(
+        "Newsletter title",
+        r#"<p><a href="https://example.com/article?x=1&amp;y=2">Read it</a> or <a href="mailto:editor@example.com">reply</a></p>"#,
+        "Read it at https://example.com/article",
+    ))
+    .await
+    .error_for_status()
+    .unwrap();
+    let requests = app.email_server.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    (app.latest_issue_id().await, body["HtmlContent"].as_str().unwrap().to_string())
+}
+
+// the first tracking link to `endpoint` in `html`, pointed at the test app's port
+fn find_link(app: &TestApp, html: &str, endpoint: &str) -> String {
+    let start = html.find(endpoint).unwrap_or_else(|| panic!("no {} link in {}", endpoint, html));
+    let end = html[start..].find('"').unwrap();
+    format!("{}{}", app.address, &html[start..start + end])
+}
+
+fn no_redirects() -> reqwest::Client {
+    reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap()
+}
+
+async fn engagement(app: &TestApp, issue_id: &str) -> serde_json::Value {
+    let report: serde_json::Value = app
+        .admin(Method::GET, &format!("/issues/{}/report", issue_id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    report["engagement"].clone()
+}
+
+#[tokio::test]
+async fn opens_and_clicks_are_recorded_per_issue() {
+    let app = tracked_app().await;
+    let (issue_id, html) = publish(&app).await;
+
+    let pixel = reqwest::get(find_link(&app, &html, "/t/o/")).await.unwrap();
+    let click = no_redirects().get(find_link(&app, &html, "/t/c/")).send().await.unwrap();
+    no_redirects().get(find_link(&app, &html, "/t/c/")).send().await.unwrap();
+
+    assert_eq!(pixel.status().as_u16(), 200);
+    assert_eq!(pixel.headers()["Content-Type"], "image/gif");
+    assert_eq!(click.status().as_u16(), 302);
+    assert_eq!(click.headers()["Location"], "https://example.com/article?x=1&y=2");
+    assert!(html.contains(r#"href="mailto:editor@example.com""#));
+    assert_eq!(
+        engagement(&app, &issue_id).await,
+        serde_json::json!({ "opens": 1, "unique_opens": 1, "clicks": 2, "unique_clicks": 1 })
+    );
+}
+
+#[tokio::test]
+async fn forged_click_tokens_dont_redirect() {
+    let app = tracked_app().await;
+    let (_, html) = publish(&app).await;
+    let link = find_link(&app, &html, "/t/c/");
+    let (_, signature) = link.rsplit_once('.').unwrap();
+    // a payload pointing elsewhere, with the genuine signature
+    let forged = format!("{}/t/c/{}.{}", app.address, "aHR0cHM6Ly9ldmlsLmV4YW1wbGU", signature);
+
+    let response = no_redirects().get(forged).send().await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+    assert!(response.headers().get("Location").is_none());
+}
+
+#[tokio::test]
+async fn subscribers_can_opt_out_of_tracking() {
+    let app = tracked_app().await;
+    let (first_issue, tracked_html) = publish(&app).await;
+    let link = app.preferences_link("carol@gmail.com").await;
+
+    let response = reqwest::Client::new()
+        .patch(link)
+        .json(&serde_json::json!({ "tracking_opt_out": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let preferences: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(preferences["tracking_opt_out"], true);
+
+    // links already sent still work, but nothing is recorded
+    let click = no_redirects().get(find_link(&app, &tracked_html, "/t/c/")).send().await.unwrap();
+    assert_eq!(click.status().as_u16(), 302);
+    assert_eq!(engagement(&app, &first_issue).await["clicks"], 0);
+    // and the next newsletter isn't tracked at all
+    let (_, html) = publish(&app).await;
+    assert!(html.contains(r#"href="https://example.com/article?x=1&amp;y=2""#));
+    assert!(!html.contains("/t/o/"));
+}
+
+#[tokio::test]
+async fn newsletters_are_untracked_unless_enabled() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=carol&email=carol%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let (_, html) = publish(&app).await;
+
+    assert!(!html.contains("/t/c/"));
+    assert!(!html.contains("/t/o/"));
+}
+
+#[tokio::test]
+async fn the_access_report_includes_opens_and_clicks() {
+    let app = tracked_app().await;
+    let (issue_id, html) = publish(&app).await;
+    no_redirects().get(find_link(&app, &html, "/t/c/")).send().await.unwrap();
+
+    let report: serde_json::Value = app
+        .admin(Method::POST, "/privacy/access")
+        .json(&serde_json::json!({ "email": "carol@gmail.com" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(report["engagement"][0]["issue_id"], issue_id);
+    assert_eq!(report["engagement"][0]["kind"], "click");
+    assert_eq!(report["engagement"][0]["url"], "https://example.com/article?x=1&y=2");
+}
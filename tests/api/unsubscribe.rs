@@ -0,0 +1,118 @@
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{newsletter_for, spawn_app, TestApp};
+
+// publish to `lists` and return the unsubscribe link of the email that was sent
+async fn unsubscribe_link(app: &TestApp, lists: &[&str]) -> reqwest::Url {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(&newsletter_for(lists)).await.error_for_status().unwrap();
+    let requests = app.email_server.received_requests().await.unwrap();
+    app.get_confirmation_links(requests.last().unwrap()).plain_text
+}
+
+async fn memberships(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"SELECT lists.slug, list_memberships.status FROM list_memberships
+        JOIN lists ON lists.id = list_memberships.list_id ORDER BY lists.slug"#
+    )
+    .fetch_all(&app.connection_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|m| (m.slug, m.status))
+    .collect()
+}
+
+#[tokio::test]
+async fn the_link_in_a_newsletter_unsubscribes_from_its_list_only() {
+    let app = spawn_app().await;
+    app.create_list("engineering", "Engineering notes").await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com&list=engineering")
+        .await;
+
+    let link = unsubscribe_link(&app, &["engineering"]).await;
+    let response = reqwest::get(link.clone()).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        memberships(&app).await,
+        [
+            ("engineering".to_string(), "unsubscribed".to_string()),
+            ("newsletter".to_string(), "confirmed".to_string()),
+        ]
+    );
+    // clicking twice is fine
+    let response = reqwest::Client::new().post(link).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn unsubscribing_without_a_list_leaves_every_list() {
+    let app = spawn_app().await;
+    app.create_list("engineering", "Engineering notes").await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com&list=engineering")
+        .await;
+
+    // on both targeted lists, so the link leaves all of them
+    let link = unsubscribe_link(&app, &["newsletter", "engineering"]).await;
+    assert!(!link.query_pairs().any(|(k, _)| k == "list"));
+    reqwest::get(link).await.unwrap().error_for_status().unwrap();
+
+    assert!(memberships(&app).await.iter().all(|(_, status)| status == "unsubscribed"));
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(&newsletter_for(&["newsletter", "engineering"]))
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn the_list_in_an_unsubscribe_link_is_percent_encoded() {
+    let app = spawn_app().await;
+    // slugs from before they were checked could be anything
+    sqlx::query!("INSERT INTO lists (id, slug, name) VALUES (gen_random_uuid(), 'r&d=1', 'Research')")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com&list=r%26d%3D1")
+        .await;
+
+    let link = unsubscribe_link(&app, &["r&d=1"]).await;
+    assert!(link.as_str().ends_with("&list=r%26d%3D1"));
+    reqwest::get(link).await.unwrap().error_for_status().unwrap();
+
+    assert_eq!(
+        memberships(&app).await,
+        [
+            ("newsletter".to_string(), "confirmed".to_string()),
+            ("r&d=1".to_string(), "unsubscribed".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unsubscribe_links_must_be_signed() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    let link = unsubscribe_link(&app, &[]).await;
+    let token = link.query_pairs().find(|(k, _)| k == "token").unwrap().1.to_string();
+    let (_, rest) = token.split_once('.').unwrap();
+
+    let forged = format!("{}/subscriptions/unsubscribe?token={}.{}", app.address, uuid::Uuid::new_v4(), rest);
+    let response = reqwest::get(forged).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(memberships(&app).await[0].1, "confirmed");
+}